@@ -0,0 +1,172 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! The Client Agent accepts the connections of game clients.
+//!
+//! Clients connect over TCP, or over TLS if it is configured, and
+//! may also connect over WebSocket. The client protocol is not
+//! implemented yet, so the datagrams that clients send are dropped.
+
+use donet_core::globals::*;
+use donet_daemon::config;
+use donet_daemon::service::*;
+use donet_network::addr::PeerAddr;
+use donet_network::{tcp, ws};
+use donet_network::{Client, ClientEvent, ConnectionHandle};
+use log::{error, info, trace, warn};
+use std::io::Result;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+/// Capacity of the event channel of each connection.
+const CONNECTION_EVENT_QUEUE_SIZE: usize = 100;
+
+pub struct ClientAgent {
+    binding: Arc<Mutex<tcp::Acceptor>>,
+    /// Listener of WebSocket clients, until it is served.
+    ws_binding: Option<ws::Acceptor>,
+    /// Largest datagram accepted from each client.
+    max_frame_size: DgSizeTag,
+}
+
+impl DonetService for ClientAgent {
+    type Service = Self;
+    type Configuration = config::ClientAgent;
+
+    async fn create(
+        conf: Self::Configuration,
+        _: Option<DCFile<'static>>,
+    ) -> Result<Arc<Mutex<Self::Service>>> {
+        let binding: tcp::Acceptor = match &conf.tls {
+            Some(tls) => tcp::Acceptor::bind_tls(&conf.bind, tls.load()?).await?,
+            None => tcp::Acceptor::bind(&conf.bind).await?,
+        };
+        let ws_binding: Option<ws::Acceptor> = match &conf.ws_bind {
+            Some(ws_addr) => Some(ws::Acceptor::bind(ws_addr).await?),
+            None => None,
+        };
+
+        Ok(Arc::new(Mutex::new(ClientAgent {
+            binding: Arc::new(Mutex::new(binding)),
+            ws_binding,
+            max_frame_size: conf.max_frame_size.unwrap_or(DG_SIZE_MAX),
+        })))
+    }
+
+    async fn start(conf: config::DonetConfig, _: Option<DCFile<'static>>) -> Result<JoinHandle<Result<()>>> {
+        // We can unwrap safely here since this function only is called if it is `Some`.
+        let service_conf = conf.services.client_agent.expect("CA conf not found.");
+
+        let service = ClientAgent::create(service_conf, None).await?;
+
+        Ok(Self::spawn_async_task(
+            async move { ClientAgent::main(service).await },
+        ))
+    }
+
+    async fn main(service: Arc<Mutex<Self::Service>>) -> Result<()> {
+        let (binding, ws_binding, max_frame_size) = {
+            let mut locked_service = service.lock().await;
+            (
+                locked_service.binding.clone(),
+                locked_service.ws_binding.take(),
+                locked_service.max_frame_size,
+            )
+        };
+
+        if let Some(ws_binding) = ws_binding {
+            tokio::spawn(ClientAgent::ws_accept_loop(ws_binding, max_frame_size));
+        }
+        let binding_lock = binding.lock().await;
+
+        // start the main loop (accepting new TCP connections)
+        loop {
+            match binding_lock.accept().await {
+                Ok(incoming) => {
+                    let address: PeerAddr = incoming.address;
+                    info!("Received incoming connection from {}.", address);
+
+                    // the TLS handshake, if any, must not hold up accepting others
+                    tokio::spawn(async move {
+                        match incoming.handshake().await {
+                            Ok(client) => ClientAgent::new_connection(client, max_frame_size).await,
+                            Err(err) => warn!("Failed handshake with {}: {}", address, err),
+                        }
+                    });
+                }
+                Err(socket_err) => error!("Failed to get client: {}", socket_err),
+            }
+        }
+    }
+}
+
+impl ClientAgent {
+    /// Accepts the clients that connect over WebSocket.
+    async fn ws_accept_loop(binding: ws::Acceptor, max_frame_size: DgSizeTag) {
+        loop {
+            match binding.accept().await {
+                Ok(incoming) => {
+                    let address: PeerAddr = incoming.address.into();
+                    info!("Received incoming WebSocket connection from {}.", address);
+
+                    // the WebSocket handshake must not hold up accepting others
+                    tokio::spawn(async move {
+                        match incoming.handshake().await {
+                            Ok(client) => ClientAgent::new_connection(client, max_frame_size).await,
+                            Err(err) => warn!("Failed handshake with {}: {}", address, err),
+                        }
+                    });
+                }
+                Err(socket_err) => error!("Failed to get WebSocket client: {}", socket_err),
+            }
+        }
+    }
+
+    /// Spawns the tasks of a newly connected client.
+    async fn new_connection(mut client: Client, max_frame_size: DgSizeTag) {
+        client.set_max_frame_size(max_frame_size);
+
+        let (tx, rx) = mpsc::channel::<ClientEvent>(CONNECTION_EVENT_QUEUE_SIZE);
+        let _handle: ConnectionHandle = client.spawn_recv_send_tasks(tx).await;
+
+        tokio::spawn(ClientAgent::connection_loop(client, rx));
+    }
+
+    /// Handles the events of a client's connection, until it is closed.
+    ///
+    /// Owns the [`Client`], as dropping it closes the connection.
+    async fn connection_loop(client: Client, mut rx: mpsc::Receiver<ClientEvent>) {
+        while let Some(event) = rx.recv().await {
+            match event {
+                ClientEvent::Received(data) => {
+                    trace!(
+                        "Dropped datagram of {} bytes from {}.",
+                        data.dg.size(),
+                        data.remote
+                    );
+                }
+                ClientEvent::Disconnected(disconnect) => {
+                    info!("Client {} disconnected: {}", disconnect.remote, disconnect.reason);
+                }
+            }
+        }
+        drop(client);
+    }
+}
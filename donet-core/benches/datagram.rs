@@ -0,0 +1,144 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Benchmarks for the [`Datagram`] and [`DatagramIterator`] APIs.
+//!
+//! Each group compares the copying (compatibility) API against
+//! the zero-copy API backed by shared buffers.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use donet_core::datagram::datagram::Datagram;
+use donet_core::datagram::iterator::DatagramIterator;
+use donet_core::globals::*;
+
+/// Payload sizes, in bytes, to run each benchmark with.
+const PAYLOAD_SIZES: &[usize] = &[64, 1024, 16 * 1024, 60 * 1024];
+
+/// Number of subscribers a datagram is fanned out to.
+const FAN_OUT: usize = 64;
+
+fn blob_datagram(size: usize) -> Datagram {
+    let mut dg: Datagram = Datagram::default();
+    dg.add_blob(vec![0xab_u8; size]).unwrap();
+    dg
+}
+
+fn read_blob(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_blob");
+
+    for size in PAYLOAD_SIZES {
+        let dgi: DatagramIterator = blob_datagram(*size).into();
+
+        group.throughput(Throughput::Bytes(*size as u64));
+
+        group.bench_with_input(BenchmarkId::new("read_data", size), &dgi, |b, dgi| {
+            b.iter(|| {
+                let mut dgi: DatagramIterator = dgi.clone();
+                let len: DgSizeTag = dgi.read_size().unwrap();
+                black_box(dgi.read_data(len.into()).unwrap())
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("read_bytes", size), &dgi, |b, dgi| {
+            b.iter(|| {
+                let mut dgi: DatagramIterator = dgi.clone();
+                black_box(dgi.read_blob().unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn read_datagram(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_datagram");
+
+    for size in PAYLOAD_SIZES {
+        let dgi: DatagramIterator = blob_datagram(*size).into();
+
+        group.throughput(Throughput::Bytes(*size as u64));
+
+        // how a nested datagram had to be read before shared buffers
+        group.bench_with_input(BenchmarkId::new("copy", size), &dgi, |b, dgi| {
+            b.iter(|| {
+                let mut dgi: DatagramIterator = dgi.clone();
+                let len: DgSizeTag = dgi.read_size().unwrap();
+
+                let mut dg: Datagram = Datagram::default();
+                dg.add_data(dgi.read_data(len.into()).unwrap()).unwrap();
+                black_box(dg)
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("shared", size), &dgi, |b, dgi| {
+            b.iter(|| {
+                let mut dgi: DatagramIterator = dgi.clone();
+                black_box(dgi.read_datagram().unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fan_out");
+
+    for size in PAYLOAD_SIZES {
+        let owned: Datagram = blob_datagram(*size);
+
+        let mut frozen: Datagram = owned.clone();
+        frozen.freeze();
+
+        group.throughput(Throughput::Bytes((*size * FAN_OUT) as u64));
+
+        group.bench_with_input(BenchmarkId::new("owned", size), &owned, |b, dg| {
+            b.iter(|| {
+                for _ in 0..FAN_OUT {
+                    black_box(dg.clone());
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("shared", size), &frozen, |b, dg| {
+            b.iter(|| {
+                for _ in 0..FAN_OUT {
+                    black_box(dg.clone());
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn read_integers(c: &mut Criterion) {
+    let mut dg: Datagram = Datagram::default();
+
+    for i in 0..1024_u64 {
+        dg.add_u64(i).unwrap();
+    }
+    let dgi: DatagramIterator = dg.into();
+
+    c.bench_function("read_u64_x1024", |b| {
+        b.iter(|| {
+            let mut dgi: DatagramIterator = dgi.clone();
+            for _ in 0..1024 {
+                black_box(dgi.read_u64().unwrap());
+            }
+        })
+    });
+}
+
+criterion_group!(benches, read_blob, read_datagram, fan_out, read_integers);
+criterion_main!(benches);
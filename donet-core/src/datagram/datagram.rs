@@ -18,10 +18,16 @@
 */
 
 //! Provides structure to write network packets (datagrams).
+//!
+//! A [`Datagram`] is backed by a reference-counted byte buffer from
+//! the [`bytes`] crate. A datagram that is being written to owns a
+//! mutable buffer, while a datagram made from received bytes (or
+//! frozen with [`Datagram::freeze`]) shares its buffer, so cloning
+//! and slicing it does not copy the payload.
 
-use crate::datagram::byte_order as endianness;
 use crate::globals::*;
 use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
 use thiserror::Error;
 
 /// Custom error type for [`Datagram`].
@@ -39,10 +45,30 @@ impl From<DatagramError> for std::io::Error {
     }
 }
 
+/// Byte storage of a [`Datagram`].
+///
+/// Writing to a [`Buffer::Shared`] buffer moves it into a mutable
+/// buffer first, which only copies the bytes if the shared buffer
+/// is still referenced elsewhere.
+#[derive(Debug, Clone)]
+enum Buffer {
+    Owned(BytesMut),
+    Shared(Bytes),
+}
+
+impl Buffer {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Owned(buf) => buf,
+            Self::Shared(buf) => buf,
+        }
+    }
+}
+
 /// Representation of a new network message (datagram) to be sent.
 #[derive(Debug, Clone)]
 pub struct Datagram {
-    buffer: Vec<u8>,
+    buffer: Buffer,
     index: usize,
     /// See [`Datagram::override_cap`].
     cap: usize,
@@ -51,13 +77,36 @@ pub struct Datagram {
 impl Default for Datagram {
     fn default() -> Self {
         Self {
-            buffer: vec![],
+            buffer: Buffer::Owned(BytesMut::new()),
             index: 0,
             cap: usize::from(DgSizeTag::MAX),
         }
     }
 }
 
+/// Creates a [`Datagram`] that shares the given buffer.
+///
+/// No bytes are copied. The size cap of the new datagram is raised
+/// to the size of the buffer if it is larger than the default cap.
+impl From<Bytes> for Datagram {
+    fn from(value: Bytes) -> Self {
+        let size: usize = value.len();
+
+        Self {
+            buffer: Buffer::Shared(value),
+            index: size,
+            cap: std::cmp::max(size, usize::from(DgSizeTag::MAX)),
+        }
+    }
+}
+
+/// Consumes the [`Datagram`] and returns its buffer as [`Bytes`].
+impl From<Datagram> for Bytes {
+    fn from(value: Datagram) -> Self {
+        value.into_bytes()
+    }
+}
+
 /// Appends another datagram's raw bytes to this datagram.
 ///
 /// Consumes the right-hand-side [`Datagram`].
@@ -65,9 +114,7 @@ impl std::ops::Add for Datagram {
     type Output = Result<Datagram, DatagramError>;
 
     fn add(mut self, rhs: Self) -> Self::Output {
-        let dg_buffer: Vec<u8> = rhs.get_data();
-
-        self.add_data(dg_buffer)?;
+        self.add_data(rhs.get_buffer())?;
         Ok(self)
     }
 }
@@ -85,6 +132,21 @@ impl Datagram {
         Ok(())
     }
 
+    /// Returns the mutable buffer of this datagram, moving a shared
+    /// buffer into a new mutable buffer if needed.
+    fn buffer_mut(&mut self) -> &mut BytesMut {
+        if let Buffer::Shared(shared) = &mut self.buffer {
+            let shared: Bytes = std::mem::take(shared);
+
+            // Only copies if the shared buffer has other references.
+            self.buffer = Buffer::Owned(BytesMut::from(shared));
+        }
+        match &mut self.buffer {
+            Buffer::Owned(buf) => buf,
+            Buffer::Shared(_) => unreachable!(),
+        }
+    }
+
     /// Overrides the byte limit for this [`Datagram`].
     ///
     /// It should **always** be set to the MAX of the size
@@ -110,54 +172,31 @@ impl Datagram {
     /// Adds an unsigned 8-bit integer value to the datagram.
     pub fn add_u8(&mut self, v: u8) -> Result<(), DatagramError> {
         self.check_add_length(1)?;
-        self.buffer.push(v);
+        self.buffer_mut().put_u8(v);
         self.index += 1;
         Ok(())
     }
 
     /// Adds an unsigned 16-bit integer value to the datagram.
-    pub fn add_u16(&mut self, mut v: u16) -> Result<(), DatagramError> {
+    pub fn add_u16(&mut self, v: u16) -> Result<(), DatagramError> {
         self.check_add_length(2)?;
-
-        v = endianness::swap_le_16(v);
-
-        self.buffer.push((v & 0x00ff) as u8);
-        self.buffer.push(((v & 0xff00) >> 8) as u8);
-
+        self.buffer_mut().put_u16_le(v);
         self.index += 2;
         Ok(())
     }
 
     /// Adds an unsigned 32-bit integer value to the datagram.
-    pub fn add_u32(&mut self, mut v: u32) -> Result<(), DatagramError> {
+    pub fn add_u32(&mut self, v: u32) -> Result<(), DatagramError> {
         self.check_add_length(4)?;
-
-        v = endianness::swap_le_32(v);
-
-        self.buffer.push((v & 0x000000ff) as u8);
-        self.buffer.push(((v & 0x0000ff00) >> 8) as u8);
-        self.buffer.push(((v & 0x00ff0000) >> 16) as u8);
-        self.buffer.push(((v & 0xff000000) >> 24) as u8);
-
+        self.buffer_mut().put_u32_le(v);
         self.index += 4;
         Ok(())
     }
 
     /// Adds an unsigned 64-bit integer value to the datagram.
-    pub fn add_u64(&mut self, mut v: u64) -> Result<(), DatagramError> {
+    pub fn add_u64(&mut self, v: u64) -> Result<(), DatagramError> {
         self.check_add_length(8)?;
-
-        v = endianness::swap_le_64(v);
-
-        self.buffer.push((v & 0x00000000000000ff) as u8);
-        self.buffer.push(((v & 0x000000000000ff00) >> 8) as u8);
-        self.buffer.push(((v & 0x0000000000ff0000) >> 16) as u8);
-        self.buffer.push(((v & 0x00000000ff000000) >> 24) as u8);
-        self.buffer.push(((v & 0x000000ff00000000) >> 32) as u8);
-        self.buffer.push(((v & 0x0000ff0000000000) >> 40) as u8);
-        self.buffer.push(((v & 0x00ff000000000000) >> 48) as u8);
-        self.buffer.push(((v & 0xff00000000000000) >> 56) as u8);
-
+        self.buffer_mut().put_u64_le(v);
         self.index += 8;
         Ok(())
     }
@@ -186,13 +225,13 @@ impl Datagram {
     /// 32-bit IEEE 754 floating point. same bitwise operations.
     #[inline(always)]
     pub fn add_f32(&mut self, v: f32) -> Result<(), DatagramError> {
-        self.add_u32(v as u32)
+        self.add_u32(v.to_bits())
     }
 
     /// 64-bit IEEE 754 floating point. same bitwise operations.
     #[inline(always)]
     pub fn add_f64(&mut self, v: f64) -> Result<(), DatagramError> {
-        self.add_u64(v as u64)
+        self.add_u64(v.to_bits())
     }
 
     /// Adds a Datagram / Field length tag to the end of the datagram.
@@ -226,16 +265,18 @@ impl Datagram {
         self.add_u32(zone)
     }
 
-    /// Adds raw bytes to the datagram via an unsigned 8-bit integer vector.
+    /// Adds raw bytes to the datagram, such as an unsigned 8-bit
+    /// integer vector, a byte slice, or a [`Bytes`] buffer.
     ///
     /// **NOTE**: not to be confused with [`Datagram::add_blob`], which
     /// adds a dclass blob to the datagram.
     ///
-    pub fn add_data(&mut self, mut bytes: Vec<u8>) -> Result<(), DatagramError> {
+    pub fn add_data<B: AsRef<[u8]>>(&mut self, bytes: B) -> Result<(), DatagramError> {
+        let bytes: &[u8] = bytes.as_ref();
         let size: usize = bytes.len();
 
         self.check_add_length(size)?;
-        self.buffer.append(&mut bytes);
+        self.buffer_mut().extend_from_slice(bytes);
 
         self.index += size;
         Ok(())
@@ -255,12 +296,9 @@ impl Datagram {
         // Add string length to the datagram
         self.add_u16(size.try_into().expect("String size should fit u16."))?;
 
-        // convert the string into a byte array, as a vector
-        let mut str_bytes: Vec<u8> = str.as_bytes().to_vec();
-
         // make sure the byte array won't overflow the datagram
-        self.check_add_length(str_bytes.len())?;
-        self.buffer.append(&mut str_bytes);
+        self.check_add_length(size)?;
+        self.buffer_mut().extend_from_slice(str.as_bytes());
 
         self.index += size;
         Ok(())
@@ -268,7 +306,8 @@ impl Datagram {
 
     /// Adds a dclass blob value (binary data) to the end of the datagram.
     /// A 16-bit length tag prefix with the blob's size in bytes is added.
-    pub fn add_blob(&mut self, mut bytes: Vec<u8>) -> Result<(), DatagramError> {
+    pub fn add_blob<B: AsRef<[u8]>>(&mut self, bytes: B) -> Result<(), DatagramError> {
+        let bytes: &[u8] = bytes.as_ref();
         let size: usize = bytes.len();
 
         // add blob size in bytes
@@ -283,7 +322,7 @@ impl Datagram {
 
         // manually check add length before appending byte array
         self.check_add_length(size)?;
-        self.buffer.append(&mut bytes);
+        self.buffer_mut().extend_from_slice(bytes);
 
         self.index += size;
         Ok(())
@@ -296,9 +335,7 @@ impl Datagram {
         // get start length (before push)
         let start: usize = self.index;
 
-        for _ in 1..size {
-            self.buffer.push(0);
-        }
+        self.buffer_mut().put_bytes(0, size.saturating_sub(1));
         self.index += size;
         Ok(start)
     }
@@ -359,24 +396,60 @@ impl Datagram {
 
     /// Returns the size of this [`Datagram`].
     pub fn size(&self) -> usize {
-        self.buffer.len()
+        self.buffer.as_slice().len()
     }
 
     /// Returns a reference to this [`Datagram`]'s byte buffer.
     pub fn get_buffer(&self) -> &[u8] {
-        &self.buffer
+        self.buffer.as_slice()
     }
 
     /// Similar to [`Self::get_buffer`], but returns a copy of the buffer.
+    ///
+    /// Prefer [`Self::get_bytes`] or [`Self::into_bytes`], which do not
+    /// copy the datagram's payload.
     pub fn get_data(&self) -> Vec<u8> {
-        // we can't give out ownership of our vector,
-        // so a copy of the vector is made instead
-        let mut vec_copy: Vec<u8> = vec![];
-        for byte in &self.buffer {
-            // dereference the borrowed 'byte'
-            vec_copy.push(*byte);
+        self.buffer.as_slice().to_vec()
+    }
+
+    /// Returns a [`Bytes`] handle to this datagram's buffer.
+    ///
+    /// This is a cheap reference count increment if the datagram is
+    /// shared. Otherwise, the buffer is frozen first; see [`Self::freeze`].
+    pub fn get_bytes(&mut self) -> Bytes {
+        self.freeze();
+
+        match &self.buffer {
+            Buffer::Shared(buf) => buf.clone(),
+            Buffer::Owned(_) => unreachable!(),
         }
-        vec_copy
+    }
+
+    /// Consumes this [`Datagram`] and returns its buffer as [`Bytes`]
+    /// without copying it.
+    pub fn into_bytes(self) -> Bytes {
+        match self.buffer {
+            Buffer::Owned(buf) => buf.freeze(),
+            Buffer::Shared(buf) => buf,
+        }
+    }
+
+    /// Converts this datagram's buffer into a shared, reference-counted
+    /// buffer, so that clones of this datagram do not copy its payload.
+    ///
+    /// Datagrams that are routed to many receivers should be frozen
+    /// once before being cloned. Adding data to a frozen datagram is
+    /// still allowed, but it will move the bytes to a new buffer.
+    pub fn freeze(&mut self) {
+        if let Buffer::Owned(buf) = &mut self.buffer {
+            let owned: BytesMut = std::mem::take(buf);
+            self.buffer = Buffer::Shared(owned.freeze());
+        }
+    }
+
+    /// Returns `true` if this datagram's buffer is shared.
+    pub fn is_shared(&self) -> bool {
+        matches!(self.buffer, Buffer::Shared(_))
     }
 }
 
@@ -502,6 +575,39 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn shared_buffer_clone() {
+        let mut dg: Datagram = Datagram::default();
+
+        assert!(dg.add_channel(CHANNEL_MAX).is_ok());
+        assert!(!dg.is_shared());
+
+        dg.freeze();
+        assert!(dg.is_shared());
+
+        // a clone of a frozen datagram must reference the same buffer
+        let dg_clone: Datagram = dg.clone();
+
+        assert_eq!(dg.get_buffer().as_ptr(), dg_clone.get_buffer().as_ptr());
+        assert_eq!(dg.get_bytes().as_ptr(), dg_clone.into_bytes().as_ptr());
+    }
+
+    #[test]
+    fn shared_buffer_copy_on_write() {
+        let bytes: Bytes = Bytes::from_static(&[1, 2, 3]);
+        let mut dg: Datagram = Datagram::from(bytes.clone());
+
+        assert_eq!(dg.size(), 3);
+        assert_eq!(dg.get_buffer().as_ptr(), bytes.as_ptr());
+
+        // writing to a shared datagram must not modify the shared buffer
+        assert!(dg.add_u8(4).is_ok());
+
+        assert!(!dg.is_shared());
+        assert_eq!(dg.get_buffer(), &[1, 2, 3, 4]);
+        assert_eq!(bytes, Bytes::from_static(&[1, 2, 3]));
+    }
+
     #[test]
     fn overflow_test() {
         let mut dg: Datagram = Datagram::default();
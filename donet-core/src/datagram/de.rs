@@ -0,0 +1,498 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Provides a [`serde`] deserializer that reads Rust values from a
+//! [`DatagramIterator`] using the Donet wire format.
+//!
+//! See the [`super::ser`] module for the encoding rules. As the wire
+//! format is not self-describing, types that rely on
+//! [`serde::Deserializer::deserialize_any`] are not supported.
+
+use super::datagram::Datagram;
+use super::iterator::{DatagramIterator, IteratorError};
+use crate::globals::DgSizeTag;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use thiserror::Error;
+
+/// Custom error type for [`Deserializer`].
+#[derive(Debug, Error, PartialEq)]
+pub enum DeserializeError {
+    #[error("iterator error; {0}")]
+    IteratorError(#[from] IteratorError),
+    /// This error kind is returned when the elements of a sequence
+    /// or map do not add up to the size given by its length tag.
+    #[error("length tag does not match the size of its elements")]
+    LengthMismatch,
+    /// This error kind is returned by [`from_datagram`] when bytes
+    /// are left in the datagram after reading the value.
+    #[error("{0} trailing bytes after value")]
+    TrailingBytes(usize),
+    #[error("unsupported; {0}")]
+    Unsupported(&'static str),
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl de::Error for DeserializeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+/// Deserializes a value from the current position of a
+/// [`DatagramIterator`], leaving the iterator after the value.
+pub fn from_iterator<T>(dgi: &mut DatagramIterator) -> Result<T, DeserializeError>
+where
+    T: DeserializeOwned,
+{
+    T::deserialize(&mut Deserializer::new(dgi))
+}
+
+/// Deserializes a value from a [`Datagram`].
+///
+/// Returns [`DeserializeError::TrailingBytes`] if the datagram holds
+/// more data than the value that was read.
+pub fn from_datagram<T>(dg: Datagram) -> Result<T, DeserializeError>
+where
+    T: DeserializeOwned,
+{
+    let mut dgi: DatagramIterator = dg.into();
+    let value: T = from_iterator(&mut dgi)?;
+
+    match dgi.get_remaining() {
+        0 => Ok(value),
+        n => Err(DeserializeError::TrailingBytes(n)),
+    }
+}
+
+/// A [`serde::Deserializer`] that reads values from a [`DatagramIterator`].
+pub struct Deserializer<'a> {
+    dgi: &'a mut DatagramIterator,
+}
+
+impl<'a> Deserializer<'a> {
+    pub fn new(dgi: &'a mut DatagramIterator) -> Self {
+        Self { dgi }
+    }
+
+    /// Reads a 16-bit length tag and returns the index
+    /// in the datagram where the tagged elements end.
+    fn read_end_index(&mut self) -> Result<usize, DeserializeError> {
+        let size: DgSizeTag = self.dgi.read_size()?;
+        let end: usize = self.dgi.tell() + usize::from(size);
+
+        self.dgi.check_read_length(usize::from(size))?;
+        Ok(end)
+    }
+
+    /// Checks that the elements read after a length tag ended exactly
+    /// at the index given by [`Self::read_end_index`].
+    fn check_end_index(&mut self, end: usize) -> Result<(), DeserializeError> {
+        match self.dgi.tell() == end {
+            true => Ok(()),
+            false => Err(DeserializeError::LengthMismatch),
+        }
+    }
+}
+
+impl<'de, 'a, 'b> de::Deserializer<'de> for &'a mut Deserializer<'b> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, DeserializeError> {
+        Err(DeserializeError::Unsupported(
+            "deserialize_any; the Donet wire format is not self-describing",
+        ))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_bool(self.dgi.read_bool()?)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_i8(self.dgi.read_i8()?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_i16(self.dgi.read_i16()?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_i32(self.dgi.read_i32()?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_i64(self.dgi.read_i64()?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_u8(self.dgi.read_u8()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_u16(self.dgi.read_u16()?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_u32(self.dgi.read_u32()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_u64(self.dgi.read_u64()?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_f32(self.dgi.read_f32()?)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_f64(self.dgi.read_f64()?)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_char(char::from(self.dgi.read_u8()?))
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_string(self.dgi.read_string()?)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_byte_buf(self.dgi.read_blob()?.to_vec())
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        match self.dgi.read_u8()? {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(self),
+            _ => Err(IteratorError::InvalidRead("Invalid option tag.").into()),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        let end: usize = self.read_end_index()?;
+        let value: V::Value = visitor.visit_seq(LengthPrefixed { de: self, end })?;
+
+        self.check_end_index(end)?;
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        visitor.visit_seq(Fixed { de: self, len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        let end: usize = self.read_end_index()?;
+        let value: V::Value = visitor.visit_map(LengthPrefixed { de: self, end })?;
+
+        self.check_end_index(end)?;
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, DeserializeError> {
+        Err(DeserializeError::Unsupported(
+            "deserialize_identifier; field names are not encoded",
+        ))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, DeserializeError> {
+        Err(DeserializeError::Unsupported(
+            "deserialize_ignored_any; the Donet wire format is not self-describing",
+        ))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl<'de, 'a, 'b> de::EnumAccess<'de> for &'a mut Deserializer<'b> {
+    type Error = DeserializeError;
+    type Variant = Self;
+
+    fn variant_seed<S>(self, seed: S) -> Result<(S::Value, Self), DeserializeError>
+    where
+        S: DeserializeSeed<'de>,
+    {
+        let index: u32 = self.dgi.read_u8()?.into();
+        let variant: S::Value =
+            seed.deserialize(IntoDeserializer::<DeserializeError>::into_deserializer(index))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a, 'b> de::VariantAccess<'de> for &'a mut Deserializer<'b> {
+    type Error = DeserializeError;
+
+    fn unit_variant(self) -> Result<(), DeserializeError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<S>(self, seed: S) -> Result<S::Value, DeserializeError>
+    where
+        S: DeserializeSeed<'de>,
+    {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, DeserializeError> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeserializeError> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+/// Reads a known number of elements, for tuples and structs.
+struct Fixed<'a, 'b> {
+    de: &'a mut Deserializer<'b>,
+    len: usize,
+}
+
+impl<'de, 'a, 'b> de::SeqAccess<'de> for Fixed<'a, 'b> {
+    type Error = DeserializeError;
+
+    fn next_element_seed<S>(&mut self, seed: S) -> Result<Option<S::Value>, DeserializeError>
+    where
+        S: DeserializeSeed<'de>,
+    {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+/// Reads elements until the index given by a length tag
+/// is reached, for sequences and maps.
+struct LengthPrefixed<'a, 'b> {
+    de: &'a mut Deserializer<'b>,
+    end: usize,
+}
+
+impl<'a, 'b> LengthPrefixed<'a, 'b> {
+    fn has_next(&mut self) -> Result<bool, DeserializeError> {
+        let index: usize = self.de.dgi.tell();
+
+        match index.cmp(&self.end) {
+            std::cmp::Ordering::Less => Ok(true),
+            std::cmp::Ordering::Equal => Ok(false),
+            std::cmp::Ordering::Greater => Err(DeserializeError::LengthMismatch),
+        }
+    }
+}
+
+impl<'de, 'a, 'b> de::SeqAccess<'de> for LengthPrefixed<'a, 'b> {
+    type Error = DeserializeError;
+
+    fn next_element_seed<S>(&mut self, seed: S) -> Result<Option<S::Value>, DeserializeError>
+    where
+        S: DeserializeSeed<'de>,
+    {
+        if !self.has_next()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+impl<'de, 'a, 'b> de::MapAccess<'de> for LengthPrefixed<'a, 'b> {
+    type Error = DeserializeError;
+
+    fn next_key_seed<S>(&mut self, seed: S) -> Result<Option<S::Value>, DeserializeError>
+    where
+        S: DeserializeSeed<'de>,
+    {
+        if !self.has_next()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<S>(&mut self, seed: S) -> Result<S::Value, DeserializeError>
+    where
+        S: DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datagram::ser::to_datagram;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Avatar {
+        do_id: u32,
+        name: String,
+        position: (f32, f32, f32),
+        dna: Vec<u8>,
+        friends: Vec<u32>,
+        guild: Option<String>,
+        flags: [bool; 2],
+        stats: BTreeMap<u8, i16>,
+        state: State,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum State {
+        Idle,
+        Moving(u16),
+        Fighting { target: u32, hp: i8 },
+    }
+
+    fn avatar() -> Avatar {
+        Avatar {
+            do_id: 100_000_001,
+            name: String::from("Flippy"),
+            position: (-1.5, 0.25, 1024.0),
+            dna: vec![0xAA, 0xBB, 0xCC],
+            friends: vec![100_000_002, 100_000_003],
+            guild: None,
+            flags: [true, false],
+            stats: BTreeMap::from([(0, -5), (1, 300)]),
+            state: State::Fighting { target: 7, hp: -1 },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let value: Avatar = avatar();
+        let dg: Datagram = to_datagram(&value).unwrap();
+
+        assert_eq!(from_datagram::<Avatar>(dg), Ok(value));
+
+        for state in [State::Idle, State::Moving(u16::MAX)] {
+            let dg: Datagram = to_datagram(&state).unwrap();
+            assert_eq!(from_datagram::<State>(dg), Ok(state));
+        }
+    }
+
+    #[test]
+    fn read_after_header() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_u16(1337).unwrap();
+        dg = (dg + to_datagram(&avatar()).unwrap()).unwrap();
+        dg.add_u8(0xFF).unwrap();
+
+        let mut dgi: DatagramIterator = dg.into();
+        assert_eq!(dgi.read_u16(), Ok(1337));
+        assert_eq!(from_iterator::<Avatar>(&mut dgi), Ok(avatar()));
+        assert_eq!(dgi.read_u8(), Ok(0xFF));
+    }
+
+    #[test]
+    fn deserialize_errors() {
+        // Not enough bytes for a u32.
+        let dg: Datagram = to_datagram(&1_u16).unwrap();
+        assert_eq!(
+            from_datagram::<u32>(dg),
+            Err(DeserializeError::IteratorError(IteratorError::EndOfFile))
+        );
+
+        // Bytes left over after the value.
+        let dg: Datagram = to_datagram(&1_u32).unwrap();
+        assert_eq!(from_datagram::<u16>(dg), Err(DeserializeError::TrailingBytes(2)));
+
+        // Array length tag that does not line up with its elements.
+        let mut dg: Datagram = Datagram::default();
+        dg.add_blob([0, 0, 0]).unwrap();
+        dg.add_u8(0).unwrap();
+        assert_eq!(
+            from_datagram::<Vec<u16>>(dg),
+            Err(DeserializeError::LengthMismatch)
+        );
+
+        // Invalid option tag.
+        let mut dg: Datagram = Datagram::default();
+        dg.add_u8(2).unwrap();
+        assert!(from_datagram::<Option<u8>>(dg).is_err());
+    }
+}
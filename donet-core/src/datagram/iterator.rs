@@ -18,14 +18,17 @@
 */
 
 //! Provides structure for iterating over network packets (datagrams).
+//!
+//! A [`DatagramIterator`] reads from a shared [`Bytes`] buffer, so
+//! blobs and nested datagrams can be read as slices of the original
+//! buffer without copying their payload.
 
 use super::datagram::{Datagram, DatagramError};
-use crate::datagram::byte_order as endianness;
 use crate::globals::*;
 use crate::protocol::*;
+use bytes::Bytes;
 use std::mem;
 use std::string::FromUtf8Error;
-use strum::IntoEnumIterator;
 use thiserror::Error;
 
 /// Custom error type for [`DatagramIterator`].
@@ -62,17 +65,29 @@ impl From<IteratorError> for std::io::Error {
 }
 
 /// Utility for iterating value by value of a datagram message.
-#[derive(Debug)]
+///
+/// Cloning a [`DatagramIterator`] is cheap, as it only increments
+/// the reference count of the underlying buffer.
+#[derive(Debug, Clone)]
 pub struct DatagramIterator {
-    datagram: Datagram,
+    buffer: Bytes,
     index: usize,
 }
 
 /// Create a new [`DatagramIterator`] from a [`Datagram`].
+///
+/// The datagram's buffer is frozen, not copied.
 impl From<Datagram> for DatagramIterator {
     fn from(value: Datagram) -> Self {
+        value.into_bytes().into()
+    }
+}
+
+/// Create a new [`DatagramIterator`] from a shared [`Bytes`] buffer.
+impl From<Bytes> for DatagramIterator {
+    fn from(value: Bytes) -> Self {
         Self {
-            datagram: value,
+            buffer: value,
             index: 0,
         }
     }
@@ -82,7 +97,7 @@ impl DatagramIterator {
     pub fn check_read_length(&mut self, bytes: usize) -> Result<(), IteratorError> {
         let new_index: usize = self.index + bytes;
 
-        if new_index > self.datagram.size() {
+        if new_index > self.buffer.len() {
             return Err(IteratorError::EndOfFile);
         }
         Ok(())
@@ -110,97 +125,81 @@ impl DatagramIterator {
 
     /// Returns the number of unread bytes left in the datagram
     pub fn get_remaining(&mut self) -> usize {
-        self.datagram.size() - self.index
+        self.buffer.len() - self.index
+    }
+
+    /// Returns a handle to the entire underlying buffer,
+    /// regardless of the current read position.
+    pub fn get_bytes(&self) -> Bytes {
+        self.buffer.clone()
     }
 
     /// Reads the next number of bytes in the datagram.
+    ///
+    /// Returns a copy of the bytes read. To read without
+    /// copying, use [`Self::read_bytes`] instead.
     pub fn read_data(&mut self, bytes: usize) -> Result<Vec<u8>, IteratorError> {
-        self.check_read_length(bytes)?;
-        let data: Vec<u8> = self.datagram.get_data();
+        self.read_bytes(bytes).map(|data| data.to_vec())
+    }
 
-        let mut new_data: Vec<u8> = vec![];
-        let read_end: usize = self.index + bytes;
+    /// Reads the next number of bytes in the datagram as a
+    /// slice of the underlying buffer. Does not copy the bytes.
+    pub fn read_bytes(&mut self, bytes: usize) -> Result<Bytes, IteratorError> {
+        self.check_read_length(bytes)?;
 
-        for item in data.iter().take(read_end).skip(self.index) {
-            new_data.push(*item);
-        }
+        let data: Bytes = self.buffer.slice(self.index..self.index + bytes);
         self.index += bytes;
 
-        Ok(new_data)
+        Ok(data)
+    }
+
+    /// Reads all the remaining bytes in the datagram as a
+    /// slice of the underlying buffer. Does not copy the bytes.
+    pub fn read_remaining(&mut self) -> Bytes {
+        let data: Bytes = self.buffer.slice(self.index..);
+        self.index = self.buffer.len();
+        data
     }
 
     pub fn read_u8(&mut self) -> Result<u8, IteratorError> {
         self.check_read_length(1)?;
-        let data: Vec<u8> = self.datagram.get_data();
 
-        match data.get(self.index) {
-            Some(v) => {
-                self.index += 1; // bytes
-                Ok(*v)
-            }
-            None => Err(IteratorError::EndOfFile),
-        }
+        let value: u8 = self.buffer[self.index];
+        self.index += 1; // bytes
+
+        Ok(value)
     }
 
     pub fn read_u16(&mut self) -> Result<u16, IteratorError> {
         self.check_read_length(2)?;
-        let data: Vec<u8> = self.datagram.get_data();
-
-        // bitwise operations to concatenate two u8's into one u16.
-        // graphical explanation:
-        //      a0   (byte 1; 0x28)     b0   (byte 2; 0x23)
-        //      00101000                01000110
-        //
-        //      NOTE: Turns out rust casts these in big-endian
-        //
-        //      [ a1 = a0 as u16 ]      [ b1 = b0 as u16 ]
-        //      00000000 00101000       00000000 01000110
-        //
-        //          v v v v v           [ b2 = b1 << 8 ]
-        //                              01000110 00000000
-        //
-        //              00000000 00101000 = a1
-        //          OR  01000110 00000000 = b2
-        //
-        //              01000110 00101000  (u16, 2 bytes; 0x2328; 9000 decimal)
-        //
-        //  After, we use the swap_le_xx() function to make sure the bytes
-        //  are swapped to the native system byte endianness.
-        //
-        let value: u16 = (data[self.index] as u16) | ((data[self.index + 1] as u16) << 8);
+        let data: &[u8] = &self.buffer[self.index..self.index + 2];
+
+        // Datagrams are always little-endian, so we
+        // convert the bytes to the native byte order.
+        let value: u16 = u16::from_le_bytes(data.try_into().expect("Slice should be 2 bytes."));
         self.index += 2;
 
-        Ok(endianness::swap_le_16(value))
+        Ok(value)
     }
 
     pub fn read_u32(&mut self) -> Result<u32, IteratorError> {
         self.check_read_length(4)?;
-        let data: Vec<u8> = self.datagram.get_data();
-
-        let value: u32 = (data[self.index] as u32)
-            | ((data[self.index + 1] as u32) << 8)
-            | ((data[self.index + 2] as u32) << 16)
-            | ((data[self.index + 3] as u32) << 24);
+        let data: &[u8] = &self.buffer[self.index..self.index + 4];
 
+        let value: u32 = u32::from_le_bytes(data.try_into().expect("Slice should be 4 bytes."));
         self.index += 4;
-        Ok(endianness::swap_le_32(value))
+
+        Ok(value)
     }
 
     pub fn read_u64(&mut self) -> Result<u64, IteratorError> {
         self.check_read_length(8)?;
-        let data: Vec<u8> = self.datagram.get_data();
-
-        let value: u64 = (data[self.index] as u64)
-            | ((data[self.index + 1] as u64) << 8)
-            | ((data[self.index + 2] as u64) << 16)
-            | ((data[self.index + 3] as u64) << 24)
-            | ((data[self.index + 4] as u64) << 32)
-            | ((data[self.index + 5] as u64) << 40)
-            | ((data[self.index + 6] as u64) << 48)
-            | ((data[self.index + 7] as u64) << 56);
+        let data: &[u8] = &self.buffer[self.index..self.index + 8];
 
+        let value: u64 = u64::from_le_bytes(data.try_into().expect("Slice should be 8 bytes."));
         self.index += 8;
-        Ok(endianness::swap_le_64(value))
+
+        Ok(value)
     }
 
     // Signed integer aliases, same read operation.
@@ -227,13 +226,13 @@ impl DatagramIterator {
     /// 32-bit IEEE 754 floating point in native endianness.
     #[inline]
     pub fn read_f32(&mut self) -> Result<f32, IteratorError> {
-        self.read_u32().map(|v| v as f32)
+        self.read_u32().map(f32::from_bits)
     }
 
     /// 64-bit IEEE 754 floating point in native endianness.
     #[inline]
     pub fn read_f64(&mut self) -> Result<f64, IteratorError> {
-        self.read_u64().map(|v| v as f64)
+        self.read_u64().map(f64::from_bits)
     }
 
     #[inline]
@@ -281,17 +280,22 @@ impl DatagramIterator {
     }
 
     /// Reads a `blob` data type and returns a [`Datagram`].
+    ///
+    /// The returned datagram shares this iterator's buffer.
     pub fn read_datagram(&mut self) -> Result<Datagram, IteratorError> {
         let dg_size: DgSizeTag = self.read_size()?;
 
-        let dg_payload: Vec<u8> = self.read_data(usize::from(dg_size))?;
+        let dg_payload: Bytes = self.read_bytes(usize::from(dg_size))?;
 
-        let mut new_dg: Datagram = Datagram::default();
+        Ok(Datagram::from(dg_payload))
+    }
 
-        if let Err(e) = new_dg.add_data(dg_payload) {
-            return Err(IteratorError::DatagramError(e));
-        }
-        Ok(new_dg)
+    /// Reads a `blob` data type as a slice of the underlying buffer.
+    /// Does not copy the blob's bytes.
+    pub fn read_blob(&mut self) -> Result<Bytes, IteratorError> {
+        let blob_size: DgSizeTag = self.read_size()?;
+
+        self.read_bytes(usize::from(blob_size))
     }
 
     /// Get the recipient count in a datagram message.
@@ -306,15 +310,8 @@ impl DatagramIterator {
     pub fn read_msg_type(&mut self) -> Result<Protocol, IteratorError> {
         let msg_type: MsgType = self.read_u16()?; // read message type
 
-        for message in Protocol::iter() {
-            let msg_id: MsgType = message.into();
-            if msg_type == msg_id {
-                return Ok(message);
-            }
-        }
-        Err(IteratorError::InvalidRead(
-            "Tried to read an invalid message type.",
-        ))
+        Protocol::try_from(msg_type)
+            .map_err(|_| IteratorError::InvalidRead("Tried to read an invalid message type."))
     }
 
     /// Get the recipient count in a datagram message.
@@ -339,16 +336,8 @@ impl DatagramIterator {
         let msg_type: MsgType = self.read_u16()?; // read message type
         self.index = start_index; // do not advance dgi index
 
-        for message in Protocol::iter() {
-            let msg_id: MsgType = message.into();
-
-            if msg_type == msg_id {
-                return Ok(message);
-            }
-        }
-        Err(IteratorError::InvalidRead(
-            "Tried to read an invalid message type.",
-        ))
+        Protocol::try_from(msg_type)
+            .map_err(|_| IteratorError::InvalidRead("Tried to read an invalid message type."))
     }
 }
 
@@ -451,6 +440,32 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn dgi_zero_copy_reads() -> Result<(), IteratorError> {
+        let mut dg: Datagram = Datagram::default();
+        let mut nested: Datagram = Datagram::default();
+
+        assert!(nested.add_u32(DOID_MAX).is_ok());
+        assert!(dg.add_blob(nested.get_buffer()).is_ok());
+        assert!(dg.add_blob(vec![1, 2, 3]).is_ok());
+
+        let mut dgi: DatagramIterator = dg.into();
+        let buffer: Bytes = dgi.get_bytes();
+
+        // both reads should be slices of the iterator's buffer
+        let res_dg: Datagram = dgi.read_datagram()?;
+        let res_blob: Bytes = dgi.read_blob()?;
+
+        assert!(res_dg.is_shared());
+        assert_eq!(res_dg.get_buffer(), nested.get_buffer());
+        assert_eq!(res_dg.get_buffer().as_ptr(), buffer[2..].as_ptr());
+
+        assert_eq!(res_blob, Bytes::from_static(&[1, 2, 3]));
+        assert_eq!(res_blob.as_ptr(), buffer[8..].as_ptr());
+        assert_eq!(dgi.get_remaining(), 0);
+        Ok(())
+    }
+
     #[test]
     fn dgi_read_message_type() -> Result<(), IteratorError> {
         let mut dg: Datagram = Datagram::default();
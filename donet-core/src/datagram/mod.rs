@@ -25,7 +25,10 @@
 //! - Iterating through and extracting information from received datagrams.
 //! - Converting endianness of datagram bytes to native byte order.
 //! - Datagram-level error handling.
+//! - Serializing Rust types to datagrams with [`serde`], see [`ser`] and [`de`].
 
 pub mod byte_order;
 pub mod datagram;
+pub mod de;
 pub mod iterator;
+pub mod ser;
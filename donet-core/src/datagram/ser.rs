@@ -0,0 +1,498 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Provides a [`serde`] serializer that writes Rust values to a
+//! [`Datagram`] using the Donet wire format.
+//!
+//! The encoding follows the same rules used for DC field values:
+//!
+//! - Integers and floats are written in little-endian byte order.
+//! - `bool` is written as an unsigned 8-bit integer (0x00 or 0x01).
+//! - `char` is written as a DC `char`, an unsigned 8-bit integer.
+//! - Strings and byte buffers are prefixed with a 16-bit length tag.
+//! - Sequences and maps are prefixed with a 16-bit length tag that
+//!   holds the size of their elements **in bytes**, like DC varying
+//!   arrays. A `Vec<u8>` is therefore encoded the same way as a blob.
+//! - Tuples, fixed-size arrays, and structs have no prefix, as their
+//!   layout is known to the reader. Their fields are written in order.
+//! - `Option` is written as an 8-bit tag (0x00 for `None`, 0x01 for
+//!   `Some`), followed by the value if present.
+//! - Enums are written as their variant index as an unsigned 8-bit
+//!   integer, followed by the variant's fields, if any.
+//!
+//! 128-bit integers are not supported.
+
+use super::datagram::{Datagram, DatagramError};
+use serde::ser::{self, Serialize};
+use thiserror::Error;
+
+/// Custom error type for [`Serializer`].
+#[derive(Debug, Error, PartialEq)]
+pub enum SerializeError {
+    #[error("datagram error; {0}")]
+    DatagramError(#[from] DatagramError),
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl ser::Error for SerializeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+}
+
+/// Serializes a value into a new [`Datagram`].
+pub fn to_datagram<T>(value: &T) -> Result<Datagram, SerializeError>
+where
+    T: Serialize + ?Sized,
+{
+    let mut dg: Datagram = Datagram::default();
+    value.serialize(&mut Serializer::new(&mut dg))?;
+    Ok(dg)
+}
+
+/// A [`serde::Serializer`] that appends values to a [`Datagram`].
+///
+/// To write a value after a message header, create a serializer
+/// over the datagram that already holds the header:
+///
+/// ```rust
+/// use donet_core::datagram::datagram::Datagram;
+/// use donet_core::datagram::ser::Serializer;
+/// use donet_core::Protocol;
+/// use serde::Serialize;
+///
+/// #[derive(Serialize)]
+/// struct AddChannel {
+///     channel: u64,
+/// }
+///
+/// let mut dg = Datagram::default();
+/// dg.add_control_header(Protocol::MDAddChannel.into()).unwrap();
+///
+/// let body = AddChannel { channel: 1000 };
+/// body.serialize(&mut Serializer::new(&mut dg)).unwrap();
+///
+/// assert_eq!(dg.size(), 1 + 8 + 2 + 8);
+/// ```
+pub struct Serializer<'a> {
+    dg: &'a mut Datagram,
+}
+
+impl<'a> Serializer<'a> {
+    pub fn new(dg: &'a mut Datagram) -> Self {
+        Self { dg }
+    }
+
+    /// Writes an enum variant index as an unsigned 8-bit integer.
+    fn add_variant_index(&mut self, index: u32) -> Result<(), SerializeError> {
+        match u8::try_from(index) {
+            Ok(index) => Ok(self.dg.add_u8(index)?),
+            Err(_) => Err(DatagramError::ImpossibleCast("Enum variant index does not fit in u8.").into()),
+        }
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    type SerializeSeq = LengthPrefixed<'a>;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = LengthPrefixed<'a>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), SerializeError> {
+        Ok(self.dg.add_bool(v)?)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), SerializeError> {
+        Ok(self.dg.add_i8(v)?)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), SerializeError> {
+        Ok(self.dg.add_i16(v)?)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), SerializeError> {
+        Ok(self.dg.add_i32(v)?)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), SerializeError> {
+        Ok(self.dg.add_i64(v)?)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), SerializeError> {
+        Ok(self.dg.add_u8(v)?)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), SerializeError> {
+        Ok(self.dg.add_u16(v)?)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), SerializeError> {
+        Ok(self.dg.add_u32(v)?)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), SerializeError> {
+        Ok(self.dg.add_u64(v)?)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), SerializeError> {
+        Ok(self.dg.add_f32(v)?)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), SerializeError> {
+        Ok(self.dg.add_f64(v)?)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), SerializeError> {
+        match u8::try_from(v) {
+            Ok(v) => Ok(self.dg.add_u8(v)?),
+            Err(_) => Err(DatagramError::ImpossibleCast("Character does not fit in a DC char.").into()),
+        }
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), SerializeError> {
+        Ok(self.dg.add_string(v)?)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), SerializeError> {
+        Ok(self.dg.add_blob(v)?)
+    }
+
+    fn serialize_none(self) -> Result<(), SerializeError> {
+        Ok(self.dg.add_u8(0)?)
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.dg.add_u8(1)?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerializeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerializeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), SerializeError> {
+        self.add_variant_index(variant_index)
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.add_variant_index(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, SerializeError> {
+        Ok(LengthPrefixed::new(self.dg))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self, SerializeError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self, SerializeError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, SerializeError> {
+        self.add_variant_index(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, SerializeError> {
+        Ok(LengthPrefixed::new(self.dg))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, SerializeError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self, SerializeError> {
+        self.add_variant_index(variant_index)?;
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl<'a, 'b> ser::SerializeTuple for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleStruct for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeTupleVariant for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeStruct for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::SerializeStructVariant for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T>(&mut self, _key: &'static str, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        Ok(())
+    }
+}
+
+/// Serializes the elements of a sequence or map into a separate
+/// buffer, so that their total size in bytes can be written as
+/// a 16-bit length tag before them once the sequence ends.
+pub struct LengthPrefixed<'a> {
+    dg: &'a mut Datagram,
+    elements: Datagram,
+}
+
+impl<'a> LengthPrefixed<'a> {
+    fn new(dg: &'a mut Datagram) -> Self {
+        Self {
+            dg,
+            elements: Datagram::default(),
+        }
+    }
+
+    fn add_element<T>(&mut self, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(&mut Serializer::new(&mut self.elements))
+    }
+
+    fn finish(self) -> Result<(), SerializeError> {
+        Ok(self.dg.add_blob(self.elements.get_buffer())?)
+    }
+}
+
+impl<'a> ser::SerializeSeq for LengthPrefixed<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.add_element(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeMap for LengthPrefixed<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.add_element(key)
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), SerializeError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.add_element(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct SetLocation {
+        do_id: u32,
+        parent: u32,
+        zone: u32,
+    }
+
+    #[derive(Serialize)]
+    enum Shape {
+        Empty,
+        Circle(f32),
+        Rect { w: u16, h: u16 },
+    }
+
+    #[test]
+    fn serialize_struct_fields_in_order() {
+        let value = SetLocation {
+            do_id: 1000,
+            parent: 1,
+            zone: 2,
+        };
+        let dg: Datagram = to_datagram(&value).unwrap();
+
+        let mut expected: Datagram = Datagram::default();
+        expected.add_doid(1000).unwrap();
+        expected.add_location(1, 2).unwrap();
+
+        assert_eq!(dg.get_buffer(), expected.get_buffer());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn serialize_length_prefixed() {
+        let dg: Datagram = to_datagram(&("hi", vec![1_u16, 2], b"ab".to_vec(), true)).unwrap();
+
+        assert_eq!(dg.get_buffer(), &[
+            2, 0, b'h', b'i', // string
+            4, 0, 1, 0, 2, 0, // array of u16 (length tag is in bytes)
+            2, 0, b'a', b'b', // Vec<u8> is encoded as a blob
+            1, // bool
+        ]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn serialize_option_and_enum() {
+        let value = (None::<u8>, Some(7_u8), Shape::Empty, Shape::Rect { w: 3, h: 4 });
+        let dg: Datagram = to_datagram(&value).unwrap();
+
+        assert_eq!(dg.get_buffer(), &[
+            0, // None
+            1, 7, // Some(7)
+            0, // Shape::Empty
+            2, 3, 0, 4, 0, // Shape::Rect
+        ]);
+        assert!(to_datagram(&Shape::Circle(1.5)).is_ok());
+    }
+
+    #[test]
+    fn serialize_errors() {
+        assert!(matches!(to_datagram(&'€'), Err(SerializeError::DatagramError(_))));
+        assert!(matches!(to_datagram(&1_u128), Err(SerializeError::Custom(_))));
+
+        let too_big: Vec<u8> = vec![0; usize::from(u16::MAX) + 1];
+        assert!(to_datagram(&too_big).is_err());
+    }
+}
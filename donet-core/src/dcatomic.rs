@@ -53,6 +53,11 @@ impl LegacyDCHash for DCAtomicField<'_> {
 }
 
 impl<'dc> DCAtomicField<'dc> {
+    #[inline(always)]
+    pub fn get_base_field(&self) -> &DCField<'dc> {
+        &self.base_field
+    }
+
     #[inline(always)]
     pub fn get_num_elements(&self) -> usize {
         self.elements.len()
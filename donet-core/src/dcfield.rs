@@ -142,6 +142,11 @@ impl<'dc> DCField<'dc> {
         self.field_name.clone()
     }
 
+    #[inline(always)]
+    pub fn get_field_type(&self) -> Option<&DCTypeDefinition> {
+        self.field_type.as_ref()
+    }
+
     /// Gets the parent DClass element reference.
     ///
     /// Panics if this field's parent element is not a DClass.
@@ -81,6 +81,10 @@ pub struct DCFile<'dc> {
     keywords: Vec<DCKeyword>,
     type_defs: Vec<DCTypeDefinition>,
     field_id_2_field: Vec<&'dc DCField<'dc>>,
+    /// Parameter types of every field, by field ID.
+    field_types: Vec<Vec<DCTypeDefinition>>,
+    /// IDs of the `required` fields of every class, by class ID.
+    required_fields: Vec<Vec<globals::FieldId>>,
     // TODO: type_id_2_type, type_name_2_type
     all_object_valid: bool,
     inherited_fields_stale: bool,
@@ -99,6 +103,12 @@ impl From<interim::DCFile> for DCFile<'_> {
             keywords.push(kw.into());
         }
 
+        let required_fields: Vec<Vec<globals::FieldId>> = value
+            .dclasses
+            .into_iter()
+            .map(|dclass| dclass.required_fields)
+            .collect();
+
         Self {
             config: value.config,
             baked_legacy_hash: 0_u32,
@@ -108,6 +118,8 @@ impl From<interim::DCFile> for DCFile<'_> {
             keywords,
             type_defs: vec![],
             field_id_2_field: vec![],
+            field_types: value.field_types,
+            required_fields,
             all_object_valid: true,
             inherited_fields_stale: false,
         }
@@ -244,6 +256,28 @@ impl<'dc> DCFile<'dc> {
         todo!();
     }
 
+    // ---------- Field Types ---------- //
+
+    /// Returns the types of the parameters of every field, by field ID.
+    ///
+    /// A molecular field's parameters are those of its atomic fields,
+    /// in order. Parameters whose type is a struct are left as such.
+    pub fn get_field_types(&self) -> impl Iterator<Item = (globals::FieldId, &[DCTypeDefinition])> {
+        self.field_types
+            .iter()
+            .enumerate()
+            .map(|(field_id, types)| (field_id as globals::FieldId, types.as_slice()))
+    }
+
+    /// Returns the IDs of the `required` fields of every class, by class
+    /// ID, with those inherited from its parents first.
+    pub fn get_required_fields(&self) -> impl Iterator<Item = (globals::DClassId, &[globals::FieldId])> {
+        self.required_fields
+            .iter()
+            .enumerate()
+            .map(|(dclass_id, required)| (dclass_id as globals::DClassId, required.as_slice()))
+    }
+
     // ---------- DC Struct ---------- //
 
     pub fn get_num_structs(&self) -> usize {
@@ -295,6 +329,8 @@ mod tests {
             keywords: vec![],
             type_defs: vec![],
             field_id_2_field: vec![],
+            field_types: vec![],
+            required_fields: vec![],
             all_object_valid: false,
             inherited_fields_stale: false,
         };
@@ -319,14 +355,16 @@ mod tests {
 /// Contains intermediate DC file structure and logic
 /// for semantic analysis as the DC file is being built.
 pub(crate) mod interim {
-    use super::{ast, globals, DCField, DCFileConfig};
+    use super::{ast, globals, DCField, DCFileConfig, DCTypeDefinition};
     use crate::dckeyword::interim::DCKeyword;
     use crate::dclass::interim::DClass;
     use crate::dcstruct::interim::DCStruct;
+    use crate::dctype::DCTypeEnum;
     use crate::parser::error::{Diagnostic, SemanticError};
+    use crate::parser::lexer::Span;
     use crate::parser::pipeline::PipelineData;
     use anyhow::{anyhow, Result};
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
 
     #[derive(Debug)]
     pub struct PythonImport {
@@ -343,6 +381,10 @@ pub(crate) mod interim {
         pub imports: Vec<PythonImport>,
         pub keywords: Vec<DCKeyword>,
         //pub field_id_2_field: Vec<Rc<DCField>>,
+        /// Parameter types of every field, by field ID.
+        pub field_types: Vec<Vec<DCTypeDefinition>>,
+        /// Types declared with `typedef`, by alias.
+        pub type_aliases: HashMap<String, DCTypeDefinition>,
         // TODO: type_id_2_type, type_name_2_type
         pub all_object_valid: bool,
         pub inherited_fields_stale: bool,
@@ -357,6 +399,8 @@ pub(crate) mod interim {
                 imports: vec![],
                 keywords: vec![],
                 //field_id_2_field: vec![],
+                field_types: vec![],
+                type_aliases: HashMap::default(),
                 all_object_valid: true,
                 inherited_fields_stale: false,
             }
@@ -466,12 +510,205 @@ pub(crate) mod interim {
             self.keywords.push(new_kw);
         }
 
-        pub fn add_typedef(&mut self, _name: String) -> Result<(), ()> {
-            todo!();
+        /// Records the type of a `typedef`, so that parameters
+        /// of the alias are known by their type on the wire.
+        pub fn add_typedef(&mut self, pipeline: &mut PipelineData, typedef: ast::TypeDefinition) {
+            let Some(alias) = typedef.alias_identifier else {
+                return;
+            };
+            if self.type_aliases.contains_key(&alias) {
+                Self::emit_error(pipeline, typedef.span, SemanticError::AlreadyDefined(alias));
+                return;
+            }
+            let dtype: DCTypeDefinition = match typedef.array_range {
+                Some(range) => {
+                    let array: ast::TypeWithArray = match typedef.data_type {
+                        ast::NonMethodDataType::NumericType(numeric) => ast::TypeWithArray {
+                            span: typedef.span,
+                            data_type: ast::ArrayableType::Numeric(numeric),
+                            array_ranges: vec![range],
+                        },
+                        ast::NonMethodDataType::StructType(name) => ast::TypeWithArray {
+                            span: typedef.span,
+                            data_type: ast::ArrayableType::Struct(name),
+                            array_ranges: vec![range],
+                        },
+                        ast::NonMethodDataType::TypeWithArray(mut array) => {
+                            array.array_ranges.push(range);
+                            array
+                        }
+                    };
+                    self.array_type(&array)
+                }
+                None => self.parameter_type(&typedef.data_type),
+            };
+            self.type_aliases.insert(alias, dtype);
         }
 
-        pub fn add_dclass(&mut self, dclass: DClass) {
-            self.dclasses.push(dclass);
+        /// Adds a dclass, assigning IDs to the class and to each of its
+        /// fields, in the order that they are declared in the DC file.
+        ///
+        /// Undefined parents and atomic fields are not reported yet. Their
+        /// fields are not inherited, and a molecular field made of them is
+        /// typed as a method, so its values are never accepted.
+        pub fn add_dclass(&mut self, pipeline: &mut PipelineData, dclass: ast::DClass) {
+            let mut new_class = DClass {
+                span: dclass.span,
+                identifier: dclass.identifier.clone(),
+                parents: dclass.parents.clone(),
+                fields: vec![],
+                class_id: 0,
+                is_bogus_class: true,
+                class_parents: vec![],
+                field_ids: HashMap::default(),
+                required_fields: vec![],
+            };
+            let Ok(class_id) = self.get_next_dclass_id(pipeline, &new_class) else {
+                return;
+            };
+            new_class.class_id = class_id;
+
+            if dclass.parents.len() > 1 && !self.config.dc_multiple_inheritance {
+                Self::emit_error(pipeline, dclass.span, SemanticError::MultipleInheritanceDisabled);
+            }
+            for parent_name in &dclass.parents {
+                if let Some(parent) = self
+                    .dclasses
+                    .iter()
+                    .find(|parent| parent.identifier == *parent_name)
+                {
+                    new_class.field_ids.extend(parent.field_ids.clone());
+                    new_class.required_fields.extend(&parent.required_fields);
+                }
+            }
+
+            for field in dclass.fields {
+                let (span, identifier, required, types) = match &field {
+                    ast::AtomicOrMolecular::Atomic(atomic) => {
+                        let types: Vec<DCTypeDefinition> = atomic
+                            .parameters
+                            .iter()
+                            .map(|parameter| self.parameter_type(&parameter.data_type))
+                            .collect();
+                        let required: bool = atomic.keywords.iter().any(|keyword| keyword == "required");
+
+                        (atomic.span, atomic.identifier.clone(), required, types)
+                    }
+                    ast::AtomicOrMolecular::Molecular(molecular) => {
+                        let mut types: Vec<DCTypeDefinition> = vec![];
+
+                        for atomic in &molecular.atomic_field_identifiers {
+                            match new_class.field_ids.get(atomic) {
+                                Some(field_id) => {
+                                    types.extend_from_slice(&self.field_types[usize::from(*field_id)])
+                                }
+                                None => types.push(DCTypeEnum::TMethod.into()),
+                            }
+                        }
+                        (molecular.span, Some(molecular.identifier.clone()), false, types)
+                    }
+                };
+                let Ok(field_id) = globals::FieldId::try_from(self.field_types.len()) else {
+                    Self::emit_error(pipeline, span, SemanticError::FieldOverflow);
+                    return;
+                };
+                self.field_types.push(types);
+
+                if let Some(identifier) = identifier {
+                    new_class.field_ids.insert(identifier, field_id);
+                }
+                if required {
+                    new_class.required_fields.push(field_id);
+                }
+                new_class.add_class_field(field);
+            }
+            self.dclasses.push(new_class);
+        }
+
+        /// Returns the type of a parameter on the wire, resolving type
+        /// aliases. Structs, whose layout is not known, are left as such.
+        fn parameter_type(&self, data_type: &ast::NonMethodDataType) -> DCTypeDefinition {
+            match data_type {
+                ast::NonMethodDataType::NumericType(numeric) => numeric.base_type.clone().into(),
+                ast::NonMethodDataType::StructType(name) => self
+                    .type_aliases
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_else(|| DCTypeEnum::TStruct.into()),
+                ast::NonMethodDataType::TypeWithArray(array) => self.array_type(array),
+            }
+        }
+
+        /// Returns the type of an array, string, or blob parameter, which
+        /// has a fixed size if all of its dimensions, and its elements, do.
+        fn array_type(&self, array: &ast::TypeWithArray) -> DCTypeDefinition {
+            let (data_type, element_size): (DCTypeEnum, Option<u16>) = match &array.data_type {
+                ast::ArrayableType::Numeric(numeric) => {
+                    (DCTypeEnum::TArray, Self::numeric_size(&numeric.base_type))
+                }
+                ast::ArrayableType::Struct(name) => match self.type_aliases.get(name) {
+                    Some(alias) if alias.get_dc_type() != DCTypeEnum::TStruct => {
+                        let size: Option<u16> = match alias.is_variable_length() {
+                            true => Self::numeric_size(&alias.data_type),
+                            false => Some(alias.get_size()),
+                        };
+                        (DCTypeEnum::TArray, size)
+                    }
+                    _ => return DCTypeEnum::TStruct.into(),
+                },
+                ast::ArrayableType::Sized(token) => match token {
+                    ast::SizedTypeToken::String => (DCTypeEnum::TString, Some(1)),
+                    ast::SizedTypeToken::Blob => (DCTypeEnum::TBlob, Some(1)),
+                    // always has a 32-bit length tag
+                    ast::SizedTypeToken::Blob32 => (DCTypeEnum::TBlob32, None),
+                    ast::SizedTypeToken::Int8Array | ast::SizedTypeToken::UInt8Array => {
+                        (DCTypeEnum::TArray, Some(1))
+                    }
+                    ast::SizedTypeToken::Int16Array | ast::SizedTypeToken::UInt16Array => {
+                        (DCTypeEnum::TArray, Some(2))
+                    }
+                    ast::SizedTypeToken::Int32Array | ast::SizedTypeToken::UInt32Array => {
+                        (DCTypeEnum::TArray, Some(4))
+                    }
+                    ast::SizedTypeToken::UInt32UInt8Array => (DCTypeEnum::TArray, Some(5)),
+                },
+            };
+            // the number of elements, if every dimension has a fixed size
+            let count: Option<u16> = match array.array_ranges.is_empty() {
+                true => None,
+                false => array.array_ranges.iter().try_fold(1_u16, |count, range| {
+                    (range.start == range.end)
+                        .then_some(range.start as u16)?
+                        .checked_mul(count)
+                }),
+            };
+            let mut dtype: DCTypeDefinition = data_type.into();
+
+            if let Some(size) = count
+                .zip(element_size)
+                .and_then(|(count, size)| count.checked_mul(size))
+            {
+                dtype.size = size;
+            }
+            dtype
+        }
+
+        fn numeric_size(data_type: &DCTypeEnum) -> Option<u16> {
+            match data_type {
+                DCTypeEnum::TInt8 | DCTypeEnum::TUInt8 | DCTypeEnum::TChar => Some(1),
+                DCTypeEnum::TInt16 | DCTypeEnum::TUInt16 => Some(2),
+                DCTypeEnum::TInt32 | DCTypeEnum::TUInt32 | DCTypeEnum::TFloat32 => Some(4),
+                DCTypeEnum::TInt64 | DCTypeEnum::TUInt64 | DCTypeEnum::TFloat64 => Some(8),
+                _ => None,
+            }
+        }
+
+        fn emit_error(pipeline: &mut PipelineData, span: Span, error: SemanticError) {
+            let diag: Diagnostic = Diagnostic::error(span, pipeline, error);
+
+            pipeline
+                .emit_diagnostic(diag.into())
+                .expect("Failed to emit diagnostic.");
         }
 
         pub fn add_struct(&mut self, _strct: DCStruct) {
@@ -501,7 +738,7 @@ pub(crate) mod interim {
 
                 return Err(anyhow!("Ran out of 16-bit DClass IDs!"));
             }
-            Ok(dc_num - 1_u16)
+            Ok(dc_num)
         }
     }
 }
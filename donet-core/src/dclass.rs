@@ -140,11 +140,23 @@ impl<'dc> DClass<'dc> {
     }
 
     #[inline(always)]
-    pub fn get_parent(&self, index: usize) -> Option<&'static DClass> {
+    pub fn get_parent(&self, index: usize) -> Option<&'dc DClass<'dc>> {
         // copy the reference inside the option instead of a reference to the reference
         self.class_parents.get(index).cloned()
     }
 
+    #[inline(always)]
+    pub fn get_num_fields(&self) -> usize {
+        self.fields.len()
+    }
+
+    /// Returns a field declared in this class, not including
+    /// the fields inherited from its parents.
+    #[inline(always)]
+    pub fn get_field(&self, index: usize) -> Option<&'dc ClassField> {
+        self.fields.get(index).copied()
+    }
+
     #[inline(always)]
     pub fn has_constructor(&self) -> bool {
         self.constructor.is_some()
@@ -167,6 +179,7 @@ pub(crate) mod interim {
     use crate::parser::ast;
     use crate::parser::lexer::Span;
     use std::cell::RefCell;
+    use std::collections::HashMap;
     use std::rc::Rc;
 
     #[derive(Debug)]
@@ -178,6 +191,10 @@ pub(crate) mod interim {
         pub class_id: globals::DClassId,
         pub is_bogus_class: bool,
         pub class_parents: Vec<Rc<RefCell<DClass>>>,
+        /// IDs of the fields of this class and its parents, by name.
+        pub field_ids: HashMap<String, globals::FieldId>,
+        /// IDs of the `required` fields, with those inherited first.
+        pub required_fields: Vec<globals::FieldId>,
     }
 
     impl DClass {
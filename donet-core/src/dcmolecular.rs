@@ -51,6 +51,11 @@ impl LegacyDCHash for DCMolecularField<'_> {
 }
 
 impl<'dc> DCMolecularField<'dc> {
+    #[inline(always)]
+    pub fn get_base_field(&self) -> &DCField<'dc> {
+        &self.base_field
+    }
+
     #[inline(always)]
     pub fn get_num_atomics(&self) -> usize {
         self.atomic_fields.len()
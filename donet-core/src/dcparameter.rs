@@ -53,6 +53,11 @@ impl<'dc> DCParameter<'dc> {
         self.parent
     }
 
+    #[inline(always)]
+    pub fn get_type(&self) -> &DCTypeDefinition {
+        &self.base_type
+    }
+
     #[inline(always)]
     pub fn has_default_value(&self) -> bool {
         self.has_default_value
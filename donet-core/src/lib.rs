@@ -37,7 +37,8 @@
 //! Below is a list of the available feature flags.
 //!
 //! - **`full`**: Enables all feature flags available for donet-core.
-//! - **`datagram`**: Includes Datagram / Datagram Iterator source for writing network packets.
+//! - **`datagram`**: Includes Datagram / Datagram Iterator source for writing network packets,
+//!   and typed message structs for every message type.
 //! - **`dcfile`**: Includes the DC file lexer, parser, and DC element structures.
 //!
 //! You can return to the Donet manual at [`docs.donet-server.org`].
@@ -62,6 +63,8 @@ extern crate cfg_if;
 
 #[cfg(feature = "datagram")]
 pub mod datagram;
+#[cfg(feature = "datagram")]
+pub mod messages;
 
 cfg_if! {
     if #[cfg(feature = "dcfile")] {
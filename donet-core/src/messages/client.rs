@@ -0,0 +1,147 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Messages sent between clients and the Client Agent.
+
+use super::Payload;
+use crate::globals::*;
+
+protocol_messages! {
+    /// `CLIENT_HELLO` (1)
+    ClientHello {
+        dc_hash: DCFileHash,
+        version: String,
+    }
+
+    /// `CLIENT_HELLO_RESP` (2)
+    ClientHelloResp {}
+
+    /// `CLIENT_DISCONNECT` (3)
+    ClientDisconnect {}
+
+    /// `CLIENT_EJECT` (4)
+    ClientEject {
+        error_code: u16,
+        reason: String,
+    }
+
+    /// `CLIENT_HEARTBEAT` (5)
+    ClientHeartbeat {}
+
+    /// `CLIENT_OBJECT_SET_FIELD` (120)
+    ClientObjectSetField {
+        do_id: DoId,
+        field_id: FieldId,
+        /// The field's value.
+        data: Payload,
+    }
+
+    /// `CLIENT_OBJECT_SET_FIELDS` (121)
+    ClientObjectSetFields {
+        do_id: DoId,
+        field_count: u16,
+        /// `field_count` pairs of field ID and value.
+        data: Payload,
+    }
+
+    /// `CLIENT_OBJECT_LEAVING` (132)
+    ClientObjectLeaving {
+        do_id: DoId,
+    }
+
+    /// `CLIENT_OBJECT_LEAVING_OWNER` (161)
+    ClientObjectLeavingOwner {
+        do_id: DoId,
+    }
+
+    /// `CLIENT_ENTER_OBJECT_REQUIRED` (142)
+    ClientEnterObjectRequired {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields.
+        data: Payload,
+    }
+
+    /// `CLIENT_ENTER_OBJECT_REQUIRED_OTHER` (143)
+    ClientEnterObjectRequiredOther {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields, followed by other fields.
+        data: Payload,
+    }
+
+    /// `CLIENT_ENTER_OBJECT_REQUIRED_OWNER` (172)
+    ClientEnterObjectRequiredOwner {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields.
+        data: Payload,
+    }
+
+    /// `CLIENT_ENTER_OBJECT_REQUIRED_OTHER_OWNER` (173)
+    ClientEnterObjectRequiredOwnerOther {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields, followed by other fields.
+        data: Payload,
+    }
+
+    /// `CLIENT_DONE_INTEREST_RESP` (204)
+    ClientDoneInterestResp {
+        context: u32,
+        interest_id: u16,
+    }
+
+    /// `CLIENT_ADD_INTEREST` (200)
+    ClientAddInterest {
+        context: u32,
+        interest_id: u16,
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+
+    /// `CLIENT_ADD_INTEREST_MULTIPLE` (201)
+    ClientAddInterestMultiple {
+        context: u32,
+        interest_id: u16,
+        parent_id: DoId,
+        zones: Vec<Zone>,
+    }
+
+    /// `CLIENT_REMOVE_INTEREST` (203)
+    ClientRemoveInterest {
+        context: u32,
+        interest_id: u16,
+    }
+
+    /// `CLIENT_OBJECT_LOCATION` (140)
+    ClientObjectLocation {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+}
@@ -0,0 +1,138 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Messages handled by the Client Agent.
+
+use crate::globals::*;
+use bytes::Bytes;
+
+protocol_messages! {
+    /// `CLIENTAGENT_SET_STATE` (1000)
+    CASetState {
+        ca_state: u16,
+    }
+
+    /// `CLIENTAGENT_SET_CLIENT_ID` (1001)
+    CASetClientID {
+        channel: Channel,
+    }
+
+    /// `CLIENTAGENT_SEND_DATAGRAM` (1002)
+    CASendDatagram {
+        datagram: Bytes,
+    }
+
+    /// `CLIENTAGENT_EJECT` (1004)
+    CAEject {
+        error_code: u16,
+        reason: String,
+    }
+
+    /// `CLIENTAGENT_DROP` (1005)
+    CADrop {}
+
+    /// `CLIENTAGENT_GET_NETWORK_ADDRESS` (1006)
+    CAGetNetworkAddress {
+        context: u32,
+    }
+
+    /// `CLIENTAGENT_GET_NETWORK_ADDRESS_RESP` (1007)
+    CAGetNetworkAddressResp {
+        context: u32,
+        remote_ip: String,
+        remote_port: u16,
+        local_ip: String,
+        local_port: u16,
+    }
+
+    /// `CLIENTAGENT_DECLARE_OBJECT` (1010)
+    CADeclareObject {
+        do_id: DoId,
+        dclass_id: DClassId,
+    }
+
+    /// `CLIENTAGENT_UNDECLARE_OBJECT` (1011)
+    CAUndeclareObject {
+        do_id: DoId,
+    }
+
+    /// `CLIENTAGENT_ADD_SESSION_OBJECT` (1012)
+    CAAddSessionObject {
+        do_id: DoId,
+    }
+
+    /// `CLIENTAGENT_REMOVE_SESSION_OBJECT` (1013)
+    CARemoveSessionObject {
+        do_id: DoId,
+    }
+
+    /// `CLIENTAGENT_SET_FIELDS_SENDABLE` (1014)
+    CASetFieldsSendable {
+        do_id: DoId,
+        field_ids: Vec<FieldId>,
+    }
+
+    /// `CLIENTAGENT_GET_TLVS` (1015)
+    CAGetTLVs {
+        context: u32,
+    }
+
+    /// `CLIENTAGENT_GET_TLVS_RESP` (1016)
+    CAGetTLVsResp {
+        context: u32,
+        tlvs: Bytes,
+    }
+
+    /// `CLIENTAGENT_OPEN_CHANNEL` (1100)
+    CAOpenChannel {
+        channel: Channel,
+    }
+
+    /// `CLIENTAGENT_CLOSE_CHANNEL` (1101)
+    CACloseChannel {
+        channel: Channel,
+    }
+
+    /// `CLIENTAGENT_ADD_POST_REMOVE` (1110)
+    CAAddPostRemove {
+        datagram: Bytes,
+    }
+
+    /// `CLIENTAGENT_CLEAR_POST_REMOVES` (1111)
+    CAClearPostRemoves {}
+
+    /// `CLIENTAGENT_ADD_INTEREST` (1200)
+    CAAddInterest {
+        interest_id: u16,
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+
+    /// `CLIENTAGENT_ADD_INTEREST_MULTIPLE` (1201)
+    CAAddInterestMultiple {
+        interest_id: u16,
+        parent_id: DoId,
+        zones: Vec<Zone>,
+    }
+
+    /// `CLIENTAGENT_REMOVE_INTEREST` (1203)
+    CARemoveInterest {
+        interest_id: u16,
+    }
+}
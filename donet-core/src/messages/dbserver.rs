@@ -0,0 +1,185 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Messages handled by the Database Server.
+
+use super::Payload;
+use crate::globals::*;
+
+protocol_messages! {
+    /// `DBSERVER_CREATE_OBJECT` (3000)
+    DBCreateObject {
+        context: u32,
+        dclass_id: DClassId,
+        field_count: u16,
+        /// `field_count` pairs of field ID and value.
+        data: Payload,
+    }
+
+    /// `DBSERVER_CREATE_OBJECT_RESP` (3001)
+    DBCreateObjectResp {
+        context: u32,
+        do_id: DoId,
+    }
+
+    /// `DBSERVER_OBJECT_GET_FIELD` (3010)
+    DBObjectGetField {
+        context: u32,
+        do_id: DoId,
+        field_id: FieldId,
+    }
+
+    /// `DBSERVER_OBJECT_GET_FIELD_RESP` (3011)
+    DBObjectGetFieldResp {
+        context: u32,
+        success: bool,
+        /// The field ID and its value, if successful.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_GET_FIELDS` (3012)
+    DBObjectGetFields {
+        context: u32,
+        do_id: DoId,
+        field_ids: Vec<FieldId>,
+    }
+
+    /// `DBSERVER_OBJECT_GET_FIELDS_RESP` (3013)
+    DBObjectGetFieldsResp {
+        context: u32,
+        success: bool,
+        /// The field count, followed by pairs of field ID and value, if successful.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_GET_ALL` (3014)
+    DBObjectGetAll {
+        context: u32,
+        do_id: DoId,
+    }
+
+    /// `DBSERVER_OBJECT_GET_ALL_RESP` (3015)
+    DBObjectGetAllResp {
+        context: u32,
+        success: bool,
+        /// The class ID, field count, and pairs of field ID and value, if successful.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELD` (3020)
+    DBObjectSetField {
+        do_id: DoId,
+        field_id: FieldId,
+        /// The field's value.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELDS` (3021)
+    DBObjectSetFields {
+        do_id: DoId,
+        field_count: u16,
+        /// `field_count` pairs of field ID and value.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELD_IF_EQUALS` (3022)
+    DBObjectSetFieldIfEquals {
+        context: u32,
+        do_id: DoId,
+        field_id: FieldId,
+        /// The field's expected value, followed by its new value.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELD_IF_EQUALS_RESP` (3023)
+    DBObjectSetFieldIfEqualsResp {
+        context: u32,
+        success: bool,
+        /// The field ID and its current value, if not successful.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELDS_IF_EQUALS` (3024)
+    DBObjectSetFieldsIfEquals {
+        context: u32,
+        do_id: DoId,
+        field_count: u16,
+        /// `field_count` sets of field ID, expected value, and new value.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELDS_IF_EQUALS_RESP` (3025)
+    DBObjectSetFieldsIfEqualsResp {
+        context: u32,
+        success: bool,
+        /// The field count, followed by pairs of field ID and current value, if not successful.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELD_IF_EMPTY` (3026)
+    DBObjectSetFieldIfEmpty {
+        context: u32,
+        do_id: DoId,
+        field_id: FieldId,
+        /// The field's new value.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELD_IF_EMPTY_RESP` (3027)
+    DBObjectSetFieldIfEmptyResp {
+        context: u32,
+        success: bool,
+        /// The field ID and its current value, if not successful.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELDS_IF_EMPTY` (3028)
+    DBObjectSetFieldsIfEmpty {
+        context: u32,
+        do_id: DoId,
+        field_count: u16,
+        /// `field_count` pairs of field ID and new value.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_SET_FIELDS_IF_EMPTY_RESP` (3029)
+    DBObjectSetFieldsIfEmptyResp {
+        context: u32,
+        success: bool,
+        /// The field count, followed by pairs of field ID and current value, if not successful.
+        data: Payload,
+    }
+
+    /// `DBSERVER_OBJECT_DELETE_FIELD` (3030)
+    DBObjectDeleteField {
+        do_id: DoId,
+        field_id: FieldId,
+    }
+
+    /// `DBSERVER_OBJECT_DELETE_FIELDS` (3031)
+    DBObjectDeleteFields {
+        do_id: DoId,
+        field_ids: Vec<FieldId>,
+    }
+
+    /// `DBSERVER_OBJECT_DELETE` (3032)
+    DBObjectDelete {
+        do_id: DoId,
+    }
+}
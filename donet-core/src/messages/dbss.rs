@@ -0,0 +1,72 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Messages handled by the Database State Server.
+
+use super::Payload;
+use crate::globals::*;
+
+protocol_messages! {
+    /// `DBSS_OBJECT_ACTIVATE_WITH_DEFAULTS` (2200)
+    DBSSObjectActivateWithDefaults {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+
+    /// `DBSS_OBJECT_ACTIVATE_WITH_DEFAULTS_OTHER` (2201)
+    DBSSObjectActivateWithDefaultsOther {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of other fields to activate the object with.
+        data: Payload,
+    }
+
+    /// `DBSS_OBJECT_GET_ACTIVATED` (2207)
+    DBSSObjectGetActivated {
+        context: u32,
+        do_id: DoId,
+    }
+
+    /// `DBSS_OBJECT_GET_ACTIVATED_RESP` (2208)
+    DBSSObjectGetActivatedResp {
+        context: u32,
+        do_id: DoId,
+        is_active: bool,
+    }
+
+    /// `DBSS_OBJECT_DELETE_FIELD_DISK` (2230)
+    DBSSObjectDeleteFieldDisk {
+        do_id: DoId,
+        field_id: FieldId,
+    }
+
+    /// `DBSS_OBJECT_DELETE_FIELDS_DISK` (2231)
+    DBSSObjectDeleteFieldsDisk {
+        do_id: DoId,
+        field_ids: Vec<FieldId>,
+    }
+
+    /// `DBSS_OBJECT_DELETE_DISK` (2232)
+    DBSSObjectDeleteDisk {
+        do_id: DoId,
+    }
+}
@@ -0,0 +1,132 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Control messages handled by the Message Director.
+
+use super::Payload;
+use crate::globals::*;
+use bytes::Bytes;
+
+protocol_messages! {
+    /// `CONTROL_ADD_CHANNEL` (9000)
+    MDAddChannel {
+        channel: Channel,
+    }
+
+    /// `CONTROL_REMOVE_CHANNEL` (9001)
+    MDRemoveChannel {
+        channel: Channel,
+    }
+
+    /// `CONTROL_ADD_RANGE` (9002)
+    MDAddRange {
+        low: Channel,
+        high: Channel,
+    }
+
+    /// `CONTROL_REMOVE_RANGE` (9003)
+    MDRemoveRange {
+        low: Channel,
+        high: Channel,
+    }
+
+    /// `CONTROL_ADD_POST_REMOVE` (9010)
+    MDAddPostRemove {
+        sender: Channel,
+        datagram: Bytes,
+    }
+
+    /// `CONTROL_CLEAR_POST_REMOVES` (9011)
+    MDClearPostRemoves {
+        sender: Channel,
+    }
+
+    /// `CONTROL_SET_CON_NAME` (9012)
+    MDSetConName {
+        name: String,
+    }
+
+    /// `CONTROL_SET_CON_URL` (9013)
+    MDSetConUrl {
+        url: String,
+    }
+
+    /// `CONTROL_LOG_MESSAGE` (9014)
+    MDLogMessage {
+        /// A datagram with the log message in MessagePack format.
+        msgpack_datagram: Bytes,
+    }
+
+    /// `CONTROL_PEER_HELLO` (9020)
+    MDPeerHello {
+        /// Identifies the peer MD within its mesh.
+        md_id: u32,
+    }
+
+    /// `CONTROL_PEER_FORWARD` (9021)
+    MDPeerForward {
+        /// The MD that routed the datagram to its peers.
+        origin: u32,
+        /// The forwarded datagram, which is the rest of the message.
+        datagram: Payload,
+    }
+
+    /// `CONTROL_WATCH_INTEREST` (9022)
+    MDWatchInterest {}
+
+    /// `CONTROL_INTEREST_SYNCED` (9023)
+    MDInterestSynced {}
+
+    /// `CONTROL_ADD_TRACE` (9024)
+    MDAddTrace {
+        channel: Channel,
+        /// One in this many datagrams of the channel are traced.
+        sampling: u32,
+    }
+
+    /// `CONTROL_REMOVE_TRACE` (9025)
+    MDRemoveTrace {
+        channel: Channel,
+    }
+
+    /// `CONTROL_TRACED` (9026)
+    MDTraced {
+        trace_id: u64,
+        /// The MDs that the datagram has passed through, in order.
+        hops: Vec<u32>,
+        /// The traced datagram, which is the rest of the message.
+        datagram: Payload,
+    }
+
+    /// `CONTROL_AUTHENTICATE` (9027)
+    MDAuthenticate {
+        role: String,
+        /// The shared secret of the role.
+        secret: String,
+    }
+
+    /// `CONTROL_RESUME` (9028)
+    MDResume {
+        /// Identifies the subscriber across its reconnections.
+        session: u64,
+    }
+
+    /// `CONTROL_RESYNC` (9029)
+    MDResync {}
+}
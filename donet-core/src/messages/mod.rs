@@ -0,0 +1,612 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Typed message bodies for every message type in the Donet protocol.
+//!
+//! Each message type defined in [`Protocol`] has a struct of the same
+//! name, which implements [`ProtocolMessage`] to encode its body to a
+//! [`Datagram`] and decode it from a [`DatagramIterator`]. The message
+//! header (recipients, sender, and message type) is not part of the
+//! struct, and is written with [`Datagram::add_internal_header`] or
+//! [`Datagram::add_control_header`] as before.
+//!
+//! The [`Message`] enum wraps all message structs, and reads the
+//! message type from the datagram to decide which body to decode.
+//!
+//! Field values (the `<VALUE>`, `<REQUIRED>`, and `<OTHER>` parameters
+//! in the protocol reference) can only be unpacked with the DC file, so
+//! messages keep them as a [`Payload`] with the rest of the message.
+
+use crate::datagram::datagram::{Datagram, DatagramError};
+use crate::datagram::iterator::{DatagramIterator, IteratorError};
+use crate::protocol::Protocol;
+use bytes::Bytes;
+use validate::ValidationError;
+
+/// A message body in the Donet protocol.
+pub trait ProtocolMessage: Sized {
+    /// The message type of this message.
+    const MSG_TYPE: Protocol;
+
+    /// Adds this message's body to the end of the datagram.
+    fn encode(&self, dg: &mut Datagram) -> Result<(), DatagramError>;
+
+    /// Reads this message's body, starting after the message type.
+    fn decode(dgi: &mut DatagramIterator) -> Result<Self, IteratorError>;
+
+    /// Reads this message's body like [`Self::decode`], but rejects
+    /// malformed parameters and reports which parameter was invalid.
+    fn decode_validated(dgi: &mut DatagramIterator) -> Result<Self, ValidationError>;
+}
+
+/// A value that can be a parameter of a message.
+pub trait MessageField: Sized {
+    fn encode(&self, dg: &mut Datagram) -> Result<(), DatagramError>;
+
+    fn decode(dgi: &mut DatagramIterator) -> Result<Self, IteratorError>;
+
+    /// Reads the value like [`Self::decode`], but returns an error for
+    /// values that are accepted by it, such as a `bool` that is not 0 or 1.
+    fn decode_strict(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+        Self::decode(dgi)
+    }
+}
+
+/// The remaining bytes of a message, which hold DC field values.
+///
+/// A [`Payload`] is not length-prefixed. It reads until the end of the
+/// datagram, so it is always the last parameter of a message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Payload(pub Bytes);
+
+impl MessageField for Payload {
+    fn encode(&self, dg: &mut Datagram) -> Result<(), DatagramError> {
+        dg.add_data(&self.0)
+    }
+
+    fn decode(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+        Ok(Self(dgi.read_remaining()))
+    }
+}
+
+macro_rules! impl_message_field {
+    ($($ty:ty => $add:ident, $read:ident;)*) => {
+        $(
+            impl MessageField for $ty {
+                #[inline]
+                fn encode(&self, dg: &mut Datagram) -> Result<(), DatagramError> {
+                    dg.$add(*self)
+                }
+
+                #[inline]
+                fn decode(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+                    dgi.$read()
+                }
+            }
+        )*
+    };
+}
+
+impl_message_field! {
+    u8 => add_u8, read_u8;
+    u16 => add_u16, read_u16;
+    u32 => add_u32, read_u32;
+    u64 => add_u64, read_u64;
+}
+
+impl MessageField for bool {
+    fn encode(&self, dg: &mut Datagram) -> Result<(), DatagramError> {
+        dg.add_bool(*self)
+    }
+
+    fn decode(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+        dgi.read_bool()
+    }
+
+    fn decode_strict(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+        match dgi.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(IteratorError::InvalidRead("Boolean value is not 0x00 or 0x01.")),
+        }
+    }
+}
+
+impl MessageField for String {
+    fn encode(&self, dg: &mut Datagram) -> Result<(), DatagramError> {
+        dg.add_string(self)
+    }
+
+    fn decode(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+        dgi.read_string()
+    }
+}
+
+/// [`Bytes`] are encoded as a `blob`, with a 16-bit length tag.
+impl MessageField for Bytes {
+    fn encode(&self, dg: &mut Datagram) -> Result<(), DatagramError> {
+        dg.add_blob(self)
+    }
+
+    fn decode(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+        dgi.read_blob()
+    }
+}
+
+/// Vectors are encoded as a 16-bit element count, followed by
+/// each element, such as the `n_zones` and `zone_id` parameters.
+impl<T: MessageField> MessageField for Vec<T> {
+    fn encode(&self, dg: &mut Datagram) -> Result<(), DatagramError> {
+        match u16::try_from(self.len()) {
+            Ok(n) => dg.add_u16(n)?,
+            Err(_) => {
+                return Err(DatagramError::ImpossibleCast(
+                    "Element count does not fit in u16.",
+                ))
+            }
+        }
+        for element in self {
+            element.encode(dg)?;
+        }
+        Ok(())
+    }
+
+    fn decode(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+        let n: u16 = dgi.read_u16()?;
+        let mut elements: Vec<T> = Vec::with_capacity(n.into());
+
+        for _ in 0..n {
+            elements.push(T::decode(dgi)?);
+        }
+        Ok(elements)
+    }
+
+    fn decode_strict(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+        let n: u16 = dgi.read_u16()?;
+        let mut elements: Vec<T> = Vec::with_capacity(n.into());
+
+        for _ in 0..n {
+            elements.push(T::decode_strict(dgi)?);
+        }
+        Ok(elements)
+    }
+}
+
+/// Generates a struct for each message, along with its
+/// [`ProtocolMessage`] implementation. Fields are encoded
+/// in the order that they are declared.
+macro_rules! protocol_messages {
+    ($(
+        $(#[$meta:meta])*
+        $name:ident {
+            $($(#[$field_meta:meta])* $field:ident: $ty:ty),* $(,)?
+        }
+    )*) => {
+        $(
+            $(#[$meta])*
+            #[derive(Debug, Clone, Default, PartialEq)]
+            pub struct $name {
+                $($(#[$field_meta])* pub $field: $ty,)*
+            }
+
+            impl $crate::messages::ProtocolMessage for $name {
+                const MSG_TYPE: $crate::protocol::Protocol = $crate::protocol::Protocol::$name;
+
+                #[allow(unused_variables)]
+                fn encode(
+                    &self,
+                    dg: &mut $crate::datagram::datagram::Datagram,
+                ) -> Result<(), $crate::datagram::datagram::DatagramError> {
+                    $($crate::messages::MessageField::encode(&self.$field, dg)?;)*
+                    Ok(())
+                }
+
+                #[allow(unused_variables)]
+                fn decode(
+                    dgi: &mut $crate::datagram::iterator::DatagramIterator,
+                ) -> Result<Self, $crate::datagram::iterator::IteratorError> {
+                    Ok(Self {
+                        $($field: $crate::messages::MessageField::decode(dgi)?,)*
+                    })
+                }
+
+                #[allow(unused_variables)]
+                fn decode_validated(
+                    dgi: &mut $crate::datagram::iterator::DatagramIterator,
+                ) -> Result<Self, $crate::messages::validate::ValidationError> {
+                    Ok(Self {
+                        $($field: $crate::messages::validate::parameter(
+                            dgi,
+                            stringify!($name),
+                            stringify!($field),
+                        )?,)*
+                    })
+                }
+            }
+
+            #[cfg(test)]
+            impl $crate::messages::tests::Sample for $name {
+                fn sample() -> Self {
+                    Self {
+                        $($field: $crate::messages::tests::Sample::sample(),)*
+                    }
+                }
+            }
+        )*
+    };
+}
+
+pub mod client;
+pub mod clientagent;
+pub mod dbserver;
+pub mod dbss;
+pub mod messagedirector;
+pub mod stateserver;
+pub mod validate;
+
+pub use client::*;
+pub use clientagent::*;
+pub use dbserver::*;
+pub use dbss::*;
+pub use messagedirector::*;
+pub use stateserver::*;
+
+/// Generates the [`Message`] enum from the list of all messages.
+///
+/// The match on [`Protocol`] in [`Message::decode`] is exhaustive,
+/// so every variant of [`Protocol`] must have a message struct.
+macro_rules! message_enum {
+    ($($name:ident,)*) => {
+        /// Any message in the Donet protocol.
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Message {
+            $($name($name),)*
+        }
+
+        impl Message {
+            /// Returns the message type of this message.
+            pub fn msg_type(&self) -> Protocol {
+                match self {
+                    $(Self::$name(_) => Protocol::$name,)*
+                }
+            }
+
+            /// Adds the message type and this message's
+            /// body to the end of the datagram.
+            pub fn encode(&self, dg: &mut Datagram) -> Result<(), DatagramError> {
+                dg.add_u16(self.msg_type().into())?;
+
+                match self {
+                    $(Self::$name(msg) => msg.encode(dg),)*
+                }
+            }
+
+            /// Reads the message type with [`DatagramIterator::read_msg_type`],
+            /// then reads the body of the message type that was read.
+            pub fn decode(dgi: &mut DatagramIterator) -> Result<Self, IteratorError> {
+                match dgi.read_msg_type()? {
+                    $(Protocol::$name => Ok(Self::$name($name::decode(dgi)?)),)*
+                }
+            }
+
+            /// Reads the body of the given message type with
+            /// [`ProtocolMessage::decode_validated`].
+            pub(crate) fn decode_validated(
+                msg_type: Protocol,
+                dgi: &mut DatagramIterator,
+            ) -> Result<Self, ValidationError> {
+                match msg_type {
+                    $(Protocol::$name => Ok(Self::$name($name::decode_validated(dgi)?)),)*
+                }
+            }
+        }
+
+        $(
+            impl From<$name> for Message {
+                fn from(value: $name) -> Self {
+                    Self::$name(value)
+                }
+            }
+        )*
+
+        /// A round-trip test for every message.
+        #[cfg(test)]
+        #[allow(non_snake_case)]
+        mod round_trip {
+            use super::tests::{assert_round_trip, Sample};
+            use super::*;
+
+            $(
+                #[test]
+                fn $name() {
+                    assert_round_trip($name::sample());
+                }
+            )*
+        }
+    };
+}
+
+message_enum! {
+    // Client
+    ClientHello,
+    ClientHelloResp,
+    ClientDisconnect,
+    ClientEject,
+    ClientHeartbeat,
+    ClientObjectSetField,
+    ClientObjectSetFields,
+    ClientObjectLeaving,
+    ClientObjectLeavingOwner,
+    ClientEnterObjectRequired,
+    ClientEnterObjectRequiredOther,
+    ClientEnterObjectRequiredOwner,
+    ClientEnterObjectRequiredOwnerOther,
+    ClientDoneInterestResp,
+    ClientAddInterest,
+    ClientAddInterestMultiple,
+    ClientRemoveInterest,
+    ClientObjectLocation,
+    // Client Agent
+    CASetState,
+    CASetClientID,
+    CASendDatagram,
+    CAEject,
+    CADrop,
+    CAGetNetworkAddress,
+    CAGetNetworkAddressResp,
+    CADeclareObject,
+    CAUndeclareObject,
+    CAAddSessionObject,
+    CARemoveSessionObject,
+    CASetFieldsSendable,
+    CAGetTLVs,
+    CAGetTLVsResp,
+    CAOpenChannel,
+    CACloseChannel,
+    CAAddPostRemove,
+    CAClearPostRemoves,
+    CAAddInterest,
+    CAAddInterestMultiple,
+    CARemoveInterest,
+    // State Server
+    SSCreateObjectWithRequired,
+    SSCreateObjectWithRequiredOther,
+    SSDeleteAIObjects,
+    SSObjectGetField,
+    SSObjectGetFieldResp,
+    SSObjectGetFields,
+    SSObjectGetFieldsResp,
+    SSObjectGetAll,
+    SSObjectGetAllResp,
+    SSObjectSetField,
+    SSObjectSetFields,
+    SSObjectDeleteFieldRAM,
+    SSObjectDeleteFieldsRAM,
+    SSObjectDeleteRAM,
+    SSObjectSetLocation,
+    SSObjectChangingLocation,
+    SSObjectEnterLocationWithRequired,
+    SSObjectEnterLocationWithRequiredOther,
+    SSObjectGetLocation,
+    SSObjectGetLocationResp,
+    SSObjectLocationAck,
+    SSObjectSetAI,
+    SSObjectChangingAI,
+    SSObjectEnterAIWithRequired,
+    SSObjectEnterAIWithRequiredOther,
+    SSObjectGetAI,
+    SSObjectGetAIResp,
+    SSObjectSetOwner,
+    SSObjectChangingOwner,
+    SSObjectEnterOwnerWithRequired,
+    SSObjectEnterOwnerWithRequiredOther,
+    SSObjectGetOwner,
+    SSObjectGetOwnerResp,
+    SSObjectEnterInterestWithRequired,
+    SSObjectEnterInterestWithRequiredOther,
+    SSObjectGetZoneObjects,
+    SSObjectGetZonesObjects,
+    SSObjectGetChildren,
+    SSObjectGetZoneCount,
+    SSObjectGetZoneCountResp,
+    SSObjectGetZonesCount,
+    SSObjectGetZonesCountResp,
+    SSObjectGetChildCount,
+    SSObjectGetChildCountResp,
+    SSObjectDeleteZone,
+    SSObjectDeleteZones,
+    SSObjectDeleteChildren,
+    SSGetActiveZones,
+    SSGetActiveZonesResp,
+    // Database State Server
+    DBSSObjectActivateWithDefaults,
+    DBSSObjectActivateWithDefaultsOther,
+    DBSSObjectGetActivated,
+    DBSSObjectGetActivatedResp,
+    DBSSObjectDeleteFieldDisk,
+    DBSSObjectDeleteFieldsDisk,
+    DBSSObjectDeleteDisk,
+    // Database Server
+    DBCreateObject,
+    DBCreateObjectResp,
+    DBObjectGetField,
+    DBObjectGetFieldResp,
+    DBObjectGetFields,
+    DBObjectGetFieldsResp,
+    DBObjectGetAll,
+    DBObjectGetAllResp,
+    DBObjectSetField,
+    DBObjectSetFields,
+    DBObjectSetFieldIfEquals,
+    DBObjectSetFieldIfEqualsResp,
+    DBObjectSetFieldsIfEquals,
+    DBObjectSetFieldsIfEqualsResp,
+    DBObjectSetFieldIfEmpty,
+    DBObjectSetFieldIfEmptyResp,
+    DBObjectSetFieldsIfEmpty,
+    DBObjectSetFieldsIfEmptyResp,
+    DBObjectDeleteField,
+    DBObjectDeleteFields,
+    DBObjectDelete,
+    // Message Director
+    MDAddChannel,
+    MDRemoveChannel,
+    MDAddRange,
+    MDRemoveRange,
+    MDAddPostRemove,
+    MDClearPostRemoves,
+    MDSetConName,
+    MDSetConUrl,
+    MDLogMessage,
+    MDPeerHello,
+    MDPeerForward,
+    MDWatchInterest,
+    MDInterestSynced,
+    MDAddTrace,
+    MDRemoveTrace,
+    MDTraced,
+    MDAuthenticate,
+    MDResume,
+    MDResync,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Creates a value with non-default data for round-trip tests.
+    pub trait Sample {
+        fn sample() -> Self;
+    }
+
+    impl Sample for bool {
+        fn sample() -> Self {
+            true
+        }
+    }
+
+    impl Sample for u8 {
+        fn sample() -> Self {
+            0xA1
+        }
+    }
+
+    impl Sample for u16 {
+        fn sample() -> Self {
+            0xB2B3
+        }
+    }
+
+    impl Sample for u32 {
+        fn sample() -> Self {
+            0xC4C5C6C7
+        }
+    }
+
+    impl Sample for u64 {
+        fn sample() -> Self {
+            0xD8D9DADBDCDDDEDF
+        }
+    }
+
+    impl Sample for String {
+        fn sample() -> Self {
+            String::from("Donet")
+        }
+    }
+
+    impl Sample for Bytes {
+        fn sample() -> Self {
+            Bytes::from_static(&[0x01, 0x02, 0x03])
+        }
+    }
+
+    impl Sample for Payload {
+        fn sample() -> Self {
+            Self(Bytes::from_static(&[0xF0, 0xF1, 0xF2, 0xF3, 0xF4]))
+        }
+    }
+
+    impl<T: Sample> Sample for Vec<T> {
+        fn sample() -> Self {
+            vec![T::sample(), T::sample(), T::sample()]
+        }
+    }
+
+    /// Encodes a message, decodes it through [`Message::decode`],
+    /// and checks that the decoded message equals the original.
+    pub fn assert_round_trip<M>(msg: M)
+    where
+        M: ProtocolMessage + Into<Message> + Clone,
+    {
+        let msg: Message = msg.into();
+        let mut dg: Datagram = Datagram::default();
+
+        msg.encode(&mut dg).expect("Failed to encode message.");
+
+        let mut dgi: DatagramIterator = dg.into();
+        let decoded: Message = Message::decode(&mut dgi).expect("Failed to decode message.");
+
+        assert_eq!(decoded.msg_type(), M::MSG_TYPE);
+        assert_eq!(decoded, msg);
+        assert_eq!(dgi.get_remaining(), 0);
+    }
+
+    #[test]
+    fn encode_body_layout() {
+        let msg = SSObjectSetField {
+            do_id: 1000,
+            field_id: 5,
+            data: Payload(Bytes::from_static(&[0xAA, 0xBB])),
+        };
+        let mut dg: Datagram = Datagram::default();
+        msg.encode(&mut dg).unwrap();
+
+        assert_eq!(dg.get_buffer(), &[0xE8, 0x03, 0, 0, 5, 0, 0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn decode_after_internal_header() {
+        let msg = CAAddInterestMultiple {
+            interest_id: 7,
+            parent_id: 4000,
+            zones: vec![2000, 2001],
+        };
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![1234], 5678, CAAddInterestMultiple::MSG_TYPE.into())
+            .unwrap();
+        msg.encode(&mut dg).unwrap();
+
+        let mut dgi: DatagramIterator = dg.into();
+        assert_eq!(dgi.read_recipient_count(), Ok(1));
+        assert_eq!(dgi.read_channel(), Ok(1234));
+        assert_eq!(dgi.read_channel(), Ok(5678));
+        assert_eq!(Message::decode(&mut dgi), Ok(Message::from(msg)));
+    }
+
+    #[test]
+    fn decode_truncated_body() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_u16(Protocol::MDAddRange.into()).unwrap();
+        dg.add_channel(100).unwrap();
+
+        let mut dgi: DatagramIterator = dg.into();
+        assert_eq!(Message::decode(&mut dgi), Err(IteratorError::EndOfFile));
+    }
+}
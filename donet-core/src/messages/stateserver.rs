@@ -0,0 +1,381 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Messages handled by the State Server.
+
+use super::Payload;
+use crate::globals::*;
+
+protocol_messages! {
+    /// `STATESERVER_CREATE_OBJECT_WITH_REQUIRED` (2000)
+    SSCreateObjectWithRequired {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_CREATE_OBJECT_WITH_REQUIRED_OTHER` (2001)
+    SSCreateObjectWithRequiredOther {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields, followed by other fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_DELETE_AI_OBJECTS` (2009)
+    SSDeleteAIObjects {
+        ai_channel: Channel,
+    }
+
+    /// `STATESERVER_OBJECT_GET_FIELD` (2010)
+    SSObjectGetField {
+        context: u32,
+        do_id: DoId,
+        field_id: FieldId,
+    }
+
+    /// `STATESERVER_OBJECT_GET_FIELD_RESP` (2011)
+    SSObjectGetFieldResp {
+        context: u32,
+        success: bool,
+        /// The field ID and its value, if successful.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_GET_FIELDS` (2012)
+    SSObjectGetFields {
+        context: u32,
+        do_id: DoId,
+        field_ids: Vec<FieldId>,
+    }
+
+    /// `STATESERVER_OBJECT_GET_FIELDS_RESP` (2013)
+    SSObjectGetFieldsResp {
+        context: u32,
+        success: bool,
+        field_count: u16,
+        /// `field_count` pairs of field ID and value.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_GET_ALL` (2014)
+    SSObjectGetAll {
+        context: u32,
+        do_id: DoId,
+    }
+
+    /// `STATESERVER_OBJECT_GET_ALL_RESP` (2015)
+    SSObjectGetAllResp {
+        context: u32,
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields, followed by other fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_SET_FIELD` (2020)
+    SSObjectSetField {
+        do_id: DoId,
+        field_id: FieldId,
+        /// The field's value.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_SET_FIELDS` (2021)
+    SSObjectSetFields {
+        do_id: DoId,
+        field_count: u16,
+        /// `field_count` pairs of field ID and value.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_DELETE_FIELD_RAM` (2030)
+    SSObjectDeleteFieldRAM {
+        do_id: DoId,
+        field_id: FieldId,
+    }
+
+    /// `STATESERVER_OBJECT_DELETE_FIELDS_RAM` (2031)
+    SSObjectDeleteFieldsRAM {
+        do_id: DoId,
+        field_ids: Vec<FieldId>,
+    }
+
+    /// `STATESERVER_OBJECT_DELETE_RAM` (2032)
+    SSObjectDeleteRAM {
+        do_id: DoId,
+    }
+
+    /// `STATESERVER_OBJECT_SET_LOCATION` (2040)
+    SSObjectSetLocation {
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+
+    /// `STATESERVER_OBJECT_CHANGING_LOCATION` (2041)
+    SSObjectChangingLocation {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        old_parent_id: DoId,
+        old_zone_id: Zone,
+    }
+
+    /// `STATESERVER_OBJECT_ENTER_LOCATION_WITH_REQUIRED` (2042)
+    SSObjectEnterLocationWithRequired {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_ENTER_LOCATION_WITH_REQUIRED_OTHER` (2043)
+    SSObjectEnterLocationWithRequiredOther {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields, followed by other fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_GET_LOCATION` (2044)
+    SSObjectGetLocation {
+        context: u32,
+    }
+
+    /// `STATESERVER_OBJECT_GET_LOCATION_RESP` (2045)
+    SSObjectGetLocationResp {
+        context: u32,
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+
+    /// `STATESERVER_OBJECT_LOCATION_ACK` (2046)
+    SSObjectLocationAck {
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+
+    /// `STATESERVER_OBJECT_SET_AI` (2050)
+    SSObjectSetAI {
+        ai_channel: Channel,
+    }
+
+    /// `STATESERVER_OBJECT_CHANGING_AI` (2051)
+    SSObjectChangingAI {
+        do_id: DoId,
+        new_ai: Channel,
+        old_ai: Channel,
+    }
+
+    /// `STATESERVER_OBJECT_ENTER_AI_WITH_REQUIRED` (2052)
+    SSObjectEnterAIWithRequired {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_ENTER_AI_WITH_REQUIRED_OTHER` (2053)
+    SSObjectEnterAIWithRequiredOther {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields, followed by other fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_GET_AI` (2054)
+    SSObjectGetAI {
+        context: u32,
+    }
+
+    /// `STATESERVER_OBJECT_GET_AI_RESP` (2055)
+    SSObjectGetAIResp {
+        context: u32,
+        do_id: DoId,
+        ai_channel: Channel,
+    }
+
+    /// `STATESERVER_OBJECT_SET_OWNER` (2060)
+    SSObjectSetOwner {
+        owner_channel: Channel,
+    }
+
+    /// `STATESERVER_OBJECT_CHANGING_OWNER` (2061)
+    SSObjectChangingOwner {
+        do_id: DoId,
+        new_owner: Channel,
+        old_owner: Channel,
+    }
+
+    /// `STATESERVER_OBJECT_ENTER_OWNER_WITH_REQUIRED` (2062)
+    SSObjectEnterOwnerWithRequired {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_ENTER_OWNER_WITH_REQUIRED_OTHER` (2063)
+    SSObjectEnterOwnerWithRequiredOther {
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields, followed by other fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_GET_OWNER` (2064)
+    SSObjectGetOwner {
+        context: u32,
+    }
+
+    /// `STATESERVER_OBJECT_GET_OWNER_RESP` (2065)
+    SSObjectGetOwnerResp {
+        context: u32,
+        do_id: DoId,
+        owner_channel: Channel,
+    }
+
+    /// `STATESERVER_OBJECT_ENTER_INTEREST_WITH_REQUIRED` (2066)
+    SSObjectEnterInterestWithRequired {
+        context: u32,
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_ENTER_INTEREST_WITH_REQUIRED_OTHER` (2067)
+    SSObjectEnterInterestWithRequiredOther {
+        context: u32,
+        do_id: DoId,
+        parent_id: DoId,
+        zone_id: Zone,
+        dclass_id: DClassId,
+        /// Values of the class's `required` fields, followed by other fields.
+        data: Payload,
+    }
+
+    /// `STATESERVER_OBJECT_GET_ZONE_OBJECTS` (2100)
+    SSObjectGetZoneObjects {
+        context: u32,
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+
+    /// `STATESERVER_OBJECT_GET_ZONES_OBJECTS` (2102)
+    SSObjectGetZonesObjects {
+        context: u32,
+        parent_id: DoId,
+        zones: Vec<Zone>,
+    }
+
+    /// `STATESERVER_OBJECT_GET_CHILDREN` (2104)
+    SSObjectGetChildren {
+        context: u32,
+        parent_id: DoId,
+    }
+
+    /// `STATESERVER_OBJECT_GET_ZONE_COUNT` (2110)
+    SSObjectGetZoneCount {
+        context: u32,
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+
+    /// `STATESERVER_OBJECT_GET_ZONE_COUNT_RESP` (2111)
+    SSObjectGetZoneCountResp {
+        context: u32,
+        count: u32,
+    }
+
+    /// `STATESERVER_OBJECT_GET_ZONES_COUNT` (2112)
+    SSObjectGetZonesCount {
+        context: u32,
+        parent_id: DoId,
+        zones: Vec<Zone>,
+    }
+
+    /// `STATESERVER_OBJECT_GET_ZONES_COUNT_RESP` (2113)
+    SSObjectGetZonesCountResp {
+        context: u32,
+        count: u32,
+    }
+
+    /// `STATESERVER_OBJECT_GET_CHILD_COUNT` (2114)
+    SSObjectGetChildCount {
+        context: u32,
+        parent_id: DoId,
+    }
+
+    /// `STATESERVER_OBJECT_GET_CHILD_COUNT_RESP` (2115)
+    SSObjectGetChildCountResp {
+        context: u32,
+        object_count: u32,
+    }
+
+    /// `STATESERVER_OBJECT_DELETE_ZONE` (2120)
+    SSObjectDeleteZone {
+        parent_id: DoId,
+        zone_id: Zone,
+    }
+
+    /// `STATESERVER_OBJECT_DELETE_ZONES` (2122)
+    SSObjectDeleteZones {
+        parent_id: DoId,
+        zones: Vec<Zone>,
+    }
+
+    /// `STATESERVER_OBJECT_DELETE_CHILDREN` (2124)
+    SSObjectDeleteChildren {
+        parent_id: DoId,
+    }
+
+    /// `STATESERVER_GET_ACTIVE_ZONES` (2125)
+    SSGetActiveZones {
+        context: u32,
+    }
+
+    /// `STATESERVER_GET_ACTIVE_ZONES_RESP` (2126)
+    SSGetActiveZonesResp {
+        context: u32,
+        zones: Vec<Zone>,
+    }
+}
@@ -0,0 +1,807 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Validation of whole datagrams before they are dispatched.
+//!
+//! [`DatagramIterator`] only reports an error once a read runs past
+//! the end of the datagram. A [`Validator`] checks the header, message
+//! type, and body of a datagram against the typed message definitions,
+//! and that no bytes are left over. If given a [`FieldSchema`], it also
+//! checks the DC field values in the message's [`Payload`].
+//!
+//! Services should validate a datagram before acting on it, so that a
+//! malformed message is rejected before any state is mutated. Errors
+//! describe which element of the datagram was invalid, and where.
+
+use super::{Message, MessageField, Payload};
+use crate::datagram::iterator::{DatagramIterator, IteratorError};
+use crate::globals::*;
+use crate::protocol::Protocol;
+use thiserror::Error;
+
+/// An element of a datagram that failed validation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element {
+    RecipientCount,
+    /// Recipient channel, by its index in the header.
+    Recipient(u8),
+    Sender,
+    MsgType,
+    /// A parameter of a message body, by message and parameter name.
+    Parameter {
+        message: &'static str,
+        name: &'static str,
+    },
+    /// The field count of a list of field values.
+    FieldCount,
+    /// The field ID of a field value.
+    FieldId,
+    /// A packed DC field value.
+    FieldValue(FieldId),
+    /// The end of the datagram, or of a message's field values.
+    End,
+}
+
+impl std::fmt::Display for Element {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RecipientCount => write!(f, "recipient count"),
+            Self::Recipient(index) => write!(f, "recipient #{}", index),
+            Self::Sender => write!(f, "sender"),
+            Self::MsgType => write!(f, "message type"),
+            Self::Parameter { message, name } => write!(f, "{}.{}", message, name),
+            Self::FieldCount => write!(f, "field count"),
+            Self::FieldId => write!(f, "field ID"),
+            Self::FieldValue(field_id) => write!(f, "value of field {}", field_id),
+            Self::End => write!(f, "end of message"),
+        }
+    }
+}
+
+/// The reason that an element of a datagram failed validation.
+#[derive(Debug, Error, PartialEq)]
+pub enum ValidationErrorKind {
+    #[error("datagram ends before this element")]
+    Truncated,
+    #[error("internal message has no recipients")]
+    NoRecipients,
+    #[error("unknown message type {0}")]
+    UnknownMsgType(MsgType),
+    #[error("{0} trailing bytes")]
+    TrailingBytes(usize),
+    #[error("unknown field {0}")]
+    UnknownField(FieldId),
+    #[error("unknown class {0}")]
+    UnknownClass(DClassId),
+    #[error("invalid value; {0}")]
+    InvalidValue(String),
+}
+
+impl From<IteratorError> for ValidationErrorKind {
+    fn from(value: IteratorError) -> Self {
+        match value {
+            IteratorError::EndOfFile => Self::Truncated,
+            err => Self::InvalidValue(err.to_string()),
+        }
+    }
+}
+
+/// Custom error type for [`Validator`].
+///
+/// `offset` is the index of the invalid element in the datagram, in bytes.
+#[derive(Debug, Error, PartialEq)]
+#[error("invalid {element} at byte {offset}; {kind}")]
+pub struct ValidationError {
+    pub element: Element,
+    pub offset: usize,
+    pub kind: ValidationErrorKind,
+}
+
+impl ValidationError {
+    pub fn new(element: Element, offset: usize, kind: ValidationErrorKind) -> Self {
+        Self {
+            element,
+            offset,
+            kind,
+        }
+    }
+}
+
+/// Reads a message parameter with [`MessageField::decode_strict`].
+///
+/// Used by the [`ProtocolMessage::decode_validated`] implementations.
+///
+/// [`ProtocolMessage::decode_validated`]: super::ProtocolMessage::decode_validated
+pub fn parameter<T: MessageField>(
+    dgi: &mut DatagramIterator,
+    message: &'static str,
+    name: &'static str,
+) -> Result<T, ValidationError> {
+    let offset: usize = dgi.tell();
+
+    T::decode_strict(dgi)
+        .map_err(|err| ValidationError::new(Element::Parameter { message, name }, offset, err.into()))
+}
+
+/// Provides the DC field types that are needed to validate
+/// the field values in a message's [`Payload`].
+pub trait FieldSchema {
+    /// Reads one packed value of the given field, returning
+    /// an error if the field is unknown or the value is malformed.
+    fn read_field(&self, field_id: FieldId, dgi: &mut DatagramIterator) -> Result<(), ValidationErrorKind>;
+
+    /// Returns the IDs of the given class's `required` fields, in order.
+    fn required_fields(&self, dclass_id: DClassId) -> Result<Vec<FieldId>, ValidationErrorKind>;
+}
+
+/// The header of a validated internal message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageHeader {
+    pub recipients: Vec<Channel>,
+    /// `None` if this is a control message, which has no sender.
+    pub sender: Option<Channel>,
+}
+
+/// Validates datagrams against the typed message definitions.
+#[derive(Default)]
+pub struct Validator<'s> {
+    schema: Option<&'s dyn FieldSchema>,
+}
+
+impl<'s> Validator<'s> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a [`Validator`] that also checks the
+    /// field values in message payloads.
+    pub fn with_schema(schema: &'s dyn FieldSchema) -> Self {
+        Self { schema: Some(schema) }
+    }
+
+    /// Validates an internal message, which starts with the internal
+    /// header, or the control header if it is a control message.
+    ///
+    /// The datagram is validated from its start, regardless
+    /// of the read position of the given iterator.
+    pub fn validate_internal(
+        &self,
+        dgi: &DatagramIterator,
+    ) -> Result<(MessageHeader, Message), ValidationError> {
+        let mut dgi: DatagramIterator = dgi.clone();
+        dgi.seek(0);
+
+        let recipient_count: u8 = read(&mut dgi, Element::RecipientCount, DatagramIterator::read_u8)?;
+
+        if recipient_count == 0 {
+            return Err(ValidationError::new(
+                Element::RecipientCount,
+                0,
+                ValidationErrorKind::NoRecipients,
+            ));
+        }
+        let mut recipients: Vec<Channel> = Vec::with_capacity(recipient_count.into());
+
+        for index in 0..recipient_count {
+            recipients.push(read(
+                &mut dgi,
+                Element::Recipient(index),
+                DatagramIterator::read_channel,
+            )?);
+        }
+
+        let sender: Option<Channel> = match recipients[..] {
+            [CONTROL_CHANNEL] => None,
+            _ => Some(read(&mut dgi, Element::Sender, DatagramIterator::read_channel)?),
+        };
+        let message: Message = self.validate_message(&mut dgi)?;
+
+        Ok((MessageHeader { recipients, sender }, message))
+    }
+
+    /// Validates a client message, which has no header before its message type.
+    ///
+    /// The datagram is validated from its start, regardless
+    /// of the read position of the given iterator.
+    pub fn validate_client(&self, dgi: &DatagramIterator) -> Result<Message, ValidationError> {
+        let mut dgi: DatagramIterator = dgi.clone();
+        dgi.seek(0);
+
+        self.validate_message(&mut dgi)
+    }
+
+    /// Validates the message type, body, and end of a message.
+    fn validate_message(&self, dgi: &mut DatagramIterator) -> Result<Message, ValidationError> {
+        let offset: usize = dgi.tell();
+        let msg_type: MsgType = read(dgi, Element::MsgType, DatagramIterator::read_u16)?;
+
+        let msg_type: Protocol = Protocol::try_from(msg_type).map_err(|_| {
+            ValidationError::new(
+                Element::MsgType,
+                offset,
+                ValidationErrorKind::UnknownMsgType(msg_type),
+            )
+        })?;
+        let message: Message = Message::decode_validated(msg_type, dgi)?;
+
+        if let Some(schema) = self.schema {
+            if let Some((layout, payload)) = payload_layout(&message) {
+                let offset: usize = dgi.tell() - payload.0.len();
+                validate_payload(schema, layout, payload, offset)?;
+            }
+        }
+        check_end(dgi)?;
+        Ok(message)
+    }
+}
+
+/// Reads an element, converting an [`IteratorError`] into a [`ValidationError`].
+fn read<T>(
+    dgi: &mut DatagramIterator,
+    element: Element,
+    read_fn: fn(&mut DatagramIterator) -> Result<T, IteratorError>,
+) -> Result<T, ValidationError> {
+    let offset: usize = dgi.tell();
+    read_fn(dgi).map_err(|err| ValidationError::new(element, offset, err.into()))
+}
+
+/// Returns an error if there are bytes left to read.
+fn check_end(dgi: &mut DatagramIterator) -> Result<(), ValidationError> {
+    match dgi.get_remaining() {
+        0 => Ok(()),
+        n => Err(ValidationError::new(
+            Element::End,
+            dgi.tell(),
+            ValidationErrorKind::TrailingBytes(n),
+        )),
+    }
+}
+
+/// What the [`Payload`] of a message holds.
+enum PayloadLayout {
+    /// One value of the given field.
+    Value(FieldId),
+    /// The given number of field ID and value pairs.
+    Fields(u16),
+    /// Values of the class's `required` fields.
+    Required(DClassId),
+    /// Values of the class's `required` fields, followed by
+    /// a field count and field ID and value pairs.
+    RequiredOther(DClassId),
+    /// A field count, followed by field ID and value pairs.
+    Other,
+}
+
+/// Returns the layout of a message's payload, if it can be
+/// validated. Payloads of other messages are not checked.
+fn payload_layout(message: &Message) -> Option<(PayloadLayout, &Payload)> {
+    use PayloadLayout::*;
+
+    Some(match message {
+        Message::ClientObjectSetField(m) => (Value(m.field_id), &m.data),
+        Message::SSObjectSetField(m) => (Value(m.field_id), &m.data),
+        Message::DBObjectSetField(m) => (Value(m.field_id), &m.data),
+        Message::ClientObjectSetFields(m) => (Fields(m.field_count), &m.data),
+        Message::SSObjectSetFields(m) => (Fields(m.field_count), &m.data),
+        Message::DBObjectSetFields(m) => (Fields(m.field_count), &m.data),
+        Message::DBCreateObject(m) => (Fields(m.field_count), &m.data),
+        Message::ClientEnterObjectRequired(m) => (Required(m.dclass_id), &m.data),
+        Message::ClientEnterObjectRequiredOwner(m) => (Required(m.dclass_id), &m.data),
+        Message::SSCreateObjectWithRequired(m) => (Required(m.dclass_id), &m.data),
+        Message::SSObjectEnterLocationWithRequired(m) => (Required(m.dclass_id), &m.data),
+        Message::SSObjectEnterAIWithRequired(m) => (Required(m.dclass_id), &m.data),
+        Message::SSObjectEnterOwnerWithRequired(m) => (Required(m.dclass_id), &m.data),
+        Message::SSObjectEnterInterestWithRequired(m) => (Required(m.dclass_id), &m.data),
+        Message::ClientEnterObjectRequiredOther(m) => (RequiredOther(m.dclass_id), &m.data),
+        Message::ClientEnterObjectRequiredOwnerOther(m) => (RequiredOther(m.dclass_id), &m.data),
+        Message::SSCreateObjectWithRequiredOther(m) => (RequiredOther(m.dclass_id), &m.data),
+        Message::SSObjectGetAllResp(m) => (RequiredOther(m.dclass_id), &m.data),
+        Message::SSObjectEnterLocationWithRequiredOther(m) => (RequiredOther(m.dclass_id), &m.data),
+        Message::SSObjectEnterAIWithRequiredOther(m) => (RequiredOther(m.dclass_id), &m.data),
+        Message::SSObjectEnterOwnerWithRequiredOther(m) => (RequiredOther(m.dclass_id), &m.data),
+        Message::SSObjectEnterInterestWithRequiredOther(m) => (RequiredOther(m.dclass_id), &m.data),
+        Message::DBSSObjectActivateWithDefaultsOther(m) => (Other, &m.data),
+        _ => return None,
+    })
+}
+
+/// Checks the field values in a payload against the schema.
+/// `offset` is the index of the payload in the datagram.
+fn validate_payload(
+    schema: &dyn FieldSchema,
+    layout: PayloadLayout,
+    payload: &Payload,
+    offset: usize,
+) -> Result<(), ValidationError> {
+    let mut dgi: DatagramIterator = payload.0.clone().into();
+
+    let error = |element: Element, index: usize, kind: ValidationErrorKind| {
+        ValidationError::new(element, offset + index, kind)
+    };
+
+    let read_value = |dgi: &mut DatagramIterator, field_id: FieldId| {
+        let index: usize = dgi.tell();
+        schema
+            .read_field(field_id, dgi)
+            .map_err(|kind| error(Element::FieldValue(field_id), index, kind))
+    };
+
+    let read_fields = |dgi: &mut DatagramIterator, count: u16| {
+        for _ in 0..count {
+            let index: usize = dgi.tell();
+            let field_id: FieldId = dgi
+                .read_u16()
+                .map_err(|err| error(Element::FieldId, index, err.into()))?;
+
+            read_value(dgi, field_id)?;
+        }
+        Ok(())
+    };
+
+    let read_count = |dgi: &mut DatagramIterator| {
+        let index: usize = dgi.tell();
+        dgi.read_u16()
+            .map_err(|err| error(Element::FieldCount, index, err.into()))
+    };
+
+    let read_required = |dgi: &mut DatagramIterator, dclass_id: DClassId| {
+        let fields: Vec<FieldId> = schema
+            .required_fields(dclass_id)
+            .map_err(|kind| error(Element::FieldValue(0), dgi.tell(), kind))?;
+
+        for field_id in fields {
+            read_value(dgi, field_id)?;
+        }
+        Ok::<(), ValidationError>(())
+    };
+
+    match layout {
+        PayloadLayout::Value(field_id) => read_value(&mut dgi, field_id)?,
+        PayloadLayout::Fields(count) => read_fields(&mut dgi, count)?,
+        PayloadLayout::Required(dclass_id) => read_required(&mut dgi, dclass_id)?,
+        PayloadLayout::RequiredOther(dclass_id) => {
+            read_required(&mut dgi, dclass_id)?;
+            let count: u16 = read_count(&mut dgi)?;
+            read_fields(&mut dgi, count)?;
+        }
+        PayloadLayout::Other => {
+            let count: u16 = read_count(&mut dgi)?;
+            read_fields(&mut dgi, count)?;
+        }
+    }
+
+    match dgi.get_remaining() {
+        0 => Ok(()),
+        n => Err(error(
+            Element::End,
+            dgi.tell(),
+            ValidationErrorKind::TrailingBytes(n),
+        )),
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "dcfile")] {
+        use crate::dcfile::DCFile;
+        use crate::dctype::{DCTypeDefinition, DCTypeEnum};
+        use std::collections::HashMap;
+
+        /// A [`FieldSchema`] built from the parameter types of DC fields.
+        ///
+        /// Struct and method parameters cannot be validated by their
+        /// type alone, so values of fields with them are rejected.
+        ///
+        /// No service deserializes field updates yet, so nothing builds
+        /// a schema outside of tests. The State Server and database will.
+        #[derive(Debug, Default)]
+        pub struct FieldTypes {
+            fields: HashMap<FieldId, Vec<DCTypeDefinition>>,
+            required: HashMap<DClassId, Vec<FieldId>>,
+        }
+
+        impl FieldTypes {
+            pub fn new() -> Self {
+                Self::default()
+            }
+
+            /// Builds the types of every field and the `required` fields
+            /// of every class declared in a DC file.
+            pub fn from_dc_file(dc_file: &DCFile) -> Self {
+                let mut schema: Self = Self::new();
+
+                for (field_id, parameters) in dc_file.get_field_types() {
+                    schema.add_field(field_id, parameters.to_vec());
+                }
+                for (dclass_id, required) in dc_file.get_required_fields() {
+                    schema.add_dclass(dclass_id, required.to_vec());
+                }
+                schema
+            }
+
+            /// Sets the types of a field's parameters, in order.
+            pub fn add_field(&mut self, field_id: FieldId, parameters: Vec<DCTypeDefinition>) {
+                self.fields.insert(field_id, parameters);
+            }
+
+            /// Sets the IDs of a class's `required` fields, in order.
+            pub fn add_dclass(&mut self, dclass_id: DClassId, required: Vec<FieldId>) {
+                self.required.insert(dclass_id, required);
+            }
+
+            /// Reads past one packed value of the given type.
+            fn skip_value(dtype: &DCTypeDefinition, dgi: &mut DatagramIterator) -> Result<(), ValidationErrorKind> {
+                let size: usize = match dtype.data_type {
+                    DCTypeEnum::TInt8 | DCTypeEnum::TUInt8 | DCTypeEnum::TChar => 1,
+                    DCTypeEnum::TInt16 | DCTypeEnum::TUInt16 => 2,
+                    DCTypeEnum::TInt32 | DCTypeEnum::TUInt32 | DCTypeEnum::TFloat32 => 4,
+                    DCTypeEnum::TInt64 | DCTypeEnum::TUInt64 | DCTypeEnum::TFloat64 => 8,
+                    DCTypeEnum::TString | DCTypeEnum::TBlob | DCTypeEnum::TArray
+                        if !dtype.is_variable_length() =>
+                    {
+                        dtype.get_size().into()
+                    }
+                    DCTypeEnum::TString
+                    | DCTypeEnum::TVarString
+                    | DCTypeEnum::TBlob
+                    | DCTypeEnum::TVarBlob
+                    | DCTypeEnum::TArray
+                    | DCTypeEnum::TVarArray => dgi.read_size()?.into(),
+                    DCTypeEnum::TBlob32 | DCTypeEnum::TVarBlob32 => dgi.read_u32()? as usize,
+                    DCTypeEnum::TStruct | DCTypeEnum::TMethod => {
+                        return Err(ValidationErrorKind::InvalidValue(format!(
+                            "{} parameters cannot be validated",
+                            dtype.data_type
+                        )))
+                    }
+                };
+                dgi.skip(size)?;
+                Ok(())
+            }
+        }
+
+        impl FieldSchema for FieldTypes {
+            fn read_field(&self, field_id: FieldId, dgi: &mut DatagramIterator) -> Result<(), ValidationErrorKind> {
+                let parameters = self
+                    .fields
+                    .get(&field_id)
+                    .ok_or(ValidationErrorKind::UnknownField(field_id))?;
+
+                for dtype in parameters {
+                    Self::skip_value(dtype, dgi)?;
+                }
+                Ok(())
+            }
+
+            fn required_fields(&self, dclass_id: DClassId) -> Result<Vec<FieldId>, ValidationErrorKind> {
+                self.required
+                    .get(&dclass_id)
+                    .cloned()
+                    .ok_or(ValidationErrorKind::UnknownClass(dclass_id))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datagram::datagram::Datagram;
+    use crate::messages::{DBObjectGetFieldResp, MDAddChannel, ProtocolMessage, SSObjectSetField};
+    use bytes::Bytes;
+
+    fn internal(msg: &impl ProtocolMessage) -> Datagram {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![4000, 4001], 5000, msg_type(msg))
+            .unwrap();
+        msg.encode(&mut dg).unwrap();
+        dg
+    }
+
+    fn msg_type<M: ProtocolMessage>(_: &M) -> MsgType {
+        M::MSG_TYPE.into()
+    }
+
+    #[test]
+    fn valid_internal_message() {
+        let msg: SSObjectSetField = SSObjectSetField {
+            do_id: 1000,
+            field_id: 10,
+            data: Payload(Bytes::from_static(&[1, 2])),
+        };
+        let (header, message) = Validator::new()
+            .validate_internal(&internal(&msg).into())
+            .unwrap();
+
+        assert_eq!(header.recipients, vec![4000, 4001]);
+        assert_eq!(header.sender, Some(5000));
+        assert_eq!(message, Message::SSObjectSetField(msg));
+    }
+
+    #[test]
+    fn valid_control_message() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_control_header(MDAddChannel::MSG_TYPE.into()).unwrap();
+        dg.add_channel(4000).unwrap();
+
+        let (header, message) = Validator::new().validate_internal(&dg.into()).unwrap();
+
+        assert_eq!(header.sender, None);
+        assert_eq!(message, Message::MDAddChannel(MDAddChannel { channel: 4000 }));
+    }
+
+    #[test]
+    fn truncated_parameter() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![4000], 5000, SSObjectSetField::MSG_TYPE.into())
+            .unwrap();
+        dg.add_doid(1000).unwrap();
+        dg.add_u8(0).unwrap(); // half of the field ID
+
+        let err: ValidationError = Validator::new().validate_internal(&dg.into()).unwrap_err();
+
+        assert_eq!(
+            err,
+            ValidationError::new(
+                Element::Parameter {
+                    message: "SSObjectSetField",
+                    name: "field_id",
+                },
+                23,
+                ValidationErrorKind::Truncated,
+            )
+        );
+        assert_eq!(
+            err.to_string(),
+            "invalid SSObjectSetField.field_id at byte 23; datagram ends before this element"
+        );
+    }
+
+    #[test]
+    fn truncated_recipient() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_u8(2).unwrap();
+        dg.add_channel(4000).unwrap();
+
+        let err: ValidationError = Validator::new().validate_internal(&dg.into()).unwrap_err();
+
+        assert_eq!(
+            err,
+            ValidationError::new(Element::Recipient(1), 9, ValidationErrorKind::Truncated)
+        );
+    }
+
+    #[test]
+    fn no_recipients() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_u8(0).unwrap();
+
+        let err: ValidationError = Validator::new().validate_internal(&dg.into()).unwrap_err();
+
+        assert_eq!(err.kind, ValidationErrorKind::NoRecipients);
+    }
+
+    #[test]
+    fn unknown_msg_type() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![4000], 5000, 65000).unwrap();
+
+        let err: ValidationError = Validator::new().validate_internal(&dg.into()).unwrap_err();
+
+        assert_eq!(
+            err,
+            ValidationError::new(Element::MsgType, 17, ValidationErrorKind::UnknownMsgType(65000))
+        );
+    }
+
+    #[test]
+    fn invalid_bool() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![4000], 5000, DBObjectGetFieldResp::MSG_TYPE.into())
+            .unwrap();
+        dg.add_u32(1).unwrap();
+        dg.add_u8(2).unwrap();
+
+        let err: ValidationError = Validator::new().validate_internal(&dg.into()).unwrap_err();
+
+        assert_eq!(
+            err.element,
+            Element::Parameter {
+                message: "DBObjectGetFieldResp",
+                name: "success",
+            }
+        );
+        assert_eq!(err.offset, 23);
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_control_header(MDAddChannel::MSG_TYPE.into()).unwrap();
+        dg.add_channel(4000).unwrap();
+        dg.add_u16(0).unwrap();
+
+        let err: ValidationError = Validator::new().validate_internal(&dg.into()).unwrap_err();
+
+        assert_eq!(
+            err,
+            ValidationError::new(Element::End, 19, ValidationErrorKind::TrailingBytes(2))
+        );
+    }
+
+    #[test]
+    fn client_message() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_u16(65000).unwrap();
+
+        let err: ValidationError = Validator::new().validate_client(&dg.into()).unwrap_err();
+
+        assert_eq!(
+            err,
+            ValidationError::new(Element::MsgType, 0, ValidationErrorKind::UnknownMsgType(65000))
+        );
+    }
+
+    #[cfg(feature = "dcfile")]
+    mod schema {
+        use super::*;
+        use crate::dctype::{DCTypeDefinition, DCTypeEnum};
+
+        fn schema() -> FieldTypes {
+            let mut schema: FieldTypes = FieldTypes::new();
+            schema.add_field(
+                10,
+                vec![DCTypeEnum::TUInt16.into(), DCTypeEnum::TVarString.into()],
+            );
+            schema.add_dclass(1, vec![10]);
+            schema
+        }
+
+        fn set_field(field_id: FieldId, data: &'static [u8]) -> Datagram {
+            internal(&SSObjectSetField {
+                do_id: 1000,
+                field_id,
+                data: Payload(Bytes::from_static(data)),
+            })
+        }
+
+        #[test]
+        fn valid_field_value() {
+            let schema: FieldTypes = schema();
+            let dg: Datagram = set_field(10, &[0x01, 0x00, 0x02, 0x00, b'h', b'i']);
+
+            assert!(Validator::with_schema(&schema)
+                .validate_internal(&dg.into())
+                .is_ok());
+        }
+
+        #[test]
+        fn field_value_trailing_bytes() {
+            let schema: FieldTypes = schema();
+            let dg: Datagram = set_field(10, &[0x01, 0x00, 0x00, 0x00, 0xFF]);
+
+            // Without a schema, the payload is not checked.
+            assert!(Validator::new().validate_internal(&dg.clone().into()).is_ok());
+
+            let err: ValidationError = Validator::with_schema(&schema)
+                .validate_internal(&dg.into())
+                .unwrap_err();
+
+            assert_eq!(
+                err,
+                ValidationError::new(Element::End, 37, ValidationErrorKind::TrailingBytes(1))
+            );
+        }
+
+        #[test]
+        fn truncated_field_value() {
+            let schema: FieldTypes = schema();
+            let dg: Datagram = set_field(10, &[0x01, 0x00, 0x05, 0x00, b'h']);
+
+            let err: ValidationError = Validator::with_schema(&schema)
+                .validate_internal(&dg.into())
+                .unwrap_err();
+
+            assert_eq!(
+                err,
+                ValidationError::new(Element::FieldValue(10), 33, ValidationErrorKind::Truncated)
+            );
+        }
+
+        #[test]
+        fn unknown_field() {
+            let schema: FieldTypes = schema();
+            let dg: Datagram = set_field(11, &[0x01]);
+
+            let err: ValidationError = Validator::with_schema(&schema)
+                .validate_internal(&dg.into())
+                .unwrap_err();
+
+            assert_eq!(err.kind, ValidationErrorKind::UnknownField(11));
+        }
+
+        #[test]
+        fn schema_from_dc_file() {
+            use crate::dcfile::DCFile;
+            use crate::dconfig::DCFileConfig;
+
+            let dc_string: &str = "
+                typedef uint32 doId;
+
+                dclass DistributedDonut {
+                    set_name(string name) required broadcast ram;
+                    set_size(uint16 size) required;
+                };
+
+                dclass DistributedFilledDonut : DistributedDonut {
+                    set_filling(string(4) filling);
+                    set_owner(doId owner) required;
+                    set_label : set_name, set_size;
+                };
+            ";
+            let dc_file: DCFile = crate::read_dc(DCFileConfig::default(), dc_string.into()).unwrap();
+            let schema: FieldTypes = FieldTypes::from_dc_file(&dc_file);
+            let validator: Validator<'_> = Validator::with_schema(&schema);
+
+            assert_eq!(schema.required_fields(0), Ok(vec![0, 1]));
+            assert_eq!(schema.required_fields(1), Ok(vec![0, 1, 3]));
+
+            for (field_id, value) in [
+                (0, &[0x02, 0x00, b'h', b'i'][..]),
+                (1, &[0x05, 0x00]),
+                (2, b"jam!"),
+                (3, &[0x01, 0x00, 0x00, 0x00]),
+                (4, &[0x02, 0x00, b'h', b'i', 0x05, 0x00]),
+            ] {
+                let dg: Datagram = set_field(field_id, value);
+                assert!(validator.validate_internal(&dg.into()).is_ok(), "{}", field_id);
+            }
+
+            // a uint8 where a uint16 is expected
+            let err: ValidationError = validator
+                .validate_internal(&set_field(1, &[0x05]).into())
+                .unwrap_err();
+            assert_eq!(err.kind, ValidationErrorKind::Truncated);
+
+            // a length-tagged string where a fixed-size string is expected
+            let err: ValidationError = validator
+                .validate_internal(&set_field(2, &[0x04, 0x00, b'j', b'a', b'm', b'!']).into())
+                .unwrap_err();
+            assert_eq!(err.kind, ValidationErrorKind::TrailingBytes(2));
+
+            let err: ValidationError = validator
+                .validate_internal(&set_field(5, &[0x01]).into())
+                .unwrap_err();
+            assert_eq!(err.kind, ValidationErrorKind::UnknownField(5));
+        }
+
+        #[test]
+        fn fixed_size_value() {
+            let mut schema: FieldTypes = FieldTypes::new();
+            let mut dtype: DCTypeDefinition = DCTypeEnum::TBlob.into();
+            dtype.size = 3;
+            schema.add_field(10, vec![dtype]);
+
+            let dg: Datagram = set_field(10, &[0x01, 0x02, 0x03]);
+
+            assert!(Validator::with_schema(&schema)
+                .validate_internal(&dg.into())
+                .is_ok());
+        }
+    }
+}
@@ -529,10 +529,18 @@ parser! {
     }
 
     parameter: ast::Parameter {
+        #[no_reduce(Identifier)] // the parameter may be named
         nonmethod_type[nmt] => nmt.into(),
         nonmethod_type[nmt] Equals type_value[value] => {
             let mut param: ast::Parameter = nmt.into();
 
+            param.default_value = Some(value);
+            param
+        },
+        nonmethod_type_with_name[nmt] => nmt.into(),
+        nonmethod_type_with_name[nmt] Equals type_value[value] => {
+            let mut param: ast::Parameter = nmt.into();
+
             param.default_value = Some(value);
             param
         },
@@ -59,8 +59,12 @@ pub fn semantic_analyzer<'a>(pipeline: &mut PipelineData) -> Result<dcfile::DCFi
                     dc_file.add_keyword(pipeline, keyword);
                 }
                 ast::TypeDeclaration::StructType(_) => {}
-                ast::TypeDeclaration::DClassType(_) => {}
-                ast::TypeDeclaration::TypedefType(_) => {}
+                ast::TypeDeclaration::DClassType(dclass) => {
+                    dc_file.add_dclass(pipeline, dclass);
+                }
+                ast::TypeDeclaration::TypedefType(typedef) => {
+                    dc_file.add_typedef(pipeline, typedef);
+                }
                 // Ignore is returned by productions that parsed certain
                 // grammar that may be deprecated but ignored for
                 // compatibility & should not be added to the DC file.
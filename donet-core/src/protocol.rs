@@ -20,11 +20,13 @@
 //! This module defines the `Protocol` enum, which stores every
 //! type of message in the Donet protocol, along with their 16-bit ID.
 
-use strum_macros::EnumIter;
+use crate::globals::MsgType;
+use strum_macros::{EnumIter, FromRepr};
+use thiserror::Error;
 
 /// Enum variants for all message types in the Donet protocol.
 #[repr(u16)] // 16-bit alignment
-#[derive(Debug, Copy, Clone, PartialEq, EnumIter)]
+#[derive(Debug, Copy, Clone, PartialEq, EnumIter, FromRepr)]
 pub enum Protocol {
     /// Client Messages
     ClientHello = 1,
@@ -62,6 +64,8 @@ pub enum Protocol {
     CAAddSessionObject = 1012,
     CARemoveSessionObject = 1013,
     CASetFieldsSendable = 1014,
+    CAGetTLVs = 1015,
+    CAGetTLVsResp = 1016,
     CAOpenChannel = 1100,
     CACloseChannel = 1101,
     CAAddPostRemove = 1110,
@@ -91,6 +95,7 @@ pub enum Protocol {
     SSObjectEnterLocationWithRequiredOther = 2043,
     SSObjectGetLocation = 2044,
     SSObjectGetLocationResp = 2045,
+    SSObjectLocationAck = 2046,
     SSObjectSetAI = 2050,
     SSObjectChangingAI = 2051,
     SSObjectEnterAIWithRequired = 2052,
@@ -103,6 +108,8 @@ pub enum Protocol {
     SSObjectEnterOwnerWithRequiredOther = 2063,
     SSObjectGetOwner = 2064,
     SSObjectGetOwnerResp = 2065,
+    SSObjectEnterInterestWithRequired = 2066,
+    SSObjectEnterInterestWithRequiredOther = 2067,
     SSObjectGetZoneObjects = 2100,
     SSObjectGetZonesObjects = 2102,
     SSObjectGetChildren = 2104,
@@ -115,6 +122,8 @@ pub enum Protocol {
     SSObjectDeleteZone = 2120,
     SSObjectDeleteZones = 2122,
     SSObjectDeleteChildren = 2124,
+    SSGetActiveZones = 2125,
+    SSGetActiveZonesResp = 2126,
 
     /// Database State Server
     DBSSObjectActivateWithDefaults = 2200,
@@ -142,6 +151,8 @@ pub enum Protocol {
     DBObjectSetFieldsIfEqualsResp = 3025,
     DBObjectSetFieldIfEmpty = 3026,
     DBObjectSetFieldIfEmptyResp = 3027,
+    DBObjectSetFieldsIfEmpty = 3028,
+    DBObjectSetFieldsIfEmptyResp = 3029,
     DBObjectDeleteField = 3030,
     DBObjectDeleteFields = 3031,
     DBObjectDelete = 3032,
@@ -156,4 +167,50 @@ pub enum Protocol {
     MDSetConName = 9012,
     MDSetConUrl = 9013,
     MDLogMessage = 9014,
+    MDPeerHello = 9020,
+    MDPeerForward = 9021,
+    MDWatchInterest = 9022,
+    MDInterestSynced = 9023,
+    MDAddTrace = 9024,
+    MDRemoveTrace = 9025,
+    MDTraced = 9026,
+    MDAuthenticate = 9027,
+    MDResume = 9028,
+    MDResync = 9029,
+}
+
+/// Custom error type for [`Protocol`].
+#[derive(Debug, Error, PartialEq)]
+pub enum ProtocolError {
+    /// This error kind is returned when converting a
+    /// message type ID that has no [`Protocol`] variant.
+    #[error("unknown message type; {0}")]
+    UnknownMsgType(MsgType),
+}
+
+impl TryFrom<MsgType> for Protocol {
+    type Error = ProtocolError;
+
+    fn try_from(value: MsgType) -> Result<Self, Self::Error> {
+        Self::from_repr(value).ok_or(ProtocolError::UnknownMsgType(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msg_type_try_from() {
+        assert_eq!(Protocol::try_from(9000), Ok(Protocol::MDAddChannel));
+        assert_eq!(
+            Protocol::try_from(3029),
+            Ok(Protocol::DBObjectSetFieldsIfEmptyResp)
+        );
+        assert_eq!(Protocol::try_from(0), Err(ProtocolError::UnknownMsgType(0)));
+        assert_eq!(
+            Protocol::try_from(u16::MAX),
+            Err(ProtocolError::UnknownMsgType(u16::MAX))
+        );
+    }
 }
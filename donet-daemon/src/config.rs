@@ -17,7 +17,13 @@
     License along with Donet. If not, see <https://www.gnu.org/licenses/>.
 */
 
+use donet_core::globals::DgSizeTag;
+use donet_network::compress::CompressionConfig;
+use donet_network::{addr, tls};
 use serde::Deserialize;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Deserialize, PartialEq, Debug, Clone)]
 pub struct DonetConfig {
@@ -31,6 +37,8 @@ pub struct Daemon {
     pub name: String,
     pub id: Option<u32>,
     pub log_level: Option<String>,
+    /// Address to serve the `/metrics` HTTP endpoint on.
+    pub metrics_bind: Option<String>, // '<host>:<port>' or 'unix:<path>'
 }
 
 #[derive(Deserialize, PartialEq, Debug, Clone)]
@@ -55,15 +63,192 @@ pub struct Services {
 
 #[derive(Deserialize, PartialEq, Debug, Clone)]
 pub struct ClientAgent {
-    pub bind: String, // '<host>:<port>'
+    pub bind: String,            // '<host>:<port>' or 'unix:<path>'
+    pub ws_bind: Option<String>, // '<host>:<port>'
     pub dc_file_hash: Option<u32>,
     pub version_string: String,
+    /// Largest message accepted from a client, in bytes.
+    pub max_frame_size: Option<DgSizeTag>,
+    pub tls: Option<TlsServer>,
 }
 
 #[derive(Deserialize, PartialEq, Debug, Clone)]
 pub struct MessageDirector {
-    pub bind: String,             // '<host>:<port>'
-    pub upstream: Option<String>, // '<host>:<port>'
+    pub bind: String,             // '<host>:<port>' or 'unix:<path>'
+    pub upstream: Option<String>, // '<host>:<port>' or 'unix:<path>'
+    pub tls: Option<TlsServer>,
+    pub upstream_tls: Option<TlsClient>,
+    pub send_queue: Option<SendQueue>,
+    pub read_buffer: Option<ReadBuffer>,
+    /// Largest message accepted from a subscriber or an upstream MD, in bytes.
+    pub max_frame_size: Option<DgSizeTag>,
+    pub upstream_reconnect: Option<UpstreamReconnect>,
+    /// Compression of the connections accepted on `bind`.
+    pub compression: Option<Compression>,
+    pub upstream_compression: Option<Compression>,
+    /// Peer MDs of a mesh, linked to like the upstream MD.
+    pub peers: Option<Vec<String>>, // '<host>:<port>' or 'unix:<path>'
+    /// Local endpoint for inspecting and controlling the MD.
+    pub admin_bind: Option<String>, // '<host>:<port>' or 'unix:<path>'
+    /// Roles of subscribers. If there are none, subscribers are not restricted.
+    pub roles: Option<Vec<Role>>,
+    /// Role of the subscribers that have not authenticated.
+    pub default_role: Option<String>,
+    /// Authenticates us to the upstream MD and to peer MDs.
+    pub upstream_auth: Option<Credentials>,
+    /// Journal of the subscriptions and post removes of the subscribers.
+    pub persistence: Option<Persistence>,
+}
+
+/// A role of the subscribers of an MD. See donet-message-director's acl.rs.
+///
+/// Rules are channels ('4000'), ranges of channels ('4000-4999'), or '*'.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Role {
+    pub name: String,
+    /// Shared secret that subscribers authenticate as this role with.
+    pub secret: Option<String>,
+    /// SHA-256 fingerprints of the TLS client certificates given this role.
+    pub certificates: Option<Vec<String>>,
+    /// Channels and ranges that subscribers may subscribe to.
+    pub subscribe: Option<Vec<String>>,
+    /// Sender channels that subscribers may send messages as.
+    pub send_as: Option<Vec<String>>,
+    /// Channels that subscribers may send messages to.
+    pub send_to: Option<Vec<String>>,
+    /// If subscribers may watch our interest, trace, and introduce themselves as peer MDs.
+    pub control: Option<bool>,
+}
+
+/// A role, and its shared secret, to authenticate as.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Credentials {
+    pub role: String,
+    pub secret: String,
+}
+
+/// TLS settings of a listening socket. Paths are to PEM files.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct TlsServer {
+    pub cert: String,
+    pub key: String,
+    /// If set, clients must present a certificate signed by this CA bundle.
+    pub client_ca: Option<String>,
+}
+
+/// TLS settings of an outgoing connection. Paths are to PEM files.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct TlsClient {
+    /// CA bundle that the server's certificate must be signed by.
+    pub ca: String,
+    /// Name the server's certificate must be valid for.
+    /// Defaults to the host of the connection's address.
+    pub server_name: Option<String>,
+    /// Certificate and key, for servers that require mutual TLS.
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+impl TlsServer {
+    /// Loads the TLS configuration of a listening socket.
+    pub fn load(&self) -> Result<Arc<tls::ServerConfig>> {
+        let identity = tls::Identity::from_files(Path::new(&self.cert), Path::new(&self.key))?;
+
+        let client_ca = match &self.client_ca {
+            Some(path) => Some(tls::load_root_store(Path::new(path))?),
+            None => None,
+        };
+        Ok(tls::server_config(identity, client_ca)?)
+    }
+}
+
+impl TlsClient {
+    /// Loads the TLS configuration of an outgoing connection.
+    pub fn load(&self) -> Result<Arc<tls::ClientConfig>> {
+        let roots = tls::load_root_store(Path::new(&self.ca))?;
+
+        let identity = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Some(tls::Identity::from_files(Path::new(cert), Path::new(key))?),
+            (None, None) => None,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "TLS 'cert' and 'key' must be given together.",
+                ))
+            }
+        };
+        Ok(tls::client_config(roots, identity)?)
+    }
+
+    /// Returns the configured server name, or the host of the given address.
+    /// Unix domain sockets are on this host, so default to 'localhost'.
+    pub fn get_server_name<'a>(&'a self, address: &'a str) -> &'a str {
+        match &self.server_name {
+            Some(name) => name,
+            None if address.starts_with(addr::UNIX_PREFIX) => "localhost",
+            None => {
+                let host: &str = address.rsplit_once(':').map_or(address, |(host, _)| host);
+
+                // IPv6 addresses are written as '[<ip>]:<port>'
+                host.trim_start_matches('[').trim_end_matches(']')
+            }
+        }
+    }
+}
+
+/// Budget of each connection's send queue. See donet-network's queue.rs.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct SendQueue {
+    pub max_messages: Option<usize>,
+    pub max_bytes: Option<usize>,
+    pub policy: Option<String>, // 'block', 'drop_oldest', or 'disconnect'
+}
+
+/// Sizing of each connection's read buffer. See donet-network's frame.rs.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct ReadBuffer {
+    pub min_read_size: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+/// Reconnection to a lost upstream MD. See donet-message-director's upstream.rs.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct UpstreamReconnect {
+    pub min_delay_ms: Option<u64>,
+    pub max_delay_ms: Option<u64>,
+    pub max_buffered_bytes: Option<usize>,
+}
+
+/// State of the MD that outlives it. See donet-message-director's persist.rs.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Persistence {
+    pub path: String,
+    /// How long a restarted MD waits for its subscribers to resume.
+    pub grace_period_ms: Option<u64>,
+}
+
+/// Opt-in compression of a link. See donet-network's compress.rs.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Compression {
+    pub codec: Option<String>, // 'lz4'
+    pub threshold: Option<usize>,
+}
+
+impl Compression {
+    /// Loads the compression configuration of a link.
+    pub fn load(&self) -> Result<CompressionConfig> {
+        let mut config = CompressionConfig::default();
+
+        if let Some(codec) = &self.codec {
+            config.codec = codec
+                .parse()
+                .map_err(|err: String| Error::new(ErrorKind::InvalidInput, err))?;
+        }
+        if let Some(threshold) = self.threshold {
+            config.threshold = threshold;
+        }
+        Ok(config)
+    }
 }
 
 #[derive(Deserialize, PartialEq, Debug, Clone)]
@@ -80,7 +80,7 @@ impl LoggedEvent {
             dg.add_u8((size & 0xff) as u8).unwrap();
         }
 
-        dg.add_data(value.as_bytes().to_vec()).unwrap();
+        dg.add_data(value.as_bytes()).unwrap();
     }
 }
 
@@ -24,5 +24,6 @@ pub mod config;
 pub mod event;
 pub mod logger;
 pub mod meson;
+pub mod metrics;
 pub mod service;
 pub mod subscriber;
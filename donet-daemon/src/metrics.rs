@@ -0,0 +1,197 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Metrics of the services of a daemon, which are served in the
+//! Prometheus text format on an HTTP `/metrics` endpoint.
+//!
+//! Every service of the daemon registers its metrics on the same
+//! [`Registry`], returned by [`registry`], under a prefix of its own.
+
+use donet_network::tcp;
+use donet_network::transport::{BoxedTransport, Transport};
+use log::{error, info, warn};
+use prometheus_client::encoding::text;
+use prometheus_client::registry::Registry;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{LazyLock, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::task::JoinHandle;
+
+/// Largest HTTP request head that we read.
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// Time allowed for a scraper to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Content type of the text format of [`text::encode`].
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(|| Mutex::new(Registry::with_prefix("donet")));
+
+/// Returns the registry shared by every service of the daemon.
+///
+/// Services should register their metrics on a sub-registry,
+/// such as `registry().sub_registry_with_prefix("md")`.
+pub fn registry() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().expect("Metrics registry lock poisoned.")
+}
+
+/// Binds the metrics endpoint to the given address, and spawns a
+/// task that serves the shared registry on it, until it is aborted.
+pub async fn serve(bind: &str) -> Result<JoinHandle<()>> {
+    let listener: tcp::Listener = tcp::Listener::bind(bind).await?;
+
+    info!("Serving metrics on {}.", bind);
+
+    Ok(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, address)) => {
+                    tokio::spawn(async move {
+                        let (reader, writer) = BoxedTransport::from(socket).into_split();
+
+                        if let Err(err) = handle_request(&REGISTRY, reader, writer).await {
+                            warn!("Failed to serve metrics to {}: {}", address, err);
+                        }
+                    });
+                }
+                Err(err) => error!("Failed to get metrics connection: {}", err),
+            }
+        }
+    }))
+}
+
+/// Answers a single HTTP request, then closes the connection.
+async fn handle_request<R, W>(registry: &Mutex<Registry>, reader: R, mut writer: W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let request_line: String = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(reader))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "Request timed out."))??;
+
+    let mut words = request_line.split_whitespace();
+
+    let (status, content_type, body): (&str, &str, String) = match (words.next(), words.next()) {
+        (Some("GET"), Some(target)) if target.split('?').next() == Some("/metrics") => {
+            let mut body: String = String::new();
+
+            // the lock is not held across an await point
+            let encoded = text::encode(
+                &mut body,
+                &registry.lock().expect("Metrics registry lock poisoned."),
+            );
+
+            match encoded {
+                Ok(()) => ("200 OK", CONTENT_TYPE, body),
+                Err(_) => (
+                    "500 Internal Server Error",
+                    "text/plain",
+                    "Failed to encode metrics.\n".into(),
+                ),
+            }
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", "Not Found\n".into()),
+        (Some(_), Some(_)) => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method Not Allowed\n".into(),
+        ),
+        _ => ("400 Bad Request", "text/plain", "Bad Request\n".into()),
+    };
+    let head: String = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+/// Reads the head of an HTTP request, and returns its request line.
+async fn read_request_head<R: AsyncRead + Unpin>(reader: R) -> Result<String> {
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_SIZE));
+    let mut request_line: String = String::new();
+
+    reader.read_line(&mut request_line).await?;
+
+    // the headers are of no use to us, but are read so that the
+    // scraper does not see the connection reset before our answer
+    loop {
+        let mut header: String = String::new();
+
+        match reader.read_line(&mut header).await? {
+            0 => return Err(Error::new(ErrorKind::InvalidData, "Incomplete request head.")),
+            _ if header.trim_end().is_empty() => return Ok(request_line),
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus_client::metrics::counter::Counter;
+
+    /// Sends a request, and returns the response.
+    async fn request(registry: &Mutex<Registry>, request: &str) -> String {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+
+        client_writer.write_all(request.as_bytes()).await.unwrap();
+        handle_request(registry, server_reader, server_writer)
+            .await
+            .unwrap();
+
+        let mut response: String = String::new();
+        client_reader.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let registry: Mutex<Registry> = Mutex::new(Registry::with_prefix("donet"));
+        let counter: Counter = Counter::default();
+
+        registry.lock().unwrap().sub_registry_with_prefix("md").register(
+            "routed_datagrams",
+            "Datagrams routed",
+            counter.clone(),
+        );
+        counter.inc_by(3);
+
+        let response: String = request(&registry, "GET /metrics HTTP/1.1\r\nHost: md\r\n\r\n").await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n"));
+        assert!(response.contains("donet_md_routed_datagrams_total 3\n"));
+        assert!(response.ends_with("# EOF\n"));
+
+        let response: String = request(&registry, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response: String = request(&registry, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -18,7 +18,10 @@
 */
 
 use donet_core::datagram::datagram::Datagram;
+use donet_core::datagram::iterator::DatagramIterator;
+use donet_core::globals::CONTROL_CHANNEL;
 use donet_core::Protocol;
+use donet_network::transport::{BoxedTransport, Transport};
 use donet_network::*;
 use std::future::Future;
 use std::io::{Error, ErrorKind, Result};
@@ -29,14 +32,33 @@ use std::io::{Error, ErrorKind, Result};
 ///
 /// It is called a subcriber, as its indirectly a subscriber
 /// to the Donet cluster via its message director service.
-pub trait ClusterSubscriber
+///
+/// The connection to the message director may be over any
+/// [`Transport`], such as an in-memory pipe in tests.
+pub trait ClusterSubscriber<T: Transport = BoxedTransport>
 where
-    Self: HasClient,
+    Self: HasClient<T>,
 {
     /// Here is where the Donet service receives incoming
     /// messages from the cluster, provided by a message director.
     fn receive_datagram(dg: Datagram) -> impl Future<Output = Result<()>>;
 
+    /// Here is where the Donet service replays its subscriptions and
+    /// post removes, once its MD restarted and recovered its session.
+    fn resync(&mut self) -> impl Future<Output = Result<()>>;
+
+    /// Handles a datagram from this service's MD. A `CONTROL_RESYNC`
+    /// is answered with [`ClusterSubscriber::resync`], and the other
+    /// datagrams are given to [`ClusterSubscriber::receive_datagram`].
+    fn handle_datagram(&mut self, dg: Datagram) -> impl Future<Output = Result<()>> {
+        async move {
+            if is_resync(&dg) {
+                return self.resync().await;
+            }
+            Self::receive_datagram(dg).await
+        }
+    }
+
     /// Sends a log message (blob in msgpack format) to the message
     /// director, which then routes it to an event logger service.
     fn send_log(&mut self, msgpack_blob: Datagram) -> impl Future<Output = Result<()>> {
@@ -45,7 +67,24 @@ where
 
             // TODO: fix clashing result types (core result and IO result)
             dg.add_control_header(Protocol::MDLogMessage.into())?;
-            dg.add_blob(msgpack_blob.get_data())?;
+            dg.add_blob(msgpack_blob.get_buffer())?;
+
+            if let Err(err) = self.get_client().lock().await.stage_datagram(dg).await {
+                return Err(Error::new(ErrorKind::Other, err.to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    /// Sends a `CONTROL_AUTHENTICATE` message to this service's MD, which
+    /// holds this service to the rules of the role from then on.
+    fn authenticate(&mut self, role: String, secret: String) -> impl Future<Output = Result<()>> {
+        async move {
+            let mut dg: Datagram = Datagram::default();
+
+            dg.add_control_header(Protocol::MDAuthenticate.into())?;
+            dg.add_string(&role)?;
+            dg.add_string(&secret)?;
 
             if let Err(err) = self.get_client().lock().await.stage_datagram(dg).await {
                 return Err(Error::new(ErrorKind::Other, err.to_string()));
@@ -54,6 +93,27 @@ where
         }
     }
 
+    /// Sends a `CONTROL_RESUME` message to this service's MD, which names
+    /// the session of this service across its connections. If the MD
+    /// restarted, it answers with a `CONTROL_RESYNC`, which is handled
+    /// by [`ClusterSubscriber::handle_datagram`].
+    ///
+    /// The session ID should come from a CSPRNG, as whoever knows it
+    /// may resume the session with the same role and certificate.
+    fn resume(&mut self, session: u64) -> impl Future<Output = Result<()>> {
+        async move {
+            let mut dg: Datagram = Datagram::default();
+
+            dg.add_control_header(Protocol::MDResume.into())?;
+            dg.add_u64(session)?;
+
+            if let Err(err) = self.get_client().lock().await.stage_datagram(dg).await {
+                return Err(Error::other(err.to_string()));
+            }
+            Ok(())
+        }
+    }
+
     /// Sends a `CONTROL_SET_CON_NAME` message to this service's MD.
     fn set_connection_name(&mut self, name: String) -> impl Future<Output = Result<()>> {
         async move {
@@ -84,3 +144,29 @@ where
         }
     }
 }
+
+/// Returns `true` if the datagram is a `CONTROL_RESYNC` from the MD.
+fn is_resync(dg: &Datagram) -> bool {
+    let mut dgi: DatagramIterator = dg.clone().into();
+
+    matches!(dgi.read_recipient_count(), Ok(1))
+        && matches!(dgi.read_channel(), Ok(CONTROL_CHANNEL))
+        && matches!(dgi.read_msg_type(), Ok(Protocol::MDResync))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resync_datagram() {
+        let control = |msg_type: Protocol| {
+            let mut dg: Datagram = Datagram::default();
+            dg.add_control_header(msg_type.into()).unwrap();
+            dg
+        };
+        assert!(is_resync(&control(Protocol::MDResync)));
+        assert!(!is_resync(&control(Protocol::MDResume)));
+        assert!(!is_resync(&Datagram::default()));
+    }
+}
@@ -158,11 +158,11 @@ pub fn decode_to_json(out: &mut String, dgi: &mut DatagramIterator) -> Result<()
     } else if marker == 0xca {
         // float32
         let data: u32 = dgi.read_u32()?;
-        out.push_str(&format!("{}", byte_order::swap_be_32(data) as f32));
+        out.push_str(&format!("{}", f32::from_bits(byte_order::swap_be_32(data))));
     } else if marker == 0xcb {
         // float64
         let data: u64 = dgi.read_u64()?;
-        out.push_str(&format!("{}", byte_order::swap_be_64(data) as f64));
+        out.push_str(&format!("{}", f64::from_bits(byte_order::swap_be_64(data))));
     } else if marker == 0xcc {
         // uint8
         out.push_str(&format!("{}", dgi.read_u8()?));
@@ -241,12 +241,12 @@ mod tests {
         let mut dg: Datagram = Datagram::default();
 
         dg.add_data(vec![0x80 + 0x3, 0xa0 + 0x4])?; // fixmap (3), fixstr (4)
-        dg.add_data("test".as_bytes().to_vec())?; // "test"
+        dg.add_data("test".as_bytes())?; // "test"
         dg.add_data(vec![0xc3, 0xa0 + 0x4])?; // true, fixstr (4)
-        dg.add_data("test".as_bytes().to_vec())?; // "test"
+        dg.add_data("test".as_bytes())?; // "test"
         dg.add_data(vec![0x3])?; // positive fixint (3)
         dg.add_data(vec![0xa0 + 0x4])?; // fixstr (4)
-        dg.add_data("test".as_bytes().to_vec())?; // "test"
+        dg.add_data("test".as_bytes())?; // "test"
         dg.add_data(vec![0xc0])?; // null
 
         decode_to_json(&mut output, &mut DatagramIterator::from(dg))?;
@@ -293,13 +293,13 @@ mod tests {
 
         dg.add_data(vec![0x90 + 0x2])?; // fixarray (2)
         dg.add_data(vec![0xca])?; // float32
-        dg.add_f32(f32::MAX)?; // value
+        dg.add_data(1.5_f32.to_be_bytes())?; // value
         dg.add_data(vec![0xcb])?; // float64
-        dg.add_f64(f64::MAX)?; // value
+        dg.add_data((-0.25_f64).to_be_bytes())?; // value
 
         decode_to_json(&mut output, &mut DatagramIterator::from(dg))?;
 
-        assert_eq!(output.as_str(), "[4294967300, 18446744073709552000]");
+        assert_eq!(output.as_str(), "[1.5, -0.25]");
         Ok(())
     }
 }
@@ -0,0 +1,212 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Measures how routing through a Message Director scales with cores.
+//!
+//! Starts an MD on an in-memory address, connects pairs of services to
+//! it, and has the first of each pair send datagrams to a channel that
+//! only the second is subscribed to. Each connection is routed from its
+//! own task, so throughput should grow with the number of worker threads,
+//! up to the number of cores on the machine.
+//!
+//! Run with `cargo bench -p donet-message-director --bench routing`.
+
+use donet_core::datagram::datagram::Datagram;
+use donet_core::globals::Channel;
+use donet_core::Protocol;
+use donet_daemon::config;
+use donet_daemon::service::DonetService;
+use donet_message_director::MessageDirector;
+use donet_network::{tcp, Client, ClientEvent};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const PAIRS: usize = 64;
+const DATAGRAMS_PER_SENDER: usize = 5_000;
+const PAYLOAD_SIZE: usize = 64;
+const WORKER_THREADS: [usize; 4] = [1, 2, 4, 8];
+
+/// Channel that the receiver of the first pair subscribes to.
+const FIRST_CHANNEL: Channel = 10_000;
+
+fn md_config(bind: &str) -> config::DonetConfig {
+    config::DonetConfig {
+        daemon: config::Daemon {
+            name: "routing-bench".to_owned(),
+            id: None,
+            log_level: None,
+            metrics_bind: None,
+        },
+        global: config::Global {
+            eventlogger: None,
+            dc_files: vec![],
+            dc_multiple_inheritance: None,
+            dc_sort_inheritance_by_file: None,
+            dc_virtual_inheritance: None,
+        },
+        services: config::Services {
+            client_agent: None,
+            message_director: Some(config::MessageDirector {
+                bind: bind.to_owned(),
+                upstream: None,
+                tls: None,
+                upstream_tls: None,
+                // slow receivers hold up their senders, instead of being dropped
+                send_queue: Some(config::SendQueue {
+                    max_messages: None,
+                    max_bytes: None,
+                    policy: Some("block".to_owned()),
+                }),
+                upstream_reconnect: None,
+                compression: None,
+                upstream_compression: None,
+                peers: None,
+                admin_bind: None,
+                roles: None,
+                default_role: None,
+                upstream_auth: None,
+                persistence: None,
+                read_buffer: None,
+                max_frame_size: None,
+            }),
+            state_server: None,
+            database_server: None,
+            dbss: None,
+            event_logger: None,
+        },
+    }
+}
+
+async fn connect(uri: &str) -> (Client, mpsc::Receiver<ClientEvent>) {
+    let mut client: Client = tcp::Connection::connect(uri).await.unwrap().into();
+    let (tx, rx) = mpsc::channel::<ClientEvent>(1024);
+
+    client.spawn_recv_send_tasks(tx).await;
+    (client, rx)
+}
+
+/// Routes datagrams between every pair, and returns how long it took.
+async fn run(address: &str) -> Duration {
+    let _md: JoinHandle<std::io::Result<()>> = MessageDirector::start(md_config(address), None)
+        .await
+        .expect("Failed to start the MD.");
+
+    let mut senders: Vec<(Client, Datagram)> = Vec::with_capacity(PAIRS);
+    let mut receivers: Vec<(Client, mpsc::Receiver<ClientEvent>)> = Vec::with_capacity(PAIRS);
+
+    for pair in 0..PAIRS {
+        let channel: Channel = FIRST_CHANNEL + pair as Channel;
+        let (mut receiver, receiver_rx) = connect(address).await;
+
+        let mut add_channel: Datagram = Datagram::default();
+        add_channel
+            .add_control_header(Protocol::MDAddChannel.into())
+            .unwrap();
+        add_channel.add_channel(channel).unwrap();
+        receiver.stage_datagram(add_channel).await.unwrap();
+
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![channel], 1337, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+        dg.add_data(vec![0xAB; PAYLOAD_SIZE]).unwrap();
+        dg.freeze();
+
+        let (sender, _) = connect(address).await;
+
+        senders.push((sender, dg));
+        receivers.push((receiver, receiver_rx));
+    }
+
+    // let the MD apply every subscription
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let start: Instant = Instant::now();
+
+    let receiving: Vec<JoinHandle<Client>> = receivers
+        .into_iter()
+        .map(|(receiver, mut rx)| {
+            tokio::spawn(async move {
+                for _ in 0..DATAGRAMS_PER_SENDER {
+                    match rx.recv().await {
+                        Some(ClientEvent::Received(_)) => {}
+                        _ => panic!("Receiver lost its connection during benchmark."),
+                    }
+                }
+                receiver
+            })
+        })
+        .collect();
+
+    let sending: Vec<JoinHandle<Client>> = senders
+        .into_iter()
+        .map(|(mut sender, dg)| {
+            tokio::spawn(async move {
+                for _ in 0..DATAGRAMS_PER_SENDER {
+                    sender.stage_datagram(dg.clone()).await.unwrap();
+                }
+                sender
+            })
+        })
+        .collect();
+
+    let mut clients: Vec<Client> = vec![];
+
+    for task in receiving {
+        clients.push(task.await.unwrap());
+    }
+    let elapsed: Duration = start.elapsed();
+
+    for task in sending {
+        clients.push(task.await.unwrap());
+    }
+    for client in &mut clients {
+        client.close();
+    }
+    elapsed
+}
+
+fn main() {
+    let cores: usize = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+    let total: usize = PAIRS * DATAGRAMS_PER_SENDER;
+
+    println!(
+        "{} datagrams of {} bytes over {} sender/receiver pairs, on {} cores",
+        total, PAYLOAD_SIZE, PAIRS, cores
+    );
+
+    for threads in WORKER_THREADS {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .enable_all()
+            .build()
+            .expect("Failed to build the tokio runtime.");
+
+        let address: String = format!("memory:routing-bench-{}", threads);
+        let elapsed: Duration = runtime.block_on(run(&address));
+
+        println!(
+            "  {} worker threads: {:.3} s, {:.0} datagrams/s",
+            threads,
+            elapsed.as_secs_f64(),
+            total as f64 / elapsed.as_secs_f64()
+        );
+        runtime.shutdown_background();
+    }
+}
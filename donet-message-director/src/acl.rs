@@ -0,0 +1,497 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Access control of the subscribers of a Message Director.
+//!
+//! Each subscriber has a [`Role`], whose rules decide which channels
+//! it may subscribe to, which sender channels it may send messages
+//! as, and which channels it may send messages to. Only roles with
+//! the `control` permission may send the control messages meant for
+//! MDs and operators, such as `CONTROL_WATCH_INTEREST`. A session is
+//! only resumed with `CONTROL_RESUME` by a subscriber of the role, and
+//! with the TLS client certificate, that named it.
+//!
+//! A subscriber is given a role by the fingerprint of the TLS client
+//! certificate that it presented, or else the default role of the MD,
+//! until it authenticates with `CONTROL_AUTHENTICATE` and the shared
+//! secret of another role. If the MD has no roles configured, every
+//! subscriber is unrestricted.
+
+use donet_core::datagram::datagram::{Datagram, DatagramError};
+use donet_core::globals::Channel;
+use donet_core::messages::{MDAuthenticate, ProtocolMessage};
+use donet_core::Protocol;
+use donet_daemon::config;
+use donet_network::tls::{self, CertificateDer};
+use std::io::{Error, ErrorKind, Result};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Channels that a role allows, as sorted ranges that do not touch.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rules(Vec<RangeInclusive<Channel>>);
+
+impl Rules {
+    pub fn everything() -> Self {
+        Self(vec![0..=Channel::MAX])
+    }
+
+    /// Parses the rules of a role, which are channels ('4000'),
+    /// ranges of channels ('4000-4999'), or '*' for every channel.
+    pub fn parse(rules: &[String]) -> std::result::Result<Self, String> {
+        let mut ranges: Vec<RangeInclusive<Channel>> = rules
+            .iter()
+            .map(|rule| parse_rule(rule))
+            .collect::<std::result::Result<_, _>>()?;
+
+        ranges.sort_by_key(|range| *range.start());
+
+        // merge ranges that overlap or touch, so that a range of channels
+        // is allowed if all of its channels are, even across rules
+        let mut merged: Vec<RangeInclusive<Channel>> = vec![];
+
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start().saturating_sub(1) <= *last.end() => {
+                    *last = *last.start()..=*last.end().max(range.end());
+                }
+                _ => merged.push(range),
+            }
+        }
+        Ok(Self(merged))
+    }
+
+    pub fn allows(&self, channel: Channel) -> bool {
+        self.allows_range(channel..=channel)
+    }
+
+    /// Returns `true` if every channel of the range is allowed.
+    pub fn allows_range(&self, range: RangeInclusive<Channel>) -> bool {
+        self.0
+            .iter()
+            .any(|rule| rule.start() <= range.start() && range.end() <= rule.end())
+    }
+}
+
+fn parse_rule(rule: &str) -> std::result::Result<RangeInclusive<Channel>, String> {
+    let invalid = || format!("Invalid channel rule '{}'.", rule);
+    let channel = |channel: &str| Channel::from_str(channel.trim()).map_err(|_| invalid());
+
+    if rule.trim() == "*" {
+        return Ok(0..=Channel::MAX);
+    }
+    match rule.split_once('-') {
+        Some((min, max)) => {
+            let (min, max) = (channel(min)?, channel(max)?);
+
+            if min > max {
+                return Err(invalid());
+            }
+            Ok(min..=max)
+        }
+        None => channel(rule).map(|channel| channel..=channel),
+    }
+}
+
+/// What a subscriber was denied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Denied {
+    Subscribe(Channel, Channel),
+    SendAs(Channel),
+    SendTo(Channel),
+    Authenticate,
+    Control(Protocol),
+    Resume,
+}
+
+impl Denied {
+    /// Returns the name of the denied action, for logging.
+    pub fn action(&self) -> &'static str {
+        match self {
+            Self::Subscribe(..) => "subscribe",
+            Self::SendAs(_) => "send_as",
+            Self::SendTo(_) => "send_to",
+            Self::Authenticate => "authenticate",
+            Self::Control(_) => "control",
+            Self::Resume => "resume",
+        }
+    }
+
+    /// Returns the channels of the denied action, for logging.
+    pub fn channels(&self) -> String {
+        match *self {
+            Self::Subscribe(min, max) if min == max => min.to_string(),
+            Self::Subscribe(min, max) => format!("{}-{}", min, max),
+            Self::SendAs(channel) | Self::SendTo(channel) => channel.to_string(),
+            Self::Authenticate | Self::Control(_) | Self::Resume => String::new(),
+        }
+    }
+}
+
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Authenticate => write!(f, "authenticate"),
+            Self::Resume => write!(f, "resume"),
+            Self::Control(msg_type) => write!(f, "control {:?}", msg_type),
+            _ => write!(f, "{} {}", self.action(), self.channels()),
+        }
+    }
+}
+
+/// A role of subscribers, with the rules that they are held to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Role {
+    name: String,
+    secret: Option<String>,
+    /// Fingerprints of the TLS client certificates given this role.
+    certificates: Vec<String>,
+    subscribe: Rules,
+    send_as: Rules,
+    send_to: Rules,
+    /// If the role may send the control messages of [`Role::may_control`].
+    control: bool,
+}
+
+impl Role {
+    /// The role of every subscriber of an MD with no roles configured.
+    pub fn unrestricted() -> Self {
+        Self {
+            name: "unrestricted".to_owned(),
+            secret: None,
+            certificates: vec![],
+            subscribe: Rules::everything(),
+            send_as: Rules::everything(),
+            send_to: Rules::everything(),
+            control: true,
+        }
+    }
+
+    /// The role of a subscriber that has not authenticated, if there is
+    /// no default role, which is allowed nothing.
+    pub fn unauthenticated() -> Self {
+        Self {
+            name: "unauthenticated".to_owned(),
+            secret: None,
+            certificates: vec![],
+            subscribe: Rules::default(),
+            send_as: Rules::default(),
+            send_to: Rules::default(),
+            control: false,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns `Err` if the role may not subscribe to the whole range.
+    pub fn may_subscribe(&self, min: Channel, max: Channel) -> std::result::Result<(), Denied> {
+        match self.subscribe.allows_range(min..=max) {
+            true => Ok(()),
+            false => Err(Denied::Subscribe(min, max)),
+        }
+    }
+
+    /// Returns `Err` if the role may not send the given control message.
+    ///
+    /// Watching our interest, tracing, and introducing oneself as a peer
+    /// MD are only for MDs and operators, so they need the `control`
+    /// permission. Any role may send the other control messages.
+    pub fn may_control(&self, msg_type: Protocol) -> std::result::Result<(), Denied> {
+        match msg_type {
+            Protocol::MDWatchInterest
+            | Protocol::MDAddTrace
+            | Protocol::MDRemoveTrace
+            | Protocol::MDPeerHello
+                if !self.control =>
+            {
+                Err(Denied::Control(msg_type))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns `Err` if the role may not resume a session that was named
+    /// by a subscriber of the given role.
+    ///
+    /// Otherwise, a subscriber could resume the session of a more
+    /// privileged one, and stop its post removes from being routed.
+    pub fn may_resume(&self, recorded: Option<&str>) -> std::result::Result<(), Denied> {
+        match recorded == Some(self.name.as_str()) {
+            true => Ok(()),
+            false => Err(Denied::Resume),
+        }
+    }
+
+    /// Returns `Err` if the role may not send as the sender, or to one of the recipients.
+    pub fn may_send(&self, sender: Channel, recipients: &[Channel]) -> std::result::Result<(), Denied> {
+        if !self.send_as.allows(sender) {
+            return Err(Denied::SendAs(sender));
+        }
+        match recipients.iter().find(|channel| !self.send_to.allows(**channel)) {
+            Some(channel) => Err(Denied::SendTo(*channel)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TryFrom<&config::Role> for Role {
+    type Error = Error;
+
+    fn try_from(value: &config::Role) -> Result<Self> {
+        let rules = |rules: &Option<Vec<String>>| {
+            Rules::parse(rules.as_deref().unwrap_or_default()).map_err(|err: String| {
+                Error::new(ErrorKind::InvalidInput, format!("Role '{}': {}", value.name, err))
+            })
+        };
+        Ok(Self {
+            name: value.name.clone(),
+            secret: value.secret.clone(),
+            certificates: value
+                .certificates
+                .iter()
+                .flatten()
+                .map(|fingerprint| fingerprint.replace(':', "").to_lowercase())
+                .collect(),
+            subscribe: rules(&value.subscribe)?,
+            send_as: rules(&value.send_as)?,
+            send_to: rules(&value.send_to)?,
+            control: value.control.unwrap_or(false),
+        })
+    }
+}
+
+/// The roles of a Message Director, which its subscribers are given.
+pub struct AccessControl {
+    roles: Vec<Arc<Role>>,
+    /// Role of the subscribers that have not authenticated.
+    default_role: Arc<Role>,
+}
+
+impl Default for AccessControl {
+    /// Access control of an MD with no roles, whose subscribers are unrestricted.
+    fn default() -> Self {
+        Self {
+            roles: vec![],
+            default_role: Arc::new(Role::unrestricted()),
+        }
+    }
+}
+
+impl AccessControl {
+    /// Loads the roles of the MD. Subscribers are unrestricted if there are none.
+    pub fn new(roles: &[config::Role], default_role: Option<&str>) -> Result<Self> {
+        if roles.is_empty() {
+            if let Some(name) = default_role {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Default role '{}' is not one of the roles.", name),
+                ));
+            }
+            return Ok(Self::default());
+        }
+        let roles: Vec<Arc<Role>> = roles
+            .iter()
+            .map(|role| Role::try_from(role).map(Arc::new))
+            .collect::<Result<_>>()?;
+
+        let default_role: Arc<Role> = match default_role {
+            Some(name) => roles
+                .iter()
+                .find(|role| role.name == name)
+                .cloned()
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Default role '{}' is not one of the roles.", name),
+                    )
+                })?,
+            None => Arc::new(Role::unauthenticated()),
+        };
+        Ok(Self { roles, default_role })
+    }
+
+    /// Returns the role of a new subscriber, by the TLS client
+    /// certificate that it presented, if any.
+    pub fn initial_role(&self, certificate: Option<&CertificateDer<'_>>) -> Arc<Role> {
+        let Some(certificate) = certificate else {
+            return self.default_role.clone();
+        };
+        let fingerprint: String = tls::fingerprint(certificate);
+
+        self.roles
+            .iter()
+            .find(|role| role.certificates.contains(&fingerprint))
+            .unwrap_or(&self.default_role)
+            .clone()
+    }
+
+    /// Returns the role named by a `CONTROL_AUTHENTICATE`, if its secret matches.
+    pub fn authenticate(&self, role: &str, secret: &str) -> std::result::Result<Arc<Role>, Denied> {
+        self.roles
+            .iter()
+            .find(|candidate| candidate.name == role)
+            .filter(|candidate| {
+                let expected: &str = candidate.secret.as_deref().unwrap_or_default();
+                !expected.is_empty() && constant_time_eq(expected.as_bytes(), secret.as_bytes())
+            })
+            .cloned()
+            .ok_or(Denied::Authenticate)
+    }
+}
+
+/// Compares two secrets in time that does not depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Returns the `CONTROL_AUTHENTICATE` that authenticates as the given role.
+pub fn authenticate(credentials: &config::Credentials) -> std::result::Result<Datagram, DatagramError> {
+    let mut dg: Datagram = Datagram::default();
+
+    dg.add_control_header(MDAuthenticate::MSG_TYPE.into())?;
+
+    let authenticate = MDAuthenticate {
+        role: credentials.role.clone(),
+        secret: credentials.secret.clone(),
+    };
+    authenticate.encode(&mut dg)?;
+    Ok(dg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Rules {
+        let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        Rules::parse(&rules).unwrap()
+    }
+
+    fn role_config(name: &str, secret: Option<&str>) -> config::Role {
+        config::Role {
+            name: name.to_owned(),
+            secret: secret.map(str::to_owned),
+            certificates: None,
+            subscribe: Some(vec!["4000-4999".to_owned()]),
+            send_as: Some(vec!["1337".to_owned()]),
+            send_to: Some(vec!["*".to_owned()]),
+            control: None,
+        }
+    }
+
+    #[test]
+    fn channel_rules() {
+        let allowed: Rules = rules(&["5000-5999", "4000", "6000-6999", "4001"]);
+
+        assert_eq!(allowed, Rules(vec![4000..=4001, 5000..=6999]));
+        assert!(allowed.allows(4001));
+        assert!(!allowed.allows(4002));
+        assert!(allowed.allows_range(5500..=6500));
+        assert!(!allowed.allows_range(4000..=5000));
+
+        assert_eq!(rules(&["*"]), Rules::everything());
+        assert!(!Rules::default().allows(0));
+
+        for invalid in ["", "abc", "5-4", "1-2-3", "-1"] {
+            assert!(Rules::parse(&[invalid.to_owned()]).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn role_rules() {
+        let role: Role = Role::try_from(&role_config("ai", None)).unwrap();
+
+        assert_eq!(role.may_subscribe(4000, 4999), Ok(()));
+        assert_eq!(role.may_subscribe(4000, 5000), Err(Denied::Subscribe(4000, 5000)));
+        assert_eq!(role.may_send(1337, &[1, 2]), Ok(()));
+        assert_eq!(role.may_send(1338, &[1]), Err(Denied::SendAs(1338)));
+
+        assert!(Role::unrestricted().may_send(1338, &[1]).is_ok());
+        assert_eq!(
+            Role::unauthenticated().may_send(1337, &[1]),
+            Err(Denied::SendAs(1337))
+        );
+    }
+
+    #[test]
+    fn control_permission() {
+        let mut md: config::Role = role_config("md", None);
+        md.control = Some(true);
+
+        let ai: Role = Role::try_from(&role_config("ai", None)).unwrap();
+        let md: Role = Role::try_from(&md).unwrap();
+
+        assert_eq!(ai.may_control(Protocol::MDAddChannel), Ok(()));
+        assert_eq!(md.may_control(Protocol::MDWatchInterest), Ok(()));
+
+        for msg_type in [
+            Protocol::MDWatchInterest,
+            Protocol::MDAddTrace,
+            Protocol::MDRemoveTrace,
+            Protocol::MDPeerHello,
+        ] {
+            assert_eq!(ai.may_control(msg_type), Err(Denied::Control(msg_type)));
+            assert_eq!(Role::unrestricted().may_control(msg_type), Ok(()));
+            assert!(Role::unauthenticated().may_control(msg_type).is_err());
+        }
+    }
+
+    #[test]
+    fn resume_permission() {
+        let ai: Role = Role::try_from(&role_config("ai", None)).unwrap();
+
+        assert_eq!(ai.may_resume(Some("ai")), Ok(()));
+        assert_eq!(ai.may_resume(Some("md")), Err(Denied::Resume));
+        assert_eq!(ai.may_resume(None), Err(Denied::Resume));
+    }
+
+    #[test]
+    fn assigned_roles() {
+        let certificate = CertificateDer::from(vec![0x30, 0x00]);
+        let mut by_certificate: config::Role = role_config("md", None);
+        by_certificate.certificates = Some(vec![tls::fingerprint(&certificate).to_uppercase()]);
+
+        let roles = [role_config("ai", Some("hunter2")), by_certificate];
+        let acl = AccessControl::new(&roles, Some("ai")).unwrap();
+
+        assert_eq!(acl.initial_role(None).get_name(), "ai");
+        assert_eq!(acl.initial_role(Some(&certificate)).get_name(), "md");
+
+        assert_eq!(acl.authenticate("ai", "hunter2").unwrap().get_name(), "ai");
+        assert_eq!(acl.authenticate("ai", "hunter3"), Err(Denied::Authenticate));
+        // roles without a secret cannot be authenticated as
+        assert_eq!(acl.authenticate("md", ""), Err(Denied::Authenticate));
+
+        assert!(AccessControl::new(&roles, Some("admin")).is_err());
+        assert_eq!(
+            AccessControl::new(&roles, None)
+                .unwrap()
+                .initial_role(None)
+                .get_name(),
+            "unauthenticated"
+        );
+        assert_eq!(
+            AccessControl::default().initial_role(None).get_name(),
+            "unrestricted"
+        );
+    }
+}
@@ -0,0 +1,309 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Admin endpoint of the Message Director, for inspecting
+//! its subscribers and channel map, and controlling them.
+//!
+//! Each line received is a command, which is answered with
+//! a line of JSON. The commands are:
+//!
+//! - `subscribers`: lists the subscribers, with their connection
+//!   name and URL, subscriptions, send queue depth, and post removes.
+//! - `channels`: dumps the channel map of our subscribers,
+//!   and of the peer MDs of our mesh.
+//! - `disconnect <remote>`: disconnects the subscriber with
+//!   the given remote address, as listed by `subscribers`.
+//!
+//! A command that fails is answered with `{"error": "<reason>"}`.
+
+use crate::channel_map::ChannelMap;
+use crate::mesh::MdId;
+use crate::subscriber::{Subscriber, SubscriberRef};
+use crate::MessageDirector;
+use donet_core::globals::Channel;
+use donet_network::queue::QueueStats;
+use donet_network::tcp;
+use donet_network::transport::{BoxedTransport, Transport};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::io::Result;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// A command received by the admin endpoint.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Subscribers,
+    Channels,
+    Disconnect(String),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+
+        let command: Self = match (words.next(), words.next()) {
+            (Some("subscribers"), None) => Self::Subscribers,
+            (Some("channels"), None) => Self::Channels,
+            (Some("disconnect"), Some(remote)) => Self::Disconnect(remote.to_owned()),
+            (Some("disconnect"), None) => return Err("Missing remote address to disconnect.".into()),
+            _ => return Err(format!("Unknown command '{}'.", s.trim())),
+        };
+        match words.next() {
+            Some(_) => Err(format!("Too many arguments in '{}'.", s.trim())),
+            None => Ok(command),
+        }
+    }
+}
+
+/// The answer to a command, written as a line of JSON.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    Subscribers(Vec<SubscriberInfo>),
+    Channels {
+        local: ChannelMapInfo,
+        mesh: ChannelMapInfo,
+    },
+    Disconnected(String),
+    Error(String),
+}
+
+#[derive(Debug, Serialize)]
+struct SubscriberInfo {
+    remote: String,
+    name: Option<String>,
+    url: Option<String>,
+    /// ID of the MD, if the subscriber is a peer of our mesh.
+    peer_id: Option<MdId>,
+    channels: Vec<Channel>,
+    ranges: Vec<(Channel, Channel)>,
+    queued_messages: usize,
+    queued_bytes: usize,
+    peak_queued_bytes: usize,
+    dropped_messages: u64,
+    post_removes: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ChannelMapInfo {
+    channels: Vec<ChannelInfo>,
+    ranges: Vec<RangeInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChannelInfo {
+    channel: Channel,
+    subscribers: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RangeInfo {
+    min: Channel,
+    max: Channel,
+    subscribers: Vec<String>,
+}
+
+impl SubscriberInfo {
+    async fn new(sub: &SubscriberRef) -> Self {
+        let queue: QueueStats = sub.get_queue_stats().unwrap_or_default();
+        let locked_sub: MutexGuard<'_, Subscriber> = sub.lock().await;
+
+        let mut channels: Vec<Channel> = locked_sub.subscribed_channels.iter().copied().collect();
+        channels.sort_unstable();
+
+        Self {
+            remote: sub.get_remote().to_string(),
+            name: locked_sub.connection_name.clone(),
+            url: locked_sub.connection_web_url.clone(),
+            peer_id: locked_sub.peer_id,
+            channels,
+            ranges: locked_sub
+                .get_scope()
+                .ranges
+                .into_iter()
+                .map(|range| (*range.start(), *range.end()))
+                .collect(),
+            queued_messages: queue.messages,
+            queued_bytes: queue.bytes,
+            peak_queued_bytes: queue.peak_bytes,
+            dropped_messages: queue.dropped,
+            post_removes: locked_sub.post_removes.iter_all().map(|(_, dgs)| dgs.len()).sum(),
+        }
+    }
+}
+
+impl From<&ChannelMap> for ChannelMapInfo {
+    fn from(value: &ChannelMap) -> Self {
+        let mut channels: Vec<ChannelInfo> = value
+            .iter_channels()
+            .filter(|(_, subs)| !subs.is_empty())
+            .map(|(channel, subs)| ChannelInfo {
+                channel,
+                subscribers: remotes(subs),
+            })
+            .collect();
+        channels.sort_unstable_by_key(|info| info.channel);
+
+        Self {
+            channels,
+            ranges: value
+                .iter_ranges()
+                .map(|(range, subs)| RangeInfo {
+                    min: *range.start(),
+                    max: *range.end(),
+                    subscribers: remotes(subs),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Returns the sorted remote addresses of the given subscribers.
+fn remotes(subs: &imbl::HashSet<SubscriberRef>) -> Vec<String> {
+    let mut remotes: Vec<String> = subs.iter().map(|sub| sub.get_remote().to_string()).collect();
+    remotes.sort_unstable();
+    remotes
+}
+
+/// Accepts connections to the admin endpoint, each handled by its own task.
+pub async fn serve(service: Arc<Mutex<MessageDirector>>, listener: tcp::Listener) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, address)) => {
+                info!("Received admin connection from {}.", address);
+
+                let service = service.clone();
+
+                tokio::spawn(async move {
+                    let (reader, writer) = BoxedTransport::from(socket).into_split();
+
+                    if let Err(err) = handle_connection(&service, reader, writer).await {
+                        warn!("Admin connection {} failed: {}", address, err);
+                    }
+                });
+            }
+            Err(err) => error!("Failed to get admin connection: {}", err),
+        }
+    }
+}
+
+/// Answers each command received on an admin connection, until it is closed.
+async fn handle_connection<R, W>(
+    service: &Arc<Mutex<MessageDirector>>,
+    reader: R,
+    mut writer: W,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response: Response = match line.parse::<Command>() {
+            Ok(command) => execute(service, command).await,
+            Err(err) => Response::Error(err),
+        };
+        let mut json: Vec<u8> = serde_json::to_vec(&response)?;
+
+        json.push(b'\n');
+        writer.write_all(&json).await?;
+    }
+    Ok(())
+}
+
+async fn execute(service: &Arc<Mutex<MessageDirector>>, command: Command) -> Response {
+    let locked_service: MutexGuard<'_, MessageDirector> = service.lock().await;
+
+    match command {
+        Command::Subscribers => {
+            let mut subscribers: Vec<SubscriberInfo> = vec![];
+
+            for sub in &locked_service.subscribers {
+                subscribers.push(SubscriberInfo::new(sub).await);
+            }
+            subscribers.sort_unstable_by(|a, b| a.remote.cmp(&b.remote));
+            Response::Subscribers(subscribers)
+        }
+        Command::Channels => Response::Channels {
+            local: (&locked_service.channel_map).into(),
+            mesh: (&locked_service.peer_interest.channel_map).into(),
+        },
+        Command::Disconnect(remote) => {
+            let sub: Option<&SubscriberRef> = locked_service
+                .subscribers
+                .iter()
+                .find(|sub| sub.get_remote().to_string() == remote);
+
+            match sub {
+                // it is removed once its connection has closed,
+                // like any other subscriber that disconnects
+                Some(sub) => {
+                    info!("Disconnecting subscriber {} by admin command.", remote);
+                    sub.lock().await.receive_disconnect().await;
+                    Response::Disconnected(remote)
+                }
+                None => Response::Error(format!("No subscriber with remote address {}.", remote)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("subscribers".parse(), Ok(Command::Subscribers));
+        assert_eq!(" channels \n".parse(), Ok(Command::Channels));
+        assert_eq!(
+            "disconnect 127.0.0.1:5000".parse(),
+            Ok(Command::Disconnect("127.0.0.1:5000".into()))
+        );
+        assert!("disconnect".parse::<Command>().is_err());
+        assert!("disconnect a b".parse::<Command>().is_err());
+        assert!("channels 1234".parse::<Command>().is_err());
+        assert!("shutdown".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn response_json() {
+        let response = Response::Disconnected("127.0.0.1:5000".into());
+
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"disconnected":"127.0.0.1:5000"}"#
+        );
+        let response = Response::Error("No subscriber.".into());
+
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"error":"No subscriber."}"#
+        );
+    }
+}
@@ -17,43 +17,102 @@
     License along with Donet. If not, see <https://www.gnu.org/licenses/>.
 */
 
+use super::interval_map::IntervalMap;
 use super::subscriber::*;
 use donet_core::globals::Channel;
 use gcollections::ops::*;
 use interval::interval_set::ToIntervalSet;
 use interval::IntervalSet;
-use multimap::MultiMap;
-use rangemap::RangeInclusiveMap;
 use std::collections::HashSet;
-use std::ops::{Range, RangeInclusive};
+use std::ops::RangeInclusive;
 use tokio::sync::MutexGuard;
 
-/// Iterates over all ranges in a [`rangemap::RangeInclusiveMap`],
-/// and filters out ranges that do NOT overlap with the given
-/// `target` range.
-fn equal_range(
-    map: &RangeInclusiveMap<Channel, HashSet<SubscriberRef>>,
-    target: Range<Channel>,
-) -> Vec<(&RangeInclusive<Channel>, &HashSet<SubscriberRef>)> {
-    map.iter()
-        .filter(|(range, _)| {
-            // Check if the range overlaps with the target range
-            range.start() < &target.end && range.end() > &target.start
-        })
-        .collect()
-}
-
 /// Data model to store all channel subscriptions created via a
 /// Message Director service instance.
 ///
 /// Functionality with callbacks for handling changes in mapping can
 /// be achieved by implementing the [`ChannelCoordinator`] trait.
-#[derive(Default)]
+///
+/// Cloning a [`ChannelMap`] is cheap, as its maps are shared with the
+/// clone until either one is changed. This is what lets the MD publish
+/// snapshots of its subscriptions to be routed with, without a lock.
+#[derive(Default, Clone)]
 pub struct ChannelMap {
     /// Single channel subscriptions
-    subscriptions: MultiMap<Channel, SubscriberRef>,
+    subscriptions: imbl::HashMap<Channel, imbl::HashSet<SubscriberRef>>,
     /// Channel range subscriptions
-    range_subscriptions: RangeInclusiveMap<Channel, HashSet<SubscriberRef>>,
+    range_subscriptions: IntervalMap<SubscriberRef>,
+}
+
+impl ChannelMap {
+    /// Returns the subscribers of the given channels.
+    #[allow(clippy::mutable_key_type)]
+    pub fn lookup_channels(&self, channels: &[Channel]) -> HashSet<SubscriberRef> {
+        let mut subs: HashSet<SubscriberRef> = HashSet::default();
+
+        for channel in channels {
+            // Run through single-channel subscriptions map
+            if let Some(chan_subs) = self.subscriptions.get(channel) {
+                subs.extend(chan_subs.iter().cloned());
+            }
+
+            // Run through range subscriptions map
+            if let Some(range_subs) = self.range_subscriptions.get(*channel) {
+                subs.extend(range_subs.iter().cloned());
+            }
+        }
+        subs
+    }
+
+    /// Returns `true` if the given channel has at least one subscriber.
+    pub fn has_subscribers(&self, channel: Channel) -> bool {
+        self.subscriptions.contains_key(&channel) || self.range_subscriptions.get(channel).is_some()
+    }
+
+    /// Returns `true` if the given channel has a single channel
+    /// subscriber other than the given one.
+    pub fn has_other_subscribers(&self, channel: Channel, except: &SubscriberRef) -> bool {
+        self.subscriptions
+            .get(&channel)
+            .is_some_and(|subs| subs.iter().any(|sub| sub != except))
+    }
+
+    /// Returns the parts of the given range that have a range
+    /// subscriber, other than the given one, if any.
+    pub fn covered_ranges(
+        &self,
+        range: RangeInclusive<Channel>,
+        except: Option<&SubscriberRef>,
+    ) -> Vec<RangeInclusive<Channel>> {
+        self.range_subscriptions
+            .covered(range, |subs| subs.iter().any(|sub| Some(sub) != except))
+    }
+
+    /// Returns the channels that have at least one subscriber.
+    pub fn get_channels(&self) -> Vec<Channel> {
+        self.subscriptions
+            .iter()
+            .filter(|(_, subs)| !subs.is_empty())
+            .map(|(channel, _)| *channel)
+            .collect()
+    }
+
+    /// Returns the channel ranges that have at least one subscriber.
+    pub fn get_ranges(&self) -> Vec<RangeInclusive<Channel>> {
+        self.range_subscriptions.ranges()
+    }
+
+    /// Iterates over the subscribers of each single channel subscription.
+    pub fn iter_channels(&self) -> impl Iterator<Item = (Channel, &imbl::HashSet<SubscriberRef>)> {
+        self.subscriptions.iter().map(|(channel, subs)| (*channel, subs))
+    }
+
+    /// Iterates over the subscribers of each part of the range subscriptions.
+    pub fn iter_ranges(
+        &self,
+    ) -> impl Iterator<Item = (RangeInclusive<Channel>, &imbl::HashSet<SubscriberRef>)> {
+        self.range_subscriptions.iter()
+    }
 }
 
 /// Struct implementing this trait must own a [`ChannelMap`].
@@ -67,6 +126,10 @@ pub trait HasChannelMap {
 ///
 /// The implementing type must also implement [`HasChannelMap`],
 /// to guarantee that there is a [`ChannelMap`] in memory.
+///
+/// The subscribers of each channel and range are reference counted,
+/// so the callbacks are only called when a channel or part of a range
+/// gets its first subscriber, or loses its last one.
 pub trait ChannelCoordinator
 where
     Self: HasChannelMap,
@@ -74,8 +137,8 @@ where
     // Callbacks that must be implemented manually.
     async fn on_add_channel(&mut self, channel: Channel);
     async fn on_remove_channel(&mut self, channel: Channel);
-    async fn on_add_range(&mut self, range: Range<Channel>);
-    async fn on_remove_range(&mut self, range: Range<Channel>);
+    async fn on_add_range(&mut self, range: RangeInclusive<Channel>);
+    async fn on_remove_range(&mut self, range: RangeInclusive<Channel>);
 
     /// Adds a single channel to the subscriber's subscribed channels map.
     async fn subscribe_channel(&mut self, sub: SubscriberRef, chan: Channel) {
@@ -87,12 +150,15 @@ where
 
         locked_sub.subscribed_channels.insert(chan);
 
-        let has_subscriptions: bool = !locked_sub.subscribed_channels.is_empty();
+        let subscribers: &mut imbl::HashSet<SubscriberRef> =
+            self.get_channel_map().subscriptions.entry(chan).or_default();
+
+        let is_first: bool = subscribers.is_empty();
+        subscribers.insert(sub.clone());
 
-        if has_subscriptions {
+        if is_first {
             Self::on_add_channel(self, chan).await;
         }
-        self.get_channel_map().subscriptions.insert(chan, sub.clone());
     }
 
     /// Removes the given channel from the subscribed channels map.
@@ -118,117 +184,78 @@ where
 
     /// Adds an object to be subscribed to a range of channels.
     ///
-    /// The given range is inclusive.
+    /// The given range is inclusive. The ranges of different
+    /// subscribers may overlap.
     async fn subscribe_range(&mut self, sub: SubscriberRef, min: Channel, max: Channel) {
+        if min > max {
+            return;
+        }
         {
             let mut locked_sub: MutexGuard<'_, Subscriber> = sub.lock().await;
 
             // Create a new closed interval set using given range
             let new_interval: IntervalSet<Channel> = vec![(min, max)].to_interval_set();
 
-            // Create a new set with the given subscriber
-            let mut new_sub_set: HashSet<SubscriberRef> = HashSet::default();
-            new_sub_set.insert(sub.clone());
-
             // Update channel range subscription mappings
-            locked_sub.subscribed_ranges.extend(new_interval);
-
-            self.get_channel_map()
-                .range_subscriptions
-                .insert(RangeInclusive::new(min, max), new_sub_set);
+            locked_sub.subscribed_ranges = locked_sub.subscribed_ranges.union(&new_interval);
         }
 
-        // Finally, check if any part of this interval is a new range.
-        // (Check if any range of this interval does NOT overlap an existing range.)
-        let new_range: Range<Channel> = min..max;
+        // Upstream the parts of this range that had no subscribers.
+        let new_ranges: Vec<RangeInclusive<Channel>> = self
+            .get_channel_map()
+            .range_subscriptions
+            .insert(min..=max, sub.clone());
 
-        // Get overlapping ranges from map's range subscription map.
-        let interval_range: Vec<_> = equal_range(&self.get_channel_map().range_subscriptions, new_range);
-
-        for segment in interval_range {
-            if segment.1.len() == 1 {
-                // There's a segment of the interval that has only one item
-                // (our newly added subscriber), which confirms this IS a new
-                // range. So, we should upstream the range addition.
-                Self::on_add_range(self, min..max).await;
-                break;
-            }
+        for range in new_ranges {
+            Self::on_add_range(self, range).await;
         }
     }
 
     /// Performs the reverse of the `Self::subscribe_range()` function.
     ///
-    /// The given range is inclusive.
+    /// The given range is inclusive, and may cover only part of
+    /// the ranges that the subscriber is subscribed to.
     async fn unsubscribe_range(&mut self, sub: SubscriberRef, min: Channel, max: Channel) {
-        // if we have no range subscriptions mapped, do nothing and return early
-        if self.get_channel_map().range_subscriptions.is_empty() {
+        if min > max {
             return;
         }
-
-        // prepare subscriber set
-        let mut sub_set: HashSet<SubscriberRef> = HashSet::default();
-        sub_set.insert(sub.clone());
-
-        // Construct the interval we are removing, bounded to range subscriptions.
-        let map: &mut ChannelMap = self.get_channel_map();
-
-        // We can simply unwrap the first/last as we check that there is at least
-        // one range subscription in the beginning of this function.
-        let rs_first = map.range_subscriptions.first_range_value().unwrap();
-        let rs_last = map.range_subscriptions.last_range_value().unwrap();
-
-        let lower: Channel = *rs_first.0.start();
-        let upper: Channel = *rs_last.0.end();
-
-        let union_lower: Channel = std::cmp::max(min, lower);
-        let union_upper: Channel = std::cmp::max(max, upper);
-
-        let range: Range<Channel> = union_lower..union_upper;
-
-        let i_set: IntervalSet<Channel> = vec![(union_lower, union_upper)].to_interval_set();
-
-        // Speculate the channel ranges that will have no subscribers
-        // after this subscriber is removed.
-        let mut dead_ranges: IntervalSet<Channel> = i_set.clone();
-        let interval_range = equal_range(&map.range_subscriptions, union_lower..union_upper);
-
-        // go through interval range and remove ranges that will still
-        // have subscribers after this subscriber is removed
-        for (range, range_subs) in interval_range {
-            let has_subscribers: bool = !range_subs.is_empty();
-            let is_only_subscriber: bool = (range_subs.len() == 1) && range_subs.contains(&sub);
-
-            if has_subscribers && !is_only_subscriber {
-                // we are not the last subscriber in this range, so don't delete it
-                dead_ranges = &dead_ranges - vec![(*range.start(), *range.end())].to_interval_set();
-            }
-        }
+        let i_set: IntervalSet<Channel> = vec![(min, max)].to_interval_set();
 
         // update range mappings on both subscriber and channel map
         let mut locked_sub: MutexGuard<'_, Subscriber> = sub.lock().await;
 
-        locked_sub.subscribed_ranges = &locked_sub.subscribed_ranges - i_set;
-        map.range_subscriptions
-            .remove(RangeInclusive::new(range.start, range.end));
+        locked_sub.subscribed_ranges = locked_sub.subscribed_ranges.difference(&i_set);
+
+        // the channel ranges that have no subscribers after this subscriber is removed
+        let dead_ranges: Vec<RangeInclusive<Channel>> =
+            self.get_channel_map().range_subscriptions.remove(min..=max, &sub);
 
         // clone subscriber's channel subscriptions to avoid double borrow
         let chans = locked_sub.subscribed_channels.clone();
 
         // delete single channel subscriptions that fall within the range
+        let mut dead_channels: Vec<Channel> = vec![];
+
         for channel in &chans {
             if (min <= *channel) && (*channel <= max) {
-                // we do **not** call `Self::unsubscribe_channel`, because
-                // that might send off an `on_remove_channel` event.
-                // Instead, we should update this manually.
-                Self::remove_subscriber(self, sub.clone(), *channel).await;
-
+                // we do **not** call `Self::unsubscribe_channel`, as
+                // the subscriber is still locked. Instead, we should
+                // update this manually.
+                if Self::remove_subscriber(self, sub.clone(), *channel).await {
+                    dead_channels.push(*channel);
+                }
                 locked_sub.subscribed_channels.remove(channel);
             }
         }
+        drop(locked_sub);
+
+        for channel in dead_channels {
+            Self::on_remove_channel(self, channel).await;
+        }
 
         // finally, have our channel coordinator delete any new 'dead' ranges
         for range in dead_ranges {
-            Self::on_remove_range(self, range.lower()..range.upper()).await;
+            Self::on_remove_range(self, range).await;
         }
     }
 
@@ -256,7 +283,7 @@ where
         }
     }
 
-    /// Removes the given subscriber from the MultiMap for a given
+    /// Removes the given subscriber from the subscribers of a given
     /// channel.
     ///
     /// Returns `true` **only** if:
@@ -269,36 +296,19 @@ where
     async fn remove_subscriber(&mut self, sub: SubscriberRef, chan: Channel) -> bool {
         let map: &mut ChannelMap = self.get_channel_map();
 
-        let locked_sub: MutexGuard<'_, Subscriber> = sub.lock().await;
-        let mut sub_count: usize = map.subscriptions.len();
-
-        if sub_count == 0 {
+        // subscribers are compared by remote address, so the
+        // subscriber does not need to be locked to be found
+        let Some(subscriptions) = map.subscriptions.get_mut(&chan) else {
+            return false;
+        };
+        if subscriptions.remove(&sub).is_none() {
             return false;
         }
-        for (key, subscriptions) in map.subscriptions.iter_all_mut() {
-            if *key != chan {
-                continue;
-            }
-            let mut index: usize = 0;
-            let mut found: bool = false;
-
-            for subscription in subscriptions.iter_mut() {
-                if *subscription.lock().await == *locked_sub {
-                    found = true;
-                    sub_count -= 1;
-                }
-                index += 1;
-            }
-            if found {
-                // The swap_remove() function requires a second mutable
-                // borrow of the vector which is illegal, so I had to work
-                // around this by using a `found` flag and performing the
-                // remove outside of the for loop which turns values into
-                // an iterator. This way we don't perform a second borrow.
-                subscriptions.swap_remove(index);
-            }
+        if subscriptions.is_empty() {
+            map.subscriptions.remove(&chan);
+            return true;
         }
-        sub_count == 0
+        false
     }
 
     /// Checks if a given subscriber has a subscription on the given
@@ -314,30 +324,12 @@ where
         }
         false
     }
-
-    /// Populates a set with the subscribers for a list of channels.
-    fn lookup_channels(&mut self, channels: Vec<Channel>, subs: &mut HashSet<SubscriberRef>) {
-        for channel in channels {
-            // Run through single-channel subscriptions map
-            if let Some(chan_subs) = self.get_channel_map().subscriptions.get_vec(&channel) {
-                subs.extend(chan_subs.iter().cloned());
-            }
-
-            // Run through range subscriptions map
-            for (_range, range_subs) in self
-                .get_channel_map()
-                .range_subscriptions
-                .overlapping(RangeInclusive::new(channel, channel))
-            {
-                subs.extend(range_subs.iter().cloned());
-            }
-        }
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use donet_network::addr::PeerAddr;
     use std::net::SocketAddr;
     use std::str::FromStr;
     use std::sync::atomic::{AtomicBool, Ordering};
@@ -350,6 +342,11 @@ mod tests {
         got_add_range: AtomicBool,
         got_remove_channel: AtomicBool,
         got_remove_range: AtomicBool,
+        // Arguments passed to the callbacks, in order.
+        added_channels: Vec<Channel>,
+        removed_channels: Vec<Channel>,
+        added_ranges: Vec<RangeInclusive<Channel>>,
+        removed_ranges: Vec<RangeInclusive<Channel>>,
     }
 
     impl HasChannelMap for MockChannelCoordinator {
@@ -359,27 +356,31 @@ mod tests {
     }
 
     impl ChannelCoordinator for MockChannelCoordinator {
-        async fn on_add_channel(&mut self, _channel: Channel) {
+        async fn on_add_channel(&mut self, channel: Channel) {
             self.got_add_channel.swap(true, Ordering::SeqCst);
+            self.added_channels.push(channel);
         }
 
-        async fn on_add_range(&mut self, _range: Range<Channel>) {
+        async fn on_add_range(&mut self, range: RangeInclusive<Channel>) {
             self.got_add_range.swap(true, Ordering::SeqCst);
+            self.added_ranges.push(range);
         }
 
-        async fn on_remove_channel(&mut self, _channel: Channel) {
+        async fn on_remove_channel(&mut self, channel: Channel) {
             self.got_remove_channel.swap(true, Ordering::SeqCst);
+            self.removed_channels.push(channel);
         }
 
-        async fn on_remove_range(&mut self, _range: Range<Channel>) {
+        async fn on_remove_range(&mut self, range: RangeInclusive<Channel>) {
             self.got_remove_range.swap(true, Ordering::SeqCst);
+            self.removed_ranges.push(range);
         }
     }
 
     #[tokio::test]
     async fn single_subscription() {
         let mut mock = MockChannelCoordinator::default();
-        let mock_sub_1 = SubscriberRef::from(SocketAddr::from_str("127.0.0.1:1").unwrap());
+        let mock_sub_1 = SubscriberRef::from(PeerAddr::from(SocketAddr::from_str("127.0.0.1:1").unwrap()));
 
         mock.subscribe_channel(mock_sub_1.clone(), 1000).await;
 
@@ -392,7 +393,7 @@ mod tests {
     #[tokio::test]
     async fn range_subscription() {
         let mut mock = MockChannelCoordinator::default();
-        let mock_sub_1 = SubscriberRef::from(SocketAddr::from_str("127.0.0.1:1").unwrap());
+        let mock_sub_1 = SubscriberRef::from(PeerAddr::from(SocketAddr::from_str("127.0.0.1:1").unwrap()));
 
         // test range subscription
         let min: Channel = 1000;
@@ -414,4 +415,106 @@ mod tests {
         assert!(!mock.is_subscribed(&sub_lock, min - 1).await);
         assert!(!mock.is_subscribed(&sub_lock, max + 1).await);
     }
+
+    #[tokio::test]
+    async fn single_unsubscription() {
+        let mut mock = MockChannelCoordinator::default();
+        let mock_sub_1 = SubscriberRef::from(PeerAddr::from(SocketAddr::from_str("127.0.0.1:1").unwrap()));
+        let mock_sub_2 = SubscriberRef::from(PeerAddr::from(SocketAddr::from_str("127.0.0.1:2").unwrap()));
+
+        mock.subscribe_channel(mock_sub_1.clone(), 1000).await;
+        mock.subscribe_channel(mock_sub_2.clone(), 1000).await;
+
+        // the channel still has a subscriber
+        mock.unsubscribe_channel(mock_sub_1.clone(), 1000).await;
+        assert!(!*mock.got_remove_channel.get_mut());
+
+        mock.unsubscribe_all(mock_sub_2.clone()).await;
+        assert!(*mock.got_remove_channel.get_mut());
+
+        assert!(mock.map.lookup_channels(&[1000]).is_empty());
+        assert!(mock.map.get_channels().is_empty());
+    }
+
+    #[tokio::test]
+    async fn channel_reference_counts() {
+        let mut mock = MockChannelCoordinator::default();
+        let mock_sub_1 = SubscriberRef::from(PeerAddr::from(SocketAddr::from_str("127.0.0.1:1").unwrap()));
+        let mock_sub_2 = SubscriberRef::from(PeerAddr::from(SocketAddr::from_str("127.0.0.1:2").unwrap()));
+
+        // only the first subscriber of a channel adds it
+        mock.subscribe_channel(mock_sub_1.clone(), 1000).await;
+        mock.subscribe_channel(mock_sub_2.clone(), 1000).await;
+        mock.subscribe_channel(mock_sub_2.clone(), 1001).await;
+        assert_eq!(mock.added_channels, [1000, 1001]);
+
+        // and only the last one removes it, including by a range
+        mock.unsubscribe_channel(mock_sub_2.clone(), 1000).await;
+        assert!(mock.removed_channels.is_empty());
+
+        mock.unsubscribe_range(mock_sub_2.clone(), 900, 1100).await;
+        assert_eq!(mock.removed_channels, [1001]);
+        assert!(mock.removed_ranges.is_empty());
+
+        mock.unsubscribe_channel(mock_sub_1.clone(), 1000).await;
+        assert_eq!(mock.removed_channels, [1001, 1000]);
+
+        // resubscribing adds the channel again
+        mock.subscribe_channel(mock_sub_2.clone(), 1000).await;
+        assert_eq!(mock.added_channels, [1000, 1001, 1000]);
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_unchanged() {
+        let mut mock = MockChannelCoordinator::default();
+        let mock_sub_1 = SubscriberRef::from(PeerAddr::from(SocketAddr::from_str("127.0.0.1:1").unwrap()));
+
+        mock.subscribe_channel(mock_sub_1.clone(), 1000).await;
+        let snapshot: ChannelMap = mock.map.clone();
+
+        mock.subscribe_range(mock_sub_1.clone(), 2000, 3000).await;
+        mock.unsubscribe_channel(mock_sub_1.clone(), 1000).await;
+
+        assert!(snapshot.lookup_channels(&[1000]).contains(&mock_sub_1));
+        assert!(snapshot.lookup_channels(&[2500]).is_empty());
+
+        assert!(mock.map.lookup_channels(&[1000]).is_empty());
+        assert!(mock.map.lookup_channels(&[2500]).contains(&mock_sub_1));
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    #[tokio::test]
+    async fn overlapping_range_subscriptions() {
+        let mut mock = MockChannelCoordinator::default();
+        let mock_sub_1 = SubscriberRef::from(PeerAddr::from(SocketAddr::from_str("127.0.0.1:1").unwrap()));
+        let mock_sub_2 = SubscriberRef::from(PeerAddr::from(SocketAddr::from_str("127.0.0.1:2").unwrap()));
+
+        mock.subscribe_range(mock_sub_1.clone(), 1000, 2000).await;
+        mock.subscribe_range(mock_sub_2.clone(), 1500, 2500).await;
+
+        // only the part of the second range that had no subscribers is new
+        assert_eq!(mock.added_ranges, [1000..=2000, 2001..=2500]);
+
+        let subs = mock.map.lookup_channels(&[1750]);
+        assert!(subs.contains(&mock_sub_1) && subs.contains(&mock_sub_2));
+
+        // split the range of the first subscriber
+        mock.unsubscribe_range(mock_sub_1.clone(), 1100, 1600).await;
+        assert_eq!(mock.removed_ranges, [1100..=1499]);
+
+        assert!(mock.map.lookup_channels(&[1200]).is_empty());
+        assert_eq!(mock.map.lookup_channels(&[1550]).len(), 1);
+        assert!(mock.map.lookup_channels(&[1050]).contains(&mock_sub_1));
+        assert!(mock.map.lookup_channels(&[1700]).contains(&mock_sub_1));
+        assert_eq!(mock.map.get_ranges(), [1000..=1099, 1500..=2500]);
+
+        assert!(!mock.is_subscribed(&mock_sub_1.lock().await, 1200).await);
+        assert!(mock.is_subscribed(&mock_sub_1.lock().await, 1700).await);
+
+        mock.unsubscribe_all(mock_sub_1.clone()).await;
+        mock.unsubscribe_all(mock_sub_2.clone()).await;
+
+        assert_eq!(mock.removed_ranges, [1100..=1499, 1000..=1099, 1500..=2500]);
+        assert!(mock.map.get_ranges().is_empty());
+    }
 }
@@ -0,0 +1,72 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Events that the Message Director itself logs, such
+//! as trace spans and denied access to channels.
+
+use crate::upstream::UpstreamMD;
+use donet_core::datagram::datagram::Datagram;
+use donet_core::Protocol;
+use donet_daemon::event::LoggedEvent;
+use donet_network::udp;
+use log::{trace, warn};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Sends events to the event logger, or to our upstream
+/// MD as log messages, if we have no event logger.
+#[derive(Clone)]
+pub struct EventLog {
+    event_logger: Option<Arc<udp::Socket>>,
+    upstream: Option<Arc<Mutex<UpstreamMD>>>,
+}
+
+impl EventLog {
+    pub fn new(event_logger: Option<Arc<udp::Socket>>, upstream: Option<Arc<Mutex<UpstreamMD>>>) -> Self {
+        Self {
+            event_logger,
+            upstream,
+        }
+    }
+
+    /// Sends an event to the event logger, or upstream if we have none.
+    pub async fn log(&self, event: LoggedEvent) {
+        let event: Datagram = event.make_datagram();
+
+        if let Some(logger) = &self.event_logger {
+            if let Err(err) = logger.socket.send(event.get_buffer()).await {
+                warn!("Failed to send event to the event logger: {}", err);
+            }
+            return;
+        }
+        let Some(upstream) = &self.upstream else {
+            return trace!("Dropping event; No event logger found.");
+        };
+        let mut log_message: Datagram = Datagram::default();
+
+        let result = log_message
+            .add_control_header(Protocol::MDLogMessage.into())
+            .and_then(|_| log_message.add_blob(event.get_buffer()));
+
+        match result {
+            Ok(()) => upstream.lock().await.stage_datagram(log_message).await,
+            Err(err) => warn!("Failed to send event upstream: {}", err),
+        }
+    }
+}
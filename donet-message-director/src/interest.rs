@@ -0,0 +1,358 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Interest of the rest of a cluster, which decides what a
+//! Message Director sends to its upstream MD.
+//!
+//! A downstream MD watches the interest of its upstream MD with a
+//! `CONTROL_WATCH_INTEREST`. The upstream MD then tells it which
+//! channels and ranges the rest of the cluster subscribes to, as
+//! add and remove channel and range control messages, leaving out
+//! the subscriptions of the downstream MD itself.
+//!
+//! The downstream MD keeps these in an [`Interest`], and sends a
+//! datagram upstream only if the upstream MD wants one of its
+//! recipients, or if a recipient has no subscribers of its own.
+
+use crate::channel_map::{ChannelCoordinator, ChannelMap};
+use crate::interval_map::IntervalMap;
+use crate::subscriber::SubscriberRef;
+use donet_core::datagram::datagram::Datagram;
+use donet_core::datagram::iterator::{DatagramIterator, IteratorError};
+use donet_core::globals::Channel;
+use donet_core::Protocol;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+
+/// A change of subscriptions, as sent in a control message.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Change {
+    AddChannel(Channel),
+    RemoveChannel(Channel),
+    AddRange(Channel, Channel),
+    RemoveRange(Channel, Channel),
+}
+
+impl Change {
+    /// Reads the arguments of a control message of the given type.
+    ///
+    /// Returns `None` if the message type is not a change of subscriptions.
+    pub fn read(msg_type: Protocol, dgi: &mut DatagramIterator) -> Result<Option<Self>, IteratorError> {
+        Ok(Some(match msg_type {
+            Protocol::MDAddChannel => Self::AddChannel(dgi.read_channel()?),
+            Protocol::MDRemoveChannel => Self::RemoveChannel(dgi.read_channel()?),
+            Protocol::MDAddRange => Self::AddRange(dgi.read_channel()?, dgi.read_channel()?),
+            Protocol::MDRemoveRange => Self::RemoveRange(dgi.read_channel()?, dgi.read_channel()?),
+            _ => return Ok(None),
+        }))
+    }
+
+    /// Returns the channels and ranges touched by this change.
+    pub fn get_scope(&self) -> Scope {
+        match *self {
+            Self::AddChannel(channel) | Self::RemoveChannel(channel) => Scope::channel(channel),
+            Self::AddRange(min, max) | Self::RemoveRange(min, max) => Scope::range(min..=max),
+        }
+    }
+
+    /// Applies this change to the subscriptions of the given subscriber.
+    pub async fn subscribe(self, coordinator: &mut impl ChannelCoordinator, sub: SubscriberRef) {
+        match self {
+            Self::AddChannel(channel) => coordinator.subscribe_channel(sub, channel).await,
+            Self::RemoveChannel(channel) => coordinator.unsubscribe_channel(sub, channel).await,
+            Self::AddRange(min, max) => coordinator.subscribe_range(sub, min, max).await,
+            Self::RemoveRange(min, max) => coordinator.unsubscribe_range(sub, min, max).await,
+        }
+    }
+}
+
+/// Channels and ranges that an upstream MD wants from us.
+#[derive(Debug, Default, Clone)]
+pub struct Interest {
+    channels: imbl::HashSet<Channel>,
+    ranges: IntervalMap<()>,
+}
+
+impl Interest {
+    /// Returns `true` if the given channel is wanted.
+    pub fn contains(&self, channel: Channel) -> bool {
+        self.channels.contains(&channel) || self.ranges.get(channel).is_some()
+    }
+
+    pub fn add_channel(&mut self, channel: Channel) {
+        self.channels.insert(channel);
+    }
+
+    pub fn remove_channel(&mut self, channel: Channel) {
+        self.channels.remove(&channel);
+    }
+
+    pub fn add_range(&mut self, range: RangeInclusive<Channel>) {
+        self.ranges.insert(range, ());
+    }
+
+    pub fn remove_range(&mut self, range: RangeInclusive<Channel>) {
+        self.ranges.remove(range, &());
+    }
+
+    /// Applies a change that our upstream MD sent us.
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::AddChannel(channel) => self.add_channel(channel),
+            Change::RemoveChannel(channel) => self.remove_channel(channel),
+            Change::AddRange(min, max) => self.add_range(min..=max),
+            Change::RemoveRange(min, max) => self.remove_range(min..=max),
+        }
+    }
+
+    pub fn get_channels(&self) -> Vec<Channel> {
+        self.channels.iter().copied().collect()
+    }
+
+    pub fn get_ranges(&self) -> Vec<RangeInclusive<Channel>> {
+        self.ranges.ranges()
+    }
+}
+
+/// The channels and ranges touched by a change of subscriptions.
+#[derive(Debug, Default)]
+pub struct Scope {
+    pub channels: Vec<Channel>,
+    pub ranges: Vec<RangeInclusive<Channel>>,
+}
+
+impl Scope {
+    pub fn channel(channel: Channel) -> Self {
+        Self {
+            channels: vec![channel],
+            ..Default::default()
+        }
+    }
+
+    pub fn range(range: RangeInclusive<Channel>) -> Self {
+        Self {
+            ranges: vec![range],
+            ..Default::default()
+        }
+    }
+}
+
+/// The interest that a downstream MD was told about, within a [`Scope`].
+#[derive(Debug, Default, PartialEq)]
+pub struct View {
+    channels: HashSet<Channel>,
+    ranges: Vec<RangeInclusive<Channel>>,
+}
+
+impl View {
+    /// Returns the control messages that take a downstream
+    /// MD from the interest of this view to the given one.
+    pub fn updates(&self, next: &View) -> Vec<Datagram> {
+        let mut updates: Vec<Datagram> = vec![];
+
+        for channel in next.channels.difference(&self.channels) {
+            updates.push(control(Protocol::MDAddChannel, &[*channel]));
+        }
+        for channel in self.channels.difference(&next.channels) {
+            updates.push(control(Protocol::MDRemoveChannel, &[*channel]));
+        }
+
+        // tag the parts of each range by the views that have them
+        let mut ranges: IntervalMap<bool> = IntervalMap::default();
+
+        for range in &self.ranges {
+            ranges.insert(range.clone(), false);
+        }
+        for range in &next.ranges {
+            ranges.insert(range.clone(), true);
+        }
+        for (range, in_next) in ranges.iter() {
+            if in_next.len() > 1 {
+                continue;
+            }
+            let msg_type: Protocol = match in_next.contains(&true) {
+                true => Protocol::MDAddRange,
+                false => Protocol::MDRemoveRange,
+            };
+            updates.push(control(msg_type, &[*range.start(), *range.end()]));
+        }
+        updates
+    }
+}
+
+/// Everything that the downstream MDs of a Message Director may
+/// want from it, which are the subscriptions of its subscribers,
+/// of the peers of its mesh, and of its own upstream MD.
+pub struct Sources<'a> {
+    pub subscribers: &'a ChannelMap,
+    pub peers: &'a ChannelMap,
+    pub upstream: &'a Interest,
+}
+
+impl Sources<'_> {
+    /// Returns the interest of everyone but the given downstream
+    /// MD, within the given scope.
+    pub fn view(&self, scope: &Scope, watcher: &SubscriberRef) -> View {
+        let channels: HashSet<Channel> = scope
+            .channels
+            .iter()
+            .copied()
+            .filter(|channel| {
+                self.subscribers.has_other_subscribers(*channel, watcher)
+                    || self.peers.has_other_subscribers(*channel, watcher)
+                    || self.upstream.channels.contains(channel)
+            })
+            .collect();
+
+        let mut ranges: IntervalMap<()> = IntervalMap::default();
+
+        for range in &scope.ranges {
+            let covered = [
+                self.subscribers.covered_ranges(range.clone(), Some(watcher)),
+                self.peers.covered_ranges(range.clone(), Some(watcher)),
+                self.upstream.ranges.covered(range.clone(), |_| true),
+            ];
+            for part in covered.into_iter().flatten() {
+                ranges.insert(part, ());
+            }
+        }
+        View {
+            channels,
+            ranges: ranges.ranges(),
+        }
+    }
+
+    /// Returns a scope that covers every subscription.
+    pub fn everything(&self) -> Scope {
+        let mut channels: HashSet<Channel> = HashSet::default();
+
+        channels.extend(self.subscribers.get_channels());
+        channels.extend(self.peers.get_channels());
+        channels.extend(self.upstream.get_channels());
+
+        Scope {
+            channels: channels.into_iter().collect(),
+            ranges: vec![0..=Channel::MAX],
+        }
+    }
+}
+
+/// Returns a control message of the given type, with the given channels.
+pub fn control(msg_type: Protocol, channels: &[Channel]) -> Datagram {
+    let mut dg: Datagram = Datagram::default();
+
+    dg.add_control_header(msg_type.into()).unwrap();
+
+    for channel in channels {
+        dg.add_channel(*channel).unwrap();
+    }
+    dg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel_map::ChannelCoordinator;
+    use crate::mesh::PeerInterest;
+    use donet_core::datagram::iterator::DatagramIterator;
+    use donet_network::addr::PeerAddr;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    fn subscriber(port: u16) -> SubscriberRef {
+        let addr: SocketAddr = SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap();
+        PeerAddr::from(addr).into()
+    }
+
+    /// Returns the message type and channels of each control message.
+    fn decode(updates: Vec<Datagram>) -> Vec<(Protocol, Vec<Channel>)> {
+        let mut decoded: Vec<(Protocol, Vec<Channel>)> = updates
+            .into_iter()
+            .map(|dg| {
+                let mut dgi: DatagramIterator = dg.into();
+                dgi.read_recipient_count().unwrap();
+                dgi.read_channel().unwrap();
+
+                let msg_type: Protocol = dgi.read_msg_type().unwrap();
+                let mut channels: Vec<Channel> = vec![];
+
+                while dgi.get_remaining() > 0 {
+                    channels.push(dgi.read_channel().unwrap());
+                }
+                (msg_type, channels)
+            })
+            .collect();
+
+        decoded.sort_by_key(|(msg_type, channels)| (u16::from(*msg_type), channels.clone()));
+        decoded
+    }
+
+    #[tokio::test]
+    async fn watcher_is_left_out() {
+        let watcher: SubscriberRef = subscriber(1);
+        let other: SubscriberRef = subscriber(2);
+
+        let mut subscribers = PeerInterest::default();
+        let peers = PeerInterest::default();
+        let mut upstream = Interest::default();
+
+        subscribers.subscribe_channel(watcher.clone(), 1000).await;
+        subscribers.subscribe_range(watcher.clone(), 0, 99).await;
+        subscribers.subscribe_channel(other.clone(), 2000).await;
+        subscribers.subscribe_range(other.clone(), 50, 149).await;
+        upstream.add_channel(3000);
+        upstream.add_range(140..=199);
+
+        let sources = Sources {
+            subscribers: &subscribers.channel_map,
+            peers: &peers.channel_map,
+            upstream: &upstream,
+        };
+        let view: View = sources.view(&sources.everything(), &watcher);
+
+        assert_eq!(view.channels, HashSet::from([2000, 3000]));
+        assert_eq!(view.ranges, [50..=199]);
+    }
+
+    #[test]
+    fn view_updates() {
+        let before = View {
+            channels: HashSet::from([1000, 1001]),
+            ranges: vec![0..=99, 200..=299],
+        };
+        let after = View {
+            channels: HashSet::from([1001, 1002]),
+            ranges: vec![50..=249],
+        };
+        let updates = decode(before.updates(&after));
+
+        assert_eq!(
+            updates,
+            [
+                (Protocol::MDAddChannel, vec![1002]),
+                (Protocol::MDRemoveChannel, vec![1000]),
+                (Protocol::MDAddRange, vec![100, 199]),
+                (Protocol::MDRemoveRange, vec![0, 49]),
+                (Protocol::MDRemoveRange, vec![250, 299]),
+            ]
+        );
+        assert!(after.updates(&after).is_empty());
+    }
+}
@@ -0,0 +1,494 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Maps ranges of channels to the subscribers of those ranges.
+
+use donet_core::globals::Channel;
+use std::hash::Hash;
+use std::ops::RangeInclusive;
+
+/// A run of channels that all have the same values.
+#[derive(Debug, Clone)]
+struct Segment<T: Clone + Hash + Eq> {
+    /// Last channel of the segment, inclusive.
+    end: Channel,
+    values: imbl::HashSet<T>,
+}
+
+/// Maps inclusive ranges of channels to sets of values, such as the
+/// subscribers of each range. The ranges of different values may overlap.
+///
+/// The ranges are stored as disjoint segments, keyed by their first
+/// channel, each holding the values whose ranges cover all of it.
+/// Adding or removing a range splits the segments at its bounds, and
+/// merges neighbouring segments that are left with the same values.
+/// So, finding the values of a channel is a single lookup, which is
+/// logarithmic in the number of segments.
+///
+/// Like the rest of [`crate::channel_map::ChannelMap`], cloning an
+/// [`IntervalMap`] is cheap, as the clones share their segments.
+#[derive(Debug, Clone)]
+pub struct IntervalMap<T: Clone + Hash + Eq> {
+    segments: imbl::OrdMap<Channel, Segment<T>>,
+}
+
+impl<T: Clone + Hash + Eq> Default for IntervalMap<T> {
+    fn default() -> Self {
+        Self {
+            segments: imbl::OrdMap::new(),
+        }
+    }
+}
+
+impl<T: Clone + Hash + Eq> IntervalMap<T> {
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Returns the values whose ranges contain the given channel.
+    pub fn get(&self, channel: Channel) -> Option<&imbl::HashSet<T>> {
+        match self.segments.get_prev(&channel) {
+            Some((_, segment)) if segment.end >= channel => Some(&segment.values),
+            _ => None,
+        }
+    }
+
+    /// Iterates over the segments of the map, in order of their channels.
+    pub fn iter(&self) -> impl Iterator<Item = (RangeInclusive<Channel>, &imbl::HashSet<T>)> {
+        self.segments
+            .iter()
+            .map(|(start, segment)| (*start..=segment.end, &segment.values))
+    }
+
+    /// Returns the ranges of channels that have at least one
+    /// value, merging the segments that are next to each other.
+    pub fn ranges(&self) -> Vec<RangeInclusive<Channel>> {
+        merge_adjacent(self.iter().map(|(range, _)| range))
+    }
+
+    /// Returns the parts of the given range whose values pass the
+    /// given filter, merging the parts that are next to each other.
+    pub fn covered(
+        &self,
+        range: RangeInclusive<Channel>,
+        filter: impl Fn(&imbl::HashSet<T>) -> bool,
+    ) -> Vec<RangeInclusive<Channel>> {
+        let (min, max) = range.into_inner();
+
+        if min > max {
+            return vec![];
+        }
+        // the segment that contains `min` may start before it
+        let first: Channel = match self.segments.get_prev(&min) {
+            Some((start, _)) => *start,
+            None => min,
+        };
+        let parts = self
+            .segments
+            .range(first..=max)
+            .filter(|(_, segment)| segment.end >= min && filter(&segment.values))
+            .map(|(start, segment)| (*start).max(min)..=segment.end.min(max));
+
+        merge_adjacent(parts)
+    }
+
+    /// Adds the given value to every channel of the range.
+    ///
+    /// Returns the parts of the range that had no values before.
+    pub fn insert(&mut self, range: RangeInclusive<Channel>, value: T) -> Vec<RangeInclusive<Channel>> {
+        let (min, max) = range.into_inner();
+
+        if min > max {
+            return vec![];
+        }
+        self.split_at(min);
+        self.split_after(max);
+
+        let mut new_ranges: Vec<RangeInclusive<Channel>> = vec![];
+        // first channel that is not yet known to be covered
+        let mut next: Option<Channel> = Some(min);
+
+        for (start, end) in self.segment_bounds(min, max) {
+            if let Some(gap_start) = next.filter(|gap_start| *gap_start < start) {
+                new_ranges.push(gap_start..=start - 1);
+            }
+            let segment: &mut Segment<T> = self.segments.get_mut(&start).expect("Segment not found.");
+            segment.values.insert(value.clone());
+
+            next = end.checked_add(1);
+        }
+        if let Some(gap_start) = next.filter(|gap_start| *gap_start <= max) {
+            new_ranges.push(gap_start..=max);
+        }
+
+        for range in &new_ranges {
+            let segment = Segment {
+                end: *range.end(),
+                values: imbl::HashSet::unit(value.clone()),
+            };
+            self.segments.insert(*range.start(), segment);
+        }
+        self.merge_between(min, max);
+        new_ranges
+    }
+
+    /// Removes the given value from every channel of the range.
+    ///
+    /// Returns the parts of the range that had values
+    /// before, and have none left after the removal.
+    pub fn remove(&mut self, range: RangeInclusive<Channel>, value: &T) -> Vec<RangeInclusive<Channel>> {
+        let (min, max) = range.into_inner();
+
+        if min > max || self.is_empty() {
+            return vec![];
+        }
+        self.split_at(min);
+        self.split_after(max);
+
+        let mut dead_ranges: Vec<RangeInclusive<Channel>> = vec![];
+
+        for (start, end) in self.segment_bounds(min, max) {
+            let segment: &mut Segment<T> = self.segments.get_mut(&start).expect("Segment not found.");
+
+            if segment.values.remove(value).is_some() && segment.values.is_empty() {
+                self.segments.remove(&start);
+                dead_ranges.push(start..=end);
+            }
+        }
+        self.merge_between(min, max);
+        merge_adjacent(dead_ranges)
+    }
+
+    /// Returns the bounds of the segments that start within the given range.
+    fn segment_bounds(&self, min: Channel, max: Channel) -> Vec<(Channel, Channel)> {
+        self.segments
+            .range(min..=max)
+            .map(|(start, segment)| (*start, segment.end))
+            .collect()
+    }
+
+    /// Splits the segment containing the given channel, if
+    /// any, so that a segment starts at the given channel.
+    fn split_at(&mut self, channel: Channel) {
+        let Some((start, segment)) = self.segments.get_prev_mut(&channel) else {
+            return;
+        };
+        if *start == channel || segment.end < channel {
+            return;
+        }
+        let tail = Segment {
+            end: segment.end,
+            values: segment.values.clone(),
+        };
+        segment.end = channel - 1;
+        self.segments.insert(channel, tail);
+    }
+
+    /// Splits the segment containing the given channel, if
+    /// any, so that a segment ends at the given channel.
+    fn split_after(&mut self, channel: Channel) {
+        if let Some(next) = channel.checked_add(1) {
+            self.split_at(next);
+        }
+    }
+
+    /// Merges neighbouring segments that have the same values, from
+    /// the segment before the given range to the segment after it.
+    fn merge_between(&mut self, min: Channel, max: Channel) {
+        let first: Channel = match self.segments.get_prev(&min.saturating_sub(1)) {
+            Some((start, _)) => *start,
+            None => min,
+        };
+        let last: Channel = max.saturating_add(1);
+        let bounds: Vec<(Channel, Channel)> = self.segment_bounds(first, last);
+
+        for pair in bounds.windows(2) {
+            let (start, end) = pair[0];
+            let (next_start, next_end) = pair[1];
+
+            if end.checked_add(1) != Some(next_start) {
+                continue;
+            }
+            // the segment may have already been merged with the one before it
+            let Some((merged_start, segment)) = self.segments.get_prev(&start) else {
+                continue;
+            };
+            let merged_start: Channel = *merged_start;
+
+            if segment.values != self.segments[&next_start].values {
+                continue;
+            }
+            self.segments.remove(&next_start);
+            self.segments
+                .get_mut(&merged_start)
+                .expect("Segment not found.")
+                .end = next_end;
+        }
+    }
+}
+
+/// Merges ranges that are sorted and disjoint, where one ends right before the next.
+fn merge_adjacent(ranges: impl IntoIterator<Item = RangeInclusive<Channel>>) -> Vec<RangeInclusive<Channel>> {
+    let mut merged: Vec<RangeInclusive<Channel>> = vec![];
+
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if last.end().checked_add(1) == Some(*range.start()) => {
+                *last = *last.start()..=*range.end();
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::{BTreeSet, HashMap};
+
+    /// Channels used by the property tests. Kept small, so
+    /// that the ranges of different values often overlap.
+    const CHANNELS: Channel = 64;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Insert(Channel, Channel, u8),
+        Remove(Channel, Channel, u8),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        (any::<bool>(), 0..CHANNELS, 0..CHANNELS, 0..4_u8).prop_map(|(insert, a, b, value)| {
+            let (min, max) = (a.min(b), a.max(b));
+
+            if insert {
+                Op::Insert(min, max, value)
+            } else {
+                Op::Remove(min, max, value)
+            }
+        })
+    }
+
+    /// Naive reference model, mapping each value to the channels it covers.
+    #[derive(Default)]
+    struct Model {
+        channels: HashMap<u8, BTreeSet<Channel>>,
+    }
+
+    impl Model {
+        fn get(&self, channel: Channel) -> BTreeSet<u8> {
+            self.channels
+                .iter()
+                .filter(|(_, channels)| channels.contains(&channel))
+                .map(|(value, _)| *value)
+                .collect()
+        }
+
+        fn covered(&self) -> BTreeSet<Channel> {
+            self.channels.values().flatten().copied().collect()
+        }
+    }
+
+    fn expand(ranges: &[RangeInclusive<Channel>]) -> BTreeSet<Channel> {
+        ranges.iter().cloned().flatten().collect()
+    }
+
+    fn values(map: &IntervalMap<u8>, channel: Channel) -> BTreeSet<u8> {
+        map.get(channel)
+            .map(|values| values.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Segments must be disjoint and sorted, have values,
+    /// and differ from any neighbour they are next to.
+    fn assert_canonical(map: &IntervalMap<u8>) {
+        let segments: Vec<_> = map.iter().collect();
+
+        for (range, values) in &segments {
+            assert!(range.start() <= range.end());
+            assert!(!values.is_empty());
+        }
+        for pair in segments.windows(2) {
+            let (range, values) = &pair[0];
+            let (next_range, next_values) = &pair[1];
+
+            assert!(range.end() < next_range.start());
+
+            if range.end() + 1 == *next_range.start() {
+                assert!(values != next_values, "Segments were not merged.");
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        let mut map: IntervalMap<u8> = IntervalMap::default();
+
+        assert_eq!(map.insert(1000..=2000, 1), [1000..=2000]);
+        assert_eq!(map.insert(1500..=2500, 2), [2001..=2500]);
+        assert_eq!(map.insert(1200..=1300, 1), []);
+
+        assert_eq!(values(&map, 999), BTreeSet::new());
+        assert_eq!(values(&map, 1000), BTreeSet::from([1]));
+        assert_eq!(values(&map, 1750), BTreeSet::from([1, 2]));
+        assert_eq!(values(&map, 2500), BTreeSet::from([2]));
+        assert_eq!(map.ranges(), [1000..=2500]);
+        assert_canonical(&map);
+    }
+
+    #[test]
+    fn partial_remove() {
+        let mut map: IntervalMap<u8> = IntervalMap::default();
+
+        map.insert(1000..=2000, 1);
+        map.insert(1500..=2500, 2);
+
+        // splits the range of value 1 in two
+        assert_eq!(map.remove(1100..=1199, &1), [1100..=1199]);
+        assert_eq!(map.remove(1600..=2100, &1), []);
+        assert_eq!(map.ranges(), [1000..=1099, 1200..=2500]);
+
+        assert_eq!(values(&map, 1150), BTreeSet::new());
+        assert_eq!(values(&map, 1599), BTreeSet::from([1, 2]));
+        assert_eq!(values(&map, 1600), BTreeSet::from([2]));
+
+        // merges back together
+        assert_eq!(map.insert(1100..=1199, 1), [1100..=1199]);
+        assert_eq!(map.insert(1600..=2000, 1), []);
+        assert_eq!(map.iter().count(), 3);
+        assert_canonical(&map);
+
+        assert_eq!(map.remove(0..=Channel::MAX, &2), [2001..=2500]);
+        assert_eq!(map.remove(0..=Channel::MAX, &1), [1000..=2000]);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn channel_bounds() {
+        let mut map: IntervalMap<u8> = IntervalMap::default();
+
+        assert_eq!(map.insert(0..=Channel::MAX, 1), [0..=Channel::MAX]);
+        assert_eq!(
+            map.remove(Channel::MAX..=Channel::MAX, &1),
+            [Channel::MAX..=Channel::MAX]
+        );
+        assert_eq!(
+            map.insert(Channel::MAX - 1..=Channel::MAX, 2),
+            [Channel::MAX..=Channel::MAX]
+        );
+
+        assert_eq!(values(&map, 0), BTreeSet::from([1]));
+        assert_eq!(values(&map, Channel::MAX - 1), BTreeSet::from([1, 2]));
+        assert_eq!(values(&map, Channel::MAX), BTreeSet::from([2]));
+        assert_canonical(&map);
+    }
+
+    #[test]
+    fn covered_parts() {
+        let mut map: IntervalMap<u8> = IntervalMap::default();
+
+        map.insert(10..=20, 1);
+        map.insert(15..=30, 2);
+        map.insert(40..=Channel::MAX, 1);
+
+        assert_eq!(map.covered(12..=35, |_| true), [12..=30]);
+        assert_eq!(
+            map.covered(0..=Channel::MAX, |values| !values.contains(&2)),
+            [10..=14, 40..=Channel::MAX]
+        );
+        assert_eq!(
+            map.covered(0..=Channel::MAX, |values| values.len() > 1),
+            [15..=20]
+        );
+        assert!(map.covered(31..=39, |_| true).is_empty());
+    }
+
+    #[test]
+    fn empty_range() {
+        let mut map: IntervalMap<u8> = IntervalMap::default();
+
+        #[allow(clippy::reversed_empty_ranges)]
+        let range: RangeInclusive<Channel> = 10..=5;
+
+        assert_eq!(map.insert(range.clone(), 1), []);
+        assert_eq!(map.remove(range, &1), []);
+        assert!(map.is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn matches_model(ops in prop::collection::vec(op(), 0..40)) {
+            let mut map: IntervalMap<u8> = IntervalMap::default();
+            let mut model = Model::default();
+
+            for op in ops {
+                let covered: BTreeSet<Channel> = model.covered();
+
+                match op {
+                    Op::Insert(min, max, value) => {
+                        let new_ranges = map.insert(min..=max, value);
+                        model.channels.entry(value).or_default().extend(min..=max);
+
+                        let added: BTreeSet<Channel> = model.covered().difference(&covered).copied().collect();
+                        prop_assert_eq!(expand(&new_ranges), added);
+                    }
+                    Op::Remove(min, max, value) => {
+                        let dead_ranges = map.remove(min..=max, &value);
+                        model.channels.entry(value).or_default().retain(|c| !(min..=max).contains(c));
+
+                        let removed: BTreeSet<Channel> = covered.difference(&model.covered()).copied().collect();
+                        prop_assert_eq!(expand(&dead_ranges), removed);
+                        prop_assert_eq!(dead_ranges.clone(), merge_adjacent(dead_ranges));
+                    }
+                }
+                assert_canonical(&map);
+
+                for channel in 0..CHANNELS {
+                    prop_assert_eq!(values(&map, channel), model.get(channel));
+                }
+                prop_assert_eq!(expand(&map.ranges()), model.covered());
+            }
+        }
+
+        #[test]
+        fn clones_are_unchanged(
+            ops in prop::collection::vec(op(), 0..20),
+            more_ops in prop::collection::vec(op(), 0..20),
+        ) {
+            let mut map: IntervalMap<u8> = IntervalMap::default();
+
+            let apply = |map: &mut IntervalMap<u8>, op: &Op| {
+                match op {
+                    Op::Insert(min, max, value) => map.insert(*min..=*max, *value),
+                    Op::Remove(min, max, value) => map.remove(*min..=*max, value),
+                };
+            };
+            ops.iter().for_each(|op| apply(&mut map, op));
+
+            let snapshot: IntervalMap<u8> = map.clone();
+            let before: Vec<BTreeSet<u8>> = (0..CHANNELS).map(|c| values(&map, c)).collect();
+
+            more_ops.iter().for_each(|op| apply(&mut map, op));
+
+            let after: Vec<BTreeSet<u8>> = (0..CHANNELS).map(|c| values(&snapshot, c)).collect();
+            prop_assert_eq!(before, after);
+        }
+    }
+}
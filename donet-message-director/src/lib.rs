@@ -17,29 +17,66 @@
     License along with Donet. If not, see <https://www.gnu.org/licenses/>.
 */
 
+mod acl;
+mod admin;
 mod channel_map;
+mod event_log;
+mod interest;
+mod interval_map;
+mod mesh;
+mod metrics;
+mod persist;
+mod router;
 mod subscriber;
+mod trace;
 mod upstream;
 
+use acl::{AccessControl, Denied, Role};
 use channel_map::*;
-use core::net::SocketAddr;
 use donet_core::datagram::datagram::*;
+use donet_core::datagram::iterator::DatagramIterator;
 use donet_core::globals::*;
+use donet_core::messages::validate::Validator;
+use donet_core::messages::{MDPeerForward, MDTraced, Message};
 use donet_core::Protocol;
 use donet_daemon::config;
+use donet_daemon::event::LoggedEvent;
 use donet_daemon::service::*;
+use donet_network::addr::PeerAddr;
+use donet_network::compress::CompressionConfig;
+use donet_network::frame::ReadBufferConfig;
+use donet_network::queue::{OverflowPolicy, SendQueueConfig};
 use donet_network::{tcp, udp};
-use donet_network::{Client, HasClient, RecvData, RecvSendHandles};
+use donet_network::{Client, ClientEvent, ConnectionHandle, Disconnect, RecvData};
+use interest::{Change, Interest, Scope, Sources, View};
 use log::{error, info, trace, warn};
+use mesh::{MdId, PeerInterest};
+use multimap::MultiMap;
+use persist::{Entry, Journal, Record, SessionId};
+use router::Router;
+use std::collections::hash_map::RandomState;
 use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
 use std::io::{Error, ErrorKind, Result};
 use std::sync::Arc;
+use std::time::Duration;
 use subscriber::*;
-use tokio::net::TcpStream;
 use tokio::sync::{mpsc, Mutex, MutexGuard};
 use tokio::task::JoinHandle;
+use trace::Trace;
 use upstream::*;
 
+/// Capacity of the event channel of each connection.
+const CONNECTION_EVENT_QUEUE_SIZE: usize = 100;
+
+/// Returns a random number, which differs between calls and processes.
+///
+/// Good enough for IDs, sampling, and jitter, but not for secrets.
+fn random() -> u64 {
+    // `RandomState` is randomly seeded, and its keys change on every call
+    RandomState::new().build_hasher().finish()
+}
+
 /// Represents an internal protocol header.
 ///
 /// Includes sender/recipient routing identifiers.
@@ -48,6 +85,26 @@ struct InternalHeader {
     recipients: Vec<Channel>,
 }
 
+impl InternalHeader {
+    /// Reads the header of a datagram that is not routed straight from
+    /// a connection, such as a post remove. Returns `None` if the
+    /// datagram is a control message, which has no sender.
+    fn read(dgi: &mut DatagramIterator) -> Result<Option<Self>> {
+        let recp_count: u8 = dgi.read_recipient_count()?;
+        let mut recipients: Vec<Channel> = vec![];
+
+        for _ in 0..recp_count {
+            recipients.push(dgi.read_channel()?);
+        }
+        if recipients == [CONTROL_CHANNEL] {
+            return Ok(None);
+        }
+        let sender: Channel = dgi.read_channel()?;
+
+        Ok(Some(Self { sender, recipients }))
+    }
+}
+
 impl std::fmt::Display for InternalHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Sender: {}, ", &self.sender.to_string())?;
@@ -63,15 +120,73 @@ impl std::fmt::Display for InternalHeader {
 pub struct CreateInfo {
     service_conf: config::MessageDirector,
     event_logger_url: Option<String>,
+    /// Identifies this MD within its mesh, if it has peers.
+    daemon_id: Option<u32>,
+}
+
+/// The remote end of a connection of the MD.
+enum Remote {
+    /// A service or downstream MD, which connected to us.
+    Subscriber(SubscriberRef),
+    /// A downstream MD that watches our interest.
+    Downstream(SubscriberRef),
+    /// Our upstream MD.
+    Upstream(Arc<Mutex<UpstreamMD>>),
+    /// A peer of our mesh.
+    Peer(Arc<Mutex<UpstreamMD>>),
+}
+
+impl Remote {
+    /// Returns our link to the remote, if we connected to it.
+    fn get_link(&self) -> Option<&Arc<Mutex<UpstreamMD>>> {
+        match self {
+            Self::Subscriber(_) | Self::Downstream(_) => None,
+            Self::Upstream(link) | Self::Peer(link) => Some(link),
+        }
+    }
+
+    /// Returns the subscriber, if the remote connected to us.
+    fn get_subscriber(&self) -> Option<&SubscriberRef> {
+        match self {
+            Self::Subscriber(sub) | Self::Downstream(sub) => Some(sub),
+            Self::Upstream(_) | Self::Peer(_) => None,
+        }
+    }
 }
 
 pub struct MessageDirector {
     binding: Arc<Mutex<tcp::Acceptor>>,
-    upstream_md: Option<UpstreamMD>,
-    event_logger: Option<udp::Socket>,
+    /// Listener of the admin endpoint, until it is served.
+    admin_binding: Option<tcp::Listener>,
+    /// Routes the datagrams received by every connection.
+    router: Arc<Router>,
+    event_logger: Option<Arc<udp::Socket>>,
+    /// Send queue budget of each subscriber's connection.
+    send_queue_config: SendQueueConfig,
+    /// Sizing of each subscriber's connection read buffer.
+    read_buffer_config: ReadBufferConfig,
+    /// Largest datagram accepted from each subscriber.
+    max_frame_size: DgSizeTag,
+    /// Compression accepted on each subscriber's connection.
+    compression: Option<CompressionConfig>,
+    /// Roles that our subscribers are given.
+    acl: AccessControl,
     channel_map: ChannelMap,
+    /// Subscriptions of the peer MDs of our mesh.
+    peer_interest: PeerInterest,
     subscribers: HashSet<SubscriberRef>,
-    removed_subscribers: HashSet<SubscriberRef>,
+    /// Downstream MDs that we tell the interest of the rest of the cluster.
+    watchers: HashSet<SubscriberRef>,
+    /// What our upstream MD wants from us, as of the last time it synced.
+    upstream_interest: Interest,
+    /// Interest that our upstream MD is sending us, until it is synced.
+    pending_interest: Option<Interest>,
+    /// Records the subscriptions and post removes of our subscribers, if persisted.
+    journal: Option<Journal>,
+    /// Subscribers from before we restarted, until they resume.
+    recovered: Vec<Record>,
+    /// How long the recovered subscribers have to resume.
+    grace_period: Duration,
 }
 
 impl DonetService for MessageDirector {
@@ -84,37 +199,184 @@ impl DonetService for MessageDirector {
     ) -> Result<Arc<Mutex<Self::Service>>> {
         let bind_addr: &str = conf.service_conf.bind.as_str();
         let upstream: Option<String> = conf.service_conf.upstream;
+        let peers: Vec<String> = conf.service_conf.peers.unwrap_or_default();
+        let upstream_tls: Option<config::TlsClient> = conf.service_conf.upstream_tls;
+        let upstream_compression: Option<CompressionConfig> = match &conf.service_conf.upstream_compression {
+            Some(compression) => Some(compression.load()?),
+            None => None,
+        };
+        let compression: Option<CompressionConfig> = match &conf.service_conf.compression {
+            Some(compression) => Some(compression.load()?),
+            None => None,
+        };
+        let mut reconnect_config = ReconnectConfig::default();
+        let max_frame_size: DgSizeTag = conf.service_conf.max_frame_size.unwrap_or(DG_SIZE_MAX);
         let logger_uri: Option<String> = conf.event_logger_url;
+        let md_id: MdId = conf.daemon_id.unwrap_or_else(mesh::random_id);
+        let acl = AccessControl::new(
+            conf.service_conf.roles.as_deref().unwrap_or_default(),
+            conf.service_conf.default_role.as_deref(),
+        )?;
+        let upstream_auth: Option<config::Credentials> = conf.service_conf.upstream_auth;
+        let pending_interest: Option<Interest> = upstream.is_some().then(Interest::default);
+        let mut grace_period: Duration = persist::DEFAULT_GRACE_PERIOD;
+
+        if upstream.is_some() && !peers.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "An MD cannot have both an upstream MD and peers.",
+            ));
+        }
+
+        // By default, a subscriber that falls too far behind is disconnected,
+        // so that it cannot stall routing to every other subscriber.
+        let mut send_queue_config = SendQueueConfig {
+            policy: OverflowPolicy::Disconnect,
+            ..Default::default()
+        };
+
+        if let Some(queue_conf) = conf.service_conf.send_queue {
+            if let Some(max_messages) = queue_conf.max_messages {
+                send_queue_config.max_messages = max_messages;
+            }
+            if let Some(max_bytes) = queue_conf.max_bytes {
+                send_queue_config.max_bytes = max_bytes;
+            }
+            if let Some(policy) = queue_conf.policy {
+                send_queue_config.policy = policy
+                    .parse()
+                    .map_err(|err: String| Error::new(ErrorKind::InvalidInput, err))?;
+            }
+        }
+
+        let mut read_buffer_config = ReadBufferConfig::default();
+
+        if let Some(buffer_conf) = conf.service_conf.read_buffer {
+            if let Some(min_read_size) = buffer_conf.min_read_size {
+                read_buffer_config.min_read_size = min_read_size;
+            }
+            if let Some(max_bytes) = buffer_conf.max_bytes {
+                read_buffer_config.max_bytes = max_bytes;
+            }
+        }
+
+        if let Some(reconnect_conf) = conf.service_conf.upstream_reconnect {
+            if let Some(min_delay) = reconnect_conf.min_delay_ms {
+                reconnect_config.min_delay = Duration::from_millis(min_delay);
+            }
+            if let Some(max_delay) = reconnect_conf.max_delay_ms {
+                reconnect_config.max_delay = Duration::from_millis(max_delay);
+            }
+            if let Some(max_buffered_bytes) = reconnect_conf.max_buffered_bytes {
+                reconnect_config.max_buffered_bytes = max_buffered_bytes;
+            }
+        }
+
+        let (journal, recovered): (Option<Journal>, Vec<Record>) = match conf.service_conf.persistence {
+            Some(persistence) => {
+                if let Some(grace_period_ms) = persistence.grace_period_ms {
+                    grace_period = Duration::from_millis(grace_period_ms);
+                }
+                let (journal, recovered) = Journal::open(&persistence.path)?;
+
+                if !recovered.is_empty() {
+                    info!(
+                        "Message Director recovered {} subscribers from {}.",
+                        recovered.len(),
+                        persistence.path
+                    );
+                }
+                (Some(journal), recovered)
+            }
+            None => (None, vec![]),
+        };
+
+        // we listen before linking to peers, which may be linking to us as well
+        let binding: tcp::Acceptor = match &conf.service_conf.tls {
+            Some(tls) => tcp::Acceptor::bind_tls(bind_addr, tls.load()?).await?,
+            None => tcp::Acceptor::bind(bind_addr).await?,
+        };
+        let admin_binding: Option<tcp::Listener> = match &conf.service_conf.admin_bind {
+            Some(admin_addr) => Some(tcp::Listener::bind(admin_addr).await?),
+            None => None,
+        };
+        let event_logger: Option<Arc<udp::Socket>> = match logger_uri {
+            Some(uri) => {
+                // requesting bind port '0' lets OS allocate a port for us
+                let mut new_sock = udp::Socket::bind("0.0.0.0:0").await?;
+
+                // have this new UDP socket send packets to
+                // the event logger's UDP socket bind address
+                new_sock.connect(&uri).await?;
+
+                Some(Arc::new(new_sock))
+            }
+            None => None,
+        };
+        let mut peer_links: Vec<UpstreamMD> = vec![];
+
+        if !peers.is_empty() {
+            info!(
+                "Message Director {} will link to {} peer MDs.",
+                md_id,
+                peers.len()
+            );
+        }
+        for peer_uri in &peers {
+            peer_links.push(
+                UpstreamMD::connect_peer(
+                    peer_uri,
+                    upstream_tls.as_ref(),
+                    upstream_compression,
+                    max_frame_size,
+                    reconnect_config,
+                    upstream_auth.as_ref(),
+                    md_id,
+                )
+                .await,
+            );
+        }
 
         Ok(Arc::new(Mutex::new(MessageDirector {
-            binding: Arc::new(Mutex::new(tcp::Acceptor::bind(bind_addr).await?)),
-            upstream_md: {
+            binding: Arc::new(Mutex::new(binding)),
+            admin_binding,
+            router: Arc::new(Router::new(
+                md_id,
                 match upstream {
                     Some(md_uri) => {
                         info!("Message Director will connect to upstream MD.");
-                        Some(UpstreamMD::connect(&md_uri).await?)
-                    }
-                    None => None,
-                }
-            },
-            event_logger: {
-                match logger_uri {
-                    Some(uri) => {
-                        // requesting bind port '0' lets OS allocate a port for us
-                        let mut new_sock = udp::Socket::bind("0.0.0.0:0").await?;
-
-                        // have this new UDP socket send packets to
-                        // the event logger's UDP socket bind address
-                        new_sock.connect(&uri).await?;
-
-                        Some(new_sock)
+                        Some(
+                            UpstreamMD::connect(
+                                &md_uri,
+                                upstream_tls.as_ref(),
+                                upstream_compression,
+                                max_frame_size,
+                                reconnect_config,
+                                upstream_auth.as_ref(),
+                            )
+                            .await?,
+                        )
                     }
                     None => None,
-                }
-            },
+                },
+                peer_links,
+                event_logger.clone(),
+            )),
+            event_logger,
+            send_queue_config,
+            read_buffer_config,
+            max_frame_size,
+            compression,
+            acl,
             channel_map: ChannelMap::default(),
+            peer_interest: PeerInterest::default(),
             subscribers: HashSet::default(),
-            removed_subscribers: HashSet::default(),
+            watchers: HashSet::default(),
+            upstream_interest: Interest::default(),
+            pending_interest,
+            journal,
+            recovered,
+            grace_period,
         })))
     }
 
@@ -123,68 +385,92 @@ impl DonetService for MessageDirector {
             // We can unwrap safely here since this function only is called if it is `Some`.
             service_conf: conf.services.message_director.expect("MD conf not found."),
             event_logger_url: conf.global.eventlogger,
+            daemon_id: conf.daemon.id,
         };
 
         let service = MessageDirector::create(service_conf, None).await?;
 
+        let router: Arc<Router> = service.lock().await.router.clone();
+        let mut registry = donet_daemon::metrics::registry();
+
+        router
+            .get_metrics()
+            .register(registry.sub_registry_with_prefix("md"), router.clone());
+        drop(registry);
+
         Ok(Self::spawn_async_task(async move {
             MessageDirector::main(service).await
         }))
     }
 
     async fn main(service: Arc<Mutex<Self::Service>>) -> Result<()> {
-        // create a new mpsc channel for receiving incoming packets
-        let (tx, mut rx) = mpsc::channel::<RecvData>(100);
-
-        let service_clone_for_recv = service.clone();
-
-        // spawn a tokio task for handling received datagrams from
-        // clients connected to this MD.
-        //
-        // each client spawns tasks for handling their TCP stream,
-        // so the way we communicate across tasks is via [`mpsc::channel`].
-        let handle: JoinHandle<Result<()>> = tokio::spawn(async move {
-            while let Some(recv_data) = rx.recv().await {
-                let mut locked_service = service_clone_for_recv.lock().await;
-
-                if let Err(e) = locked_service.handle_datagram(recv_data).await {
-                    warn!("Failed to handle received datagram: {}", e);
-                }
-            }
-            todo!("unhandled error. MD incoming datagram receiver returned None.")
-        });
+        let (router, binding, admin_binding, grace_period) = {
+            let mut locked_service = service.lock().await;
+            (
+                locked_service.router.clone(),
+                locked_service.binding.clone(),
+                locked_service.admin_binding.take(),
+                (!locked_service.recovered.is_empty()).then_some(locked_service.grace_period),
+            )
+        };
 
-        // if we have an uplink connection, spawn send/receive tokio tasks
-        if let Some(upstream) = &service.lock().await.upstream_md {
-            let client = upstream.get_client();
-            let mut client_lock = client.lock().await;
+        // subscribers from before we restarted have until then to resume
+        if let Some(grace_period) = grace_period {
+            let service: Arc<Mutex<Self>> = service.clone();
 
-            let handles = client_lock.spawn_recv_send_tasks(tx.clone()).await;
+            tokio::spawn(async move {
+                tokio::time::sleep(grace_period).await;
+                service.lock().await.expire_recovered().await;
+            });
         }
 
-        let binding: Arc<Mutex<tcp::Acceptor>> = service.lock().await.binding.clone();
+        if let Some(listener) = admin_binding {
+            info!("Message Director admin endpoint is listening.");
+            tokio::spawn(admin::serve(service.clone(), listener));
+        }
+
+        // spawn send/receive tokio tasks for our upstream or peer links
+        for link in router.get_links() {
+            let (tx, rx) = mpsc::channel::<ClientEvent>(CONNECTION_EVENT_QUEUE_SIZE);
+
+            // a peer that was down at startup is reconnected to by its task
+            let rx: Option<mpsc::Receiver<ClientEvent>> =
+                link.lock().await.spawn_recv_send_tasks(tx).await.map(|_| rx);
+            tokio::spawn(MessageDirector::link_loop(
+                service.clone(),
+                router.clone(),
+                link.clone(),
+                rx,
+            ));
+        }
         let binding_lock = binding.lock().await;
 
         // start the main loop (accepting new TCP connections)
         loop {
             // here, we keep the TCP binding locked. only this loop needs it
-            match binding_lock.socket.accept().await {
-                Ok((socket, address)) => {
+            match binding_lock.accept().await {
+                Ok(incoming) => {
+                    let address: PeerAddr = incoming.address;
                     info!("Received incoming connection from {}.", address);
 
-                    let mut service_lock = service.lock().await;
-
-                    // create a new [`Subscriber`] from the new TCP connection,
-                    // and pass a clone of `tx` for receiving its datagrams
-                    match service_lock.new_connection(socket, tx.clone()).await {
-                        Ok((recv_handle, send_handle)) => {
-                            trace!("Created new subscriber.");
-                            // TODO! handle task joins
-                        }
-                        Err(err) => {
-                            info!("Failed to accept subscriber {}: {}", address, err);
+                    let service = service.clone();
+
+                    // the TLS handshake, if any, must not hold up accepting others
+                    tokio::spawn(async move {
+                        let client: Client = match incoming.handshake().await {
+                            Ok(client) => client,
+                            Err(err) => return warn!("Failed handshake with {}: {}", address, err),
+                        };
+
+                        // create a new [`Subscriber`] from the new connection,
+                        // along with a task for handling its datagrams
+                        match MessageDirector::new_connection(&service, client).await {
+                            Ok(_) => trace!("Created new subscriber."),
+                            Err(err) => {
+                                info!("Failed to accept subscriber {}: {}", address, err);
+                            }
                         }
-                    }
+                    });
                 }
                 Err(socket_err) => error!("Failed to get client: {}", socket_err),
             }
@@ -198,28 +484,30 @@ impl HasChannelMap for MessageDirector {
     }
 }
 
+/// Our subscriptions are added over the upstream link, or over
+/// the link to each peer of our mesh.
 impl ChannelCoordinator for MessageDirector {
     async fn on_add_channel(&mut self, channel: Channel) {
-        if let Some(upstream) = &mut self.upstream_md {
-            upstream.stage_add_channel(channel).await;
+        for link in self.router.get_links() {
+            link.lock().await.stage_add_channel(channel).await;
         }
     }
 
-    async fn on_add_range(&mut self, range: std::ops::Range<Channel>) {
-        if let Some(upstream) = &mut self.upstream_md {
-            upstream.stage_add_range(range).await;
+    async fn on_add_range(&mut self, range: std::ops::RangeInclusive<Channel>) {
+        for link in self.router.get_links() {
+            link.lock().await.stage_add_range(range.clone()).await;
         }
     }
 
     async fn on_remove_channel(&mut self, channel: Channel) {
-        if let Some(upstream) = &mut self.upstream_md {
-            upstream.stage_add_channel(channel).await;
+        for link in self.router.get_links() {
+            link.lock().await.stage_remove_channel(channel).await;
         }
     }
 
-    async fn on_remove_range(&mut self, range: std::ops::Range<Channel>) {
-        if let Some(upstream) = &mut self.upstream_md {
-            upstream.stage_remove_range(range).await;
+    async fn on_remove_range(&mut self, range: std::ops::RangeInclusive<Channel>) {
+        for link in self.router.get_links() {
+            link.lock().await.stage_remove_range(range.clone()).await;
         }
     }
 }
@@ -227,11 +515,14 @@ impl ChannelCoordinator for MessageDirector {
 impl MessageDirector {
     /// Allocates a new [`Subscriber`] in our hash set.
     async fn add_subscriber(&mut self, client: Client) -> Result<SubscriberRef> {
+        let role: Arc<Role> = self.acl.initial_role(client.get_peer_certificate());
+
         // create a new [`Subscriber`] structure from the new client
         let sub: Subscriber = Subscriber::new(client).await;
 
         // move new subscriber struct to the heap and keep smart pointer
         let sub_ptr: SubscriberRef = sub.into();
+        sub_ptr.set_role(role);
 
         assert!(
             self.subscribers.insert(sub_ptr.clone()),
@@ -241,79 +532,315 @@ impl MessageDirector {
     }
 
     /// Removes a [`Subscriber`] from our hash set using its
-    /// remote IPv4/6 address ([`SocketAddr`]) as the key.
-    async fn remove_subscriber(&mut self, remote: SocketAddr) -> Result<()> {
-        match self.get_subscriber_with_remote(remote) {
-            Some(sub_ref) => {
-                // unsubscribe the subscriber from all its subscriptions
-                self.unsubscribe_all(sub_ref.clone()).await;
-
-                // stop tracking participant
-                assert!(
-                    self.subscribers.remove(&sub_ref),
-                    "Tried to remove subscriber that doesn't exist.",
-                );
+    /// remote address ([`PeerAddr`]) as the key.
+    async fn remove_subscriber(&mut self, remote: PeerAddr) -> Result<()> {
+        let Some(sub_ref) = self.get_subscriber_with_remote(remote) else {
+            warn!("Tried to remove subscriber that doesn't exist.");
+            return Ok(());
+        };
+        // the subscriber is gone either way, but if this is not written,
+        // its post removes are routed again after a restart
+        let entry = Entry::Remove {
+            remote: remote.to_string(),
+        };
+        if let Err(err) = self.record(entry).await {
+            warn!("Failed to record removal of {}: {}", remote, err);
+        }
+        if self.watchers.remove(&sub_ref) {
+            self.publish_downstream();
+        }
 
-                {
-                    let mut locked_sub: MutexGuard<'_, Subscriber> = sub_ref.lock().await;
-
-                    // Send out any post-remove messages the participant may have added.
-                    // This is done last, because we don't want to send messages
-                    // through the Director while a participant is being removed, as
-                    // certain data structures may not have their invariants satisfied
-                    // during that time.
-                    locked_sub.post_remove().await;
+        let (is_peer, scope) = {
+            let locked_sub: MutexGuard<'_, Subscriber> = sub_ref.lock().await;
+            (locked_sub.peer_id.is_some(), locked_sub.get_scope())
+        };
+        let views: Vec<(SubscriberRef, View)> = self.watched_views(&scope);
+
+        // unsubscribe the subscriber from all its subscriptions
+        if is_peer {
+            self.peer_interest.unsubscribe_all(sub_ref.clone()).await;
+        } else {
+            self.unsubscribe_all(sub_ref.clone()).await;
+        }
+        self.update_watchers(&scope, views).await;
+        self.publish_channel_map();
+
+        // stop tracking participant
+        assert!(
+            self.subscribers.remove(&sub_ref),
+            "Tried to remove subscriber that doesn't exist.",
+        );
+
+        let post_removes: MultiMap<Channel, Datagram> = {
+            let mut locked_sub: MutexGuard<'_, Subscriber> = sub_ref.lock().await;
+
+            locked_sub.receive_disconnect().await;
+            locked_sub.post_remove()
+        };
+
+        // Send out any post-remove messages the participant may have added.
+        // This is done last, because we don't want to send messages
+        // through the Director while a participant is being removed, as
+        // certain data structures may not have their invariants satisfied
+        // during that time.
+        for (sender, post_removes) in post_removes {
+            for post_remove in post_removes {
+                if let Err(err) = self.route_post_remove(post_remove).await {
+                    warn!("Failed to route post remove of {}: {}", remote, err);
                 }
+            }
+            // they were sent, so our upstream MD must not send them again
+            self.recall_post_removes(sender).await;
+        }
+        Ok(())
+    }
 
-                // mark the subscriber for deletion
-                self.removed_subscribers.insert(sub_ref);
-                Ok(())
+    /// Records a change to what our subscribers have on record, if
+    /// persisted, and waits for it to be written before it is applied.
+    async fn record(&self, entry: Entry) -> Result<()> {
+        match &self.journal {
+            Some(journal) => journal.record(entry).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Routes the post removes of the subscribers from before
+    /// we restarted, which did not resume in time.
+    async fn expire_recovered(&mut self) {
+        // if it is not written, the post removes are routed again after a restart
+        if let Err(err) = self.record(Entry::Expired).await {
+            warn!("Failed to record the end of the grace period: {}", err);
+        }
+        for record in std::mem::take(&mut self.recovered) {
+            let post_removes: Vec<(Channel, Datagram)> = record.get_post_removes();
+
+            // our upstream MD routed them already, when we went down
+            if self.router.get_upstream().is_some() {
+                info!("Subscriber {} did not resume in time.", record.remote);
+                continue;
             }
-            None => {
-                warn!("Tried to remove subscriber that doesn't exist.");
-                Ok(())
+            info!(
+                "Subscriber {} did not resume in time; Routing its {} post removes.",
+                record.remote,
+                post_removes.len()
+            );
+            for (_, post_remove) in post_removes {
+                if let Err(err) = self.route_post_remove(post_remove).await {
+                    warn!("Failed to route post remove of {}: {}", record.remote, err);
+                }
             }
         }
     }
 
-    /// Takes in a [`SocketAddr`], returns a [`SubscriberRef`] or `None`.
+    /// Takes in a [`PeerAddr`], returns a [`SubscriberRef`] or `None`.
     ///
     /// Retrieval can be done by creating a dummy [`SubscriberRef`]
-    /// with the given [`SocketAddr`] value and calling the hashset's
+    /// with the given [`PeerAddr`] value and calling the hashset's
     /// `get` function with the dummy [`SubscriberRef`].
-    fn get_subscriber_with_remote(&self, remote: SocketAddr) -> Option<SubscriberRef> {
+    fn get_subscriber_with_remote(&self, remote: PeerAddr) -> Option<SubscriberRef> {
         self.subscribers.get(&remote.into()).cloned()
     }
 
+    /// Applies a change to the subscriptions of a subscriber, which are
+    /// the interest of a peer MD if the subscriber is one, and tells the
+    /// downstream MDs that watch our interest about it.
+    async fn change_subscriptions(&mut self, sub: SubscriberRef, change: Change) {
+        let mut scope: Scope = change.get_scope();
+
+        let remote: PeerAddr = sub.get_remote();
+
+        let is_peer: bool = {
+            let locked_sub: MutexGuard<'_, Subscriber> = sub.lock().await;
+
+            // the subscriber's channels within a removed range are removed as well
+            if let Change::RemoveRange(min, max) = change {
+                let channels = locked_sub.subscribed_channels.iter();
+                scope
+                    .channels
+                    .extend(channels.filter(|channel| (min..=max).contains(*channel)));
+            }
+            locked_sub.peer_id.is_some()
+        };
+
+        let views: Vec<(SubscriberRef, View)> = self.watched_views(&scope);
+
+        if is_peer {
+            change.subscribe(&mut self.peer_interest, sub).await;
+        } else {
+            let entry = Entry::Subscribe {
+                remote: remote.to_string(),
+                change,
+            };
+            if let Err(err) = self.record(entry).await {
+                warn!("Failed to record subscription change of {}: {}", remote, err);
+                return;
+            }
+            change.subscribe(self, sub).await;
+        }
+        self.update_watchers(&scope, views).await;
+    }
+
+    /// Returns everything that our downstream MDs may want from us.
+    fn interest_sources(&self) -> Sources<'_> {
+        Sources {
+            subscribers: &self.channel_map,
+            peers: &self.peer_interest.channel_map,
+            upstream: &self.upstream_interest,
+        }
+    }
+
+    /// Returns the interest that each watching downstream
+    /// MD was told about, within the given scope.
+    fn watched_views(&self, scope: &Scope) -> Vec<(SubscriberRef, View)> {
+        let sources: Sources<'_> = self.interest_sources();
+
+        self.watchers
+            .iter()
+            .map(|watcher| (watcher.clone(), sources.view(scope, watcher)))
+            .collect()
+    }
+
+    /// Tells each watching downstream MD about the changes to its interest
+    /// within the given scope, since the views were taken.
+    async fn update_watchers(&self, scope: &Scope, views: Vec<(SubscriberRef, View)>) {
+        for (watcher, view) in views {
+            let updates: Vec<Datagram> = view.updates(&self.interest_sources().view(scope, &watcher));
+
+            for dg in updates {
+                if let Err(err) = watcher.stage_datagram(dg).await {
+                    warn!("Failed to update interest of {}: {}", watcher.get_remote(), err);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Publishes our subscriptions to be routed with, once changed.
+    fn publish_channel_map(&self) {
+        self.router
+            .publish(&self.channel_map, &self.peer_interest.channel_map);
+    }
+
+    /// Publishes the downstream MDs that watch our interest, which
+    /// traced datagrams are routed to in their envelope.
+    fn publish_downstream(&self) {
+        let downstream: HashSet<PeerAddr> = self.watchers.iter().map(SubscriberRef::get_remote).collect();
+
+        self.router.publish_downstream(downstream);
+    }
+
     /// Creates a new [`Subscriber`] structure in memory from the
     /// new connected client, and spawns TCP stream handler tasks.
-    async fn new_connection(
-        &mut self,
-        socket: TcpStream,
-        tx: mpsc::Sender<RecvData>,
-    ) -> Result<RecvSendHandles> {
-        let client: Client = Client::from(socket);
+    ///
+    /// Each connection also gets a task for handling its datagrams, so
+    /// that datagrams from different connections are routed in parallel.
+    async fn new_connection(service: &Arc<Mutex<Self>>, mut client: Client) -> Result<ConnectionHandle> {
+        let mut locked_service: MutexGuard<'_, Self> = service.lock().await;
+
+        client.set_send_queue_config(locked_service.send_queue_config);
+        client.set_read_buffer_config(locked_service.read_buffer_config);
+        client.set_max_frame_size(locked_service.max_frame_size);
 
-        let sub_ptr: SubscriberRef = self.add_subscriber(client).await?;
+        if let Some(compression) = locked_service.compression {
+            client.accept_compression(compression);
+        }
+        let (tx, rx) = mpsc::channel::<ClientEvent>(CONNECTION_EVENT_QUEUE_SIZE);
 
-        let sub = sub_ptr.get_ptr();
-        let sub_lock = sub.lock().await;
+        // start recv loop for subscriber client (connection). this comes
+        // first, as the subscriber is routed to through its send queue.
+        let handle: ConnectionHandle = client.spawn_recv_send_tasks(tx).await;
 
-        let client = sub_lock.get_client();
-        drop(sub_lock);
+        let sub: SubscriberRef = locked_service.add_subscriber(client).await?;
 
-        let mut client_lock = client.lock().await;
+        tokio::spawn(MessageDirector::connection_loop(
+            service.clone(),
+            locked_service.router.clone(),
+            rx,
+            Remote::Subscriber(sub),
+        ));
+        Ok(handle)
+    }
+
+    /// Handles the events of a connection, in the order they were received.
+    ///
+    /// The connection is either a subscriber (services or downstream MDs),
+    /// or a link to our upstream MD or to a peer MD. Only control messages
+    /// and disconnects lock the MD, and the subscription changes they make
+    /// are published before the next datagram of the connection is routed.
+    async fn connection_loop(
+        service: Arc<Mutex<Self>>,
+        router: Arc<Router>,
+        mut rx: mpsc::Receiver<ClientEvent>,
+        mut remote: Remote,
+    ) {
+        while let Some(event) = rx.recv().await {
+            match event {
+                ClientEvent::Received(recv_data) => {
+                    let result = Self::handle_datagram(&service, &router, recv_data, &mut remote).await;
+
+                    if let Err(e) = result {
+                        warn!("Failed to handle received datagram: {}", e);
+                    }
+                }
+                ClientEvent::Disconnected(disconnect) => match remote.get_link() {
+                    None => service.lock().await.handle_disconnect(disconnect).await,
+                    Some(link) => {
+                        {
+                            let mut locked_link: MutexGuard<'_, UpstreamMD> = link.lock().await;
+
+                            error!("Lost link to {}: {}", locked_link.describe(), disconnect.reason);
+                            locked_link.disconnected();
+                        }
+                        // everything is routed upstream until the new link has synced
+                        if let Remote::Upstream(_) = remote {
+                            router.set_upstream_synced(false);
+                        }
+                        // other connections are still routed while this
+                        // task reconnects, then handles the new link.
+                        rx = Self::reconnect_link(&service, link).await;
+                    }
+                },
+            }
+        }
+    }
 
-        // start recv loop for subscriber client (connection)
-        Ok(client_lock.spawn_recv_send_tasks(tx).await)
+    /// Handles the events of the link to our upstream MD or to a peer MD.
+    ///
+    /// If the link was down at startup, it is reconnected to first.
+    async fn link_loop(
+        service: Arc<Mutex<Self>>,
+        router: Arc<Router>,
+        link: Arc<Mutex<UpstreamMD>>,
+        rx: Option<mpsc::Receiver<ClientEvent>>,
+    ) {
+        let rx: mpsc::Receiver<ClientEvent> = match rx {
+            Some(rx) => rx,
+            None => Self::reconnect_link(&service, &link).await,
+        };
+        let remote: Remote = match link.lock().await.is_peer() {
+            true => Remote::Peer(link.clone()),
+            false => Remote::Upstream(link.clone()),
+        };
+        Self::connection_loop(service, router, rx, remote).await
     }
 
     /// Entry point for all datagrams received from a client via their TCP socket.
     ///
     /// These datagrams can come from a subscriber (services or downstream MDs)
-    /// or they can come from our upstream MD, if one is configured.
-    async fn handle_datagram(&mut self, mut data: RecvData) -> Result<()> {
+    /// or they can come from our upstream MD or a peer MD, if one is configured.
+    async fn handle_datagram(
+        service: &Arc<Mutex<Self>>,
+        router: &Router,
+        data: RecvData,
+        remote: &mut Remote,
+    ) -> Result<()> {
         trace!("Processing datagram of {} bytes...", data.dg.size());
+        router.get_metrics().received(data.dg.size());
+
+        let from_subscriber: bool = matches!(remote, Remote::Subscriber(_) | Remote::Downstream(_));
+
+        // datagrams that other MDs traced are handled like the datagram they carry
+        let (mut data, trace): (RecvData, Option<Trace>) = Self::untrace(data)?;
 
         let recp_count: u8 = data.dgi.read_recipient_count()?;
         trace!("Recipient count: {}", recp_count);
@@ -329,57 +856,310 @@ impl MessageDirector {
         #[allow(clippy::collapsible_if)]
         if recp_count == 1 {
             if *recipients.first().expect("Zero recipients.") == CONTROL_CHANNEL {
-                return self.handle_control_msg(data).await;
+                // datagrams forwarded by peers are routed without locking the MD
+                let index: usize = data.dgi.tell();
+                let msg_type: Result<Protocol> = data.dgi.read_msg_type().map_err(Error::from);
+                data.dgi.seek(index);
+
+                if let Ok(msg_type) = msg_type {
+                    router.get_metrics().message(msg_type.into());
+                }
+
+                if let Ok(Protocol::MDPeerForward) = msg_type {
+                    return Self::handle_peer_forward(router, data, trace, remote.get_subscriber()).await;
+                }
+                if trace.is_some() {
+                    warn!("Dropping traced control message from {}.", data.remote);
+                    return Ok(());
+                }
+                let mut locked_service: MutexGuard<'_, Self> = service.lock().await;
+
+                return match remote {
+                    Remote::Subscriber(sub) | Remote::Downstream(sub) => {
+                        let sub: SubscriberRef = sub.clone();
+                        let result: Result<()> = locked_service.handle_control_msg(data).await;
+
+                        // this connection's next datagram is routed with the changes
+                        locked_service.publish_channel_map();
+
+                        if locked_service.watchers.contains(&sub) {
+                            *remote = Remote::Downstream(sub);
+                        }
+                        result
+                    }
+                    Remote::Upstream(_) => locked_service.handle_upstream_control(data).await,
+                    Remote::Peer(_) => {
+                        warn!("Dropping control message from peer MD {}.", data.remote);
+                        Ok(())
+                    }
+                };
             }
         }
 
         // not a control msg, so there is a sender field ahead
         let sender: Channel = data.dgi.read_channel()?;
 
+        // the message type is only peeked, for our metrics and traces
+        let index: usize = data.dgi.tell();
+        let msg_type: MsgType = data.dgi.read_u16()?;
+        router.get_metrics().message(msg_type);
+        data.dgi.seek(index);
+
         // Store internal header info into struct
         let header = InternalHeader { sender, recipients };
         trace!("Datagram internal header: {}", &header);
 
+        // our subscribers may only send what their role allows
+        if let Some(sub) = remote.get_subscriber() {
+            if let Err(denied) = sub.get_role().may_send(header.sender, &header.recipients) {
+                Self::deny(router, sub, denied).await;
+                return Ok(());
+            }
+        }
+        let tracer = router.get_tracer();
+        let trace: Option<Trace> = trace.or_else(|| tracer.sample(header.sender, &header.recipients));
+
+        if let Some(trace) = &trace {
+            let from: String = data.remote.to_string();
+
+            tracer
+                .record_received(trace, (header.sender, &header.recipients), msg_type, &from)
+                .await;
+        }
+
         // route the regular internal message
-        self.route_datagram(header, data).await
+        let downstream: Option<PeerAddr> = match remote {
+            Remote::Downstream(sub) => Some(sub.get_remote()),
+            _ => None,
+        };
+        router
+            .route(
+                &header.recipients,
+                data.dg,
+                from_subscriber,
+                downstream,
+                trace.as_ref(),
+            )
+            .await;
+        Ok(())
     }
 
-    /// Handles a datagram that is a CONTROL message, a.k.a it had one recipient
-    /// and the recipient channel was the control channel (channel 1).
-    async fn handle_control_msg(&mut self, mut data: RecvData) -> Result<()> {
-        let msg_type: Protocol = data.dgi.read_msg_type()?;
+    /// Unwraps a datagram that another MD sent us in a `CONTROL_TRACED`
+    /// envelope, and returns it with its trace. Other datagrams are
+    /// returned as they are.
+    fn untrace(data: RecvData) -> Result<(RecvData, Option<Trace>)> {
+        let mut dgi: DatagramIterator = data.dgi.clone();
 
-        match msg_type {
-            Protocol::MDAddChannel => {
-                let channel: Channel = data.dgi.read_channel()?;
-                let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
+        if dgi.read_recipient_count()? != 1 || dgi.read_channel()? != CONTROL_CHANNEL {
+            return Ok((data, None));
+        }
+        if !matches!(dgi.read_msg_type(), Ok(Protocol::MDTraced)) {
+            return Ok((data, None));
+        }
+        let traced: MDTraced = match Validator::new().validate_internal(&data.dgi) {
+            Ok((_, Message::MDTraced(traced))) => traced,
+            Ok(_) => unreachable!("Validated a message of another type."),
+            Err(err) => {
+                let reason: String = format!("Malformed traced datagram from {}: {}", data.remote, err);
+                return Err(Error::new(ErrorKind::InvalidData, reason));
+            }
+        };
+        let (trace, dg): (Trace, Datagram) = Trace::unwrap(traced);
+        trace!(
+            "Unwrapped datagram of trace {:016x} from {}.",
+            trace.id,
+            data.remote
+        );
 
-                self.subscribe_channel(sub, channel).await;
-                Ok(())
+        let untraced = RecvData {
+            remote: data.remote,
+            dgi: dg.clone().into(),
+            dg,
+        };
+        Ok((untraced, Some(trace)))
+    }
+
+    /// Routes a datagram that a peer MD forwarded to us to our subscribers.
+    ///
+    /// Peers forward datagrams over our links to them, so a datagram
+    /// forwarded by one of our subscribers is held to its role.
+    async fn handle_peer_forward(
+        router: &Router,
+        data: RecvData,
+        trace: Option<Trace>,
+        sub: Option<&SubscriberRef>,
+    ) -> Result<()> {
+        let forward: MDPeerForward = match Validator::new().validate_internal(&data.dgi) {
+            Ok((_, Message::MDPeerForward(forward))) => forward,
+            Ok(_) => unreachable!("Validated a message of another type."),
+            Err(err) => {
+                warn!("Dropping malformed peer forward from {}: {}", data.remote, err);
+                return Ok(());
             }
-            Protocol::MDRemoveChannel => {
-                let channel: Channel = data.dgi.read_channel()?;
-                let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
+        };
+        if forward.origin == router.get_id() {
+            warn!(
+                "Dropping datagram that looped back from {}; Are two MDs using ID {}?",
+                data.remote, forward.origin
+            );
+            return Ok(());
+        }
+        let dg: Datagram = forward.datagram.0.into();
 
-                self.unsubscribe_channel(sub, channel).await;
-                Ok(())
+        let mut dgi: DatagramIterator = dg.clone().into();
+
+        let Some(header) = InternalHeader::read(&mut dgi)? else {
+            warn!("Dropping control message forwarded by {}.", data.remote);
+            return Ok(());
+        };
+        trace!("Routing datagram from peer MD {}: {}", forward.origin, &header);
+
+        if let Some(sub) = sub {
+            if let Err(denied) = sub.get_role().may_send(header.sender, &header.recipients) {
+                Self::deny(router, sub, denied).await;
+                return Ok(());
             }
-            Protocol::MDAddRange => {
-                let min: Channel = data.dgi.read_channel()?;
-                let max: Channel = data.dgi.read_channel()?;
+        }
+        if let Some(trace) = &trace {
+            let msg_type: MsgType = dgi.read_u16()?;
+            let from: String = data.remote.to_string();
+
+            router
+                .get_tracer()
+                .record_received(trace, (header.sender, &header.recipients), msg_type, &from)
+                .await;
+        }
+        // never forwarded again, nor routed upstream
+        router
+            .route(&header.recipients, dg, false, None, trace.as_ref())
+            .await;
+        Ok(())
+    }
 
-                let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
+    /// Logs that a subscriber was denied something by its role.
+    async fn deny(router: &Router, sub: &SubscriberRef, denied: Denied) {
+        let role: Arc<Role> = sub.get_role();
 
-                self.subscribe_range(sub, min, max).await;
-                Ok(())
+        warn!(
+            "Subscriber {} with role '{}' was denied: {}",
+            sub.get_remote(),
+            role.get_name(),
+            denied
+        );
+        let mut event = LoggedEvent::new("access-denied", &format!("MD {}", router.get_id()));
+
+        event.add("remote", &sub.get_remote().to_string());
+        event.add("role", role.get_name());
+        event.add("action", denied.action());
+        event.add("channels", &denied.channels());
+
+        if let Denied::Control(msg_type) = denied {
+            event.add("msg_type", &format!("{:?}", msg_type));
+        }
+        router.get_event_log().log(event).await;
+    }
+
+    /// Handles the disconnect of one of our subscribers.
+    async fn handle_disconnect(&mut self, disconnect: Disconnect) {
+        if self.get_subscriber_with_remote(disconnect.remote).is_none() {
+            return warn!("Unknown connection {} disconnected.", disconnect.remote);
+        }
+        info!(
+            "Subscriber {} disconnected: {}",
+            disconnect.remote, disconnect.reason
+        );
+        if let Err(err) = self.remove_subscriber(disconnect.remote).await {
+            warn!("Failed to remove subscriber {}: {}", disconnect.remote, err);
+        }
+    }
+
+    /// Reconnects to our upstream MD or to a peer MD, with
+    /// backoff, and replays our subscriptions over the new link.
+    ///
+    /// Returns the receiver for the events of the new connection.
+    async fn reconnect_link(
+        service: &Arc<Mutex<Self>>,
+        link: &Arc<Mutex<UpstreamMD>>,
+    ) -> mpsc::Receiver<ClientEvent> {
+        let dialer: Dialer = link.lock().await.dialer();
+        let client: Client = dialer.redial().await;
+
+        // subscription changes are held up while replaying, so
+        // that the replay does not miss any of them
+        let mut service_lock: MutexGuard<'_, Self> = service.lock().await;
+        let replay: Replay = service_lock.get_upstream_replay().await;
+
+        let (tx, rx) = mpsc::channel::<ClientEvent>(CONNECTION_EVENT_QUEUE_SIZE);
+
+        // routing over the link is held up until the replay is sent, so
+        // that nothing is sent over the new connection ahead of our subscriptions
+        let mut locked_link: MutexGuard<'_, UpstreamMD> = link.lock().await;
+
+        // our upstream MD sends us its interest again, from scratch
+        if !locked_link.is_peer() {
+            service_lock.pending_interest = Some(Interest::default());
+        }
+        locked_link.reconnected(client, tx, replay).await;
+        rx
+    }
+
+    /// Collects the subscriptions and post removes that
+    /// our upstream MD should have on record for us.
+    async fn get_upstream_replay(&mut self) -> Replay {
+        let mut replay = Replay {
+            channels: self.channel_map.get_channels(),
+            ranges: self.channel_map.get_ranges(),
+            ..Default::default()
+        };
+        for sub in &self.subscribers {
+            let locked_sub: MutexGuard<'_, Subscriber> = sub.lock().await;
+
+            for (sender, post_removes) in locked_sub.post_removes.iter_all() {
+                for post_remove in post_removes {
+                    replay.post_removes.push((*sender, post_remove.clone()));
+                }
+            }
+        }
+        replay
+    }
+
+    /// Handles a datagram that is a CONTROL message, a.k.a it had one recipient
+    /// and the recipient channel was the control channel (channel 1).
+    async fn handle_control_msg(&mut self, mut data: RecvData) -> Result<()> {
+        // Reject malformed control messages before they change any subscriptions.
+        if let Err(err) = Validator::new().validate_internal(&data.dgi) {
+            warn!("Dropping malformed control message from {}: {}", data.remote, err);
+            return Ok(());
+        }
+        let msg_type: Protocol = data.dgi.read_msg_type()?;
+
+        if let Some(sub) = self.get_subscriber_with_remote(data.remote) {
+            if let Err(denied) = sub.get_role().may_control(msg_type) {
+                Self::deny(&self.router, &sub, denied).await;
+                return Ok(());
             }
-            Protocol::MDRemoveRange => {
-                let min: Channel = data.dgi.read_channel()?;
-                let max: Channel = data.dgi.read_channel()?;
+        }
 
+        match msg_type {
+            Protocol::MDAddChannel
+            | Protocol::MDRemoveChannel
+            | Protocol::MDAddRange
+            | Protocol::MDRemoveRange => {
+                let change: Change =
+                    Change::read(msg_type, &mut data.dgi)?.expect("Not a subscription change.");
                 let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
 
-                self.unsubscribe_range(sub, min, max).await;
+                let allowed = match change {
+                    Change::AddChannel(channel) => sub.get_role().may_subscribe(channel, channel),
+                    Change::AddRange(min, max) => sub.get_role().may_subscribe(min, max),
+                    Change::RemoveChannel(_) | Change::RemoveRange(..) => Ok(()),
+                };
+                if let Err(denied) = allowed {
+                    Self::deny(&self.router, &sub, denied).await;
+                    return Ok(());
+                }
+
+                self.change_subscriptions(sub, change).await;
                 Ok(())
             }
             Protocol::MDAddPostRemove => {
@@ -394,8 +1174,29 @@ impl MessageDirector {
 
                 let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
 
+                // a post remove is sent on the subscriber's behalf, so it is held to its role
+                let allowed = match InternalHeader::read(&mut post_remove.clone().into())? {
+                    Some(header) => sub
+                        .get_role()
+                        .may_send(sender, &[])
+                        .and_then(|_| sub.get_role().may_send(header.sender, &header.recipients)),
+                    None => sub.get_role().may_send(sender, &[]),
+                };
+                if let Err(denied) = allowed {
+                    Self::deny(&self.router, &sub, denied).await;
+                    return Ok(());
+                }
                 trace!("Subscriber with remote {} added a post remove.", sub.get_remote());
 
+                let entry = Entry::AddPostRemove {
+                    remote: data.remote.to_string(),
+                    sender,
+                    datagram: post_remove.get_data(),
+                };
+                if let Err(err) = self.record(entry).await {
+                    warn!("Failed to record post remove of {}: {}", data.remote, err);
+                    return Ok(());
+                }
                 sub.lock().await.post_removes.insert(sender, post_remove.clone());
                 self.preroute_post_remove(sender, post_remove).await;
                 Ok(())
@@ -405,8 +1206,24 @@ impl MessageDirector {
 
                 let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
 
+                // clearing is passed upstream, where it clears every post remove of the sender
+                if let Err(denied) = sub.get_role().may_send(sender, &[]) {
+                    Self::deny(&self.router, &sub, denied).await;
+                    return Ok(());
+                }
                 trace!("Subscriber with remote {} added a post remove.", sub.get_remote());
 
+                let entry = Entry::ClearPostRemoves {
+                    remote: data.remote.to_string(),
+                    sender,
+                };
+                if let Err(err) = self.record(entry).await {
+                    warn!(
+                        "Failed to record cleared post removes of {}: {}",
+                        data.remote, err
+                    );
+                    return Ok(());
+                }
                 sub.lock().await.post_removes.remove(&sender);
                 self.recall_post_removes(sender).await;
                 Ok(())
@@ -428,6 +1245,128 @@ impl MessageDirector {
                 Ok(())
             }
             Protocol::MDLogMessage => self.route_log_message(data).await,
+            Protocol::MDWatchInterest => {
+                let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
+
+                info!("Subscriber {} is watching our interest.", data.remote);
+
+                // send it everything the rest of the cluster wants, from scratch
+                let sources: Sources<'_> = self.interest_sources();
+                let view: View = sources.view(&sources.everything(), &sub);
+
+                let mut updates: Vec<Datagram> = View::default().updates(&view);
+                updates.push(interest::control(Protocol::MDInterestSynced, &[]));
+
+                for dg in updates {
+                    if let Err(err) = sub.stage_datagram(dg).await {
+                        warn!("Failed to send interest to {}: {}", data.remote, err);
+                        return Ok(());
+                    }
+                }
+                self.watchers.insert(sub);
+                self.publish_downstream();
+                Ok(())
+            }
+            Protocol::MDAuthenticate => {
+                let role: String = data.dgi.read_string()?;
+                let secret: String = data.dgi.read_string()?;
+                let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
+
+                match self.acl.authenticate(&role, &secret) {
+                    Ok(role) => {
+                        info!(
+                            "Subscriber {} authenticated as role '{}'.",
+                            data.remote,
+                            role.get_name()
+                        );
+                        sub.set_role(role);
+                    }
+                    Err(denied) => Self::deny(&self.router, &sub, denied).await,
+                }
+                Ok(())
+            }
+            Protocol::MDResume => {
+                let session: SessionId = data.dgi.read_u64()?;
+                let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
+                let role: Arc<Role> = sub.get_role();
+                let certificate: Option<String> = sub.lock().await.certificate.clone();
+
+                let recorded: Option<&Record> = self
+                    .recovered
+                    .iter()
+                    .find(|record| record.session == Some(session));
+
+                // a session is bound to the role, and certificate, that named it
+                if let Some(record) = recorded {
+                    if let Err(denied) = record.may_resume(&role, certificate.as_deref()) {
+                        Self::deny(&self.router, &sub, denied).await;
+                        return Ok(());
+                    }
+                }
+                let is_recovered: bool = recorded.is_some();
+
+                let entry = Entry::Resume {
+                    remote: data.remote.to_string(),
+                    session,
+                    role: role.get_name().to_owned(),
+                    certificate,
+                };
+                if let Err(err) = self.record(entry).await {
+                    warn!("Failed to record session of {}: {}", data.remote, err);
+                    return Ok(());
+                }
+                if !is_recovered {
+                    return Ok(());
+                }
+                if let Err(err) = self.record(Entry::Resumed { session }).await {
+                    warn!("Failed to record resumed session of {}: {}", data.remote, err);
+                    return Ok(());
+                }
+                self.recovered.retain(|record| record.session != Some(session));
+
+                info!(
+                    "Subscriber {} resumed session {:016x}; Asking it to resync.",
+                    data.remote, session
+                );
+
+                // its post removes are no longer ours to route, until it adds them again
+                if let Err(err) = sub
+                    .stage_datagram(interest::control(Protocol::MDResync, &[]))
+                    .await
+                {
+                    warn!("Failed to ask {} to resync: {}", data.remote, err);
+                }
+                Ok(())
+            }
+            Protocol::MDAddTrace => {
+                let channel: Channel = data.dgi.read_channel()?;
+                let sampling: u32 = data.dgi.read_u32()?;
+
+                info!(
+                    "Tracing 1 in {} datagrams of channel {}.",
+                    sampling.max(1),
+                    channel
+                );
+                self.router.get_tracer().set_sampling(channel, Some(sampling));
+                Ok(())
+            }
+            Protocol::MDRemoveTrace => {
+                let channel: Channel = data.dgi.read_channel()?;
+
+                info!("No longer tracing channel {}.", channel);
+                self.router.get_tracer().set_sampling(channel, None);
+                Ok(())
+            }
+            Protocol::MDPeerHello => {
+                let md_id: MdId = data.dgi.read_u32()?;
+                let sub: SubscriberRef = self.get_subscriber_with_remote(data.remote).unwrap();
+
+                info!("Subscriber {} is peer MD {}.", data.remote, md_id);
+
+                // its subscriptions from now on are the interest of the peer
+                sub.lock().await.peer_id = Some(md_id);
+                Ok(())
+            }
             _ => {
                 warn!(
                     "Received control message with a non-control message type from {}",
@@ -439,61 +1378,96 @@ impl MessageDirector {
         }
     }
 
-    /// Handles replicating and routing a datagram to its proper recipients
-    /// based on this message director's channel subscriptions map.
-    async fn route_datagram(&mut self, header: InternalHeader, mut data: RecvData) -> Result<()> {
-        let mut receiving_subscribers: HashSet<SubscriberRef> = HashSet::default();
+    /// Handles a control message from our upstream MD, which
+    /// tells us what the rest of the cluster wants from us.
+    async fn handle_upstream_control(&mut self, mut data: RecvData) -> Result<()> {
+        if let Err(err) = Validator::new().validate_internal(&data.dgi) {
+            warn!("Dropping malformed control message from {}: {}", data.remote, err);
+            return Ok(());
+        }
+        let msg_type: Protocol = data.dgi.read_msg_type()?;
 
-        // get all subscribers of the recipient channels
-        self.lookup_channels(header.recipients, &mut receiving_subscribers);
+        if msg_type == Protocol::MDInterestSynced {
+            self.sync_upstream_interest().await;
+            return Ok(());
+        }
+        if msg_type == Protocol::MDResync {
+            // we replay our subscriptions on every connection anyway
+            info!(
+                "Upstream MD {} restarted, and recovered our session.",
+                data.remote
+            );
+            return Ok(());
+        }
+        let Some(change) = Change::read(msg_type, &mut data.dgi)? else {
+            warn!("Dropping control message from upstream MD {}.", data.remote);
+            return Ok(());
+        };
 
-        // replicate the message to all receiving subscribers
-        for sub in receiving_subscribers {
-            if let Err(err) = sub.lock().await.handle_datagram(&mut data.dg).await {
-                return Err(Error::new(ErrorKind::Other, err.to_string()));
-            }
+        // until synced, the interest is collected apart from the last one
+        if let Some(pending) = &mut self.pending_interest {
+            pending.apply(change);
+            return Ok(());
         }
+        let scope: Scope = change.get_scope();
+        let views: Vec<(SubscriberRef, View)> = self.watched_views(&scope);
 
-        // Next, decide if this message needs to be routed **upstream**.
-        //
-        // First, we need to check if the sender of this message *is*
-        // the upstream md. We do this by checking if it is a subscriber.
-        let our_subscriber: bool = self.get_subscriber_with_remote(data.remote).is_some();
+        self.upstream_interest.apply(change);
+        self.update_watchers(&scope, views).await;
+        self.router.publish_interest(&self.upstream_interest);
+        Ok(())
+    }
 
-        // If the sender of this message is one of our subscribers
-        // (downstream), **and** we have an uplink connection, route
-        // the message upstream.
-        if self.upstream_md.is_some() && our_subscriber {
-            trace!("Routing upstream.");
+    /// Replaces the interest of our upstream MD with
+    /// the one that it finished sending us.
+    async fn sync_upstream_interest(&mut self) {
+        let Some(interest) = self.pending_interest.take() else {
+            return warn!("Upstream MD synced its interest twice.");
+        };
+        let mut scope = Scope {
+            channels: self.upstream_interest.get_channels(),
+            ranges: self.upstream_interest.get_ranges(),
+        };
+        scope.channels.append(&mut interest.get_channels());
+        scope.ranges.append(&mut interest.get_ranges());
 
-            // safe to unwrap here due to `is_some()` check above.
-            let upstream_lock = self.upstream_md.as_ref().expect("Upstream MD ptr not found.");
+        let views: Vec<(SubscriberRef, View)> = self.watched_views(&scope);
 
-            upstream_lock.stage_datagram(data.dg.clone()).await;
-        } else if !our_subscriber {
-            // If the sender's remote address does not match a subscriber in our hashset,
-            // then this message is from upstream. Do not bounce it back!
-            trace!("Not routing upstream; It came from there.");
-        } else {
-            // Otherwise, this is the master message director.
-            trace!("Not routing upstream; We are the master MD.");
-        }
+        self.upstream_interest = interest;
+        self.update_watchers(&scope, views).await;
+
+        self.router.publish_interest(&self.upstream_interest);
+        self.router.set_upstream_synced(true);
+    }
+
+    /// Routes a post remove of a removed subscriber, as
+    /// if the subscriber had sent it before disconnecting.
+    async fn route_post_remove(&mut self, post_remove: Datagram) -> Result<()> {
+        let Some(header) = InternalHeader::read(&mut post_remove.clone().into())? else {
+            warn!("Dropping post remove that is a control message.");
+            return Ok(());
+        };
+        trace!("Routing post remove: {}", &header);
+
+        self.router
+            .route(&header.recipients, post_remove, true, None, None)
+            .await;
         Ok(())
     }
 
     /// Sends the post remove for the given sender by sending it
     /// upstream, if there is an upstream connection.
     async fn preroute_post_remove(&mut self, sender: Channel, post_remove: Datagram) {
-        if let Some(upstream) = &mut self.upstream_md {
-            upstream.stage_post_remove(sender, post_remove).await;
+        if let Some(upstream) = self.router.get_upstream() {
+            upstream.lock().await.stage_post_remove(sender, post_remove).await;
         }
     }
 
     /// Clears all post removes for the given sender by sending it
     /// upstream, if there is an upstream connection.
     async fn recall_post_removes(&mut self, sender: Channel) {
-        if let Some(upstream) = &mut self.upstream_md {
-            upstream.recall_post_removes(sender).await;
+        if let Some(upstream) = self.router.get_upstream() {
+            upstream.lock().await.recall_post_removes(sender).await;
         }
     }
 
@@ -502,8 +1476,7 @@ impl MessageDirector {
     async fn route_log_message(&mut self, mut data: RecvData) -> Result<()> {
         match &self.event_logger {
             Some(logger) => {
-                let msgpack_blob_len: usize = data.dgi.get_remaining();
-                let msgpack_payload = data.dgi.read_data(msgpack_blob_len)?;
+                let msgpack_payload = data.dgi.read_remaining();
 
                 let _: usize = logger.socket.send(&msgpack_payload).await?;
                 Ok(())
@@ -511,9 +1484,9 @@ impl MessageDirector {
             None => {
                 // We don't have a connection to the event logger, so
                 // route the log control message upstream.
-                match &mut self.upstream_md {
+                match self.router.get_upstream() {
                     Some(upstream) => {
-                        upstream.stage_datagram(data.dg.clone()).await;
+                        upstream.lock().await.stage_datagram(data.dg.clone()).await;
                         Ok(())
                     }
                     // We don't have an upstream message director,
@@ -527,3 +1500,794 @@ impl MessageDirector {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn md_config(bind: &str, upstream: Option<&str>) -> config::MessageDirector {
+        config::MessageDirector {
+            bind: bind.to_owned(),
+            upstream: upstream.map(str::to_owned),
+            tls: None,
+            upstream_tls: None,
+            send_queue: None,
+            read_buffer: None,
+            max_frame_size: None,
+            upstream_reconnect: None,
+            compression: None,
+            upstream_compression: None,
+            peers: None,
+            admin_bind: None,
+            roles: None,
+            default_role: None,
+            upstream_auth: None,
+            persistence: None,
+        }
+    }
+
+    async fn start(service_conf: config::MessageDirector) {
+        let conf = CreateInfo {
+            service_conf,
+            event_logger_url: None,
+            daemon_id: None,
+        };
+        let service = MessageDirector::create(conf, None).await.unwrap();
+
+        tokio::spawn(MessageDirector::main(service));
+    }
+
+    async fn connect(uri: &str) -> (Client, mpsc::Receiver<ClientEvent>) {
+        let mut client: Client = tcp::Connection::connect(uri).await.unwrap().into();
+        let (tx, rx) = mpsc::channel::<ClientEvent>(8);
+
+        let _handle: ConnectionHandle = client.spawn_recv_send_tasks(tx).await;
+        (client, rx)
+    }
+
+    /// Subscribes a service on the child MD to a channel, and routes a
+    /// datagram with the given payload to it from a service on the master MD.
+    async fn assert_routed(master: &str, child: &str, payload: &[u8]) {
+        let (mut service_a, mut service_a_rx) = connect(child).await;
+        let (mut service_b, _service_b_rx) = connect(master).await;
+
+        let mut add_channel: Datagram = Datagram::default();
+        add_channel
+            .add_control_header(Protocol::MDAddChannel.into())
+            .unwrap();
+        add_channel.add_channel(5000).unwrap();
+        service_a.stage_datagram(add_channel).await.unwrap();
+
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![5000], 1337, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+        dg.add_data(payload).unwrap();
+
+        // resend until the subscription has made its way to the master MD
+        let received: Datagram = 'received: {
+            for _ in 0..100 {
+                service_b.stage_datagram(dg.clone()).await.unwrap();
+
+                match tokio::time::timeout(Duration::from_millis(20), service_a_rx.recv()).await {
+                    Ok(Some(ClientEvent::Received(data))) => break 'received data.dg,
+                    Ok(_) => panic!("Service disconnected from the child MD."),
+                    Err(_) => continue,
+                }
+            }
+            panic!("Datagram was not routed through the cluster.");
+        };
+        assert_eq!(received.get_buffer(), dg.get_buffer());
+    }
+
+    #[tokio::test]
+    async fn in_memory_cluster() {
+        start(md_config("memory:cluster-master", None)).await;
+        start(md_config("memory:cluster-child", Some("memory:cluster-master"))).await;
+
+        assert_routed("memory:cluster-master", "memory:cluster-child", &[]).await;
+    }
+
+    #[tokio::test]
+    async fn compressed_upstream() {
+        let compression = config::Compression {
+            codec: Some("lz4".to_owned()),
+            threshold: Some(0),
+        };
+        let mut master = md_config("memory:compressed-master", None);
+        master.compression = Some(compression.clone());
+
+        let mut child = md_config("memory:compressed-child", Some("memory:compressed-master"));
+        child.upstream_compression = Some(compression);
+
+        start(master).await;
+        start(child).await;
+
+        assert_routed(
+            "memory:compressed-master",
+            "memory:compressed-child",
+            &[0xAA; 2000],
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn post_remove_on_disconnect() {
+        start(md_config("memory:post-remove", None)).await;
+
+        let (mut service_a, mut service_a_rx) = connect("memory:post-remove").await;
+        let (mut service_b, _service_b_rx) = connect("memory:post-remove").await;
+
+        let mut add_channel: Datagram = Datagram::default();
+        add_channel
+            .add_control_header(Protocol::MDAddChannel.into())
+            .unwrap();
+        add_channel.add_channel(6000).unwrap();
+        service_a.stage_datagram(add_channel).await.unwrap();
+
+        // service A must be subscribed before service B disconnects
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut post_remove: Datagram = Datagram::default();
+        post_remove
+            .add_internal_header(vec![6000], 1337, Protocol::SSDeleteAIObjects.into())
+            .unwrap();
+
+        let mut add_post_remove: Datagram = Datagram::default();
+        add_post_remove
+            .add_control_header(Protocol::MDAddPostRemove.into())
+            .unwrap();
+        add_post_remove.add_channel(1337).unwrap();
+        add_post_remove.add_blob(post_remove.get_data()).unwrap();
+        service_b.stage_datagram(add_post_remove).await.unwrap();
+
+        // the post remove is sent once service B is gone
+        service_b.close();
+
+        match tokio::time::timeout(Duration::from_secs(1), service_a_rx.recv()).await {
+            Ok(Some(ClientEvent::Received(data))) => {
+                assert_eq!(data.dg.get_buffer(), post_remove.get_buffer())
+            }
+            Ok(_) => panic!("Service disconnected from the MD."),
+            Err(_) => panic!("Post remove was not routed."),
+        }
+    }
+
+    fn peer_config(bind: &str, peers: &[&str]) -> config::MessageDirector {
+        let mut conf = md_config(bind, None);
+
+        conf.peers = Some(peers.iter().map(|peer| peer.to_string()).collect());
+        conf.upstream_reconnect = Some(config::UpstreamReconnect {
+            min_delay_ms: Some(10),
+            max_delay_ms: Some(50),
+            max_buffered_bytes: None,
+        });
+        conf
+    }
+
+    async fn subscribe(service: &mut Client, channel: Channel) {
+        let mut add_channel: Datagram = Datagram::default();
+        add_channel
+            .add_control_header(Protocol::MDAddChannel.into())
+            .unwrap();
+        add_channel.add_channel(channel).unwrap();
+        service.stage_datagram(add_channel).await.unwrap();
+    }
+
+    /// Returns the datagrams received within the given time.
+    async fn drain(rx: &mut mpsc::Receiver<ClientEvent>, wait: Duration) -> Vec<Datagram> {
+        let mut received: Vec<Datagram> = vec![];
+
+        while let Ok(Some(event)) = tokio::time::timeout(wait, rx.recv()).await {
+            match event {
+                ClientEvent::Received(data) => received.push(data.dg),
+                ClientEvent::Disconnected(_) => panic!("Service disconnected from the MD."),
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn mesh_routing() {
+        let mds: [&str; 3] = ["memory:mesh-a", "memory:mesh-b", "memory:mesh-c"];
+
+        for md in mds {
+            let peers: Vec<&str> = mds.into_iter().filter(|peer| *peer != md).collect();
+            start(peer_config(md, &peers)).await;
+        }
+        let (mut sender, _sender_rx) = connect(mds[0]).await;
+        let (mut local, mut local_rx) = connect(mds[0]).await;
+        let (mut remote, mut remote_rx) = connect(mds[1]).await;
+        let (_bystander, mut bystander_rx) = connect(mds[2]).await;
+
+        subscribe(&mut local, 5000).await;
+        subscribe(&mut remote, 5000).await;
+
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![5000], 1337, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+
+        // resend until the peers have linked up, and exchanged their interest
+        let linked: bool = 'linked: {
+            for _ in 0..100 {
+                sender.stage_datagram(dg.clone()).await.unwrap();
+
+                if !drain(&mut remote_rx, Duration::from_millis(20)).await.is_empty() {
+                    break 'linked true;
+                }
+            }
+            false
+        };
+        assert!(linked, "Datagram was not forwarded across the mesh.");
+
+        drain(&mut local_rx, Duration::from_millis(50)).await;
+        drain(&mut remote_rx, Duration::from_millis(50)).await;
+
+        dg.add_data(vec![0xAA; 16]).unwrap();
+        sender.stage_datagram(dg.clone()).await.unwrap();
+
+        // each subscriber gets exactly one copy, and other MDs get none
+        for rx in [&mut local_rx, &mut remote_rx] {
+            let received: Vec<Datagram> = drain(rx, Duration::from_millis(100)).await;
+
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].get_buffer(), dg.get_buffer());
+        }
+        assert!(drain(&mut bystander_rx, Duration::from_millis(50))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn upstream_interest() {
+        start(md_config("memory:interest-master", None)).await;
+        start(md_config("memory:interest-a", Some("memory:interest-master"))).await;
+        start(md_config("memory:interest-b", Some("memory:interest-master"))).await;
+
+        let (mut sender, _sender_rx) = connect("memory:interest-a").await;
+        let (mut local, mut local_rx) = connect("memory:interest-a").await;
+        let (mut remote, mut remote_rx) = connect("memory:interest-b").await;
+
+        subscribe(&mut local, 5000).await;
+        subscribe(&mut remote, 5000).await;
+
+        // the channel has a subscriber on MD A, so it is only routed
+        // upstream once the master MD has told MD A that MD B wants it
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![5000], 1337, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+        sender.stage_datagram(dg.clone()).await.unwrap();
+
+        for rx in [&mut local_rx, &mut remote_rx] {
+            let received: Vec<Datagram> = drain(rx, Duration::from_millis(100)).await;
+
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].get_buffer(), dg.get_buffer());
+        }
+    }
+
+    /// Starts an MD with the given ID, which sends its events to the given event logger.
+    async fn start_logged(service_conf: config::MessageDirector, md_id: MdId, event_logger: &str) {
+        let conf = CreateInfo {
+            service_conf,
+            event_logger_url: Some(event_logger.to_owned()),
+            daemon_id: Some(md_id),
+        };
+        let service = MessageDirector::create(conf, None).await.unwrap();
+
+        tokio::spawn(MessageDirector::main(service));
+    }
+
+    /// Reads the fields of a logged event, whose keys and values are short strings.
+    fn event_fields(event: &[u8]) -> HashMap<String, String> {
+        let mut fields: HashMap<String, String> = HashMap::new();
+        let mut strings = vec![];
+        let mut index: usize = 1;
+
+        while index < event.len() {
+            let size: usize = usize::from(event[index] - 0xa0);
+            let string: &[u8] = &event[index + 1..index + 1 + size];
+
+            strings.push(String::from_utf8(string.to_vec()).unwrap());
+            index += 1 + size;
+        }
+        for pair in strings.chunks(2) {
+            fields.insert(pair[0].clone(), pair[1].clone());
+        }
+        fields
+    }
+
+    /// Resends a datagram until the receiving service gets it, and returns what it got.
+    async fn route_until_received(
+        sender: &mut Client,
+        receiver_rx: &mut mpsc::Receiver<ClientEvent>,
+        dg: &Datagram,
+    ) -> Datagram {
+        for _ in 0..100 {
+            sender.stage_datagram(dg.clone()).await.unwrap();
+
+            if let Some(received) = drain(receiver_rx, Duration::from_millis(20)).await.pop() {
+                return received;
+            }
+        }
+        panic!("Datagram was not routed through the cluster.");
+    }
+
+    #[tokio::test]
+    async fn traced_datagrams() {
+        let event_logger = udp::Socket::bind("127.0.0.1:0").await.unwrap();
+        let logger_addr: String = event_logger.socket.local_addr().unwrap().to_string();
+
+        start_logged(md_config("memory:trace-master", None), 1, &logger_addr).await;
+        start_logged(
+            md_config("memory:trace-child", Some("memory:trace-master")),
+            2,
+            &logger_addr,
+        )
+        .await;
+
+        let (mut master_service, mut master_rx) = connect("memory:trace-master").await;
+        let (mut child_service, mut child_rx) = connect("memory:trace-child").await;
+
+        subscribe(&mut master_service, 6000).await;
+        subscribe(&mut child_service, 5000).await;
+
+        // each service traces what it sends, down to the child MD and up to the master MD
+        for (service, channel) in [(&mut master_service, 5000), (&mut child_service, 6000)] {
+            let mut add_trace: Datagram = Datagram::default();
+            add_trace.add_control_header(Protocol::MDAddTrace.into()).unwrap();
+            add_trace.add_channel(channel).unwrap();
+            add_trace.add_u32(1).unwrap();
+            service.stage_datagram(add_trace).await.unwrap();
+        }
+        for (sender, receiver_rx, channel) in [
+            (&mut master_service, &mut child_rx, 5000),
+            (&mut child_service, &mut master_rx, 6000),
+        ] {
+            let mut dg: Datagram = Datagram::default();
+            dg.add_internal_header(vec![channel], 1337, Protocol::SSObjectSetOwner.into())
+                .unwrap();
+
+            // services are sent the datagram out of its envelope
+            let received: Datagram = route_until_received(sender, receiver_rx, &dg).await;
+            assert_eq!(received.get_buffer(), dg.get_buffer());
+        }
+
+        let mut spans: Vec<HashMap<String, String>> = vec![];
+        let mut buf: [u8; 1024] = [0; 1024];
+
+        while let Ok(Ok(size)) =
+            tokio::time::timeout(Duration::from_millis(100), event_logger.socket.recv(&mut buf)).await
+        {
+            spans.push(event_fields(&buf[..size]));
+        }
+        // spans of different MDs may arrive in any order
+        let stages_of = |trace_id: &str| -> HashSet<(String, String, String)> {
+            spans
+                .iter()
+                .filter(|span| span["trace_id"] == trace_id)
+                .map(|span| (span["md"].clone(), span["stage"].clone(), span["hops"].clone()))
+                .collect()
+        };
+        let as_stages = |stages: &[(&str, &str, &str)]| -> HashSet<(String, String, String)> {
+            stages
+                .iter()
+                .map(|(md, stage, hops)| (md.to_string(), stage.to_string(), hops.to_string()))
+                .collect()
+        };
+
+        // the spans of the datagrams that made it through, each of which was traced
+        let delivered_on = |md: &str| -> String {
+            let span = spans
+                .iter()
+                .find(|span| span["stage"] == "delivered" && span["md"] == md)
+                .expect("Traced datagram was not delivered.");
+            span["trace_id"].clone()
+        };
+        assert_eq!(
+            stages_of(&delivered_on("2")),
+            as_stages(&[
+                ("1", "received", ""),
+                ("1", "downstream", ""),
+                ("2", "received", "1"),
+                ("2", "delivered", "1"),
+            ])
+        );
+        assert_eq!(
+            stages_of(&delivered_on("1")),
+            as_stages(&[
+                ("2", "received", ""),
+                ("2", "upstream", ""),
+                ("1", "received", "2"),
+                ("1", "delivered", "2"),
+            ])
+        );
+    }
+
+    fn role(name: &str, secret: Option<&str>, rules: [&[&str]; 3], control: bool) -> config::Role {
+        let rules = rules.map(|rules| Some(rules.iter().map(|rule| rule.to_string()).collect()));
+        let [subscribe, send_as, send_to] = rules;
+
+        config::Role {
+            name: name.to_owned(),
+            secret: secret.map(str::to_owned),
+            certificates: None,
+            subscribe,
+            send_as,
+            send_to,
+            control: Some(control),
+        }
+    }
+
+    #[tokio::test]
+    async fn access_control() {
+        let event_logger = udp::Socket::bind("127.0.0.1:0").await.unwrap();
+        let logger_addr: String = event_logger.socket.local_addr().unwrap().to_string();
+
+        let mut master = md_config("memory:acl-master", None);
+        master.roles = Some(vec![
+            role(
+                "service",
+                None,
+                [&["5000-5999"], &["1337"], &["5000-5999"]],
+                false,
+            ),
+            role("md", Some("md-secret"), [&["*"], &["*"], &["*"]], true),
+        ]);
+        master.default_role = Some("service".to_owned());
+
+        let mut child = md_config("memory:acl-child", Some("memory:acl-master"));
+        child.upstream_auth = Some(config::Credentials {
+            role: "md".to_owned(),
+            secret: "md-secret".to_owned(),
+        });
+        start_logged(master, 1, &logger_addr).await;
+        start(child).await;
+
+        let (mut service, mut service_rx) = connect("memory:acl-master").await;
+        let (mut downstream, mut downstream_rx) = connect("memory:acl-child").await;
+
+        subscribe(&mut service, 5000).await;
+        subscribe(&mut service, 6000).await;
+        subscribe(&mut downstream, 6000).await;
+
+        let mut authenticate: Datagram = Datagram::default();
+        authenticate
+            .add_control_header(Protocol::MDAuthenticate.into())
+            .unwrap();
+        authenticate.add_string("md").unwrap();
+        authenticate.add_string("hunter2").unwrap();
+        service.stage_datagram(authenticate).await.unwrap();
+
+        // only MDs and operators may watch our interest, or trace
+        let mut add_trace: Datagram = Datagram::default();
+        add_trace.add_control_header(Protocol::MDAddTrace.into()).unwrap();
+        add_trace.add_channel(5000).unwrap();
+        add_trace.add_u32(1).unwrap();
+        service.stage_datagram(add_trace).await.unwrap();
+        service
+            .stage_datagram(interest::control(Protocol::MDWatchInterest, &[]))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let datagram = |sender: Channel, recipients: Vec<Channel>| {
+            let mut dg: Datagram = Datagram::default();
+            dg.add_internal_header(recipients, sender, Protocol::SSObjectSetOwner.into())
+                .unwrap();
+            dg
+        };
+        // the service may only send as its own channel, to its own channels
+        let allowed: Datagram = datagram(1337, vec![5000]);
+
+        for dg in [
+            datagram(1337, vec![6000]),
+            datagram(4242, vec![5000]),
+            allowed.clone(),
+        ] {
+            service.stage_datagram(dg).await.unwrap();
+        }
+        let received: Vec<Datagram> = drain(&mut service_rx, Duration::from_millis(100)).await;
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].get_buffer(), allowed.get_buffer());
+        assert!(drain(&mut downstream_rx, Duration::from_millis(50))
+            .await
+            .is_empty());
+
+        // the child MD authenticated, so its subscribers are not held to the service role
+        let from_downstream: Datagram = datagram(9999, vec![6000, 5000]);
+        downstream.stage_datagram(from_downstream.clone()).await.unwrap();
+
+        // the service is not subscribed to channel 6000, so it gets one copy
+        let received: Vec<Datagram> = drain(&mut service_rx, Duration::from_millis(100)).await;
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].get_buffer(), from_downstream.get_buffer());
+
+        let mut denied: HashSet<(String, String, String, String)> = HashSet::new();
+        let mut buf: [u8; 1024] = [0; 1024];
+
+        while let Ok(Ok(size)) =
+            tokio::time::timeout(Duration::from_millis(100), event_logger.socket.recv(&mut buf)).await
+        {
+            let event: HashMap<String, String> = event_fields(&buf[..size]);
+
+            assert_eq!(event["type"], "access-denied");
+            assert_eq!(event["role"], "service");
+            denied.insert((
+                event["action"].clone(),
+                event["channels"].clone(),
+                event.get("msg_type").cloned().unwrap_or_default(),
+                event["remote"].clone(),
+            ));
+        }
+        let remote: String = service.get_local().to_string();
+
+        assert_eq!(
+            denied,
+            [
+                ("subscribe", "6000", ""),
+                ("send_to", "6000", ""),
+                ("send_as", "4242", ""),
+                ("authenticate", "", ""),
+                ("control", "", "MDAddTrace"),
+                ("control", "", "MDWatchInterest"),
+            ]
+            .into_iter()
+            .map(|(action, channels, msg_type)| {
+                (
+                    action.to_owned(),
+                    channels.to_owned(),
+                    msg_type.to_owned(),
+                    remote.clone(),
+                )
+            })
+            .collect()
+        );
+    }
+
+    /// Sends a command to the admin endpoint, and returns its answer.
+    async fn admin_command(
+        admin: &mut tokio::io::BufStream<tokio::io::DuplexStream>,
+        command: &str,
+    ) -> serde_json::Value {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        admin
+            .write_all(format!("{}\n", command).as_bytes())
+            .await
+            .unwrap();
+        admin.flush().await.unwrap();
+
+        let mut line: String = String::new();
+        admin.read_line(&mut line).await.unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn admin_endpoint() {
+        let mut conf = md_config("memory:admin-md", None);
+        conf.admin_bind = Some("memory:admin-md-ctl".to_owned());
+        start(conf).await;
+
+        let (mut service, mut service_rx) = connect("memory:admin-md").await;
+
+        let mut set_name: Datagram = Datagram::default();
+        set_name
+            .add_control_header(Protocol::MDSetConName.into())
+            .unwrap();
+        set_name.add_string("AI Server").unwrap();
+        service.stage_datagram(set_name).await.unwrap();
+        subscribe(&mut service, 1234).await;
+
+        let mut admin = match tcp::Socket::connect("memory:admin-md-ctl").await.unwrap() {
+            tcp::Socket::Memory(stream, _) => tokio::io::BufStream::new(stream),
+            _ => unreachable!(),
+        };
+
+        // wait for the MD to handle the subscription
+        let mut subscribers: serde_json::Value = serde_json::Value::Null;
+
+        for _ in 0..100 {
+            subscribers = admin_command(&mut admin, "subscribers").await;
+
+            if subscribers["subscribers"][0]["channels"][0] == 1234 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let subscriber: &serde_json::Value = &subscribers["subscribers"][0];
+
+        assert_eq!(subscriber["name"], "AI Server");
+        assert_eq!(subscriber["channels"], serde_json::json!([1234]));
+        assert_eq!(subscriber["post_removes"], 0);
+
+        let remote: &str = subscriber["remote"].as_str().unwrap();
+        let channels: serde_json::Value = admin_command(&mut admin, "channels").await;
+
+        assert_eq!(
+            channels["channels"]["local"]["channels"],
+            serde_json::json!([{ "channel": 1234, "subscribers": [remote] }])
+        );
+        assert!(admin_command(&mut admin, "disconnect nobody").await["error"].is_string());
+        assert!(admin_command(&mut admin, "shutdown").await["error"].is_string());
+
+        let disconnected: serde_json::Value =
+            admin_command(&mut admin, &format!("disconnect {}", remote)).await;
+        assert_eq!(disconnected["disconnected"], remote);
+
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), service_rx.recv()).await {
+                Ok(Some(ClientEvent::Disconnected(_))) => break,
+                Ok(Some(ClientEvent::Received(_))) => continue,
+                _ => panic!("Subscriber was not disconnected."),
+            }
+        }
+        for _ in 0..100 {
+            if admin_command(&mut admin, "subscribers").await["subscribers"] == serde_json::json!([]) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Disconnected subscriber was not removed.");
+    }
+
+    fn persisted_config(bind: &str, path: &str, grace_period_ms: u64) -> config::MessageDirector {
+        let mut conf = md_config(bind, None);
+
+        conf.persistence = Some(config::Persistence {
+            path: path.to_owned(),
+            grace_period_ms: Some(grace_period_ms),
+        });
+        conf
+    }
+
+    /// Adds a post remove that sends an empty message to the given channel.
+    async fn add_post_remove(service: &mut Client, sender: Channel, channel: Channel) -> Datagram {
+        let mut post_remove: Datagram = Datagram::default();
+        post_remove
+            .add_internal_header(vec![channel], sender, Protocol::SSDeleteAIObjects.into())
+            .unwrap();
+
+        let mut add_post_remove: Datagram = Datagram::default();
+        add_post_remove
+            .add_control_header(Protocol::MDAddPostRemove.into())
+            .unwrap();
+        add_post_remove.add_channel(sender).unwrap();
+        add_post_remove.add_blob(post_remove.get_data()).unwrap();
+        service.stage_datagram(add_post_remove).await.unwrap();
+        post_remove
+    }
+
+    #[tokio::test]
+    async fn resumed_sessions() {
+        let dir: std::path::PathBuf = std::env::temp_dir();
+        let before: String = format!("{}/donet-md-before-{}", dir.display(), std::process::id());
+        let after: String = format!("{}/donet-md-after-{}", dir.display(), std::process::id());
+
+        for path in [&before, &after] {
+            for file in [path.clone(), format!("{}.journal", path)] {
+                let _ = std::fs::remove_file(file);
+            }
+        }
+        start(persisted_config("memory:resume-before", &before, 60_000)).await;
+
+        let (mut resumed, _resumed_rx) = connect("memory:resume-before").await;
+        let (mut gone, _gone_rx) = connect("memory:resume-before").await;
+
+        resumed.stage_datagram(persist::resume(7).unwrap()).await.unwrap();
+        subscribe(&mut resumed, 5000).await;
+        add_post_remove(&mut resumed, 1337, 6000).await;
+        let expired: Datagram = add_post_remove(&mut gone, 4242, 6000).await;
+
+        // as if the MD went down once its journal was written
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for (from, to) in [
+            (before.clone(), after.clone()),
+            (format!("{}.journal", before), format!("{}.journal", after)),
+        ] {
+            std::fs::copy(from, to).unwrap();
+        }
+        start(persisted_config("memory:resume-after", &after, 500)).await;
+
+        let (mut service, mut service_rx) = connect("memory:resume-after").await;
+        let (mut resumed, mut resumed_rx) = connect("memory:resume-after").await;
+
+        subscribe(&mut service, 6000).await;
+        resumed.stage_datagram(persist::resume(7).unwrap()).await.unwrap();
+
+        let received: Vec<Datagram> = drain(&mut resumed_rx, Duration::from_millis(100)).await;
+        let resync: Datagram = interest::control(Protocol::MDResync, &[]);
+
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].get_buffer(), resync.get_buffer());
+
+        // only the post remove of the subscriber that did not resume is routed
+        let received: Vec<Datagram> = drain(&mut service_rx, Duration::from_millis(1000)).await;
+
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].get_buffer(), expired.get_buffer());
+    }
+
+    #[tokio::test]
+    async fn unprivileged_resume() {
+        let dir: std::path::PathBuf = std::env::temp_dir();
+        let before: String = format!(
+            "{}/donet-md-unprivileged-before-{}",
+            dir.display(),
+            std::process::id()
+        );
+        let after: String = format!(
+            "{}/donet-md-unprivileged-after-{}",
+            dir.display(),
+            std::process::id()
+        );
+
+        for path in [&before, &after] {
+            for file in [path.clone(), format!("{}.journal", path)] {
+                let _ = std::fs::remove_file(file);
+            }
+        }
+        let roles = vec![
+            role("service", None, [&["*"], &["*"], &["*"]], false),
+            role("md", Some("md-secret"), [&["*"], &["*"], &["*"]], true),
+        ];
+        let mut conf = persisted_config("memory:unprivileged-before", &before, 60_000);
+        conf.roles = Some(roles.clone());
+        conf.default_role = Some("service".to_owned());
+        start(conf).await;
+
+        let (mut privileged, _privileged_rx) = connect("memory:unprivileged-before").await;
+
+        let mut authenticate: Datagram = Datagram::default();
+        authenticate
+            .add_control_header(Protocol::MDAuthenticate.into())
+            .unwrap();
+        authenticate.add_string("md").unwrap();
+        authenticate.add_string("md-secret").unwrap();
+        privileged.stage_datagram(authenticate).await.unwrap();
+
+        privileged
+            .stage_datagram(persist::resume(7).unwrap())
+            .await
+            .unwrap();
+        let expired: Datagram = add_post_remove(&mut privileged, 1337, 6000).await;
+
+        // as if the MD went down once its journal was written
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for (from, to) in [
+            (before.clone(), after.clone()),
+            (format!("{}.journal", before), format!("{}.journal", after)),
+        ] {
+            std::fs::copy(from, to).unwrap();
+        }
+        let mut conf = persisted_config("memory:unprivileged-after", &after, 500);
+        conf.roles = Some(roles);
+        conf.default_role = Some("service".to_owned());
+        start(conf).await;
+
+        let (mut service, mut service_rx) = connect("memory:unprivileged-after").await;
+        let (mut intruder, mut intruder_rx) = connect("memory:unprivileged-after").await;
+
+        subscribe(&mut service, 6000).await;
+        intruder
+            .stage_datagram(persist::resume(7).unwrap())
+            .await
+            .unwrap();
+
+        // the session was named by an MD, so the service may not resume it
+        assert!(drain(&mut intruder_rx, Duration::from_millis(100))
+            .await
+            .is_empty());
+
+        let received: Vec<Datagram> = drain(&mut service_rx, Duration::from_millis(1000)).await;
+
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].get_buffer(), expired.get_buffer());
+    }
+}
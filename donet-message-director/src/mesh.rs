@@ -0,0 +1,132 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Mesh mode, in which Message Directors peer with each other
+//! instead of routing everything through a master MD.
+//!
+//! Each MD opens a link to every peer listed in its configuration,
+//! introduces itself with a `CONTROL_PEER_HELLO`, then adds the
+//! channels and ranges of its own subscribers over the link, as a
+//! downstream MD would. The peer keeps these subscriptions apart from
+//! those of its subscribers, in a [`PeerInterest`], so that it never
+//! passes them on as its own interest.
+//!
+//! A datagram from one of our subscribers is forwarded to each peer
+//! that added one of its recipient channels, in a `CONTROL_PEER_FORWARD`
+//! envelope tagged with our ID. Forwarded datagrams are only routed
+//! to the subscribers of the receiving MD, so a datagram crosses the
+//! mesh at most once, and cannot loop between peers.
+
+use crate::channel_map::*;
+use donet_core::datagram::datagram::{Datagram, DatagramError};
+use donet_core::globals::Channel;
+use donet_core::messages::{MDPeerForward, MDPeerHello, Payload, ProtocolMessage};
+use std::ops::RangeInclusive;
+
+/// Identifies a Message Director within its mesh.
+pub type MdId = u32;
+
+/// Picks an ID for an MD whose daemon was not configured with one.
+pub fn random_id() -> MdId {
+    crate::random() as MdId
+}
+
+/// Returns the `CONTROL_PEER_HELLO` that an MD introduces itself with.
+pub fn peer_hello(md_id: MdId) -> Datagram {
+    let mut dg: Datagram = Datagram::default();
+
+    dg.add_control_header(MDPeerHello::MSG_TYPE.into()).unwrap();
+    MDPeerHello { md_id }.encode(&mut dg).unwrap();
+    dg
+}
+
+/// Wraps a datagram in a `CONTROL_PEER_FORWARD` envelope from the given MD.
+///
+/// Fails if the envelope does not fit in a datagram.
+pub fn peer_forward(origin: MdId, dg: &mut Datagram) -> Result<Datagram, DatagramError> {
+    let mut envelope: Datagram = Datagram::default();
+
+    envelope.add_control_header(MDPeerForward::MSG_TYPE.into())?;
+
+    let forward = MDPeerForward {
+        origin,
+        datagram: Payload(dg.get_bytes()),
+    };
+    forward.encode(&mut envelope)?;
+    Ok(envelope)
+}
+
+/// Subscriptions that the peer MDs linked to us added,
+/// which are only used to forward datagrams to them.
+#[derive(Default)]
+pub struct PeerInterest {
+    pub channel_map: ChannelMap,
+}
+
+impl HasChannelMap for PeerInterest {
+    fn get_channel_map(&mut self) -> &mut ChannelMap {
+        &mut self.channel_map
+    }
+}
+
+/// The interest of a peer is never passed on, so the callbacks do nothing.
+impl ChannelCoordinator for PeerInterest {
+    async fn on_add_channel(&mut self, _channel: Channel) {}
+
+    async fn on_remove_channel(&mut self, _channel: Channel) {}
+
+    async fn on_add_range(&mut self, _range: RangeInclusive<Channel>) {}
+
+    async fn on_remove_range(&mut self, _range: RangeInclusive<Channel>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use donet_core::datagram::iterator::DatagramIterator;
+    use donet_core::messages::validate::Validator;
+    use donet_core::messages::Message;
+    use donet_core::Protocol;
+
+    #[test]
+    fn forward_envelope() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![4000], 1337, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+
+        let envelope: Datagram = peer_forward(7, &mut dg).unwrap();
+        let dgi: DatagramIterator = envelope.into();
+
+        match Validator::new().validate_internal(&dgi).unwrap().1 {
+            Message::MDPeerForward(forward) => {
+                assert_eq!(forward.origin, 7);
+                assert_eq!(forward.datagram.0, dg.get_buffer());
+            }
+            message => panic!("Unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn oversized_forward() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_data(vec![0; usize::from(u16::MAX)]).unwrap();
+
+        assert!(peer_forward(7, &mut dg).is_err());
+    }
+}
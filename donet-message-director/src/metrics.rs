@@ -0,0 +1,316 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Traffic metrics of a Message Director, which are registered
+//! on the metrics registry of the daemon; see [`donet_daemon::metrics`].
+//!
+//! Counters and histograms are updated by the [`Router`] as datagrams
+//! are routed, without locking. The depths of the send queues are
+//! read when the metrics are scraped, by [`QueueDepths`].
+
+use crate::router::Router;
+use donet_core::globals::MsgType;
+use donet_core::Protocol;
+use donet_network::queue::QueueStats;
+use prometheus_client::collector::Collector;
+use prometheus_client::encoding::{
+    DescriptorEncoder, EncodeLabelSet, EncodeLabelValue, EncodeMetric, LabelValueEncoder,
+};
+use prometheus_client::metrics::counter::{ConstCounter, Counter};
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::ConstGauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::metrics::MetricType;
+use prometheus_client::registry::{Registry, Unit};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct MessageLabels {
+    msg_type: MsgTypeLabel,
+}
+
+/// A message type, which is labeled with the name of its
+/// [`Protocol`] variant when scraped.
+///
+/// Unknown message types share the `unknown` label, so that a
+/// peer cannot grow the family with arbitrary message types.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+struct MsgTypeLabel(Option<MsgType>);
+
+impl From<MsgType> for MsgTypeLabel {
+    fn from(value: MsgType) -> Self {
+        Self(Protocol::try_from(value).ok().map(|_| value))
+    }
+}
+
+impl EncodeLabelValue for MsgTypeLabel {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        match self.0.map(Protocol::try_from) {
+            Some(Ok(msg_type)) => write!(encoder, "{:?}", msg_type),
+            _ => write!(encoder, "unknown"),
+        }
+    }
+}
+
+/// A metric of the send queues, read from the [`QueueStats`] of each queue.
+struct QueueMetric {
+    name: String,
+    help: String,
+    metric_type: MetricType,
+    value: fn(&QueueStats) -> u64,
+}
+
+/// Traffic metrics of a Message Director.
+#[derive(Debug)]
+pub struct Metrics {
+    received_datagrams: Counter,
+    received_bytes: Counter,
+    routed_datagrams: Counter,
+    routed_bytes: Counter,
+    dropped_datagrams: Counter,
+    messages: Family<MessageLabels, Counter>,
+    fan_out: Histogram,
+    routing_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            received_datagrams: Counter::default(),
+            received_bytes: Counter::default(),
+            routed_datagrams: Counter::default(),
+            routed_bytes: Counter::default(),
+            dropped_datagrams: Counter::default(),
+            messages: Family::default(),
+            // 1 to 2048 connections
+            fan_out: Histogram::new(exponential_buckets(1.0, 2.0, 12)),
+            // 1 microsecond to about 4 seconds
+            routing_latency: Histogram::new(exponential_buckets(1e-6, 4.0, 12)),
+        }
+    }
+}
+
+impl Metrics {
+    /// Registers these metrics on the given registry, along with
+    /// a [`QueueDepths`] collector for the given router.
+    pub fn register(&self, registry: &mut Registry, router: Arc<Router>) {
+        registry.register(
+            "received_datagrams",
+            "Datagrams received on every connection",
+            self.received_datagrams.clone(),
+        );
+        registry.register(
+            "received_bytes",
+            "Bytes of the datagrams received on every connection",
+            self.received_bytes.clone(),
+        );
+        registry.register(
+            "routed_datagrams",
+            "Datagrams sent to subscribers, the upstream MD, and peer MDs",
+            self.routed_datagrams.clone(),
+        );
+        registry.register(
+            "routed_bytes",
+            "Bytes of the datagrams sent to subscribers, the upstream MD, and peer MDs",
+            self.routed_bytes.clone(),
+        );
+        registry.register(
+            "dropped_datagrams",
+            "Datagrams that could not be sent to a subscriber or peer MD",
+            self.dropped_datagrams.clone(),
+        );
+        registry.register(
+            "messages",
+            "Datagrams received, by message type",
+            self.messages.clone(),
+        );
+        registry.register(
+            "fan_out",
+            "Number of connections that each routed datagram was sent to",
+            self.fan_out.clone(),
+        );
+        registry.register_with_unit(
+            "routing_latency",
+            "Time taken to send a datagram to every connection it is routed to",
+            Unit::Seconds,
+            self.routing_latency.clone(),
+        );
+        registry.register_collector(Box::new(QueueDepths { router }));
+    }
+
+    /// Counts a datagram received on any connection.
+    pub fn received(&self, size: usize) {
+        self.received_datagrams.inc();
+        self.received_bytes.inc_by(size as u64);
+    }
+
+    /// Counts a received message of the given type.
+    pub fn message(&self, msg_type: MsgType) {
+        let labels = MessageLabels {
+            msg_type: msg_type.into(),
+        };
+        self.messages.get_or_create(&labels).inc();
+    }
+
+    /// Counts a datagram sent to one of the connections it was routed to.
+    pub fn routed(&self, size: usize) {
+        self.routed_datagrams.inc();
+        self.routed_bytes.inc_by(size as u64);
+    }
+
+    /// Counts a datagram that could not be sent to a connection.
+    pub fn dropped(&self) {
+        self.dropped_datagrams.inc();
+    }
+
+    /// Records how many connections a datagram was routed
+    /// to, and how long it took to send it to all of them.
+    pub fn route_done(&self, fan_out: usize, latency: Duration) {
+        self.fan_out.observe(fan_out as f64);
+        self.routing_latency.observe(latency.as_secs_f64());
+    }
+}
+
+/// Reports the depth of the send queue of each subscriber, and of each
+/// link to the upstream MD or to a peer MD, when the metrics are scraped.
+///
+/// The subscribers are those of the subscriptions published to the
+/// [`Router`], as no datagrams are routed to the others. A link that
+/// is locked while the metrics are scraped is left out of the scrape.
+struct QueueDepths {
+    router: Arc<Router>,
+}
+
+impl std::fmt::Debug for QueueDepths {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueDepths").finish_non_exhaustive()
+    }
+}
+
+impl Collector for QueueDepths {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> std::fmt::Result {
+        let subscribers: Vec<(String, QueueStats)> = self
+            .router
+            .get_subscribers()
+            .into_iter()
+            .filter_map(|sub| Some((sub.get_remote().to_string(), sub.get_queue_stats()?)))
+            .collect();
+
+        let links: Vec<(String, QueueStats)> = self
+            .router
+            .get_links()
+            .filter_map(|link| {
+                let locked_link = link.try_lock().ok()?;
+                Some((
+                    locked_link.get_address().to_owned(),
+                    locked_link.get_queue_stats()?,
+                ))
+            })
+            .collect();
+
+        encode_queues(&mut encoder, "subscriber", &subscribers)?;
+        encode_queues(&mut encoder, "upstream", &links)
+    }
+}
+
+/// Encodes the depth of the given send queues, labeled by remote address.
+fn encode_queues(
+    encoder: &mut DescriptorEncoder,
+    kind: &str,
+    queues: &[(String, QueueStats)],
+) -> std::fmt::Result {
+    let metrics: [QueueMetric; 3] = [
+        QueueMetric {
+            name: format!("{}_queued_datagrams", kind),
+            help: format!("Datagrams waiting in the send queue of each {}", kind),
+            metric_type: MetricType::Gauge,
+            value: |stats| stats.messages as u64,
+        },
+        QueueMetric {
+            name: format!("{}_queued_bytes", kind),
+            help: format!("Bytes waiting in the send queue of each {}", kind),
+            metric_type: MetricType::Gauge,
+            value: |stats| stats.bytes as u64,
+        },
+        QueueMetric {
+            name: format!("{}_dropped_datagrams", kind),
+            help: format!("Datagrams dropped from the send queue of each {}", kind),
+            metric_type: MetricType::Counter,
+            value: |stats| stats.dropped,
+        },
+    ];
+    for metric in &metrics {
+        let mut metric_encoder =
+            encoder.encode_descriptor(&metric.name, &metric.help, None, metric.metric_type)?;
+
+        for (remote, stats) in queues {
+            let labels: [(&str, &str); 1] = [("remote", remote)];
+            let family_encoder = metric_encoder.encode_family(&labels)?;
+
+            let value: u64 = (metric.value)(stats);
+
+            match metric.metric_type {
+                MetricType::Counter => ConstCounter::new(value).encode(family_encoder)?,
+                _ => ConstGauge::new(value as i64).encode(family_encoder)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus_client::encoding::text;
+
+    fn encode(registry: &Registry) -> String {
+        let mut encoded: String = String::new();
+        text::encode(&mut encoded, registry).unwrap();
+        encoded
+    }
+
+    #[test]
+    fn encoded_metrics() {
+        let router: Arc<Router> = Arc::new(Router::new(1, None, vec![], None));
+        let metrics: &Metrics = router.get_metrics();
+        let mut registry: Registry = Registry::with_prefix("donet");
+
+        metrics.register(registry.sub_registry_with_prefix("md"), router.clone());
+
+        metrics.received(100);
+        metrics.message(Protocol::MDAddChannel.into());
+        metrics.message(Protocol::MDAddChannel.into());
+        metrics.message(65000);
+        metrics.message(65001);
+        metrics.routed(100);
+        metrics.route_done(1, Duration::from_micros(3));
+
+        let encoded: String = encode(&registry);
+
+        assert!(encoded.contains("donet_md_received_datagrams_total 1\n"));
+        assert!(encoded.contains("donet_md_received_bytes_total 100\n"));
+        assert!(encoded.contains("donet_md_messages_total{msg_type=\"MDAddChannel\"} 2\n"));
+        assert!(encoded.contains("donet_md_messages_total{msg_type=\"unknown\"} 2\n"));
+        assert!(encoded.contains("donet_md_fan_out_count 1\n"));
+        assert!(encoded.contains("donet_md_routing_latency_seconds_count 1\n"));
+        assert!(encoded.contains("# TYPE donet_md_subscriber_queued_bytes gauge\n"));
+    }
+}
@@ -0,0 +1,579 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! State of a Message Director that outlives it.
+//!
+//! If persistence is configured, every change to the subscriptions and
+//! post removes of our subscribers is appended to a [`Journal`], as a
+//! line of JSON in `<path>.journal`. It is a write-ahead log: a change
+//! is synced to disk before the MD applies it, and is not applied if it
+//! could not be written. The journal is compacted into a snapshot at
+//! `<path>` whenever the MD starts, and once it grows long.
+//!
+//! A restarted MD recovers what its subscribers had on record, none of
+//! which are still connected. A subscriber names its session with a
+//! `CONTROL_RESUME` on every connection; once it resumes a recovered
+//! session, the MD tells it with a `CONTROL_RESYNC` to replay its
+//! subscriptions and post removes. A session is only resumed by a
+//! subscriber with the role and TLS client certificate that named it.
+//! The post removes of the subscribers that do not resume within the
+//! grace period are routed, as if they had disconnected.
+
+use crate::acl::{Denied, Role};
+use crate::interest::Change;
+use donet_core::datagram::datagram::*;
+use donet_core::globals::Channel;
+use donet_core::messages::{MDResume, ProtocolMessage};
+use gcollections::ops::*;
+use interval::interval_set::ToIntervalSet;
+use interval::IntervalSet;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Identifies a subscriber across its connections, and across our restarts.
+pub type SessionId = u64;
+
+/// How long a restarted MD waits for its subscribers to resume, by default.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// The journal is compacted into a snapshot after this many entries.
+const COMPACT_AFTER: usize = 4096;
+
+/// Returns a new session ID from the random number generator of the
+/// OS, as whoever knows a session ID may resume its session.
+pub fn random_session() -> SessionId {
+    getrandom::u64().expect("Failed to get random bytes from the OS.")
+}
+
+/// Returns the `CONTROL_RESUME` that resumes the given session.
+pub fn resume(session: SessionId) -> std::result::Result<Datagram, DatagramError> {
+    let mut dg: Datagram = Datagram::default();
+
+    dg.add_control_header(MDResume::MSG_TYPE.into())?;
+
+    MDResume { session }.encode(&mut dg)?;
+    Ok(dg)
+}
+
+/// What a subscriber has on record with us.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Remote address of the subscriber's connection, for logging.
+    pub remote: String,
+    pub session: Option<SessionId>,
+    /// Role of the subscriber when it named its session.
+    pub role: Option<String>,
+    /// Fingerprint of the TLS client certificate of the subscriber, if any.
+    pub certificate: Option<String>,
+    pub channels: BTreeSet<Channel>,
+    pub ranges: Vec<(Channel, Channel)>,
+    /// Post removes, with their sender channels, in the order they were added.
+    pub post_removes: Vec<(Channel, Vec<u8>)>,
+}
+
+impl Record {
+    fn new(remote: String) -> Self {
+        Self {
+            remote,
+            ..Default::default()
+        }
+    }
+
+    /// Applies a change to the subscriptions, as the channel map does.
+    fn subscribe(&mut self, change: Change) {
+        let ranges: IntervalSet<Channel> = self.ranges.clone().to_interval_set();
+
+        let ranges: IntervalSet<Channel> = match change {
+            Change::AddChannel(channel) => {
+                self.channels.insert(channel);
+                return;
+            }
+            Change::RemoveChannel(channel) => {
+                self.channels.remove(&channel);
+                return;
+            }
+            Change::AddRange(min, max) if min <= max => ranges.union(&vec![(min, max)].to_interval_set()),
+            Change::RemoveRange(min, max) if min <= max => {
+                // the channels within a removed range are removed as well
+                self.channels.retain(|channel| !(min..=max).contains(channel));
+                ranges.difference(&vec![(min, max)].to_interval_set())
+            }
+            Change::AddRange(..) | Change::RemoveRange(..) => return,
+        };
+        self.ranges = ranges
+            .iter()
+            .map(|range| (range.lower(), range.upper()))
+            .collect();
+    }
+
+    /// Returns the post removes, with their sender channels.
+    pub fn get_post_removes(&self) -> Vec<(Channel, Datagram)> {
+        let post_removes = self.post_removes.iter().map(|(sender, post_remove)| {
+            let mut dg: Datagram = Datagram::default();
+
+            dg.add_data(post_remove)
+                .expect("Post remove is larger than a datagram.");
+            (*sender, dg)
+        });
+        post_removes.collect()
+    }
+
+    /// Returns `Err` if a subscriber of the given role, and with the
+    /// given certificate fingerprint, may not resume this session.
+    pub fn may_resume(&self, role: &Role, certificate: Option<&str>) -> std::result::Result<(), Denied> {
+        role.may_resume(self.role.as_deref())?;
+
+        match self.certificate.as_deref() == certificate {
+            true => Ok(()),
+            false => Err(Denied::Resume),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.channels.is_empty() && self.ranges.is_empty() && self.post_removes.is_empty()
+    }
+}
+
+/// A change to what our subscribers have on record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Entry {
+    /// A subscriber of the given role and certificate named its session.
+    Resume {
+        remote: String,
+        session: SessionId,
+        role: String,
+        certificate: Option<String>,
+    },
+    Subscribe {
+        remote: String,
+        change: Change,
+    },
+    AddPostRemove {
+        remote: String,
+        sender: Channel,
+        datagram: Vec<u8>,
+    },
+    ClearPostRemoves {
+        remote: String,
+        sender: Channel,
+    },
+    /// A subscriber was removed, and its post removes were routed.
+    Remove {
+        remote: String,
+    },
+    /// A recovered session was resumed.
+    Resumed {
+        session: SessionId,
+    },
+    /// The grace period ended, and the remaining recovered post removes were routed.
+    Expired,
+}
+
+/// What our subscribers have on record, as of the last entry.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Snapshot {
+    /// Incremented on each compaction, so that a journal
+    /// that was compacted already is not replayed again.
+    generation: u64,
+    /// Records of the connected subscribers, by remote.
+    live: BTreeMap<String, Record>,
+    /// Records recovered from before the last restart.
+    recovered: Vec<Record>,
+}
+
+impl Snapshot {
+    fn apply(&mut self, entry: Entry) {
+        match entry {
+            Entry::Resume {
+                remote,
+                session,
+                role,
+                certificate,
+            } => {
+                let record: &mut Record = self.record(remote);
+
+                record.session = Some(session);
+                record.role = Some(role);
+                record.certificate = certificate;
+            }
+            Entry::Subscribe { remote, change } => self.record(remote).subscribe(change),
+            Entry::AddPostRemove {
+                remote,
+                sender,
+                datagram,
+            } => self.record(remote).post_removes.push((sender, datagram)),
+            Entry::ClearPostRemoves { remote, sender } => {
+                if let Some(record) = self.live.get_mut(&remote) {
+                    record.post_removes.retain(|(other, _)| *other != sender);
+                }
+            }
+            Entry::Remove { remote } => {
+                self.live.remove(&remote);
+            }
+            Entry::Resumed { session } => self.recovered.retain(|record| record.session != Some(session)),
+            Entry::Expired => self.recovered.clear(),
+        }
+    }
+
+    fn record(&mut self, remote: String) -> &mut Record {
+        self.live
+            .entry(remote.clone())
+            .or_insert_with(|| Record::new(remote))
+    }
+
+    /// Recovers the records of the subscribers we had before restarting.
+    fn restart(&mut self) {
+        let live: BTreeMap<String, Record> = std::mem::take(&mut self.live);
+
+        self.recovered.extend(live.into_values());
+        self.recovered.retain(|record| !record.is_empty());
+    }
+}
+
+fn journal_path(path: &str) -> String {
+    format!("{}.journal", path)
+}
+
+/// Reads the snapshot at the given path, and replays its journal onto it.
+fn load(path: &str) -> Result<Snapshot> {
+    let snapshot: Snapshot = match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+        Err(err) if err.kind() == ErrorKind::NotFound => Snapshot::default(),
+        Err(err) => return Err(err),
+    };
+    let journal: File = match File::open(journal_path(path)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(snapshot),
+        Err(err) => return Err(err),
+    };
+    let mut snapshot: Snapshot = snapshot;
+    let mut lines = BufReader::new(journal).lines();
+
+    // a journal of another generation was compacted into the snapshot already
+    let generation: Option<u64> = match lines.next() {
+        Some(line) => serde_json::from_str(&line?).ok(),
+        None => None,
+    };
+    if generation != Some(snapshot.generation) {
+        return Ok(snapshot);
+    }
+    for line in lines {
+        match serde_json::from_str::<Entry>(&line?) {
+            Ok(entry) => snapshot.apply(entry),
+            Err(err) => {
+                // the last entry may have been cut off as we went down
+                warn!(
+                    "Stopped replaying the journal of {} at a bad entry: {}",
+                    path, err
+                );
+                break;
+            }
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Writes the snapshot, and starts a new journal on top of it.
+fn compact(path: &str, snapshot: &mut Snapshot) -> Result<BufWriter<File>> {
+    snapshot.generation += 1;
+
+    // it is written aside and renamed, so that either snapshot is whole
+    let aside: String = format!("{}.tmp", path);
+    let mut file: File = File::create(&aside)?;
+
+    serde_json::to_writer(&mut file, &snapshot)?;
+    file.sync_all()?;
+    fs::rename(&aside, path)?;
+
+    let mut journal: BufWriter<File> = BufWriter::new(File::create(journal_path(path))?);
+
+    serde_json::to_writer(&mut journal, &snapshot.generation)?;
+    journal.write_all(b"\n")?;
+    journal.flush()?;
+    Ok(journal)
+}
+
+/// Owns the files of the journal, on a thread of its own.
+struct Writer {
+    path: String,
+    snapshot: Snapshot,
+    journal: BufWriter<File>,
+    entries: usize,
+}
+
+/// An entry to be written, and whoever waits for it to be synced.
+type Pending = (Entry, oneshot::Sender<Result<()>>);
+
+impl Writer {
+    fn run(mut self, rx: mpsc::Receiver<Pending>) {
+        while let Ok(pending) = rx.recv() {
+            // the entries recorded meanwhile are written, and synced, together
+            let (entries, waiters): (Vec<Entry>, Vec<_>) =
+                std::iter::once(pending).chain(rx.try_iter()).unzip();
+
+            let result: Result<()> = self.write(entries);
+
+            if let Err(err) = &result {
+                error!("Failed to write the journal of {}: {}", self.path, err);
+            }
+            for waiter in waiters {
+                let result: Result<()> = match &result {
+                    Ok(()) => Ok(()),
+                    Err(err) => Err(Error::new(err.kind(), err.to_string())),
+                };
+                // the MD may have stopped waiting
+                let _ = waiter.send(result);
+            }
+        }
+    }
+
+    fn write(&mut self, entries: Vec<Entry>) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.journal, &entry)?;
+            self.journal.write_all(b"\n")?;
+
+            self.snapshot.apply(entry);
+            self.entries += 1;
+        }
+        self.journal.flush()?;
+        self.journal.get_ref().sync_data()?;
+
+        if self.entries >= COMPACT_AFTER {
+            self.journal = compact(&self.path, &mut self.snapshot)?;
+            self.entries = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Records the changes to what our subscribers have on record.
+///
+/// Entries are written by a thread of their own, which syncs the
+/// entries recorded meanwhile together. Only the changes being
+/// recorded wait on the disk, never the routing of other messages.
+pub struct Journal {
+    tx: Option<mpsc::Sender<Pending>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl Journal {
+    /// Opens the journal at the given path, and returns the records
+    /// of the subscribers that we had before we restarted.
+    pub fn open(path: &str) -> Result<(Self, Vec<Record>)> {
+        let mut snapshot: Snapshot = load(path)?;
+        snapshot.restart();
+
+        let recovered: Vec<Record> = snapshot.recovered.clone();
+        let journal: BufWriter<File> = compact(path, &mut snapshot)?;
+
+        let writer = Writer {
+            path: path.to_owned(),
+            snapshot,
+            journal,
+            entries: 0,
+        };
+        let (tx, rx) = mpsc::channel::<Pending>();
+
+        let writer: thread::JoinHandle<()> = thread::Builder::new()
+            .name("md-journal".to_owned())
+            .spawn(move || writer.run(rx))?;
+
+        let journal = Self {
+            tx: Some(tx),
+            writer: Some(writer),
+        };
+        Ok((journal, recovered))
+    }
+
+    /// Writes an entry, and waits for it to be synced to disk.
+    pub async fn record(&self, entry: Entry) -> Result<()> {
+        let stopped = || Error::other("The journal writer has stopped.");
+
+        let tx: &mpsc::Sender<Pending> = self.tx.as_ref().ok_or_else(stopped)?;
+        let (done_tx, done_rx) = oneshot::channel::<Result<()>>();
+
+        tx.send((entry, done_tx)).map_err(|_| stopped())?;
+        done_rx.await.map_err(|_| stopped())?
+    }
+}
+
+/// Waits for the entries that were recorded to be written.
+impl Drop for Journal {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                error!("The journal writer panicked.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a path in the temporary directory, with no files at it.
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("donet-md-{}-{}", name, std::process::id()));
+        let path: String = path.to_string_lossy().into_owned();
+
+        for file in [path.clone(), journal_path(&path), format!("{}.tmp", path)] {
+            let _ = fs::remove_file(file);
+        }
+        path
+    }
+
+    #[test]
+    fn record_subscriptions() {
+        let mut record = Record::default();
+
+        for change in [
+            Change::AddChannel(5),
+            Change::AddChannel(150),
+            Change::AddRange(100, 200),
+            Change::AddRange(300, 400),
+            Change::RemoveChannel(5),
+            Change::RemoveRange(150, 350),
+            Change::AddRange(20, 10),
+        ] {
+            record.subscribe(change);
+        }
+        assert!(record.channels.is_empty());
+        assert_eq!(record.ranges, [(100, 149), (351, 400)]);
+    }
+
+    #[test]
+    fn resume_binding() {
+        let role = Role::unrestricted();
+        let record = Record {
+            role: Some(role.get_name().to_owned()),
+            certificate: Some("ab:cd".to_owned()),
+            ..Default::default()
+        };
+
+        assert_eq!(record.may_resume(&role, Some("ab:cd")), Ok(()));
+        assert_eq!(record.may_resume(&role, Some("ef:01")), Err(Denied::Resume));
+        assert_eq!(record.may_resume(&role, None), Err(Denied::Resume));
+        assert_eq!(
+            record.may_resume(&Role::unauthenticated(), Some("ab:cd")),
+            Err(Denied::Resume)
+        );
+    }
+
+    #[tokio::test]
+    async fn recovered_records() {
+        let path: String = temp_path("recovered");
+        let entry = |remote: &str| Entry::AddPostRemove {
+            remote: remote.to_owned(),
+            sender: 1337,
+            datagram: vec![1, 2, 3],
+        };
+
+        let (journal, recovered) = Journal::open(&path).unwrap();
+        assert!(recovered.is_empty());
+
+        for entry in [
+            Entry::Resume {
+                remote: "a".to_owned(),
+                session: 7,
+                role: "ai".to_owned(),
+                certificate: Some("ab:cd".to_owned()),
+            },
+            entry("a"),
+            entry("b"),
+            entry("c"),
+            Entry::ClearPostRemoves {
+                remote: "b".to_owned(),
+                sender: 1337,
+            },
+            Entry::Remove {
+                remote: "c".to_owned(),
+            },
+        ] {
+            journal.record(entry).await.unwrap();
+        }
+        drop(journal);
+
+        // only subscriber A had anything left on record
+        let (journal, recovered) = Journal::open(&path).unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].session, Some(7));
+        assert_eq!(recovered[0].role.as_deref(), Some("ai"));
+        assert_eq!(recovered[0].certificate.as_deref(), Some("ab:cd"));
+        assert_eq!(recovered[0].post_removes, [(1337, vec![1, 2, 3])]);
+
+        // a restart during the grace period recovers it again
+        drop(journal);
+        let (journal, recovered) = Journal::open(&path).unwrap();
+        assert_eq!(recovered.len(), 1);
+
+        journal.record(Entry::Resumed { session: 7 }).await.unwrap();
+        drop(journal);
+        let (_, recovered) = Journal::open(&path).unwrap();
+        assert!(recovered.is_empty());
+    }
+
+    #[tokio::test]
+    async fn compacted_journal() {
+        let path: String = temp_path("compacted");
+        let entry = |remote: &str| Entry::AddPostRemove {
+            remote: remote.to_owned(),
+            sender: 1337,
+            datagram: vec![1, 2, 3],
+        };
+
+        let (journal, _) = Journal::open(&path).unwrap();
+        journal.record(entry("a")).await.unwrap();
+        drop(journal);
+
+        // as if we went down while writing an entry
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(journal_path(&path))
+            .unwrap();
+        writeln!(file, "{{\"add_post_remove\": {{\"remote\": \"cut off").unwrap();
+
+        let (journal, recovered) = Journal::open(&path).unwrap();
+        assert_eq!(recovered.len(), 1);
+
+        journal.record(entry("b")).await.unwrap();
+        drop(journal);
+
+        // as if we went down after renaming a snapshot, before starting its journal
+        let mut snapshot: Snapshot = load(&path).unwrap();
+        snapshot.generation += 1;
+        fs::write(&path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let (_, recovered) = Journal::open(&path).unwrap();
+        let remotes: Vec<&str> = recovered.iter().map(|record| record.remote.as_str()).collect();
+
+        assert_eq!(remotes, ["a", "b"]);
+        assert!(recovered.iter().all(|record| record.post_removes.len() == 1));
+    }
+}
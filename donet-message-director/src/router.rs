@@ -0,0 +1,385 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Routing of datagrams between the connections of a Message Director.
+//!
+//! Each connection routes the datagrams it receives from its own task.
+//! Routing reads the latest [`ChannelMap`] published by the MD, and
+//! stages datagrams straight into the send queues of the subscribers,
+//! so it waits on neither the lock of the MD nor on other connections.
+//!
+//! Datagrams from our subscribers are only routed upstream if the rest
+//! of the cluster may want them; see [`crate::interest`]. In a mesh,
+//! they are forwarded to the peer MDs that added one of their
+//! recipients instead; see [`crate::mesh`].
+
+use crate::channel_map::ChannelMap;
+use crate::event_log::EventLog;
+use crate::interest::Interest;
+use crate::mesh::{self, MdId};
+use crate::metrics::Metrics;
+use crate::subscriber::SubscriberRef;
+use crate::trace::{Trace, Tracer};
+use crate::upstream::{UpstreamMD, UpstreamSender};
+use arc_swap::ArcSwap;
+use donet_core::datagram::datagram::*;
+use donet_core::globals::Channel;
+use donet_network::addr::PeerAddr;
+use donet_network::udp;
+use log::{trace, warn};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+/// Shared by the tasks of every connection of a Message Director.
+pub struct Router {
+    /// Snapshot of the MD's subscriptions, replaced
+    /// every time the MD publishes a change.
+    channel_map: ArcSwap<ChannelMap>,
+    /// Snapshot of the interest of our peer MDs, published with `channel_map`.
+    peer_map: ArcSwap<ChannelMap>,
+    /// What our upstream MD wants from us, published by the MD.
+    upstream_interest: ArcSwap<Interest>,
+    /// `false` until our upstream MD has told us all of its interest,
+    /// during which everything is routed upstream.
+    upstream_synced: AtomicBool,
+    /// Our downstream MDs, which are sent traced datagrams
+    /// in their envelope; published by the MD.
+    downstream: ArcSwap<HashSet<PeerAddr>>,
+    /// ID of this MD, which tags the datagrams forwarded to peers.
+    id: MdId,
+    upstream: Option<Arc<Mutex<UpstreamMD>>>,
+    /// Send queue of the link to `upstream`, staged to without locking it.
+    upstream_sender: Option<UpstreamSender>,
+    peers: Vec<Arc<Mutex<UpstreamMD>>>,
+    metrics: Metrics,
+    event_log: EventLog,
+    tracer: Tracer,
+}
+
+impl Router {
+    pub fn new(
+        id: MdId,
+        upstream: Option<UpstreamMD>,
+        peers: Vec<UpstreamMD>,
+        event_logger: Option<Arc<udp::Socket>>,
+    ) -> Self {
+        let upstream_sender: Option<UpstreamSender> = upstream.as_ref().map(UpstreamMD::sender);
+        let upstream: Option<Arc<Mutex<UpstreamMD>>> =
+            upstream.map(|upstream| Arc::new(Mutex::new(upstream)));
+        let event_log = EventLog::new(event_logger, upstream.clone());
+
+        Self {
+            channel_map: ArcSwap::from_pointee(ChannelMap::default()),
+            peer_map: ArcSwap::from_pointee(ChannelMap::default()),
+            upstream_interest: ArcSwap::from_pointee(Interest::default()),
+            upstream_synced: AtomicBool::new(false),
+            downstream: ArcSwap::default(),
+            id,
+            tracer: Tracer::new(id, event_log.clone()),
+            event_log,
+            upstream,
+            upstream_sender,
+            peers: peers.into_iter().map(|peer| Arc::new(Mutex::new(peer))).collect(),
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub fn get_id(&self) -> MdId {
+        self.id
+    }
+
+    pub fn get_metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn get_event_log(&self) -> &EventLog {
+        &self.event_log
+    }
+
+    pub fn get_tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    pub fn get_upstream(&self) -> Option<&Arc<Mutex<UpstreamMD>>> {
+        self.upstream.as_ref()
+    }
+
+    /// Returns the links that we add our subscriptions over,
+    /// which are the upstream MD, or the peers of a mesh.
+    pub fn get_links(&self) -> impl Iterator<Item = &Arc<Mutex<UpstreamMD>>> {
+        self.upstream.iter().chain(self.peers.iter())
+    }
+
+    /// Publishes a snapshot of the given [`ChannelMap`]s, of our
+    /// subscribers and of our peers, which datagrams are routed with
+    /// from now on.
+    ///
+    /// Changes must be published in the order they were applied,
+    /// so this should only be called while the MD is locked.
+    pub fn publish(&self, channel_map: &ChannelMap, peer_map: &ChannelMap) {
+        self.channel_map.store(Arc::new(channel_map.clone()));
+        self.peer_map.store(Arc::new(peer_map.clone()));
+    }
+
+    /// Publishes the remotes of our downstream MDs.
+    ///
+    /// Like [`Self::publish`], this should only be called while the MD is locked.
+    pub fn publish_downstream(&self, downstream: HashSet<PeerAddr>) {
+        self.downstream.store(Arc::new(downstream));
+    }
+
+    /// Returns the subscribers and peer MDs of the published subscriptions.
+    #[allow(clippy::mutable_key_type)]
+    pub fn get_subscribers(&self) -> HashSet<SubscriberRef> {
+        let mut subscribers: HashSet<SubscriberRef> = HashSet::default();
+
+        for map in [self.channel_map.load(), self.peer_map.load()] {
+            for (_, subs) in map.iter_channels() {
+                subscribers.extend(subs.iter().cloned());
+            }
+            for (_, subs) in map.iter_ranges() {
+                subscribers.extend(subs.iter().cloned());
+            }
+        }
+        subscribers
+    }
+
+    /// Publishes a snapshot of the interest of our upstream MD.
+    ///
+    /// Like [`Self::publish`], this should only be called while the MD is locked.
+    pub fn publish_interest(&self, interest: &Interest) {
+        self.upstream_interest.store(Arc::new(interest.clone()));
+    }
+
+    /// Sets whether the published interest of our upstream MD is
+    /// complete. If not, datagrams are routed upstream regardless.
+    pub fn set_upstream_synced(&self, synced: bool) {
+        self.upstream_synced.store(synced, Ordering::Release);
+    }
+
+    /// Returns `true` if a datagram from one of our subscribers should
+    /// be routed upstream, which is if one of its recipients has no
+    /// subscribers of ours, or is wanted by the rest of the cluster.
+    fn is_wanted_upstream(&self, channel_map: &ChannelMap, recipients: &[Channel]) -> bool {
+        if !self.upstream_synced.load(Ordering::Acquire) {
+            return true;
+        }
+        let interest = self.upstream_interest.load();
+
+        recipients
+            .iter()
+            .any(|channel| !channel_map.has_subscribers(*channel) || interest.contains(*channel))
+    }
+
+    /// Replicates a datagram to the subscribers of its recipient channels.
+    ///
+    /// If it came from one of our subscribers, it is also routed upstream,
+    /// and forwarded to the peers that are interested in its recipients.
+    /// If that subscriber is a downstream MD, it is not routed back to it,
+    /// as it was already routed to the subscribers of that MD.
+    ///
+    /// If the datagram is traced, the other MDs are sent it in its
+    /// envelope, and a span is recorded for every connection it is sent to.
+    #[allow(clippy::mutable_key_type)]
+    pub async fn route(
+        &self,
+        recipients: &[Channel],
+        mut dg: Datagram,
+        from_subscriber: bool,
+        downstream: Option<PeerAddr>,
+        trace: Option<&Trace>,
+    ) {
+        let start: Instant = Instant::now();
+        let mut fan_out: usize = 0;
+
+        // make sure every copy of this datagram shares the same buffer
+        dg.freeze();
+
+        let channel_map = self.channel_map.load();
+        let downstream_mds = self.downstream.load();
+
+        // what the other MDs are sent instead, if the datagram is traced
+        let traced: Option<Datagram> = trace.and_then(|trace| match trace.wrap(self.id, &dg) {
+            Ok(mut envelope) => {
+                envelope.freeze();
+                Some(envelope)
+            }
+            Err(err) => {
+                warn!("Failed to trace datagram; Routing it untraced: {}", err);
+                None
+            }
+        });
+
+        // get all subscribers of the recipient channels
+        let receiving_subscribers: HashSet<SubscriberRef> = channel_map.lookup_channels(recipients);
+
+        // replicate the message to all receiving subscribers
+        for sub in receiving_subscribers {
+            if Some(sub.get_remote()) == downstream {
+                continue;
+            }
+            let (dg, stage): (&Datagram, &str) = match &traced {
+                Some(envelope) if downstream_mds.contains(&sub.get_remote()) => (envelope, "downstream"),
+                _ => (&dg, "delivered"),
+            };
+            // a subscriber that lost its connection must not stop delivery to the others
+            match sub.stage_datagram(dg.clone()).await {
+                Ok(()) => {
+                    self.metrics.routed(dg.size());
+                    fan_out += 1;
+
+                    if let Some(trace) = trace {
+                        self.tracer
+                            .record_sent(trace, stage, recipients, &sub.get_remote().to_string())
+                            .await;
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to send datagram to subscriber {}: {}",
+                        sub.get_remote(),
+                        err
+                    );
+                    self.metrics.dropped();
+                }
+            }
+        }
+
+        // Next, decide if this message needs to be routed **upstream**.
+        //
+        // If the sender of this message is one of our subscribers
+        // (downstream), **and** we have an uplink connection, route
+        // the message upstream, unless no one else wants it.
+        match (&self.upstream, &self.upstream_sender) {
+            (Some(upstream), Some(sender)) if from_subscriber => {
+                if self.is_wanted_upstream(&channel_map, recipients) {
+                    trace!("Routing upstream.");
+                    let dg: &Datagram = traced.as_ref().unwrap_or(&dg);
+
+                    let staged: bool = match sender.load() {
+                        Some(link) => match link.stage_datagram(dg.clone()).await {
+                            Ok(()) => true,
+                            Err(err) => {
+                                warn!(
+                                    "Failed to route datagram to upstream MD {}: {}",
+                                    sender.get_address(),
+                                    err
+                                );
+                                false
+                            }
+                        },
+                        // The link is down, so the datagram is buffered.
+                        None => {
+                            upstream.lock().await.stage_datagram(dg.clone()).await;
+                            true
+                        }
+                    };
+
+                    if staged {
+                        self.metrics.routed(dg.size());
+                        fan_out += 1;
+
+                        if let Some(trace) = trace {
+                            self.tracer
+                                .record_sent(trace, "upstream", recipients, sender.get_address())
+                                .await;
+                        }
+                    } else {
+                        self.metrics.dropped();
+                    }
+                } else {
+                    trace!("Not routing upstream; No one else wants it.");
+                }
+            }
+            // If this message is from upstream, do not bounce it back!
+            (Some(_), _) => trace!("Not routing upstream; It came from there."),
+            // Otherwise, this is the master message director.
+            (None, _) => trace!("Not routing upstream; We are the master MD."),
+        }
+
+        // Datagrams forwarded by a peer are not forwarded again,
+        // so that every datagram crosses the mesh at most once.
+        if from_subscriber {
+            fan_out += self.forward_to_peers(recipients, &mut dg, trace).await;
+        }
+        self.metrics.route_done(fan_out, start.elapsed());
+    }
+
+    /// Forwards a datagram from one of our subscribers to
+    /// the peer MDs that are interested in its recipients.
+    ///
+    /// Returns the number of peers it was forwarded to.
+    #[allow(clippy::mutable_key_type)]
+    async fn forward_to_peers(
+        &self,
+        recipients: &[Channel],
+        dg: &mut Datagram,
+        trace: Option<&Trace>,
+    ) -> usize {
+        let interested_peers: HashSet<SubscriberRef> = self.peer_map.load().lookup_channels(recipients);
+        let mut forwarded: usize = 0;
+
+        if interested_peers.is_empty() {
+            return forwarded;
+        }
+        // every peer gets the same envelope, which is traced in turn
+        let forward = mesh::peer_forward(self.id, dg).and_then(|forward| match trace {
+            Some(trace) => trace.wrap(self.id, &forward),
+            None => Ok(forward),
+        });
+        let mut envelope: Datagram = match forward {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                warn!("Failed to forward datagram to peer MDs: {}", err);
+                self.metrics.dropped();
+                return forwarded;
+            }
+        };
+        envelope.freeze();
+
+        for peer in interested_peers {
+            trace!("Forwarding datagram to peer MD {}.", peer.get_remote());
+
+            match peer.stage_datagram(envelope.clone()).await {
+                Ok(()) => {
+                    self.metrics.routed(envelope.size());
+                    forwarded += 1;
+
+                    if let Some(trace) = trace {
+                        self.tracer
+                            .record_sent(trace, "forwarded", recipients, &peer.get_remote().to_string())
+                            .await;
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to forward datagram to peer MD {}: {}",
+                        peer.get_remote(),
+                        err
+                    );
+                    self.metrics.dropped();
+                }
+            }
+        }
+        forwarded
+    }
+}
@@ -17,18 +17,23 @@
     License along with Donet. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use core::net::SocketAddr;
+use crate::acl::Role;
+use crate::interest::Scope;
+use crate::mesh::MdId;
+use arc_swap::ArcSwap;
 use donet_core::datagram::datagram::*;
 use donet_core::globals::Channel;
-use donet_network::Client;
+use donet_network::addr::PeerAddr;
+use donet_network::queue::QueueStats;
+use donet_network::tls;
 use donet_network::HasClient;
+use donet_network::{Client, ClientSender, ConnectionError};
 use gcollections::ops::*;
 use interval::IntervalSet;
 use log::trace;
 use multimap::MultiMap;
 use std::collections::HashSet;
 use std::sync::Arc;
-use tokio::sync::mpsc;
 use tokio::sync::{Mutex, MutexGuard};
 
 /// A wrapper that holds a thread-safe [`std::sync::Arc`] pointer to
@@ -44,9 +49,20 @@ use tokio::sync::{Mutex, MutexGuard};
 /// The remote address is immutable and should never change, which
 /// means the comparison and hash of this structure should always be
 /// the same, satisfying the requirements for a hash set.
+///
+/// Clippy's `mutable_key_type` lint is allowed where this is used as a
+/// key, as it only sees the interior mutability of the [`Role`] and the
+/// [`Subscriber`], which are not part of the hash.
+///
+/// It also holds the [`ClientSender`] of the subscriber's connection,
+/// so that datagrams can be routed to it without locking it, and the
+/// [`Role`] of the subscriber, so that the datagrams it sends can be
+/// checked without locking it.
 #[derive(Clone)]
 pub struct SubscriberRef {
-    hash_key: SocketAddr,
+    hash_key: PeerAddr,
+    sender: Option<ClientSender>,
+    role: Arc<ArcSwap<Role>>,
     pointer: Arc<Mutex<Subscriber>>,
 }
 
@@ -54,6 +70,8 @@ impl From<Subscriber> for SubscriberRef {
     fn from(value: Subscriber) -> Self {
         Self {
             hash_key: value.remote,
+            sender: value.sender.clone(),
+            role: Arc::new(ArcSwap::from_pointee(Role::unrestricted())),
             pointer: Arc::new(Mutex::new(value)),
         }
     }
@@ -63,10 +81,12 @@ impl From<Subscriber> for SubscriberRef {
 ///
 /// Also used for unit testing, where we don't have real
 /// [`Client`] structures to make a [`Subscriber`] from.
-impl From<SocketAddr> for SubscriberRef {
-    fn from(value: SocketAddr) -> Self {
+impl From<PeerAddr> for SubscriberRef {
+    fn from(value: PeerAddr) -> Self {
         Self {
             hash_key: value,
+            sender: None,
+            role: Arc::new(ArcSwap::from_pointee(Role::unrestricted())),
             pointer: Arc::new(Mutex::new(value.into())),
         }
     }
@@ -75,7 +95,7 @@ impl From<SocketAddr> for SubscriberRef {
 /// Must implement [`core::cmp::PartialEq`] to store in a
 /// [`std::collections::HashSet`] structure.
 ///
-/// Compares the remote IPv4/6 address of both subscribers.
+/// Compares the remote address of both subscribers.
 impl PartialEq for SubscriberRef {
     fn eq(&self, other: &Self) -> bool {
         self.hash_key == other.hash_key
@@ -87,7 +107,7 @@ impl Eq for SubscriberRef {}
 /// Must implement [`std::hash::Hash`] to store in a
 /// [`std::collections::HashSet`] structure.
 ///
-/// Hashes the raw pointer of the [`std::sync::Arc`].
+/// Hashes the remote address of the subscriber.
 impl std::hash::Hash for SubscriberRef {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.hash_key.hash(state);
@@ -100,35 +120,65 @@ impl SubscriberRef {
         self.pointer.lock().await
     }
 
-    /// Get a clone of the underlying [`std::sync::Arc`]
-    /// pointer to the [`Subscriber`] mutex.
-    pub fn get_ptr(&self) -> Arc<Mutex<Subscriber>> {
-        self.pointer.clone()
-    }
-
     /// Quick way to get the remote address without locking
     /// the underlying [`Subscriber`]'s mutex.
-    pub fn get_remote(&self) -> SocketAddr {
+    pub fn get_remote(&self) -> PeerAddr {
         self.hash_key
     }
+
+    /// Returns the role of the subscriber, without
+    /// locking the underlying [`Subscriber`].
+    pub fn get_role(&self) -> Arc<Role> {
+        self.role.load_full()
+    }
+
+    /// Gives the subscriber a role, such as once it authenticates.
+    pub fn set_role(&self, role: Arc<Role>) {
+        self.role.store(role);
+    }
+
+    /// Returns the depth of the send queue of the subscriber's
+    /// connection, without locking the underlying [`Subscriber`].
+    pub fn get_queue_stats(&self) -> Option<QueueStats> {
+        self.sender.as_ref().map(ClientSender::get_queue_stats)
+    }
+
+    /// Handles a [`Datagram`] that the Message Director received,
+    /// and needs to be routed to this subscriber.
+    ///
+    /// The datagram is added to the send queue of the subscriber's
+    /// connection, without locking the underlying [`Subscriber`].
+    pub async fn stage_datagram(&self, dg: Datagram) -> Result<(), ConnectionError> {
+        trace!("Sending datagram downstream to {}", self.hash_key);
+
+        match &self.sender {
+            Some(sender) => sender.stage_datagram(dg).await,
+            None => Err(ConnectionError::Closed),
+        }
+    }
 }
 
 /// Simple representation of a participant, or subscriber,
 /// that is connected to a Message Director instance.
 #[derive(Debug)]
 pub struct Subscriber {
-    /// Remote IPv4/6 address of this subscriber.
+    /// Remote address of this subscriber.
     ///
     /// This is the unique identifier for this subscriber.
-    remote: SocketAddr,
+    remote: PeerAddr,
     /// [`Client`] for this subscriber. Can be `None`, as
     /// a dummy [`Subscriber`] struct can be created for
     /// looking up a [`SubscriberRef`] in a hash set.
     client: Option<Arc<Mutex<Client>>>,
+    /// Stages datagrams to be sent by the [`Client`]. `None` for
+    /// a dummy [`Subscriber`], or if the client's tasks were not spawned.
+    sender: Option<ClientSender>,
     /// The name for this downstream connection.
     pub connection_name: Option<String>,
     /// The web URL for this downstream connection.
     pub connection_web_url: Option<String>,
+    /// Fingerprint of the TLS client certificate this subscriber presented.
+    pub certificate: Option<String>,
     /// Single channel subscriptions
     pub subscribed_channels: HashSet<Channel>,
     /// Channel range subscriptions
@@ -136,24 +186,29 @@ pub struct Subscriber {
     /// Datagrams scheduled to be distributed upon
     /// this subscriber's unexpected disconnect.
     pub post_removes: MultiMap<Channel, Datagram>,
+    /// ID of the MD on the other end, if this is a peer of our mesh.
+    pub peer_id: Option<MdId>,
 }
 
-/// Creates a new [`Subscriber`] from a [`SocketAddr`],
+/// Creates a new [`Subscriber`] from a [`PeerAddr`],
 /// should be the remote address of the subscriber.
 ///
 /// This can also be used to make a dummy
 /// [`SubscriberRef`] for looking up a subscriber
 /// in a hash set.
-impl From<SocketAddr> for Subscriber {
-    fn from(value: SocketAddr) -> Self {
+impl From<PeerAddr> for Subscriber {
+    fn from(value: PeerAddr) -> Self {
         Self {
             client: None,
+            sender: None,
             remote: value,
             connection_name: None,
             connection_web_url: None,
+            certificate: None,
             subscribed_channels: HashSet::default(),
             subscribed_ranges: IntervalSet::empty(),
             post_removes: MultiMap::default(),
+            peer_id: None,
         }
     }
 }
@@ -177,40 +232,41 @@ impl Subscriber {
     pub async fn new(client: Client) -> Self {
         Self {
             remote: client.get_remote(),
+            sender: client.get_sender(),
+            certificate: client.get_peer_certificate().map(tls::fingerprint),
             client: Some(Arc::new(Mutex::new(client))),
             connection_name: None,
             connection_web_url: None,
             subscribed_channels: HashSet::default(),
             subscribed_ranges: IntervalSet::empty(),
             post_removes: MultiMap::default(),
+            peer_id: None,
         }
     }
 
-    /// Handles a [`Datagram`] that the Message Director received,
-    /// and needs to be routed to this subscriber.
-    pub async fn handle_datagram(
-        &mut self,
-        dg: &mut Datagram,
-    ) -> Result<(), mpsc::error::SendError<Datagram>> {
-        trace!("Sending datagram downstream to {}", self.remote);
-
-        debug_assert!(
-            self.client.is_some(),
-            "Called handle_datagram() on a dummy Subscriber!"
-        );
-
-        // if assertion passed, we can safely unwrap
-        let client: Arc<Mutex<Client>> = self.client.clone().unwrap();
-        let mut locked_client = client.lock().await;
-
-        locked_client.stage_datagram(dg.clone()).await
+    /// Closes the connection of this subscriber, once it is removed
+    /// from the Message Director. It may have already been closed.
+    pub async fn receive_disconnect(&mut self) {
+        if let Some(client) = &self.client {
+            client.lock().await.close();
+        }
     }
 
-    pub async fn receive_disconnect(&mut self) {
-        // TODO!
+    /// Takes the post removes of this subscriber, by sender,
+    /// so that they can be routed after it disconnected.
+    pub fn post_remove(&mut self) -> MultiMap<Channel, Datagram> {
+        std::mem::take(&mut self.post_removes)
     }
 
-    pub async fn post_remove(&mut self) {
-        // TODO!
+    /// Returns the channels and ranges this subscriber is subscribed to.
+    pub fn get_scope(&self) -> Scope {
+        Scope {
+            channels: self.subscribed_channels.iter().copied().collect(),
+            ranges: self
+                .subscribed_ranges
+                .iter()
+                .map(|range| range.lower()..=range.upper())
+                .collect(),
+        }
     }
 }
@@ -0,0 +1,241 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tracing of sampled datagrams across the cluster.
+//!
+//! Channels are traced at runtime with `CONTROL_ADD_TRACE`, which
+//! samples one in every so many of the datagrams that the MD receives
+//! to or from the channel. A sampled datagram is given a random trace
+//! ID, and every MD it passes through records spans of it, as it is
+//! received and as it is sent to each connection.
+//!
+//! Between MDs, a traced datagram travels in a `CONTROL_TRACED`
+//! envelope, with its trace ID and the IDs of the MDs it passed
+//! through. Subscribers that are not MDs, such as services, receive
+//! it unwrapped, so the last span of a trace is its delivery to them.
+//! Services do not record spans of their own yet, nor can they trace
+//! the doIds that are only named in the payload of a datagram.
+//!
+//! Spans are logged as [`LoggedEvent`]s through our [`EventLog`].
+
+use crate::event_log::EventLog;
+use crate::mesh::MdId;
+use crate::random;
+use arc_swap::ArcSwap;
+use donet_core::datagram::datagram::{Datagram, DatagramError};
+use donet_core::globals::{Channel, MsgType};
+use donet_core::messages::{MDTraced, Payload, ProtocolMessage};
+use donet_core::Protocol;
+use donet_daemon::event::LoggedEvent;
+use std::sync::Arc;
+
+pub type TraceId = u64;
+
+/// A traced datagram's trace ID, and the MDs it passed through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub id: TraceId,
+    pub hops: Vec<MdId>,
+}
+
+impl Trace {
+    /// Unwraps a `CONTROL_TRACED` envelope into its trace and datagram.
+    pub fn unwrap(traced: MDTraced) -> (Self, Datagram) {
+        let trace = Self {
+            id: traced.trace_id,
+            hops: traced.hops,
+        };
+        (trace, traced.datagram.0.into())
+    }
+
+    /// Wraps a datagram in a `CONTROL_TRACED` envelope, sent by the given MD.
+    ///
+    /// Fails if the envelope does not fit in a datagram.
+    pub fn wrap(&self, sender: MdId, dg: &Datagram) -> Result<Datagram, DatagramError> {
+        let mut envelope: Datagram = Datagram::default();
+
+        envelope.add_control_header(Protocol::MDTraced.into())?;
+
+        let traced = MDTraced {
+            trace_id: self.id,
+            hops: self.hops.iter().copied().chain([sender]).collect(),
+            datagram: Payload(dg.clone().into_bytes()),
+        };
+        traced.encode(&mut envelope)?;
+        Ok(envelope)
+    }
+}
+
+/// Samples the datagrams of the traced channels, and
+/// records the spans of traced datagrams.
+pub struct Tracer {
+    /// ID of this MD, which records the spans.
+    md_id: MdId,
+    /// How many datagrams of each traced channel there are per sample.
+    sampling: ArcSwap<imbl::HashMap<Channel, u32>>,
+    event_log: EventLog,
+}
+
+impl Tracer {
+    pub fn new(md_id: MdId, event_log: EventLog) -> Self {
+        Self {
+            md_id,
+            sampling: ArcSwap::default(),
+            event_log,
+        }
+    }
+
+    /// Traces one in every `sampling` datagrams of the given channel,
+    /// or stops tracing it if `None`.
+    ///
+    /// Like [`crate::router::Router::publish`], this should
+    /// only be called while the MD is locked.
+    pub fn set_sampling(&self, channel: Channel, sampling: Option<u32>) {
+        let mut traced: imbl::HashMap<Channel, u32> = (**self.sampling.load()).clone();
+
+        match sampling {
+            Some(sampling) => traced.insert(channel, sampling.max(1)),
+            None => traced.remove(&channel),
+        };
+        self.sampling.store(Arc::new(traced));
+    }
+
+    /// Returns a new trace if the datagram with the given sender and
+    /// recipients is sampled, by the most sampled of its channels.
+    pub fn sample(&self, sender: Channel, recipients: &[Channel]) -> Option<Trace> {
+        let traced = self.sampling.load();
+
+        if traced.is_empty() {
+            return None;
+        }
+        let sampling: u32 = [sender]
+            .iter()
+            .chain(recipients)
+            .filter_map(|channel| traced.get(channel).copied())
+            .min()?;
+
+        random().is_multiple_of(u64::from(sampling)).then(|| Trace {
+            id: random(),
+            hops: vec![],
+        })
+    }
+
+    /// Records the span of a traced datagram that we received from the given remote.
+    pub async fn record_received(
+        &self,
+        trace: &Trace,
+        header: (Channel, &[Channel]),
+        msg_type: MsgType,
+        from: &str,
+    ) {
+        let (sender, recipients) = header;
+        let mut span: LoggedEvent = self.span(trace, "received", recipients);
+
+        span.add("sender_channel", &sender.to_string());
+        span.add(
+            "msg_type",
+            &match Protocol::try_from(msg_type) {
+                Ok(msg_type) => format!("{:?}", msg_type),
+                Err(_) => msg_type.to_string(),
+            },
+        );
+        span.add("from", from);
+        self.event_log.log(span).await;
+    }
+
+    /// Records a span of a traced datagram that we sent to the given remote.
+    pub async fn record_sent(&self, trace: &Trace, stage: &str, recipients: &[Channel], to: &str) {
+        let mut span: LoggedEvent = self.span(trace, stage, recipients);
+
+        span.add("to", to);
+        self.event_log.log(span).await;
+    }
+
+    /// Returns a span of the given trace, at the given stage.
+    fn span(&self, trace: &Trace, stage: &str, recipients: &[Channel]) -> LoggedEvent {
+        let mut span = LoggedEvent::new("trace", &format!("MD {}", self.md_id));
+
+        span.add("trace_id", &format!("{:016x}", trace.id));
+        span.add("md", &self.md_id.to_string());
+        span.add("stage", stage);
+        span.add("hops", &join(&trace.hops));
+        span.add("recipients", &join(recipients));
+        span
+    }
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values.iter().map(T::to_string).collect::<Vec<String>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use donet_core::datagram::iterator::DatagramIterator;
+    use donet_core::messages::validate::Validator;
+    use donet_core::messages::Message;
+
+    #[test]
+    fn traced_envelope() {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_internal_header(vec![4000], 1337, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+
+        let trace = Trace {
+            id: 42,
+            hops: vec![3],
+        };
+        let envelope: Datagram = trace.wrap(7, &dg).unwrap();
+        let dgi: DatagramIterator = envelope.into();
+
+        let traced: MDTraced = match Validator::new().validate_internal(&dgi).unwrap().1 {
+            Message::MDTraced(traced) => traced,
+            message => panic!("Unexpected message: {:?}", message),
+        };
+        let (unwrapped, inner): (Trace, Datagram) = Trace::unwrap(traced);
+
+        assert_eq!(
+            unwrapped,
+            Trace {
+                id: 42,
+                hops: vec![3, 7]
+            }
+        );
+        assert_eq!(inner.get_buffer(), dg.get_buffer());
+    }
+
+    #[test]
+    fn sampled_channels() {
+        let tracer = Tracer::new(1, EventLog::new(None, None));
+
+        assert_eq!(tracer.sample(1337, &[4000]), None);
+
+        tracer.set_sampling(4000, Some(1));
+        assert!(tracer.sample(1337, &[4000]).is_some());
+        assert!(tracer.sample(4000, &[5000]).is_some());
+        assert_eq!(tracer.sample(1337, &[5000]), None);
+
+        // a sampling of zero traces every datagram, like one
+        tracer.set_sampling(5000, Some(0));
+        assert!(tracer.sample(1337, &[5000]).is_some());
+
+        tracer.set_sampling(4000, None);
+        assert_eq!(tracer.sample(1337, &[4000]), None);
+    }
+}
@@ -17,98 +17,522 @@
     License along with Donet. If not, see <https://www.gnu.org/licenses/>.
 */
 
+//! The link to an upstream Message Director.
+//!
+//! If the link drops, datagrams routed upstream are buffered, up to a
+//! budget, while the [`Dialer`] reconnects with exponential backoff.
+//! Control messages are not buffered; once reconnected, the owning MD
+//! replays its whole subscription state with [`UpstreamMD::reconnected`],
+//! so the new link ends up with the same subscriptions as the old one.
+//!
+//! Every connection to an upstream MD starts with a
+//! `CONTROL_WATCH_INTEREST`, so that the upstream MD tells us what the
+//! rest of the cluster wants from us; see [`crate::interest`]. It is
+//! preceded by a `CONTROL_RESUME`, so that an upstream MD that restarted
+//! does not route our post removes; see [`crate::persist`].
+//!
+//! The links to the peers of a mesh are [`UpstreamMD`]s as well, which
+//! introduce themselves with a `CONTROL_PEER_HELLO` on every connection.
+
+use crate::acl;
+use crate::interest;
+use crate::mesh::{self, MdId};
+use crate::persist::{self, SessionId};
+use arc_swap::ArcSwapOption;
 use donet_core::datagram::datagram::*;
 use donet_core::{globals::*, Protocol};
-use donet_network::{tcp, Client, HasClient};
+use donet_daemon::config;
+use donet_network::compress::CompressionConfig;
+use donet_network::queue::QueueStats;
+use donet_network::{tcp, Client, ClientEvent, ClientSender, ConnectionHandle};
+use log::{error, info, warn};
+use std::collections::VecDeque;
 use std::io::Result;
-use std::ops::Range;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
 
-/// Represents a connection to an upstream Message Director service.
-pub struct UpstreamMD {
-    connection: Arc<Mutex<Client>>,
+/// Settings for re-establishing a lost upstream link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectConfig {
+    /// Delay before the first reconnection attempt.
+    pub min_delay: Duration,
+    /// Longest delay between reconnection attempts.
+    pub max_delay: Duration,
+    /// Bytes of routed datagrams buffered while disconnected.
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            max_buffered_bytes: 4 * 1024 * 1024, // 4 mb
+        }
+    }
+}
+
+/// Exponential backoff with jitter, between reconnection attempts.
+#[derive(Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempts: u32,
 }
 
-impl HasClient for UpstreamMD {
-    fn get_client(&self) -> Arc<Mutex<Client>> {
-        self.connection.clone()
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            attempts: 0,
+        }
     }
+
+    /// Returns the delay before the next attempt.
+    ///
+    /// The delay doubles with each attempt, up to the maximum, and is
+    /// randomly cut by up to half, so that MDs that lost the same
+    /// upstream do not all reconnect at once.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay: Duration = self
+            .min
+            .saturating_mul(2_u32.saturating_pow(self.attempts))
+            .min(self.max);
+
+        self.attempts = self.attempts.saturating_add(1);
+
+        delay.mul_f64(1.0 - (crate::random() as f64 / u64::MAX as f64) / 2.0)
+    }
+}
+
+/// Datagrams routed upstream while the link is down.
+///
+/// Once over budget, the oldest datagrams are dropped.
+#[derive(Debug, Default)]
+struct Backlog {
+    queue: VecDeque<Datagram>,
+    bytes: usize,
+    max_bytes: usize,
+    dropped: usize,
+}
+
+impl Backlog {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    fn push(&mut self, dg: Datagram) {
+        let size: usize = dg.size();
+
+        if size > self.max_bytes {
+            self.dropped += 1;
+            return;
+        }
+        while self.bytes + size > self.max_bytes {
+            let oldest: Datagram = self.queue.pop_front().expect("Backlog bytes out of sync.");
+
+            self.bytes -= oldest.size();
+            self.dropped += 1;
+        }
+        self.bytes += size;
+        self.queue.push_back(dg);
+    }
+
+    /// Takes the buffered datagrams, and the number that were dropped.
+    fn take(&mut self) -> (VecDeque<Datagram>, usize) {
+        self.bytes = 0;
+        (std::mem::take(&mut self.queue), std::mem::take(&mut self.dropped))
+    }
+}
+
+/// Opens connections to the upstream MD.
+///
+/// Cloned out of [`UpstreamMD`], so that reconnecting does
+/// not hold the lock of the owning service.
+#[derive(Debug, Clone)]
+pub struct Dialer {
+    address: String,
+    tls: Option<config::TlsClient>,
+    compression: Option<CompressionConfig>,
+    /// Largest datagram accepted from the upstream MD.
+    max_frame_size: DgSizeTag,
+    config: ReconnectConfig,
+}
+
+impl Dialer {
+    async fn dial(&self) -> Result<Client> {
+        let connection: tcp::Connection = match &self.tls {
+            Some(tls) => {
+                let server_name: &str = tls.get_server_name(&self.address);
+                tcp::Connection::connect_tls(&self.address, tls.load()?, server_name).await?
+            }
+            None => tcp::Connection::connect(&self.address).await?,
+        };
+        let mut client: Client = connection.into();
+
+        client.set_max_frame_size(self.max_frame_size);
+
+        if let Some(compression) = self.compression {
+            client.set_compression(compression);
+        }
+        Ok(client)
+    }
+
+    /// Retries connecting, with backoff, until a connection is made.
+    pub async fn redial(&self) -> Client {
+        let mut backoff = Backoff::new(self.config.min_delay, self.config.max_delay);
+
+        loop {
+            tokio::time::sleep(backoff.next_delay()).await;
+
+            match self.dial().await {
+                Ok(client) => return client,
+                Err(err) => warn!("Failed to reconnect to MD {}: {}", self.address, err),
+            }
+        }
+    }
+}
+
+/// Subscription state of a Message Director, which
+/// is replayed to its upstream MD after reconnecting.
+#[derive(Debug, Default)]
+pub struct Replay {
+    pub channels: Vec<Channel>,
+    pub ranges: Vec<RangeInclusive<Channel>>,
+    pub post_removes: Vec<(Channel, Datagram)>,
+}
+
+/// Send queue of the link to an upstream MD, shared with the
+/// [`crate::router::Router`] so that datagrams are routed upstream
+/// without locking the [`UpstreamMD`].
+///
+/// Empty while the link is down, and while it is being reconnected,
+/// so that routed datagrams are buffered behind the replayed state.
+#[derive(Debug, Clone)]
+pub struct UpstreamSender {
+    address: Arc<str>,
+    sender: Arc<ArcSwapOption<ClientSender>>,
+}
+
+impl UpstreamSender {
+    fn new(address: &str) -> Self {
+        Self {
+            address: address.into(),
+            sender: Arc::default(),
+        }
+    }
+
+    pub fn get_address(&self) -> &str {
+        &self.address
+    }
+
+    /// Returns the send queue of the link, if it is up.
+    pub fn load(&self) -> Option<Arc<ClientSender>> {
+        self.sender.load_full()
+    }
+}
+
+/// Represents a connection to an upstream Message Director service,
+/// or to a peer MD of a mesh.
+pub struct UpstreamMD {
+    dialer: Dialer,
+    /// `None` while the link is down.
+    connection: Option<Arc<Mutex<Client>>>,
+    backlog: Backlog,
+    /// Our ID, if this is the link to a peer MD.
+    peer_id: Option<MdId>,
+    /// Role that we authenticate as, if the remote requires one.
+    credentials: Option<config::Credentials>,
+    /// Resumed on every connection to the upstream MD, which may have restarted.
+    session: SessionId,
+    sender: UpstreamSender,
 }
 
 impl UpstreamMD {
-    pub async fn connect(address: &str) -> Result<Self> {
+    pub async fn connect(
+        address: &str,
+        tls: Option<&config::TlsClient>,
+        compression: Option<CompressionConfig>,
+        max_frame_size: DgSizeTag,
+        config: ReconnectConfig,
+        credentials: Option<&config::Credentials>,
+    ) -> Result<Self> {
+        let dialer = Dialer {
+            address: address.to_owned(),
+            tls: tls.cloned(),
+            compression,
+            max_frame_size,
+            config,
+        };
+        let client: Client = dialer.dial().await?;
+
         Ok(Self {
-            connection: Arc::new(Mutex::new(tcp::Connection::connect(address).await?.into())),
+            dialer,
+            connection: Some(Arc::new(Mutex::new(client))),
+            backlog: Backlog::new(config.max_buffered_bytes),
+            peer_id: None,
+            credentials: credentials.cloned(),
+            session: persist::random_session(),
+            sender: UpstreamSender::new(address),
         })
     }
 
+    /// Links to a peer MD of a mesh, introducing ourselves with the given ID.
+    ///
+    /// Unlike an upstream MD, a peer that cannot be reached does not stop
+    /// us from starting, as the rest of the mesh can still be routed to.
+    /// The link starts down instead, and is reconnected to by its owner.
+    pub async fn connect_peer(
+        address: &str,
+        tls: Option<&config::TlsClient>,
+        compression: Option<CompressionConfig>,
+        max_frame_size: DgSizeTag,
+        config: ReconnectConfig,
+        credentials: Option<&config::Credentials>,
+        md_id: MdId,
+    ) -> Self {
+        let dialer = Dialer {
+            address: address.to_owned(),
+            tls: tls.cloned(),
+            compression,
+            max_frame_size,
+            config,
+        };
+        let connection: Option<Arc<Mutex<Client>>> = match dialer.dial().await {
+            Ok(client) => Some(Arc::new(Mutex::new(client))),
+            Err(err) => {
+                warn!("Failed to connect to peer MD {}: {}", address, err);
+                None
+            }
+        };
+
+        Self {
+            dialer,
+            connection,
+            backlog: Backlog::new(config.max_buffered_bytes),
+            peer_id: Some(md_id),
+            credentials: credentials.cloned(),
+            session: persist::random_session(),
+            sender: UpstreamSender::new(address),
+        }
+    }
+
+    /// Returns `true` if this is the link to a peer MD of a mesh.
+    pub fn is_peer(&self) -> bool {
+        self.peer_id.is_some()
+    }
+
+    /// Returns the name of the remote, for logging.
+    pub fn describe(&self) -> String {
+        match self.peer_id {
+            Some(_) => format!("peer MD {}", self.dialer.address),
+            None => format!("upstream MD {}", self.dialer.address),
+        }
+    }
+
+    pub fn get_address(&self) -> &str {
+        &self.dialer.address
+    }
+
+    /// Returns the depth of the send queue of the link, or of the
+    /// backlog while it is down. `None` if the connection is locked.
+    pub fn get_queue_stats(&self) -> Option<QueueStats> {
+        match &self.connection {
+            Some(client) => Some(client.try_lock().ok()?.get_queue_stats().unwrap_or_default()),
+            None => Some(QueueStats {
+                messages: self.backlog.queue.len(),
+                bytes: self.backlog.bytes,
+                dropped: self.backlog.dropped as u64,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn dialer(&self) -> Dialer {
+        self.dialer.clone()
+    }
+
+    /// Returns the send queue of the link, published while it is up.
+    pub fn sender(&self) -> UpstreamSender {
+        self.sender.clone()
+    }
+
+    /// Publishes the send queue of the link, if it is up.
+    async fn publish_sender(&self) {
+        let sender: Option<ClientSender> = match &self.connection {
+            Some(client) => client.lock().await.get_sender(),
+            None => None,
+        };
+        self.sender.sender.store(sender.map(Arc::new));
+    }
+
+    /// Spawns the receive and send tasks of the link, if it is up.
+    ///
+    /// We authenticate first, if we have credentials. The link to a peer
+    /// MD then introduces us to the peer, and the link to an upstream MD
+    /// resumes our session, and asks it for the interest of the cluster.
+    pub async fn spawn_recv_send_tasks(&mut self, tx: mpsc::Sender<ClientEvent>) -> Option<ConnectionHandle> {
+        let handle: Option<ConnectionHandle> = self.start(tx).await;
+
+        self.publish_sender().await;
+        handle
+    }
+
+    async fn start(&mut self, tx: mpsc::Sender<ClientEvent>) -> Option<ConnectionHandle> {
+        let handle: ConnectionHandle = match &self.connection {
+            Some(client) => client.lock().await.spawn_recv_send_tasks(tx).await,
+            None => return None,
+        };
+        if let Some(credentials) = &self.credentials {
+            match acl::authenticate(credentials) {
+                Ok(authenticate) => self.stage_datagram(authenticate).await,
+                Err(err) => warn!("Failed to authenticate to {}: {}", self.describe(), err),
+            }
+        }
+        if !self.is_peer() {
+            match persist::resume(self.session) {
+                Ok(resume) => self.stage_datagram(resume).await,
+                Err(err) => warn!("Failed to resume our session with {}: {}", self.describe(), err),
+            }
+        }
+        let hello: Datagram = match self.peer_id {
+            Some(md_id) => mesh::peer_hello(md_id),
+            None => interest::control(Protocol::MDWatchInterest, &[]),
+        };
+        self.stage_datagram(hello).await;
+        Some(handle)
+    }
+
+    /// Marks the link as down. Routed datagrams are buffered until
+    /// [`UpstreamMD::reconnected`] is called with a new connection.
+    pub fn disconnected(&mut self) {
+        self.connection = None;
+        self.sender.sender.store(None);
+    }
+
+    /// Takes a new connection to the upstream MD, and sends it the
+    /// given subscription state, followed by the buffered datagrams.
+    pub async fn reconnected(&mut self, client: Client, tx: mpsc::Sender<ClientEvent>, replay: Replay) {
+        info!("Reconnected to {}.", self.describe());
+
+        self.connection = Some(Arc::new(Mutex::new(client)));
+        self.start(tx).await;
+
+        for channel in replay.channels {
+            self.stage_add_channel(channel).await;
+        }
+        for range in replay.ranges {
+            self.stage_add_range(range).await;
+        }
+        // post removes are only routed upstream, never to peers
+        if !self.is_peer() {
+            for (sender, post_remove) in replay.post_removes {
+                self.stage_post_remove(sender, post_remove).await;
+            }
+        }
+
+        let (backlog, dropped) = self.backlog.take();
+
+        if dropped > 0 {
+            warn!(
+                "Dropped {} datagrams routed to {} while disconnected.",
+                dropped,
+                self.describe()
+            );
+        }
+        for dg in backlog {
+            self.stage_datagram(dg).await;
+        }
+        self.publish_sender().await;
+    }
+
     /// Pushes the given [`Datagram`] into the send queue channel
     /// for the send loop Tokio task for this TCP stream.
     ///
-    /// This is a thin wrapper of [`Client::stage_datagram()`].
-    pub async fn stage_datagram(&self, dg: Datagram) {
-        self.connection.lock().await.stage_datagram(dg).await.unwrap()
+    /// While the link is down, the datagram is buffered instead.
+    pub async fn stage_datagram(&mut self, dg: Datagram) {
+        match &self.connection {
+            Some(client) => {
+                if let Err(err) = client.lock().await.stage_datagram(dg).await {
+                    error!("Failed to send datagram to {}: {}", self.describe(), err);
+                }
+            }
+            None => self.backlog.push(dg),
+        }
+    }
+
+    /// Sends a control message uplink. While the link is down, it is
+    /// dropped, as the subscription state is replayed on reconnect.
+    async fn stage_control(&mut self, dg: Datagram) {
+        if self.connection.is_some() {
+            self.stage_datagram(dg).await;
+        }
     }
 
     /// Sends a `CONTROL_ADD_CHANNEL` control message uplink.
-    pub async fn stage_add_channel(&self, channel: Channel) {
+    pub async fn stage_add_channel(&mut self, channel: Channel) {
         let mut dg: Datagram = Datagram::default();
 
         dg.add_control_header(Protocol::MDAddChannel.into()).unwrap();
         dg.add_channel(channel).unwrap();
 
-        self.stage_datagram(dg).await;
+        self.stage_control(dg).await;
     }
 
     /// Sends a `CONTROL_ADD_RANGE` control message uplink.
-    pub async fn stage_add_range(&self, range: Range<Channel>) {
+    pub async fn stage_add_range(&mut self, range: RangeInclusive<Channel>) {
         let mut dg: Datagram = Datagram::default();
 
         dg.add_control_header(Protocol::MDAddRange.into()).unwrap();
 
-        dg.add_channel(range.start).unwrap();
-        dg.add_channel(range.end).unwrap();
+        dg.add_channel(*range.start()).unwrap();
+        dg.add_channel(*range.end()).unwrap();
 
-        self.stage_datagram(dg).await;
+        self.stage_control(dg).await;
     }
 
     /// Sends a `CONTROL_REMOVE_CHANNEL` control message uplink.
-    pub async fn stage_remove_channel(&self, channel: Channel) {
+    pub async fn stage_remove_channel(&mut self, channel: Channel) {
         let mut dg: Datagram = Datagram::default();
 
         dg.add_control_header(Protocol::MDRemoveChannel.into()).unwrap();
         dg.add_channel(channel).unwrap();
 
-        self.stage_datagram(dg).await;
+        self.stage_control(dg).await;
     }
 
     /// Sends a `CONTROL_REMOVE_RANGE` control message uplink.
-    pub async fn stage_remove_range(&self, range: Range<Channel>) {
+    pub async fn stage_remove_range(&mut self, range: RangeInclusive<Channel>) {
         let mut dg: Datagram = Datagram::default();
 
         dg.add_control_header(Protocol::MDRemoveRange.into()).unwrap();
 
-        dg.add_channel(range.start).unwrap();
-        dg.add_channel(range.end).unwrap();
+        dg.add_channel(*range.start()).unwrap();
+        dg.add_channel(*range.end()).unwrap();
 
-        self.stage_datagram(dg).await;
+        self.stage_control(dg).await;
     }
 
     /// Sends a `CONTROL_ADD_POST_REMOVE` control message uplink.
-    pub async fn stage_post_remove(&self, sender: Channel, post_remove: Datagram) {
+    pub async fn stage_post_remove(&mut self, sender: Channel, post_remove: Datagram) {
         let mut dg: Datagram = Datagram::default();
 
         dg.add_control_header(Protocol::MDAddPostRemove.into()).unwrap();
 
         dg.add_channel(sender).unwrap();
-        dg.add_blob(post_remove.get_data()).unwrap();
+        dg.add_blob(post_remove.get_buffer()).unwrap();
 
-        self.stage_datagram(dg).await;
+        self.stage_control(dg).await;
     }
 
     /// Sends a `CONTROL_CLEAR_POST_REMOVES` control message uplink.
-    pub async fn recall_post_removes(&self, sender: Channel) {
+    pub async fn recall_post_removes(&mut self, sender: Channel) {
         let mut dg: Datagram = Datagram::default();
 
         dg.add_control_header(Protocol::MDClearPostRemoves.into())
@@ -116,6 +540,49 @@ impl UpstreamMD {
 
         dg.add_channel(sender).unwrap();
 
-        self.stage_datagram(dg).await;
+        self.stage_control(dg).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(size: usize) -> Datagram {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_data(vec![0; size]).unwrap();
+        dg
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let min: Duration = Duration::from_millis(100);
+        let max: Duration = Duration::from_millis(1000);
+        let mut backoff = Backoff::new(min, max);
+
+        for base in [100, 200, 400, 800, 1000, 1000] {
+            let base: Duration = Duration::from_millis(base);
+            let delay: Duration = backoff.next_delay();
+
+            assert!(delay <= base, "{:?} is over {:?}", delay, base);
+            assert!(delay >= base / 2, "{:?} is under half of {:?}", delay, base);
+        }
+    }
+
+    #[test]
+    fn backlog_drops_oldest() {
+        let mut backlog = Backlog::new(10);
+
+        backlog.push(datagram(4));
+        backlog.push(datagram(5));
+        backlog.push(datagram(3)); // over budget, drops the first
+        backlog.push(datagram(11)); // larger than the budget
+
+        let (queue, dropped) = backlog.take();
+        let sizes: Vec<usize> = queue.iter().map(Datagram::size).collect();
+
+        assert_eq!(sizes, [5, 3]);
+        assert_eq!(dropped, 2);
+        assert_eq!(backlog.take().1, 0);
     }
 }
@@ -0,0 +1,167 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Measures the throughput and memory use of many connections.
+//!
+//! Simulates 10,000 connections over in-memory pipes, each with its
+//! own [`Client`] receive loop. Reports the resident set size (RSS)
+//! of the process once the connections are idle, then the rate at
+//! which datagrams are received while every connection is busy.
+//!
+//! Run with `cargo bench -p donet-network --bench connections`.
+//! RSS is read from `/proc/self/status`, so it is only reported on Linux.
+
+use donet_network::addr::PeerAddr;
+use donet_network::{Client, ClientEvent};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const CONNECTIONS: usize = 10_000;
+const DATAGRAMS_PER_CONNECTION: usize = 100;
+const DATAGRAM_SIZE: usize = 128;
+
+/// Largest number of bytes buffered by each in-memory pipe.
+const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Returns the resident set size of this process, in bytes.
+fn rss() -> Option<usize> {
+    memory_status("VmRSS:")
+}
+
+/// Returns the largest resident set size of this process so far, in bytes.
+fn peak_rss() -> Option<usize> {
+    memory_status("VmHWM:")
+}
+
+/// Reads a size, given in kilobytes, from `/proc/self/status`.
+fn memory_status(field: &str) -> Option<usize> {
+    let status: String = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line: &str = status.lines().find(|line| line.starts_with(field))?;
+    let kilobytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+fn format_bytes(bytes: Option<usize>) -> String {
+    match bytes {
+        Some(bytes) if bytes < 1024 * 1024 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+        None => "n/a".to_owned(),
+    }
+}
+
+/// Encodes the datagrams that are written to each connection.
+fn frames() -> Vec<u8> {
+    let mut frames: Vec<u8> = vec![];
+
+    for _ in 0..DATAGRAMS_PER_CONNECTION {
+        frames.extend_from_slice(&(DATAGRAM_SIZE as u16).to_le_bytes());
+        frames.extend_from_slice(&[0xAB; DATAGRAM_SIZE]);
+    }
+    frames
+}
+
+async fn run() {
+    let rss_start: Option<usize> = rss();
+    let (tx, mut rx) = mpsc::channel::<ClientEvent>(4096);
+
+    let mut clients: Vec<Client<DuplexStream>> = Vec::with_capacity(CONNECTIONS);
+    let mut remotes: Vec<DuplexStream> = Vec::with_capacity(CONNECTIONS);
+
+    for _ in 0..CONNECTIONS {
+        let (local, remote) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+        let peer: PeerAddr = PeerAddr::next_memory();
+
+        let mut client: Client<DuplexStream> = Client::new(peer, peer, local);
+        client.spawn_recv_send_tasks(tx.clone()).await;
+
+        clients.push(client);
+        remotes.push(remote);
+    }
+
+    // let every receive loop reach its first read
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let rss_idle: Option<usize> = rss();
+    let per_connection: Option<usize> = rss_idle
+        .zip(rss_start)
+        .map(|(idle, start)| idle.saturating_sub(start) / CONNECTIONS);
+
+    println!("{} idle connections", CONNECTIONS);
+    println!("  rss:            {}", format_bytes(rss_idle));
+    println!("  per connection: {}", format_bytes(per_connection));
+
+    let frames: &'static [u8] = Box::leak(frames().into_boxed_slice());
+    let start: Instant = Instant::now();
+
+    let writers: Vec<JoinHandle<DuplexStream>> = remotes
+        .into_iter()
+        .map(|mut remote| {
+            tokio::spawn(async move {
+                remote.write_all(frames).await.unwrap();
+                remote
+            })
+        })
+        .collect();
+
+    let total: usize = CONNECTIONS * DATAGRAMS_PER_CONNECTION;
+    let mut received: usize = 0;
+
+    while received < total {
+        match rx.recv().await {
+            Some(ClientEvent::Received(_)) => received += 1,
+            Some(ClientEvent::Disconnected(disconnect)) => {
+                panic!("Connection lost during benchmark: {}", disconnect.reason)
+            }
+            None => unreachable!("The benchmark holds a sender."),
+        }
+    }
+    let elapsed: Duration = start.elapsed();
+    let bytes: usize = total * DATAGRAM_SIZE;
+
+    println!(
+        "{} datagrams of {} bytes over {} busy connections",
+        total, DATAGRAM_SIZE, CONNECTIONS
+    );
+    println!("  elapsed:    {:.3} s", elapsed.as_secs_f64());
+    println!(
+        "  throughput: {:.0} datagrams/s",
+        total as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "  throughput: {:.1} MiB/s",
+        bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    );
+    println!("  peak rss:   {}", format_bytes(peak_rss()));
+
+    // the pipes are kept open until the datagrams are received
+    drop(writers);
+
+    for client in &mut clients {
+        client.close();
+    }
+}
+
+fn main() {
+    // `cargo bench` passes arguments meant for a test harness
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to build the tokio runtime.");
+    runtime.block_on(run());
+}
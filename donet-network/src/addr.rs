@@ -0,0 +1,132 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Addresses of listening sockets and connected peers.
+//!
+//! Services may be reached over TCP, with a `<host>:<port>` address,
+//! or, on the same host, over a Unix domain socket, with a
+//! `unix:<path>` address. Within one process, such as in tests, they
+//! may be reached in memory, with a `memory:<name>` address. Framing
+//! is identical on all of them.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Prefix of addresses that name a Unix domain socket.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Prefix of addresses that name an in-memory listener.
+pub const MEMORY_PREFIX: &str = "memory:";
+
+/// Identifies the remote end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    /// Remote IPv4/6 address of a TCP connection.
+    Inet(SocketAddr),
+    /// A connection over a Unix domain socket. Unix peers
+    /// are usually unnamed, so each connection is numbered.
+    Unix(u64),
+    /// An in-memory connection. Each connection is numbered.
+    Memory(u64),
+}
+
+impl PeerAddr {
+    /// Numbers a new Unix domain socket connection.
+    pub fn next_unix() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self::Unix(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Numbers a new in-memory connection.
+    pub fn next_memory() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self::Memory(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(value: SocketAddr) -> Self {
+        Self::Inet(value)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inet(addr) => addr.fmt(f),
+            Self::Unix(id) => write!(f, "unix#{}", id),
+            Self::Memory(id) => write!(f, "memory#{}", id),
+        }
+    }
+}
+
+/// An address given to [`Acceptor::bind`] or [`Connection::connect`].
+///
+/// [`Acceptor::bind`]: crate::tcp::Acceptor::bind
+/// [`Connection::connect`]: crate::tcp::Connection::connect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint<'a> {
+    /// A `<host>:<port>` address.
+    Tcp(&'a str),
+    /// The path of a Unix domain socket.
+    Unix(&'a Path),
+    /// The name of an in-memory listener.
+    Memory(&'a str),
+}
+
+impl<'a> Endpoint<'a> {
+    pub fn parse(uri: &'a str) -> Self {
+        if let Some(path) = uri.strip_prefix(UNIX_PREFIX) {
+            return Self::Unix(Path::new(path));
+        }
+        match uri.strip_prefix(MEMORY_PREFIX) {
+            Some(name) => Self::Memory(name),
+            None => Self::Tcp(uri),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_endpoints() {
+        assert_eq!(Endpoint::parse("127.0.0.1:7199"), Endpoint::Tcp("127.0.0.1:7199"));
+        assert_eq!(Endpoint::parse("[::1]:7199"), Endpoint::Tcp("[::1]:7199"));
+        assert_eq!(
+            Endpoint::parse("unix:/run/donet/md.sock"),
+            Endpoint::Unix(Path::new("/run/donet/md.sock"))
+        );
+        assert_eq!(
+            Endpoint::parse("unix:md.sock"),
+            Endpoint::Unix(Path::new("md.sock"))
+        );
+        assert_eq!(Endpoint::parse("memory:md"), Endpoint::Memory("md"));
+    }
+
+    #[test]
+    fn unique_unix_peers() {
+        assert_ne!(PeerAddr::next_unix(), PeerAddr::next_unix());
+    }
+}
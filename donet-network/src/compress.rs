@@ -0,0 +1,475 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Negotiated compression of the datagrams sent over a connection.
+//!
+//! Compression is opt-in on both ends of a connection. The end that
+//! offers compression sends an offer as its first frame, listing the
+//! codecs it can decompress. An end that has compression configured,
+//! and receives an offer for its codec, replies with an accept and
+//! compresses the datagrams it sends from then on. The offering end
+//! compresses the datagrams it sends once it receives the accept.
+//!
+//! Only connections that opted into compression accept control frames,
+//! so a remote that did not opt in closes the connection on an offer.
+//! An end that only accepts compression sends nothing until offered
+//! it, so it may opt in on connections to any remote.
+//!
+//! Offers, accepts, and compressed datagrams are sent as control
+//! frames (see [`crate::frame`]). The first byte of a control frame's
+//! body is its kind. A compressed datagram is followed by the codec,
+//! and the size of the datagram as a little-endian [`DgSizeTag`].
+//!
+//! Datagrams smaller than the configured threshold, or that
+//! do not get smaller when compressed, are sent as they are.
+
+use crate::frame::{encode_control, DG_SIZE_TAG_LEN};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use donet_core::globals::DgSizeTag;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+
+const KIND_OFFER: u8 = 1;
+const KIND_ACCEPT: u8 = 2;
+const KIND_COMPRESSED: u8 = 3;
+
+/// Size of the control frame header, followed by the kind, codec,
+/// and datagram size that prefix a compressed datagram.
+const COMPRESSED_HEADER_LEN: usize = DG_SIZE_TAG_LEN * 3 + 2;
+
+/// A compression algorithm for datagrams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// LZ4 block compression, fast enough to keep up with a busy link.
+    #[default]
+    Lz4,
+}
+
+impl Codec {
+    /// Every codec this version can decompress, in order of preference.
+    pub const ALL: [Codec; 1] = [Codec::Lz4];
+
+    fn id(self) -> u8 {
+        match self {
+            Self::Lz4 => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Self::Lz4),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(format!("unknown compression codec '{}'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+/// The compression settings of one end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub codec: Codec,
+    /// Datagrams smaller than this, in bytes, are sent uncompressed.
+    pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codec: Codec::default(),
+            threshold: 256,
+        }
+    }
+}
+
+/// A snapshot of the compression of datagrams sent on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompressionStats {
+    /// Number of datagrams sent compressed.
+    pub compressed: u64,
+    /// Number of datagrams sent uncompressed.
+    pub uncompressed: u64,
+    /// Total size of the sent datagrams, in bytes.
+    pub datagram_bytes: u64,
+    /// Total size of the frames the sent datagrams were written as, in bytes.
+    pub wire_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Returns the size of the written frames relative to
+    /// the size of their datagrams. Lower is better.
+    pub fn ratio(&self) -> f64 {
+        if self.datagram_bytes == 0 {
+            return 1.0;
+        }
+        self.wire_bytes as f64 / self.datagram_bytes as f64
+    }
+}
+
+/// Counters behind [`CompressionStats`], shared by a
+/// [`Client`] and the send loop task that updates them.
+///
+/// [`Client`]: crate::Client
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounters {
+    compressed: AtomicU64,
+    uncompressed: AtomicU64,
+    datagram_bytes: AtomicU64,
+    wire_bytes: AtomicU64,
+}
+
+impl StatsCounters {
+    fn record(&self, compressed: bool, datagram_len: usize, wire_len: usize) {
+        let frames: &AtomicU64 = match compressed {
+            true => &self.compressed,
+            false => &self.uncompressed,
+        };
+        frames.fetch_add(1, Ordering::Relaxed);
+        self.datagram_bytes
+            .fetch_add(datagram_len as u64, Ordering::Relaxed);
+        self.wire_bytes.fetch_add(wire_len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> CompressionStats {
+        CompressionStats {
+            compressed: self.compressed.load(Ordering::Relaxed),
+            uncompressed: self.uncompressed.load(Ordering::Relaxed),
+            datagram_bytes: self.datagram_bytes.load(Ordering::Relaxed),
+            wire_bytes: self.wire_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A violation of the compression protocol by the remote peer.
+#[derive(Debug, Error, PartialEq)]
+pub enum CompressionError {
+    #[error("received a control frame of unknown kind {0}")]
+    UnknownKind(u8),
+    #[error("received a truncated control frame")]
+    Truncated,
+    #[error("received a frame compressed with unknown codec {0}")]
+    UnknownCodec(u8),
+    #[error("received a compressed frame before compression was negotiated")]
+    NotNegotiated,
+    #[error("received a compressed datagram of {size} bytes, larger than the maximum of {max} bytes")]
+    TooLarge { size: DgSizeTag, max: DgSizeTag },
+    #[error("received a compressed datagram that failed to decompress")]
+    Corrupt,
+}
+
+/// A control frame of the compression protocol.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Control {
+    /// The remote can decompress these codecs. Unknown codecs are left out.
+    Offer(Vec<Codec>),
+    /// The remote will compress the datagrams it sends with this codec.
+    Accept(Codec),
+    Compressed {
+        codec: Codec,
+        size: DgSizeTag,
+        data: Bytes,
+    },
+}
+
+impl Control {
+    /// Parses the body of a control frame.
+    pub(crate) fn parse(mut body: Bytes) -> Result<Self, CompressionError> {
+        let kind: u8 = body.get_u8(); // control frames are never empty
+
+        match kind {
+            KIND_OFFER => Ok(Self::Offer(
+                body.iter().filter_map(|id| Codec::from_id(*id)).collect(),
+            )),
+            KIND_ACCEPT | KIND_COMPRESSED => {
+                if body.is_empty() {
+                    return Err(CompressionError::Truncated);
+                }
+                let id: u8 = body.get_u8();
+                let codec: Codec = Codec::from_id(id).ok_or(CompressionError::UnknownCodec(id))?;
+
+                if kind == KIND_ACCEPT {
+                    return Ok(Self::Accept(codec));
+                }
+                if body.len() < DG_SIZE_TAG_LEN {
+                    return Err(CompressionError::Truncated);
+                }
+                let size: DgSizeTag = body.get_u16_le();
+
+                Ok(Self::Compressed {
+                    codec,
+                    size,
+                    data: body,
+                })
+            }
+            kind => Err(CompressionError::UnknownKind(kind)),
+        }
+    }
+
+    /// Appends an offer of every codec in [`Codec::ALL`] to the buffer.
+    pub(crate) fn encode_offer(buffer: &mut BytesMut) {
+        let mut body: Vec<u8> = vec![KIND_OFFER];
+        body.extend(Codec::ALL.iter().map(|codec| codec.id()));
+
+        encode_control(buffer, &body);
+    }
+
+    /// Appends an accept of the given codec to the buffer.
+    pub(crate) fn encode_accept(buffer: &mut BytesMut, codec: Codec) {
+        encode_control(buffer, &[KIND_ACCEPT, codec.id()]);
+    }
+}
+
+/// Appends a datagram to the buffer, compressed if it is at least
+/// the configured threshold and gets smaller, or else as it is.
+pub(crate) fn encode_datagram(
+    buffer: &mut BytesMut,
+    payload: &[u8],
+    config: Option<&CompressionConfig>,
+    stats: Option<&StatsCounters>,
+) {
+    let start: usize = buffer.len();
+
+    let compressed: bool = match config {
+        Some(config) if payload.len() >= config.threshold => compress(buffer, payload, config.codec),
+        _ => false,
+    };
+    if !compressed {
+        buffer.put_u16_le(payload.len() as DgSizeTag);
+        buffer.extend_from_slice(payload);
+    }
+
+    if let Some(stats) = stats {
+        stats.record(compressed, payload.len(), buffer.len() - start);
+    }
+}
+
+/// Appends the payload to the buffer as a compressed datagram, unless
+/// it would not be smaller than the uncompressed frame.
+fn compress(buffer: &mut BytesMut, payload: &[u8], codec: Codec) -> bool {
+    let start: usize = buffer.len();
+    let limit: usize = DG_SIZE_TAG_LEN + payload.len();
+
+    buffer.put_u16_le(0);
+    buffer.put_u16_le(0); // body size, written once known
+    buffer.put_u8(KIND_COMPRESSED);
+    buffer.put_u8(codec.id());
+    buffer.put_u16_le(payload.len() as DgSizeTag);
+
+    let data_start: usize = buffer.len();
+
+    let written: Option<usize> = match codec {
+        Codec::Lz4 => {
+            buffer.resize(
+                data_start + lz4_flex::block::get_maximum_output_size(payload.len()),
+                0,
+            );
+            lz4_flex::block::compress_into(payload, &mut buffer[data_start..]).ok()
+        }
+    };
+
+    match written {
+        Some(len) if COMPRESSED_HEADER_LEN + len < limit => {
+            buffer.truncate(data_start + len);
+
+            // smaller than the uncompressed frame, so the body size fits
+            let body_len: DgSizeTag = (buffer.len() - start - DG_SIZE_TAG_LEN * 2) as DgSizeTag;
+            buffer[start + DG_SIZE_TAG_LEN..start + DG_SIZE_TAG_LEN * 2]
+                .copy_from_slice(&body_len.to_le_bytes());
+            true
+        }
+        _ => {
+            buffer.truncate(start);
+            false
+        }
+    }
+}
+
+/// Decompresses a datagram of `size` bytes, rejecting
+/// datagrams larger than `max_frame_size` bytes.
+pub(crate) fn decompress(
+    codec: Codec,
+    size: DgSizeTag,
+    data: &[u8],
+    max_frame_size: DgSizeTag,
+) -> Result<Bytes, CompressionError> {
+    if size > max_frame_size {
+        return Err(CompressionError::TooLarge {
+            size,
+            max: max_frame_size,
+        });
+    }
+    let mut payload: Vec<u8> = vec![0; usize::from(size)];
+
+    let written: usize = match codec {
+        Codec::Lz4 => {
+            lz4_flex::block::decompress_into(data, &mut payload).map_err(|_| CompressionError::Corrupt)?
+        }
+    };
+
+    if written != payload.len() {
+        return Err(CompressionError::Corrupt);
+    }
+    Ok(payload.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Frame, FrameDecoder};
+
+    /// Encodes the payload with the given config, and decodes it again.
+    fn round_trip(payload: &[u8], config: &CompressionConfig) -> (Frame, CompressionStats) {
+        let stats = StatsCounters::default();
+        let mut buffer: BytesMut = BytesMut::new();
+
+        encode_datagram(&mut buffer, payload, Some(config), Some(&stats));
+
+        let mut decoder: FrameDecoder = FrameDecoder::default();
+        decoder.enable_control_frames();
+        decoder.extend(&buffer);
+
+        let frame: Frame = decoder.decode_frame().unwrap().unwrap();
+        assert_eq!(decoder.buffered(), 0);
+
+        (frame, stats.snapshot())
+    }
+
+    #[test]
+    fn compress_above_threshold() {
+        let payload: Vec<u8> = b"donet ".repeat(100);
+        let (frame, stats) = round_trip(&payload, &CompressionConfig::default());
+
+        let Frame::Control(body) = frame else {
+            panic!("Expected a compressed datagram.");
+        };
+        let Control::Compressed { codec, size, data } = Control::parse(body).unwrap() else {
+            panic!("Expected a compressed datagram.");
+        };
+        assert_eq!(
+            decompress(codec, size, &data, DgSizeTag::MAX).unwrap(),
+            Bytes::from(payload.clone())
+        );
+
+        assert_eq!(stats.compressed, 1);
+        assert_eq!(stats.datagram_bytes, payload.len() as u64);
+        assert!(stats.ratio() < 0.5);
+    }
+
+    #[test]
+    fn uncompressed_below_threshold() {
+        let payload: Vec<u8> = vec![0; 100];
+        let (frame, stats) = round_trip(&payload, &CompressionConfig::default());
+
+        assert_eq!(frame, Frame::Datagram(Bytes::from(payload)));
+        assert_eq!(stats.uncompressed, 1);
+        assert_eq!(stats.wire_bytes, 102);
+    }
+
+    #[test]
+    fn uncompressed_if_not_smaller() {
+        // bytes that do not repeat do not compress
+        let payload: Vec<u8> = (0..=255).collect();
+        let config = CompressionConfig {
+            threshold: 0,
+            ..Default::default()
+        };
+        let (frame, stats) = round_trip(&payload, &config);
+
+        assert_eq!(frame, Frame::Datagram(Bytes::from(payload)));
+        assert_eq!(stats.ratio(), 258.0 / 256.0);
+    }
+
+    #[test]
+    fn parse_negotiation() {
+        let mut buffer: BytesMut = BytesMut::new();
+        Control::encode_offer(&mut buffer);
+        Control::encode_accept(&mut buffer, Codec::Lz4);
+
+        let mut decoder: FrameDecoder = FrameDecoder::default();
+        decoder.enable_control_frames();
+        decoder.extend(&buffer);
+
+        let mut parse = || match decoder.decode_frame().unwrap() {
+            Some(Frame::Control(body)) => Control::parse(body),
+            frame => panic!("Expected a control frame, got {:?}.", frame),
+        };
+        assert_eq!(parse(), Ok(Control::Offer(Codec::ALL.to_vec())));
+        assert_eq!(parse(), Ok(Control::Accept(Codec::Lz4)));
+
+        // codecs from newer versions are left out of offers
+        let offer = Bytes::from_static(&[KIND_OFFER, 0xFF, 1]);
+        assert_eq!(Control::parse(offer), Ok(Control::Offer(vec![Codec::Lz4])));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let parse = |body: &'static [u8]| Control::parse(Bytes::from_static(body));
+
+        assert_eq!(parse(&[0xFF]), Err(CompressionError::UnknownKind(0xFF)));
+        assert_eq!(parse(&[KIND_ACCEPT]), Err(CompressionError::Truncated));
+        assert_eq!(
+            parse(&[KIND_ACCEPT, 0xFF]),
+            Err(CompressionError::UnknownCodec(0xFF))
+        );
+        assert_eq!(
+            parse(&[KIND_COMPRESSED, 1, 0x10]),
+            Err(CompressionError::Truncated)
+        );
+    }
+
+    #[test]
+    fn decompress_invalid() {
+        let mut buffer: BytesMut = BytesMut::new();
+        assert!(compress(&mut buffer, &[0xAA; 1000], Codec::Lz4));
+
+        let data: &[u8] = &buffer[COMPRESSED_HEADER_LEN..];
+
+        assert_eq!(
+            decompress(Codec::Lz4, 1000, data, 999),
+            Err(CompressionError::TooLarge { size: 1000, max: 999 })
+        );
+        // the size does not match the compressed data
+        assert_eq!(
+            decompress(Codec::Lz4, 999, data, 1000),
+            Err(CompressionError::Corrupt)
+        );
+        assert_eq!(
+            decompress(Codec::Lz4, 1000, &data[1..], 1000),
+            Err(CompressionError::Corrupt)
+        );
+    }
+}
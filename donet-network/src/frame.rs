@@ -0,0 +1,507 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Length-prefixed framing of datagrams over a byte stream.
+//!
+//! Every datagram sent over a stream is prefixed with its
+//! length as a little-endian [`DgSizeTag`]. A stream read may
+//! end anywhere, including partway through a size tag, so the
+//! [`FrameDecoder`] buffers received bytes until a whole frame
+//! is available.
+//!
+//! A size tag of 0 starts a control frame instead, which carries
+//! connection-level messages such as a compression negotiation
+//! (see [`crate::compress`]). It is followed by the length of the
+//! control frame's body as another size tag, then the body itself.
+//! Only [`FrameDecoder::decode_frame`] accepts control frames, and
+//! only on connections that enabled them with
+//! [`FrameDecoder::enable_control_frames`]. Elsewhere, a size tag of 0
+//! is rejected like any other empty frame.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use donet_core::globals::{DgSizeTag, DG_SIZE_MAX};
+use thiserror::Error;
+
+/// Size in bytes of the length tag that prefixes every datagram.
+pub const DG_SIZE_TAG_LEN: usize = std::mem::size_of::<DgSizeTag>();
+
+/// A violation of the framing protocol by the remote peer.
+///
+/// The stream cannot be resynchronized after one
+/// of these, so the connection should be closed.
+#[derive(Debug, Error, PartialEq)]
+pub enum FrameError {
+    #[error("received a frame with a size tag of 0")]
+    EmptyFrame,
+    #[error("received a control frame with an empty body")]
+    EmptyControlFrame,
+    #[error("received a frame of {size} bytes, larger than the maximum of {max} bytes")]
+    FrameTooLarge { size: DgSizeTag, max: DgSizeTag },
+}
+
+/// A frame taken from the stream by [`FrameDecoder::decode_frame`].
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    /// The payload of a datagram frame.
+    Datagram(Bytes),
+    /// The body of a control frame.
+    Control(Bytes),
+}
+
+/// Appends a control frame with the given body to the buffer.
+pub fn encode_control(buffer: &mut BytesMut, body: &[u8]) {
+    debug_assert!(!body.is_empty() && body.len() <= usize::from(DgSizeTag::MAX));
+
+    buffer.put_u16_le(0);
+    buffer.put_u16_le(body.len() as DgSizeTag);
+    buffer.extend_from_slice(body);
+}
+
+/// Size in bytes of the largest frame, a control frame with a full body.
+const MAX_FRAME_LEN: usize = DG_SIZE_TAG_LEN * 2 + DgSizeTag::MAX as usize;
+
+/// Sizing of the buffer that a connection reads into.
+///
+/// The buffer starts small, so that idle connections hold little
+/// memory. While reads fill it, the read size doubles, and while
+/// reads use little of it, the read size halves again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadBufferConfig {
+    /// Smallest number of bytes read at once.
+    pub min_read_size: usize,
+    /// Most bytes held by the buffer, including those of a partially
+    /// received frame. Raised if needed to fit the largest frame.
+    pub max_bytes: usize,
+}
+
+impl Default for ReadBufferConfig {
+    fn default() -> Self {
+        Self {
+            min_read_size: 4 * 1024, // 4 KiB
+            max_bytes: 256 * 1024,   // 256 KiB
+        }
+    }
+}
+
+/// Splits a stream of bytes into datagram frames.
+///
+/// Bytes are appended to [`FrameDecoder::buffer_mut`] as they
+/// are read, then frames are taken with [`FrameDecoder::decode`].
+/// A reader that reserves room with [`FrameDecoder::prepare_read`]
+/// before each read keeps the buffer within its [`ReadBufferConfig`].
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: BytesMut,
+    max_frame_size: DgSizeTag,
+    buffer_config: ReadBufferConfig,
+    /// Bytes to read next, adapted to the size of recent reads.
+    read_size: usize,
+    /// Whether a size tag of 0 starts a control frame.
+    control_frames: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new(DG_SIZE_MAX)
+    }
+}
+
+impl FrameDecoder {
+    /// Creates a [`FrameDecoder`] that rejects frames
+    /// with a payload larger than `max_frame_size` bytes.
+    pub fn new(max_frame_size: DgSizeTag) -> Self {
+        Self::with_buffer_config(max_frame_size, ReadBufferConfig::default())
+    }
+
+    /// Creates a [`FrameDecoder`] whose buffer is sized by the given config.
+    pub fn with_buffer_config(max_frame_size: DgSizeTag, mut config: ReadBufferConfig) -> Self {
+        config.min_read_size = config.min_read_size.max(1);
+        config.max_bytes = config.max_bytes.max(MAX_FRAME_LEN).max(config.min_read_size);
+
+        Self {
+            buffer: BytesMut::new(),
+            max_frame_size,
+            buffer_config: config,
+            read_size: config.min_read_size,
+            control_frames: false,
+        }
+    }
+
+    /// Accepts control frames in [`FrameDecoder::decode_frame`].
+    pub fn enable_control_frames(&mut self) {
+        self.control_frames = true;
+    }
+
+    pub fn get_max_frame_size(&self) -> DgSizeTag {
+        self.max_frame_size
+    }
+
+    /// Returns the buffer that received bytes should be appended to.
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buffer
+    }
+
+    /// Appends received bytes to the buffer.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Reserves room in the buffer for the next read, and
+    /// returns the most bytes that should be read into it.
+    ///
+    /// If the buffer is empty and larger than needed, its memory is
+    /// released first, so a connection that goes idle after a burst
+    /// does not hold on to it.
+    pub fn prepare_read(&mut self) -> usize {
+        if self.buffer.is_empty() && self.buffer.capacity() > self.read_size {
+            self.buffer = BytesMut::new();
+        }
+        // frames never exceed the buffer limit, so there is always room
+        let limit: usize = self
+            .read_size
+            .min(self.buffer_config.max_bytes - self.buffer.len());
+
+        self.buffer.reserve(limit);
+        limit
+    }
+
+    /// Adapts the size of the next read to a read of `len` bytes,
+    /// out of the `limit` returned by [`FrameDecoder::prepare_read`].
+    pub fn record_read(&mut self, len: usize, limit: usize) {
+        let config: &ReadBufferConfig = &self.buffer_config;
+
+        if len == limit {
+            self.read_size = (self.read_size * 2).min(config.max_bytes);
+        } else if len < self.read_size / 4 {
+            self.read_size = (self.read_size / 2).max(config.min_read_size);
+        }
+    }
+
+    /// Returns the number of bytes the next read will reserve.
+    pub fn get_read_size(&self) -> usize {
+        self.read_size
+    }
+
+    /// Returns the number of buffered bytes that
+    /// are not yet part of a decoded frame.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Takes the next whole frame from the buffer, without its size tag.
+    ///
+    /// Returns `Ok(None)` if more bytes are needed. A size tag is
+    /// checked as soon as it is received, so an oversized frame is
+    /// rejected before its payload is buffered.
+    pub fn decode(&mut self) -> Result<Option<Bytes>, FrameError> {
+        if self.buffer.len() < DG_SIZE_TAG_LEN {
+            return Ok(None);
+        }
+        let size: DgSizeTag = DgSizeTag::from_le_bytes([self.buffer[0], self.buffer[1]]);
+
+        if size == 0 {
+            return Err(FrameError::EmptyFrame);
+        }
+        if size > self.max_frame_size {
+            return Err(FrameError::FrameTooLarge {
+                size,
+                max: self.max_frame_size,
+            });
+        }
+        Ok(self.split_frame(DG_SIZE_TAG_LEN, size))
+    }
+
+    /// Takes the next whole datagram or control frame from the buffer.
+    ///
+    /// Behaves like [`FrameDecoder::decode`], except that a size tag
+    /// of 0 starts a control frame if control frames are enabled. The
+    /// body of a control frame is not limited by the maximum frame
+    /// size, as it is at most [`DgSizeTag::MAX`] bytes long.
+    pub fn decode_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        if self.buffer.len() < DG_SIZE_TAG_LEN {
+            return Ok(None);
+        }
+        if !self.control_frames || self.buffer[..DG_SIZE_TAG_LEN] != [0; DG_SIZE_TAG_LEN] {
+            return Ok(self.decode()?.map(Frame::Datagram));
+        }
+        let header_len: usize = DG_SIZE_TAG_LEN * 2;
+
+        if self.buffer.len() < header_len {
+            return Ok(None);
+        }
+        let size: DgSizeTag = DgSizeTag::from_le_bytes([self.buffer[2], self.buffer[3]]);
+
+        if size == 0 {
+            return Err(FrameError::EmptyControlFrame);
+        }
+        Ok(self.split_frame(header_len, size).map(Frame::Control))
+    }
+
+    /// Splits off a frame of `size` bytes after a header of `header_len`
+    /// bytes, or returns `None` if the whole frame is not yet buffered.
+    fn split_frame(&mut self, header_len: usize, size: DgSizeTag) -> Option<Bytes> {
+        let frame_len: usize = header_len + usize::from(size);
+
+        if self.buffer.len() < frame_len {
+            // reserve the rest of this frame, so it is read in as few reads as possible
+            self.buffer.reserve(frame_len - self.buffer.len());
+            return None;
+        }
+        self.buffer.advance(header_len);
+
+        // the frame is split off without copying the payload
+        Some(self.buffer.split_to(size.into()).freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Prefixes each payload with its size tag.
+    fn encode(payloads: &[Vec<u8>]) -> Vec<u8> {
+        let mut stream: Vec<u8> = vec![];
+
+        for payload in payloads {
+            stream.extend_from_slice(&(payload.len() as DgSizeTag).to_le_bytes());
+            stream.extend_from_slice(payload);
+        }
+        stream
+    }
+
+    /// Feeds the stream to a decoder in chunks split at the given
+    /// points, and collects the frames decoded after each chunk.
+    fn decode_chunks(
+        decoder: &mut FrameDecoder,
+        stream: &[u8],
+        splits: &[usize],
+    ) -> Result<Vec<Vec<u8>>, FrameError> {
+        let mut splits: Vec<usize> = splits.iter().map(|i| i % (stream.len() + 1)).collect();
+        splits.sort_unstable();
+        splits.push(stream.len());
+
+        let mut frames: Vec<Vec<u8>> = vec![];
+        let mut start: usize = 0;
+
+        for end in splits {
+            decoder.extend(&stream[start..end]);
+            start = end;
+
+            while let Some(frame) = decoder.decode()? {
+                frames.push(frame.to_vec());
+            }
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn partial_size_tag() {
+        let mut decoder: FrameDecoder = FrameDecoder::default();
+
+        decoder.extend(&[0x02]);
+        assert_eq!(decoder.decode(), Ok(None));
+
+        decoder.extend(&[0x00, 0xAA]);
+        assert_eq!(decoder.decode(), Ok(None));
+
+        decoder.extend(&[0xBB, 0x01]);
+        assert_eq!(decoder.decode(), Ok(Some(Bytes::from_static(&[0xAA, 0xBB]))));
+        assert_eq!(decoder.decode(), Ok(None));
+        assert_eq!(decoder.buffered(), 1);
+    }
+
+    #[test]
+    fn empty_frame() {
+        let mut decoder: FrameDecoder = FrameDecoder::default();
+        decoder.extend(&[0x00, 0x00]);
+
+        assert_eq!(decoder.decode(), Err(FrameError::EmptyFrame));
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let mut decoder: FrameDecoder = FrameDecoder::new(4);
+        decoder.extend(&[0x05, 0x00]);
+
+        // rejected from the size tag alone
+        assert_eq!(
+            decoder.decode(),
+            Err(FrameError::FrameTooLarge { size: 5, max: 4 })
+        );
+    }
+
+    #[test]
+    fn control_frames() {
+        let mut stream: BytesMut = BytesMut::new();
+        encode_control(&mut stream, &[0x01, 0x02, 0x03]);
+        stream.extend_from_slice(&encode(&[vec![0xAA]]));
+
+        let mut decoder: FrameDecoder = FrameDecoder::new(1);
+        decoder.enable_control_frames();
+
+        // a partial control frame header
+        decoder.extend(&stream[..3]);
+        assert_eq!(decoder.decode_frame(), Ok(None));
+
+        // the control frame body is larger than the maximum datagram size
+        decoder.extend(&stream[3..]);
+        assert_eq!(
+            decoder.decode_frame(),
+            Ok(Some(Frame::Control(Bytes::from_static(&[0x01, 0x02, 0x03]))))
+        );
+        assert_eq!(
+            decoder.decode_frame(),
+            Ok(Some(Frame::Datagram(Bytes::from_static(&[0xAA]))))
+        );
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn adaptive_read_size() {
+        let mut decoder: FrameDecoder = FrameDecoder::with_buffer_config(
+            DG_SIZE_MAX,
+            ReadBufferConfig {
+                min_read_size: 1024,
+                max_bytes: 0, // raised to fit the largest frame
+            },
+        );
+        assert_eq!(decoder.prepare_read(), 1024);
+
+        // full reads double the read size, up to the limit
+        for _ in 0..10 {
+            let limit: usize = decoder.prepare_read();
+            decoder.record_read(limit, limit);
+        }
+        assert_eq!(decoder.get_read_size(), MAX_FRAME_LEN);
+
+        // small reads halve it again
+        for _ in 0..10 {
+            let limit: usize = decoder.prepare_read();
+            decoder.record_read(1, limit);
+        }
+        assert_eq!(decoder.get_read_size(), 1024);
+    }
+
+    #[test]
+    fn read_buffer_limit() {
+        let mut decoder: FrameDecoder = FrameDecoder::with_buffer_config(
+            DG_SIZE_MAX,
+            ReadBufferConfig {
+                min_read_size: 128 * 1024,
+                max_bytes: 128 * 1024,
+            },
+        );
+
+        // a partial frame counts against the limit
+        decoder.extend(&[0xFF, 0xFF]);
+        decoder.extend(&[0; 1000]);
+        assert_eq!(decoder.decode(), Ok(None));
+
+        assert_eq!(decoder.prepare_read(), 128 * 1024 - 1002);
+    }
+
+    #[test]
+    fn release_idle_buffer() {
+        let mut decoder: FrameDecoder = FrameDecoder::default();
+
+        // grow the read size, then fill the buffer with a frame
+        for _ in 0..4 {
+            let limit: usize = decoder.prepare_read();
+            decoder.record_read(limit, limit);
+        }
+        decoder.extend(&[0x01, 0x00, 0xAA]);
+        assert!(decoder.decode().unwrap().is_some());
+
+        // the connection goes quiet
+        let limit: usize = decoder.prepare_read();
+        decoder.record_read(0, limit);
+
+        decoder.prepare_read();
+        assert!(decoder.buffer_mut().capacity() < limit);
+    }
+
+    #[test]
+    fn empty_control_frame() {
+        let mut decoder: FrameDecoder = FrameDecoder::default();
+        decoder.enable_control_frames();
+        decoder.extend(&[0x00, 0x00, 0x00, 0x00]);
+
+        assert_eq!(decoder.decode_frame(), Err(FrameError::EmptyControlFrame));
+    }
+
+    #[test]
+    fn control_frames_disabled() {
+        let mut stream: BytesMut = BytesMut::new();
+        encode_control(&mut stream, &[0x01]);
+
+        let mut decoder: FrameDecoder = FrameDecoder::default();
+        decoder.extend(&stream);
+
+        assert_eq!(decoder.decode_frame(), Err(FrameError::EmptyFrame));
+    }
+
+    proptest! {
+        #[test]
+        fn split_stream(
+            payloads in prop::collection::vec(prop::collection::vec(any::<u8>(), 1..300), 0..20),
+            splits in prop::collection::vec(any::<usize>(), 0..40),
+        ) {
+            let stream: Vec<u8> = encode(&payloads);
+            let mut decoder: FrameDecoder = FrameDecoder::default();
+
+            prop_assert_eq!(decode_chunks(&mut decoder, &stream, &splits), Ok(payloads));
+            prop_assert_eq!(decoder.buffered(), 0);
+        }
+
+        #[test]
+        fn split_stream_partial_frame(
+            payloads in prop::collection::vec(prop::collection::vec(any::<u8>(), 1..300), 1..20),
+            splits in prop::collection::vec(any::<usize>(), 0..40),
+            cut in 1..300_usize,
+        ) {
+            let stream: Vec<u8> = encode(&payloads);
+            let last_len: usize = DG_SIZE_TAG_LEN + payloads.last().unwrap().len();
+            let cut: usize = cut % last_len + 1;
+
+            // the last frame is cut short, so it is still buffered
+            let mut decoder: FrameDecoder = FrameDecoder::default();
+            let frames = decode_chunks(&mut decoder, &stream[..stream.len() - cut], &splits);
+
+            prop_assert_eq!(frames, Ok(payloads[..payloads.len() - 1].to_vec()));
+            prop_assert_eq!(decoder.buffered(), last_len - cut);
+        }
+
+        #[test]
+        fn split_stream_oversized_frame(
+            payloads in prop::collection::vec(prop::collection::vec(any::<u8>(), 1..300), 1..20),
+            splits in prop::collection::vec(any::<usize>(), 0..40),
+            max in 1..300_u16,
+        ) {
+            let stream: Vec<u8> = encode(&payloads);
+            let mut decoder: FrameDecoder = FrameDecoder::new(max);
+
+            let expected = match payloads.iter().find(|p| p.len() > max.into()) {
+                Some(p) => Err(FrameError::FrameTooLarge { size: p.len() as DgSizeTag, max }),
+                None => Ok(payloads.clone()),
+            };
+            prop_assert_eq!(decode_chunks(&mut decoder, &stream, &splits), expected);
+        }
+    }
+}
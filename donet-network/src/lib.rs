@@ -17,36 +17,51 @@
     License along with Donet. If not, see <https://www.gnu.org/licenses/>.
 */
 
+pub mod addr;
+pub mod compress;
+pub mod frame;
+pub mod memory;
+pub mod queue;
 pub mod tcp;
+pub mod tls;
+pub mod transport;
 pub mod udp;
+pub mod ws;
 
+#[cfg(test)]
+mod test_util;
+
+use addr::PeerAddr;
+use bytes::{Bytes, BytesMut};
+use compress::{Codec, CompressionConfig, CompressionError, CompressionStats, Control, StatsCounters};
 use donet_core::datagram::datagram::*;
 use donet_core::datagram::iterator::*;
 use donet_core::globals::*;
-use log::{info, warn};
+use frame::{Frame, FrameDecoder, FrameError, ReadBufferConfig};
+use log::{debug, error, info, warn};
+use queue::{QueueError, QueueStats, SendQueue, SendQueueConfig};
 use std::collections::VecDeque;
 use std::io;
-use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use thiserror::Error;
+use tls::CertificateDer;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio::task::JoinHandle;
+use transport::{BoxedTransport, Transport};
 
-/// Size of the byte buffer for incoming TCP packets.
-///
-/// Tokio gives us entire TCP messages (after reassembling
-/// segments) so we should expect this buffer to fill above
-/// the TCP max segment size (MSS).
-const TCP_READ_BUFFER_SIZE: usize = 300 * 1024; // 300 kb
+/// Maximum number of datagrams written to the TCP stream at once.
+const SEND_BATCH_SIZE: usize = 1000;
 
 /// Data sent via an MPSC channel from a
 /// client receive loop task to a service
 /// handle receive task.
 pub struct RecvData {
-    /// Remote IPv4/6 address of the sender
-    pub remote: SocketAddr,
+    /// Remote address of the sender
+    pub remote: PeerAddr,
     /// Original datagram received
     pub dg: Datagram,
     /// Datagram Iterator. Propagated upwards to keep
@@ -54,26 +69,184 @@ pub struct RecvData {
     pub dgi: DatagramIterator,
 }
 
-pub type RecvSendHandles = (JoinHandle<io::Result<()>>, JoinHandle<io::Result<()>>);
+/// Events sent via an MPSC channel from a [`Client`]'s
+/// tasks to the service that owns the [`Client`].
+pub enum ClientEvent {
+    /// A datagram was received from the remote.
+    Received(RecvData),
+    /// The connection was closed. This is the last
+    /// event sent for a connection.
+    Disconnected(Disconnect),
+}
+
+/// Describes the end of a [`Client`]'s connection.
+#[derive(Debug)]
+pub struct Disconnect {
+    /// Remote address of the disconnected client
+    pub remote: PeerAddr,
+    pub reason: DisconnectReason,
+}
+
+#[derive(Debug)]
+pub enum DisconnectReason {
+    /// The remote closed the connection.
+    RemoteClosed,
+    /// The connection was closed with [`Client::close`].
+    LocalClosed,
+    /// The connection failed, or the remote violated the protocol.
+    Error(ConnectionError),
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RemoteClosed => write!(f, "closed by remote"),
+            Self::LocalClosed => write!(f, "closed locally"),
+            Self::Error(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Custom error type for [`Client`] connections.
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    #[error("io error; {0}")]
+    Io(#[from] io::Error),
+    #[error("framing error; {0}")]
+    Framing(#[from] FrameError),
+    #[error("compression error; {0}")]
+    Compression(#[from] CompressionError),
+    #[error("tried to send a datagram of {0} bytes, larger than the maximum frame size")]
+    OversizedDatagram(usize),
+    #[error("the connection is closed")]
+    Closed,
+    #[error("the send queue overflowed")]
+    QueueOverflow,
+    #[error("the service stopped receiving from this connection")]
+    ServiceClosed,
+    #[error("a connection task failed; {0}")]
+    TaskFailed(String),
+}
+
+impl From<QueueError> for ConnectionError {
+    fn from(value: QueueError) -> Self {
+        match value {
+            QueueError::Closed => Self::Closed,
+            QueueError::Overflow => Self::QueueOverflow,
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for ConnectionError {
+    fn from(value: tokio::task::JoinError) -> Self {
+        Self::TaskFailed(value.to_string())
+    }
+}
+
+/// Handle to the task that supervises a [`Client`]'s
+/// receive and send tasks. It ends once the connection is closed.
+pub type ConnectionHandle = JoinHandle<()>;
+
+/// Sent by the receive loop to the send loop once compression is negotiated.
+enum Negotiated {
+    /// The remote offered our codec. The offer is accepted, then
+    /// datagrams are compressed.
+    Offered(Codec),
+    /// The remote accepted our offer, so datagrams are compressed.
+    Accepted,
+}
+
+/// How the receive loop of a [`Client`] ended without an error.
+enum RecvEnd {
+    RemoteClosed,
+    LocalClosed,
+}
+
+/// The read half of a connection's byte stream, such as a TCP or TLS stream.
+pub type StreamReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The write half of a connection's byte stream, such as a TCP or TLS stream.
+pub type StreamWriter = Box<dyn AsyncWrite + Send + Unpin>;
 
 /// Ensures the implementing type owns a reference
 /// to a [`Client`] structure.
-pub trait HasClient {
-    fn get_client(&self) -> Arc<Mutex<Client>>;
+pub trait HasClient<T: Transport = BoxedTransport> {
+    fn get_client(&self) -> Arc<Mutex<Client<T>>>;
 }
 
-/// Represents a network client connected over TCP.
-#[derive(Debug)]
-pub struct Client {
-    remote: SocketAddr,
-    local: SocketAddr,
+/// Represents a network client connected over a [`Transport`],
+/// such as TCP, a Unix domain socket, TLS, or an in-memory pipe.
+///
+/// Once its tasks are spawned with [`Client::spawn_recv_send_tasks`],
+/// the connection stays open until the remote closes it, a task fails,
+/// or [`Client::close`] is called. Either way, the owning service is
+/// sent a [`ClientEvent::Disconnected`] event.
+pub struct Client<T: Transport = BoxedTransport> {
+    remote: PeerAddr,
+    local: PeerAddr,
     /// Queue of datagrams to be sent. Use this to
     /// queue datagrams to be sent to the remote address
     /// of this [`Client`]'s TCP stream.
-    send_queue_channel: Option<mpsc::Sender<Datagram>>,
-    /// Wrapped in `Option` as we will consume these halves for tasks
-    tcp_read_half: Option<OwnedReadHalf>,
-    tcp_write_half: Option<OwnedWriteHalf>,
+    send_queue: Option<Arc<SendQueue>>,
+    send_queue_config: SendQueueConfig,
+    /// Signals the receive loop to stop. Taken by [`Client::close`].
+    close_channel: Option<oneshot::Sender<()>>,
+    /// Largest datagram accepted from the remote, in bytes.
+    max_frame_size: DgSizeTag,
+    read_buffer_config: ReadBufferConfig,
+    /// `None` unless compression was opted into.
+    compression: Option<CompressionConfig>,
+    /// Whether compression is offered to the remote, or only accepted.
+    offer_compression: bool,
+    compression_stats: Option<Arc<StatsCounters>>,
+    /// Certificate that the remote presented in the TLS handshake, if any.
+    peer_certificate: Option<CertificateDer<'static>>,
+    /// Wrapped in `Option` as we will split it for tasks
+    transport: Option<T>,
+}
+
+impl<T: Transport> std::fmt::Debug for Client<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client")
+            .field("remote", &self.remote)
+            .field("local", &self.local)
+            .field("send_queue", &self.send_queue)
+            .field("max_frame_size", &self.max_frame_size)
+            .field("compression", &self.compression)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A handle that stages datagrams to be sent by a [`Client`],
+/// without needing access to the [`Client`] itself.
+///
+/// Cloned out of a [`Client`] with [`Client::get_sender`], so that
+/// many tasks can send to the same connection without locking it.
+#[derive(Debug, Clone)]
+pub struct ClientSender {
+    queue: Arc<SendQueue>,
+}
+
+impl ClientSender {
+    /// Returns `true` if datagrams can still be staged to be sent.
+    pub fn is_writable(&self) -> bool {
+        !self.queue.is_closed()
+    }
+
+    /// Returns the depth of the send queue of the [`Client`].
+    pub fn get_queue_stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Adds the given [`Datagram`] to the send queue of the [`Client`].
+    ///
+    /// Behaves like [`Client::stage_datagram`].
+    pub async fn stage_datagram(&self, dg: Datagram) -> Result<(), ConnectionError> {
+        if dg.size() > usize::from(DG_SIZE_MAX) {
+            return Err(ConnectionError::OversizedDatagram(dg.size()));
+        }
+        Ok(self.queue.push(dg).await?)
+    }
 }
 
 impl From<TcpStream> for Client {
@@ -81,178 +254,406 @@ impl From<TcpStream> for Client {
         let remote = value.peer_addr().expect("Failed to get remote address.");
         let local = value.local_addr().expect("Failed to get local address.");
 
-        let (read_half, write_half) = value.into_split();
+        Self::new(remote.into(), local.into(), BoxedTransport::new(value))
+    }
+}
 
-        Self {
-            remote,
-            local,
-            send_queue_channel: None,
-            tcp_read_half: Some(read_half),
-            tcp_write_half: Some(write_half),
-        }
+/// Unix domain socket peers are numbered with [`PeerAddr::next_unix`].
+/// The local address of the [`Client`] is the same as the remote's.
+#[cfg(unix)]
+impl From<UnixStream> for Client {
+    fn from(value: UnixStream) -> Self {
+        let peer: PeerAddr = PeerAddr::next_unix();
+
+        Self::new(peer, peer, BoxedTransport::new(value))
     }
 }
 
-/// Allows for upgrading a [`tcp::Connection`] structure, to
-/// a [`Client`] structure for advanced functionality, such
-/// as receiving and sending datagrams asynchronously.
-impl From<tcp::Connection> for Client {
-    fn from(value: tcp::Connection) -> Self {
-        value.socket.into()
+impl Client {
+    /// Creates a [`Client`] from any [`Transport`], such as a TLS stream.
+    ///
+    /// Datagrams are framed the same way over every transport.
+    pub fn from_stream<S: Transport>(remote: PeerAddr, local: PeerAddr, stream: S) -> Self {
+        Self::new(remote, local, BoxedTransport::new(stream))
     }
 }
 
-/// Util macro for truncated datagram scenarios.
-macro_rules! truncated_datagram {
-    ($remote:expr, $err:expr) => {{
-        warn!("Received truncated datagram from {}: {}", $remote, $err);
+impl<T: Transport> Client<T> {
+    pub fn new(remote: PeerAddr, local: PeerAddr, transport: T) -> Self {
+        Self {
+            remote,
+            local,
+            send_queue: None,
+            send_queue_config: SendQueueConfig::default(),
+            close_channel: None,
+            max_frame_size: DG_SIZE_MAX,
+            read_buffer_config: ReadBufferConfig::default(),
+            compression: None,
+            offer_compression: false,
+            compression_stats: None,
+            peer_certificate: None,
+            transport: Some(transport),
+        }
+    }
 
-        // no more bytes to read, break read loop
-        break;
-    }};
-}
+    /// Erases the type of this client's [`Transport`].
+    ///
+    /// Must be called before calling [`Client::spawn_recv_send_tasks`].
+    pub fn into_boxed(self) -> Client {
+        let transport = self.transport.expect("Client tasks were already spawned.");
 
-impl Client {
-    /// Returns the remote IPv4/6 address of this client.
-    pub fn get_remote(&self) -> SocketAddr {
+        Client {
+            remote: self.remote,
+            local: self.local,
+            send_queue: None,
+            send_queue_config: self.send_queue_config,
+            close_channel: None,
+            max_frame_size: self.max_frame_size,
+            read_buffer_config: self.read_buffer_config,
+            compression: self.compression,
+            offer_compression: self.offer_compression,
+            compression_stats: None,
+            peer_certificate: self.peer_certificate,
+            transport: Some(BoxedTransport::new(transport)),
+        }
+    }
+
+    /// Returns the remote address of this client.
+    pub fn get_remote(&self) -> PeerAddr {
         self.remote
     }
 
-    /// Returns the local IPv4/6 address of this client.
-    pub fn get_local(&self) -> SocketAddr {
+    /// Returns the local address of this client.
+    pub fn get_local(&self) -> PeerAddr {
         self.local
     }
 
-    /// Sends the given [`Datagram`] to the send loop task, via the
-    /// [`Client`]'s [`mpsc::Sender<Datagram>`].
-    pub async fn stage_datagram(&mut self, dg: Datagram) -> Result<(), mpsc::error::SendError<Datagram>> {
-        let tx = self
-            .send_queue_channel
-            .as_mut()
-            .expect("recv/send tasks dont exist");
-        tx.send(dg).await
+    /// Returns the certificate that the remote presented in the TLS
+    /// handshake, which only clients of mutual TLS listeners do.
+    pub fn get_peer_certificate(&self) -> Option<&CertificateDer<'static>> {
+        self.peer_certificate.as_ref()
     }
 
-    /// Spawns a tokio task for `Self::receive_loop` and `Self::send_loop`,
-    /// and returns a tuple:
+    pub(crate) fn set_peer_certificate(&mut self, cert: Option<CertificateDer<'static>>) {
+        self.peer_certificate = cert;
+    }
+
+    /// Sets the largest datagram that will be accepted from the remote.
     ///
-    /// - The first tuple element is the [`JoinHandle`] for the receive loop.
+    /// If the remote sends a larger datagram, the connection is closed.
+    /// Must be set before calling [`Client::spawn_recv_send_tasks`].
+    pub fn set_max_frame_size(&mut self, size: DgSizeTag) {
+        self.max_frame_size = size;
+    }
+
+    /// Sets how the buffer that the remote's datagrams are read into
+    /// grows and shrinks, and the most memory it may hold.
     ///
-    /// - The second tuple element is the [`JoinHandle`] for the send loop.
-    pub async fn spawn_recv_send_tasks(&mut self, incoming_tx: mpsc::Sender<RecvData>) -> RecvSendHandles {
-        let read_half = self.tcp_read_half.take().unwrap();
-        let write_half = self.tcp_write_half.take().unwrap();
+    /// Must be set before calling [`Client::spawn_recv_send_tasks`].
+    pub fn set_read_buffer_config(&mut self, config: ReadBufferConfig) {
+        self.read_buffer_config = config;
+    }
 
-        let recv_handle = tokio::spawn(Self::receive_loop(read_half, incoming_tx));
+    /// Sets the budget of the send queue, and what to do when a
+    /// datagram is staged while it is full.
+    ///
+    /// Must be set before calling [`Client::spawn_recv_send_tasks`].
+    pub fn set_send_queue_config(&mut self, config: SendQueueConfig) {
+        self.send_queue_config = config;
+    }
 
-        // send channel.
-        // queues datagrams to be sent to the remote address of this client.
-        let (tx, rx) = mpsc::channel::<Datagram>(32);
+    /// Opts into compression of the datagrams sent on this connection,
+    /// and offers it to the remote once the tasks are spawned.
+    ///
+    /// Datagrams are compressed only if the remote accepts the offer.
+    /// A remote that did not opt into compression closes the connection
+    /// on receiving it. See [`compress`]. Must be set before calling
+    /// [`Client::spawn_recv_send_tasks`].
+    pub fn set_compression(&mut self, config: CompressionConfig) {
+        self.compression = Some(config);
+        self.offer_compression = true;
+    }
 
-        self.send_queue_channel = Some(tx);
+    /// Opts into compression of the datagrams sent on this connection,
+    /// if the remote offers it. Nothing is sent to a remote that does
+    /// not, so this is safe to set on connections to any remote.
+    /// Must be set before calling [`Client::spawn_recv_send_tasks`].
+    pub fn accept_compression(&mut self, config: CompressionConfig) {
+        self.compression = Some(config);
+        self.offer_compression = false;
+    }
 
-        let send_handle = tokio::spawn(Self::send_loop(write_half, rx));
+    /// Returns how well sent datagrams have compressed, if
+    /// compression was opted into and the tasks have been spawned.
+    pub fn get_compression_stats(&self) -> Option<CompressionStats> {
+        self.compression_stats.as_ref().map(|stats| stats.snapshot())
+    }
 
-        (recv_handle, send_handle)
+    /// Returns the depth of the send queue, if the
+    /// tasks of this [`Client`] have been spawned.
+    pub fn get_queue_stats(&self) -> Option<QueueStats> {
+        self.send_queue.as_ref().map(|queue| queue.stats())
     }
 
-    /// Main asynchronous loop for handling receiving TCP packets
-    /// from this client's TCP stream.
-    async fn receive_loop(
-        read_half: OwnedReadHalf,
-        incoming_queue_tx: mpsc::Sender<RecvData>,
-    ) -> io::Result<()> {
-        let remote: SocketAddr = read_half.peer_addr()?;
+    /// Returns `true` if datagrams can still be staged to be sent.
+    pub fn is_writable(&self) -> bool {
+        self.send_queue.as_ref().is_some_and(|queue| !queue.is_closed())
+    }
 
-        loop {
-            read_half.readable().await?;
+    /// Adds the given [`Datagram`] to the send queue of this [`Client`].
+    ///
+    /// If the queue is full, the configured [`queue::OverflowPolicy`]
+    /// is applied, which may wait for room in the queue, or close the
+    /// connection and return [`ConnectionError::QueueOverflow`].
+    ///
+    /// Returns [`ConnectionError::Closed`] if the connection was closed
+    /// for writing, or if its tasks have not been spawned.
+    pub async fn stage_datagram(&mut self, dg: Datagram) -> Result<(), ConnectionError> {
+        let sender: ClientSender = self.get_sender().ok_or(ConnectionError::Closed)?;
 
-            // initializing this **after** the `await` point prevents
-            // it from being stored in the async task.
-            let mut buffer = [0_u8; TCP_READ_BUFFER_SIZE];
+        sender.stage_datagram(dg).await
+    }
 
-            match read_half.try_read(&mut buffer) {
-                Ok(0) => {
-                    info!("Lost connection from {}", remote);
+    /// Returns a [`ClientSender`] for the send queue of this
+    /// [`Client`], if its tasks have been spawned.
+    pub fn get_sender(&self) -> Option<ClientSender> {
+        self.send_queue
+            .as_ref()
+            .map(|queue| ClientSender { queue: queue.clone() })
+    }
 
-                    return Ok(()); // client closed TCP connection
-                }
-                Ok(len) => {
-                    let mut dg: Datagram = Datagram::default();
+    /// Closes the connection for writing, also known as a half-close.
+    ///
+    /// Datagrams that were already staged are sent and flushed
+    /// before the write half is shut down. Datagrams can still
+    /// be received until the remote closes the connection.
+    pub fn shutdown_write(&mut self) {
+        // the send loop ends once the closed queue is empty
+        if let Some(queue) = &self.send_queue {
+            queue.close();
+        }
+    }
 
-                    dg.override_cap(TCP_READ_BUFFER_SIZE);
+    /// Closes the connection in an orderly way.
+    ///
+    /// Datagrams that were already staged are sent and flushed before
+    /// the connection is shut down, and no more datagrams are received.
+    pub fn close(&mut self) {
+        self.shutdown_write();
 
-                    // The buffer is always a fixed size. Let's make a slice that
-                    // contains only the length of the datagram received.
-                    let mut buf_slice: Vec<u8> = buffer.to_vec();
-                    buf_slice.truncate(len);
+        if let Some(close_tx) = self.close_channel.take() {
+            // the receive loop may have already ended
+            let _ = close_tx.send(());
+        }
+    }
 
-                    // we can safely unwrap here, since the size cap for `dg` was
-                    // overridden to be the size of the read buffer size.
-                    dg.add_data(buf_slice).unwrap();
+    /// Spawns tokio tasks for `Self::receive_loop` and `Self::send_loop`,
+    /// and a task that supervises them.
+    ///
+    /// Received datagrams are sent to the given [`mpsc::Sender<ClientEvent>`].
+    /// Once both tasks have ended, a [`ClientEvent::Disconnected`] event is
+    /// sent describing why, and the returned [`ConnectionHandle`] completes.
+    pub async fn spawn_recv_send_tasks(
+        &mut self,
+        incoming_tx: mpsc::Sender<ClientEvent>,
+    ) -> ConnectionHandle {
+        let transport: T = self.transport.take().expect("Client tasks were already spawned.");
+        let (reader, writer) = transport.into_split();
 
-                    Self::split_datagrams(remote, &incoming_queue_tx, dg.into()).await;
-                    continue;
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    continue;
-                }
-                Err(err) => {
-                    return Err(err);
-                }
-            }
+        let (close_tx, close_rx) = oneshot::channel::<()>();
+        self.close_channel = Some(close_tx);
+
+        // signals the send loop once compression is negotiated
+        let (negotiated_tx, negotiated_rx) = oneshot::channel::<Negotiated>();
+        let stats: Option<Arc<StatsCounters>> = self.compression.map(|_| Arc::default());
+        self.compression_stats = stats.clone();
+
+        let mut decoder = FrameDecoder::with_buffer_config(self.max_frame_size, self.read_buffer_config);
+
+        // only connections that opted into compression take control frames
+        if self.compression.is_some() {
+            decoder.enable_control_frames();
         }
+        let recv_handle = tokio::spawn(Self::receive_loop(
+            self.remote,
+            reader,
+            decoder,
+            incoming_tx.clone(),
+            close_rx,
+            self.compression.map(|config| (config.codec, negotiated_tx)),
+        ));
+
+        // queues datagrams to be sent to the remote address of this client.
+        let queue: Arc<SendQueue> = Arc::new(SendQueue::new(self.send_queue_config));
+
+        self.send_queue = Some(queue.clone());
+
+        // signals the send loop to flush and shut down once the receive loop ends
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let send_handle = tokio::spawn(Self::send_loop(
+            writer,
+            queue,
+            shutdown_rx,
+            self.compression.map(|config| (config, negotiated_rx)),
+            self.offer_compression,
+            stats,
+        ));
+
+        tokio::spawn(Self::supervise(
+            self.remote, recv_handle, send_handle, shutdown_tx, incoming_tx,
+        ))
     }
 
-    /// Handles separating TCP packets into separate Datagrams, if multiple
-    /// found in the packet, and sends each individual datagram over the
-    /// mpsc channel using the given [`mpsc::Sender`].
-    async fn split_datagrams(
-        remote: SocketAddr,
-        incoming_tx: &mpsc::Sender<RecvData>,
-        mut dgi: DatagramIterator,
+    /// Awaits the receive and send tasks of a connection, and sends a
+    /// [`ClientEvent::Disconnected`] event once both have ended.
+    ///
+    /// If the send loop fails, the receive loop is stopped. If the
+    /// receive loop ends, the send loop is flushed and shut down.
+    async fn supervise(
+        remote: PeerAddr,
+        mut recv_handle: JoinHandle<Result<RecvEnd, ConnectionError>>,
+        mut send_handle: JoinHandle<Result<(), ConnectionError>>,
+        shutdown_tx: oneshot::Sender<()>,
+        incoming_tx: mpsc::Sender<ClientEvent>,
     ) {
-        loop {
-            let sizetag: DgSizeTag = match dgi.read_size() {
-                Ok(size) => size,
-                Err(err) => truncated_datagram!(remote, err),
-            };
+        let mut send_result: Option<Result<(), ConnectionError>> = None;
+
+        // `None` if the receive loop was stopped because the send loop failed
+        let recv_result: Option<Result<RecvEnd, ConnectionError>> = loop {
+            tokio::select! {
+                res = &mut recv_handle => break Some(res.unwrap_or_else(|err| Err(err.into()))),
+                res = &mut send_handle, if send_result.is_none() => {
+                    let res: Result<(), ConnectionError> = res.unwrap_or_else(|err| Err(err.into()));
+                    let failed: bool = res.is_err();
+                    send_result = Some(res);
+
+                    if failed {
+                        // a failed write leaves the stream unusable
+                        recv_handle.abort();
+                        break None;
+                    }
+                    // half-closed; keep receiving until the remote closes
+                }
+            }
+        };
 
-            if sizetag == 0 {
-                warn!("Received datagram with a size tag of 0. Skipping.");
-                break;
+        let send_result: Result<(), ConnectionError> = match send_result {
+            Some(res) => res,
+            None => {
+                // the send loop may have already ended
+                let _ = shutdown_tx.send(());
+                send_handle.await.unwrap_or_else(|err| Err(err.into()))
             }
+        };
+
+        let reason: DisconnectReason = match (recv_result, send_result) {
+            (Some(Err(err)), _) | (_, Err(err)) => DisconnectReason::Error(err),
+            (Some(Ok(RecvEnd::RemoteClosed)), Ok(())) => DisconnectReason::RemoteClosed,
+            (Some(Ok(RecvEnd::LocalClosed)), Ok(())) => DisconnectReason::LocalClosed,
+            (None, Ok(())) => unreachable!("Receive loop stopped without a send loop error."),
+        };
+
+        match &reason {
+            DisconnectReason::Error(err) => error!("Lost connection from {}: {}", remote, err),
+            reason => info!("Lost connection from {} ({})", remote, reason),
+        }
+
+        // the service may have already stopped receiving
+        let _ = incoming_tx
+            .send(ClientEvent::Disconnected(Disconnect { remote, reason }))
+            .await;
+    }
+
+    /// Main asynchronous loop for handling receiving TCP packets
+    /// from this client's TCP stream.
+    ///
+    /// Received bytes are buffered by the [`FrameDecoder`], so datagrams
+    /// that are split across reads are reassembled. Each read is limited
+    /// to the room it reserves, so the buffer adapts to the rate of
+    /// received bytes without exceeding its [`ReadBufferConfig`]. If the
+    /// remote violates the framing protocol, the loop ends with an error.
+    ///
+    /// If compression was opted into, `negotiation` holds our codec, and
+    /// the channel to tell the send loop once compression is negotiated.
+    async fn receive_loop(
+        remote: PeerAddr,
+        mut reader: T::Reader,
+        mut decoder: FrameDecoder,
+        incoming_queue_tx: mpsc::Sender<ClientEvent>,
+        mut close_rx: oneshot::Receiver<()>,
+        mut negotiation: Option<(Codec, oneshot::Sender<Negotiated>)>,
+    ) -> Result<RecvEnd, ConnectionError> {
+        // the remote may only compress once compression is negotiated
+        let mut negotiated: bool = false;
 
-            let mut individual_dg: Datagram = Datagram::default();
+        loop {
+            let limit: usize = decoder.prepare_read();
+            let buffer: &mut BytesMut = decoder.buffer_mut();
+            let mut limited_reader = (&mut reader).take(limit as u64);
 
-            let payload: Vec<u8> = match dgi.read_data(sizetag.into()) {
-                Ok(data) => data,
-                Err(err) => truncated_datagram!(remote, err),
+            // `read_buf` is cancel safe, so no data is lost if closed first
+            let len: usize = tokio::select! {
+                res = limited_reader.read_buf(buffer) => res?,
+                _ = &mut close_rx => return Ok(RecvEnd::LocalClosed),
             };
+            decoder.record_read(len, limit);
 
-            assert!(individual_dg.add_data(payload).is_ok());
+            if len == 0 {
+                if decoder.buffered() != 0 {
+                    warn!(
+                        "Connection from {} closed with {} bytes of a partial datagram received.",
+                        remote,
+                        decoder.buffered()
+                    );
+                }
+                return Ok(RecvEnd::RemoteClosed); // client closed TCP connection
+            }
 
-            // send individual datagram to the receive ncoming queue
-            incoming_tx
-                .send(RecvData {
+            // wait for the rest of the frame once `decode_frame` returns `None`
+            while let Some(frame) = decoder.decode_frame()? {
+                let payload: Bytes = match frame {
+                    Frame::Datagram(payload) => payload,
+                    Frame::Control(body) => match Control::parse(body)? {
+                        Control::Compressed { .. } if !negotiated => {
+                            return Err(CompressionError::NotNegotiated.into())
+                        }
+                        Control::Compressed { codec, size, data } => {
+                            compress::decompress(codec, size, &data, decoder.get_max_frame_size())?
+                        }
+                        Control::Offer(codecs) => {
+                            match negotiation.take() {
+                                Some((codec, negotiated_tx)) if codecs.contains(&codec) => {
+                                    negotiated = true;
+                                    // the send loop may have already ended
+                                    let _ = negotiated_tx.send(Negotiated::Offered(codec));
+                                }
+                                Some(_) => debug!("Not compressing datagrams sent to {}.", remote),
+                                None => {}
+                            }
+                            continue;
+                        }
+                        Control::Accept(codec) => {
+                            if let Some((_, negotiated_tx)) = negotiation.take() {
+                                negotiated = true;
+                                let _ = negotiated_tx.send(Negotiated::Accepted);
+                            }
+                            info!("Datagrams from {} will be compressed with {}.", remote, codec);
+                            continue;
+                        }
+                    },
+                };
+                let data = RecvData {
                     remote,
-                    dg: individual_dg.clone(),
-                    dgi: DatagramIterator::from(individual_dg),
-                })
-                .await
-                .expect("Tried to send received packet, but MPSC channel closed.");
-
-            let remaining: usize = dgi.get_remaining();
-
-            // if this packet has at least another size tag ahead,
-            // try separating another datagram
-            if remaining < std::mem::size_of::<DgSizeTag>() {
-                // we *should* have 0 bytes left to read, if this is a
-                // good packet. if not, its truncated (or we read it wrong)
-                if remaining != 0 {
-                    truncated_datagram!(remote, "Expected more bytes!");
-                }
-                break;
+                    dg: Datagram::from(payload.clone()),
+                    dgi: DatagramIterator::from(payload),
+                };
+
+                // send individual datagram to the receive incoming queue
+                incoming_queue_tx
+                    .send(ClientEvent::Received(data))
+                    .await
+                    .map_err(|_| ConnectionError::ServiceClosed)?;
             }
         }
     }
@@ -260,52 +661,399 @@ impl Client {
     /// Main asynchronous loop for handling sending TCP packets to the
     /// remote address of this [`Client`]'s TCP stream.
     ///
-    /// The queue of datagrams to be sent is received by this task
-    /// via the given [`mpsc::Receiver<Datagram>`] struct.
+    /// Datagrams are taken from the given [`SendQueue`] in batches.
+    ///
+    /// Once the queue is closed, or a shutdown is signaled, the datagrams
+    /// left in the queue are sent and the write half is shut down. If the
+    /// queue overflows, the loop ends with an error without waiting for
+    /// a pending write to the slow remote.
+    ///
+    /// If compression was opted into, it is offered to the remote first if
+    /// `offer` is set, and datagrams are compressed once the receive loop
+    /// signals that the remote accepted our offer, or offered its own.
     async fn send_loop(
-        mut write_half: OwnedWriteHalf,
-        mut send_queue_rx: mpsc::Receiver<Datagram>,
-    ) -> io::Result<()> {
-        loop {
-            let mut buffer: Vec<Datagram> = vec![];
+        mut writer: T::Writer,
+        queue: Arc<SendQueue>,
+        mut shutdown_rx: oneshot::Receiver<()>,
+        negotiation: Option<(CompressionConfig, oneshot::Receiver<Negotiated>)>,
+        offer: bool,
+        stats: Option<Arc<StatsCounters>>,
+    ) -> Result<(), ConnectionError> {
+        let stats: Option<&StatsCounters> = stats.as_deref();
+        let (config, mut negotiated_rx) = negotiation.unzip();
+        // set once compression is negotiated
+        let mut compression: Option<CompressionConfig> = None;
+
+        if config.is_some() && offer {
+            let mut offer: BytesMut = BytesMut::new();
+            Control::encode_offer(&mut offer);
 
+            writer.write_all(&offer).await?;
+            writer.flush().await?;
+        }
+
+        loop {
             // await until notified that more packets was added to the queue
-            let n = send_queue_rx.recv_many(&mut buffer, 1000).await;
+            let batch: Option<Vec<Datagram>> = tokio::select! {
+                batch = queue.pop_many(SEND_BATCH_SIZE) => batch?,
+                res = async { negotiated_rx.as_mut().expect("Checked by select.").await }, if negotiated_rx.is_some() => {
+                    negotiated_rx = None;
+
+                    // `Err` if the receive loop ended before negotiating
+                    match res {
+                        Ok(Negotiated::Offered(codec)) => {
+                            let mut accept: BytesMut = BytesMut::new();
+                            Control::encode_accept(&mut accept, codec);
+
+                            writer.write_all(&accept).await?;
+                            writer.flush().await?;
+                            compression = config;
+                        }
+                        Ok(Negotiated::Accepted) => compression = config,
+                        Err(_) => {}
+                    }
+                    continue;
+                }
+                _ = &mut shutdown_rx => {
+                    // flush any datagrams that were staged before the shutdown
+                    queue.close();
 
-            // if `recv_many` returns 0, it means the MPSC channel was closed.
-            if n == 0 {
-                todo!("unhandled error. tcp client dg queue receiver returned 0.")
+                    while let Some(batch) = queue.pop_many(SEND_BATCH_SIZE).await? {
+                        Self::write_datagrams(&mut writer, batch, compression.as_ref(), stats).await?;
+                    }
+                    writer.shutdown().await?;
+                    return Ok(());
+                }
+            };
+
+            // `None` means the queue is closed and empty.
+            let Some(batch) = batch else {
+                writer.shutdown().await?;
+                return Ok(());
+            };
+
+            tokio::select! {
+                res = Self::write_datagrams(&mut writer, batch, compression.as_ref(), stats) => res?,
+                _ = queue.overflowed() => return Err(ConnectionError::QueueOverflow),
             }
+        }
+    }
 
-            let mut queue: VecDeque<Datagram> = VecDeque::from(buffer);
+    /// Writes the given datagrams to the stream, each prefixed with its
+    /// size tag or compressed, and flushes it.
+    async fn write_datagrams(
+        writer: &mut T::Writer,
+        datagrams: Vec<Datagram>,
+        compression: Option<&CompressionConfig>,
+        stats: Option<&StatsCounters>,
+    ) -> io::Result<()> {
+        if datagrams.is_empty() {
+            return Ok(());
+        }
+        let mut queue: VecDeque<Datagram> = VecDeque::from(datagrams);
 
-            // prepare write buffer by reading the send queue
-            let mut write_buffer_dg: Datagram = Datagram::default();
+        // prepare write buffer by reading the send queue
+        let write_size: usize = queue.iter().map(|dg| dg.size() + frame::DG_SIZE_TAG_LEN).sum();
+        let mut write_buffer: BytesMut = BytesMut::with_capacity(write_size);
 
-            while !queue.is_empty() {
-                let mut dgi: DatagramIterator = queue.pop_front().unwrap().into();
+        while let Some(dg) = queue.pop_front() {
+            // oversized datagrams are rejected by `stage_datagram`
+            debug_assert!(dg.size() <= usize::from(DG_SIZE_MAX));
 
-                // get the size of this datagram to append size tag
-                let sizetag: usize = dgi.get_remaining();
+            compress::encode_datagram(&mut write_buffer, dg.get_buffer(), compression, stats);
+        }
 
-                // read the next bytes based on the size tag
-                let dg_payload: Result<Vec<u8>, IteratorError> = dgi.read_data(sizetag);
+        // send staged datagrams to client
+        writer.write_all(&write_buffer).await?;
+        writer.flush().await
+    }
+}
 
-                assert!(dg_payload.is_ok(), "Tried to read past datagram.");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{datagram, recv_datagram, recv_disconnect};
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use tokio::net::TcpListener;
 
-                write_buffer_dg.add_size(sizetag as DgSizeTag).unwrap();
-                write_buffer_dg.add_data(dg_payload.unwrap()).unwrap();
+    /// Returns a [`Client`] for the accepted end of a new TCP
+    /// connection, and the [`TcpStream`] for the other end.
+    async fn connect(max_frame_size: DgSizeTag) -> (Client, TcpStream) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
 
-                debug_assert!(
-                    dgi.get_remaining() == 0,
-                    "Did not read all bytes from received dg to send."
-                );
-            }
+        let mut client: Client = socket.into();
+        client.set_max_frame_size(max_frame_size);
+        (client, remote)
+    }
+
+    #[tokio::test]
+    async fn receive_split_frames() {
+        let (mut client, mut remote) = connect(DG_SIZE_MAX).await;
+        let (tx, mut rx) = mpsc::channel::<ClientEvent>(8);
+        let _handle = client.spawn_recv_send_tasks(tx).await;
+
+        // two frames, split partway through the second's size tag and payload
+        let chunks: [&[u8]; 3] = [&[0x01, 0x00, 0xAA, 0x03], &[0x00, 0xBB], &[0xCC, 0xDD]];
+
+        for chunk in chunks {
+            remote.write_all(chunk).await.unwrap();
+            remote.flush().await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(recv_datagram(&mut rx).await, &[0xAA]);
+        assert_eq!(recv_datagram(&mut rx).await, &[0xBB, 0xCC, 0xDD]);
+    }
+
+    #[tokio::test]
+    async fn close_on_oversized_frame() {
+        let (mut client, mut remote) = connect(4).await;
+        let (tx, mut rx) = mpsc::channel::<ClientEvent>(8);
+        let handle: ConnectionHandle = client.spawn_recv_send_tasks(tx).await;
 
-            // send staged datagrams to client
-            write_half.writable().await?;
-            write_half.write_all(write_buffer_dg.get_buffer()).await?;
-            write_half.flush().await?;
+        remote.write_all(&[0x05, 0x00]).await.unwrap();
+
+        let reason: DisconnectReason = recv_disconnect(&mut rx).await;
+        assert!(matches!(
+            reason,
+            DisconnectReason::Error(ConnectionError::Framing(FrameError::FrameTooLarge { .. }))
+        ));
+        handle.await.unwrap();
+
+        // the remote sees the connection closed
+        assert_eq!(remote.read(&mut [0_u8; 8]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn remote_close() {
+        let (mut client, remote) = connect(DG_SIZE_MAX).await;
+        let (tx, mut rx) = mpsc::channel::<ClientEvent>(8);
+        let _handle = client.spawn_recv_send_tasks(tx).await;
+
+        drop(remote);
+
+        assert!(matches!(
+            recv_disconnect(&mut rx).await,
+            DisconnectReason::RemoteClosed
+        ));
+        assert!(!client.is_writable());
+        assert!(matches!(
+            client.stage_datagram(datagram(&[0x01])).await,
+            Err(ConnectionError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn close_flushes_staged_datagrams() {
+        let (mut client, mut remote) = connect(DG_SIZE_MAX).await;
+        let (tx, mut rx) = mpsc::channel::<ClientEvent>(8);
+        let _handle = client.spawn_recv_send_tasks(tx).await;
+
+        client.stage_datagram(datagram(&[0xAA])).await.unwrap();
+        client.stage_datagram(datagram(&[0xBB, 0xCC])).await.unwrap();
+        client.close();
+
+        assert!(matches!(
+            recv_disconnect(&mut rx).await,
+            DisconnectReason::LocalClosed
+        ));
+
+        let mut received: Vec<u8> = vec![];
+        remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, [0x01, 0x00, 0xAA, 0x02, 0x00, 0xBB, 0xCC]);
+    }
+
+    #[tokio::test]
+    async fn stage_with_sender() {
+        let (mut client, mut remote) = connect(DG_SIZE_MAX).await;
+        assert!(client.get_sender().is_none());
+
+        let (tx, mut rx) = mpsc::channel::<ClientEvent>(8);
+        let _handle = client.spawn_recv_send_tasks(tx).await;
+        let sender: ClientSender = client.get_sender().unwrap();
+
+        sender.stage_datagram(datagram(&[0xAA])).await.unwrap();
+        client.close();
+
+        assert!(!sender.is_writable());
+        assert!(matches!(
+            sender.stage_datagram(datagram(&[0xBB])).await,
+            Err(ConnectionError::Closed)
+        ));
+        assert!(matches!(
+            recv_disconnect(&mut rx).await,
+            DisconnectReason::LocalClosed
+        ));
+
+        let mut received: Vec<u8> = vec![];
+        remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, [0x01, 0x00, 0xAA]);
+    }
+
+    #[tokio::test]
+    async fn half_close() {
+        let (mut client, mut remote) = connect(DG_SIZE_MAX).await;
+        let (tx, mut rx) = mpsc::channel::<ClientEvent>(8);
+        let _handle = client.spawn_recv_send_tasks(tx).await;
+
+        client.stage_datagram(datagram(&[0xAA])).await.unwrap();
+        client.shutdown_write();
+
+        // the remote receives the staged datagram, then the end of the stream
+        let mut received: Vec<u8> = vec![];
+        remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, [0x01, 0x00, 0xAA]);
+
+        // datagrams can still be received
+        remote.write_all(&[0x01, 0x00, 0xBB]).await.unwrap();
+        assert_eq!(recv_datagram(&mut rx).await, &[0xBB]);
+
+        drop(remote);
+        assert!(matches!(
+            recv_disconnect(&mut rx).await,
+            DisconnectReason::RemoteClosed
+        ));
+    }
+
+    #[tokio::test]
+    async fn disconnect_on_queue_overflow() {
+        let (mut client, mut remote) = connect(DG_SIZE_MAX).await;
+        let (tx, mut rx) = mpsc::channel::<ClientEvent>(8);
+
+        client.set_send_queue_config(SendQueueConfig {
+            max_messages: 1,
+            max_bytes: 1024,
+            policy: queue::OverflowPolicy::Disconnect,
+        });
+        let _handle = client.spawn_recv_send_tasks(tx).await;
+
+        // the send loop does not run until this task yields
+        client.stage_datagram(datagram(&[0xAA])).await.unwrap();
+        assert!(matches!(
+            client.stage_datagram(datagram(&[0xBB])).await,
+            Err(ConnectionError::QueueOverflow)
+        ));
+
+        assert!(matches!(
+            recv_disconnect(&mut rx).await,
+            DisconnectReason::Error(ConnectionError::QueueOverflow)
+        ));
+        assert!(!client.is_writable());
+        assert_eq!(remote.read(&mut [0_u8; 8]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn service_closed() {
+        let (mut client, mut remote) = connect(DG_SIZE_MAX).await;
+        let (tx, rx) = mpsc::channel::<ClientEvent>(8);
+        let handle: ConnectionHandle = client.spawn_recv_send_tasks(tx).await;
+
+        // the service stops receiving; the connection ends without a panic
+        drop(rx);
+        remote.write_all(&[0x01, 0x00, 0xAA]).await.unwrap();
+
+        handle.await.unwrap();
+        assert_eq!(remote.read(&mut [0_u8; 8]).await.unwrap(), 0);
+    }
+
+    /// Spawns the tasks of one end of a connection, with the given
+    /// compression, which is offered if `offer` is set.
+    async fn spawn_with_compression<T: Transport>(
+        mut client: Client<T>,
+        config: Option<CompressionConfig>,
+        offer: bool,
+    ) -> (Client<T>, mpsc::Receiver<ClientEvent>) {
+        match config {
+            Some(config) if offer => client.set_compression(config),
+            Some(config) => client.accept_compression(config),
+            None => {}
         }
+        let (tx, rx) = mpsc::channel::<ClientEvent>(8);
+        client.spawn_recv_send_tasks(tx).await;
+        (client, rx)
+    }
+
+    /// Spawns the tasks of both ends of an in-memory pipe, with the given
+    /// compression, and waits for compression to be negotiated. Like an
+    /// MD link, `a` offers compression and `b` accepts it.
+    async fn compressed_pipe(
+        a_config: Option<CompressionConfig>,
+        b_config: Option<CompressionConfig>,
+    ) -> [(Client<DuplexStream>, mpsc::Receiver<ClientEvent>); 2] {
+        let (a, b) = memory::pipe();
+
+        let a = spawn_with_compression(a, a_config, true).await;
+        let b = spawn_with_compression(b, b_config, false).await;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        [a, b]
+    }
+
+    #[tokio::test]
+    async fn compressed_link() {
+        let config = CompressionConfig::default();
+        let [(mut a, _a_rx), (mut b, mut b_rx)] = compressed_pipe(Some(config), Some(config)).await;
+
+        let large: Vec<u8> = vec![0xAA; 1000];
+        a.stage_datagram(datagram(&large)).await.unwrap();
+        a.stage_datagram(datagram(&[0xBB])).await.unwrap();
+        b.stage_datagram(datagram(&large)).await.unwrap();
+
+        assert_eq!(recv_datagram(&mut b_rx).await, large);
+        assert_eq!(recv_datagram(&mut b_rx).await, &[0xBB]);
+
+        let stats: CompressionStats = a.get_compression_stats().unwrap();
+        assert_eq!((stats.compressed, stats.uncompressed), (1, 1));
+        assert!(stats.ratio() < 0.5);
+
+        // both directions are compressed
+        assert_eq!(b.get_compression_stats().unwrap().compressed, 1);
+    }
+
+    #[tokio::test]
+    async fn accepting_end_waits_for_offer() {
+        let [(a, mut a_rx), (mut b, _b_rx)] = compressed_pipe(None, Some(CompressionConfig::default())).await;
+
+        // nothing is sent to a remote that does not offer compression
+        let large: Vec<u8> = vec![0xAA; 1000];
+        b.stage_datagram(datagram(&large)).await.unwrap();
+        assert_eq!(recv_datagram(&mut a_rx).await, large);
+
+        let stats: CompressionStats = b.get_compression_stats().unwrap();
+        assert_eq!((stats.compressed, stats.uncompressed), (0, 1));
+        assert_eq!(a.get_compression_stats(), None);
+    }
+
+    #[tokio::test]
+    async fn close_on_offer_without_opting_in() {
+        let [(_a, _a_rx), (_b, mut b_rx)] = compressed_pipe(Some(CompressionConfig::default()), None).await;
+
+        assert!(matches!(
+            recv_disconnect(&mut b_rx).await,
+            DisconnectReason::Error(ConnectionError::Framing(FrameError::EmptyFrame))
+        ));
+    }
+
+    #[tokio::test]
+    async fn close_on_compressed_frame_before_negotiation() {
+        let (mut client, mut remote) = connect(DG_SIZE_MAX).await;
+        client.accept_compression(CompressionConfig::default());
+        let (tx, mut rx) = mpsc::channel::<ClientEvent>(8);
+        let _handle = client.spawn_recv_send_tasks(tx).await;
+
+        let mut frame: BytesMut = BytesMut::new();
+        compress::encode_datagram(
+            &mut frame,
+            &[0xAA; 1000],
+            Some(&CompressionConfig::default()),
+            None,
+        );
+        remote.write_all(&frame).await.unwrap();
+
+        assert!(matches!(
+            recv_disconnect(&mut rx).await,
+            DisconnectReason::Error(ConnectionError::Compression(CompressionError::NotNegotiated))
+        ));
     }
 }
@@ -0,0 +1,178 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! In-memory transport, for running services in one process
+//! without opening any sockets, such as in tests.
+//!
+//! [`pipe`] returns two connected clients. A [`Listener`] is bound
+//! to a name, instead of an address, which [`connect`] opens in-memory
+//! connections to. Names are given as `memory:<name>` addresses to
+//! [`tcp::Acceptor::bind`] and [`tcp::Connection::connect`].
+//!
+//! [`tcp::Acceptor::bind`]: crate::tcp::Acceptor::bind
+//! [`tcp::Connection::connect`]: crate::tcp::Connection::connect
+
+use crate::addr::PeerAddr;
+use crate::Client;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{LazyLock, Mutex};
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+
+/// Bytes buffered in each direction of an in-memory connection.
+const PIPE_BUFFER_SIZE: usize = 64 * 1024; // 64 kb
+
+/// Connections not yet accepted by a [`Listener`].
+const LISTENER_BACKLOG: usize = 128;
+
+type Incoming = (DuplexStream, PeerAddr);
+
+/// Listeners that are bound, by name.
+static LISTENERS: LazyLock<Mutex<HashMap<String, mpsc::Sender<Incoming>>>> = LazyLock::new(Mutex::default);
+
+/// Returns two clients connected to each other. Both
+/// have the same address, numbered by [`PeerAddr::next_memory`].
+pub fn pipe() -> (Client<DuplexStream>, Client<DuplexStream>) {
+    let (a, b) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+    let peer: PeerAddr = PeerAddr::next_memory();
+
+    (Client::new(peer, peer, a), Client::new(peer, peer, b))
+}
+
+/// Accepts in-memory connections made to its name.
+#[derive(Debug)]
+pub struct Listener {
+    name: String,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<Incoming>>,
+}
+
+impl Listener {
+    pub fn bind(name: &str) -> Result<Self> {
+        let mut listeners = LISTENERS.lock().expect("Listeners lock poisoned.");
+
+        if listeners.get(name).is_some_and(|tx| !tx.is_closed()) {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!("Memory listener '{}' is already bound.", name),
+            ));
+        }
+        let (tx, rx) = mpsc::channel::<Incoming>(LISTENER_BACKLOG);
+        listeners.insert(name.to_owned(), tx);
+
+        Ok(Self {
+            name: name.to_owned(),
+            incoming: tokio::sync::Mutex::new(rx),
+        })
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Accepts a new connection. Both ends of the connection have
+    /// the same address, numbered by [`PeerAddr::next_memory`].
+    pub async fn accept(&self) -> Result<Incoming> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| Error::from(ErrorKind::NotConnected))
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let mut listeners = LISTENERS.lock().expect("Listeners lock poisoned.");
+
+        // the name may have been bound again by another listener
+        if listeners.get(&self.name).is_some_and(|tx| tx.is_closed()) {
+            listeners.remove(&self.name);
+        }
+    }
+}
+
+/// Opens an in-memory connection to the [`Listener`] bound to `name`.
+pub async fn connect(name: &str) -> Result<Incoming> {
+    let tx: mpsc::Sender<Incoming> = LISTENERS
+        .lock()
+        .expect("Listeners lock poisoned.")
+        .get(name)
+        .cloned()
+        .ok_or_else(|| Error::from(ErrorKind::ConnectionRefused))?;
+
+    let (local, remote) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+    let peer: PeerAddr = PeerAddr::next_memory();
+
+    tx.send((remote, peer))
+        .await
+        .map_err(|_| Error::from(ErrorKind::ConnectionRefused))?;
+
+    Ok((local, peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{datagram, recv_datagram};
+    use crate::ClientEvent;
+
+    #[tokio::test]
+    async fn datagrams_over_pipe() {
+        let (mut a, mut b) = pipe();
+        let (a_tx, mut a_rx) = mpsc::channel::<ClientEvent>(8);
+        let (b_tx, mut b_rx) = mpsc::channel::<ClientEvent>(8);
+        let _a_handle = a.spawn_recv_send_tasks(a_tx).await;
+        let _b_handle = b.spawn_recv_send_tasks(b_tx).await;
+
+        a.stage_datagram(datagram(&[0xAA])).await.unwrap();
+        b.stage_datagram(datagram(&[0xBB, 0xCC])).await.unwrap();
+
+        assert_eq!(recv_datagram(&mut b_rx).await, [0xAA]);
+        assert_eq!(recv_datagram(&mut a_rx).await, [0xBB, 0xCC]);
+
+        a.close();
+        match b_rx.recv().await.unwrap() {
+            ClientEvent::Disconnected(d) => assert_eq!(d.remote, b.get_remote()),
+            ClientEvent::Received(_) => panic!("Unexpected datagram."),
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_and_connect() {
+        assert_eq!(
+            connect("test-unbound").await.unwrap_err().kind(),
+            ErrorKind::ConnectionRefused
+        );
+        let listener: Listener = Listener::bind("test-bound").unwrap();
+
+        assert_eq!(
+            Listener::bind("test-bound").unwrap_err().kind(),
+            ErrorKind::AddrInUse
+        );
+        let (_stream, peer) = connect("test-bound").await.unwrap();
+        let (_accepted, accepted_peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, accepted_peer);
+
+        // the name is free again once the listener is dropped
+        drop(listener);
+        let _listener: Listener = Listener::bind("test-bound").unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Bounded queue of datagrams waiting to be sent on a connection.
+//!
+//! Each [`Client`] has its own send queue, with a budget of
+//! messages and bytes. The [`OverflowPolicy`] decides what happens
+//! when a datagram is staged while the queue is over budget, so that
+//! a slow remote cannot make the service that sends to it wait.
+//!
+//! [`Client`]: crate::Client
+
+use donet_core::datagram::datagram::Datagram;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/// What to do when a datagram is staged to a full send queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Wait until the send loop makes room in the queue.
+    #[default]
+    Block,
+    /// Drop the oldest queued datagrams until the new one fits.
+    DropOldest,
+    /// Close the connection.
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Self::Block),
+            "drop_oldest" => Ok(Self::DropOldest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(format!("unknown send queue overflow policy '{}'", s)),
+        }
+    }
+}
+
+/// The budget and [`OverflowPolicy`] of a send queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendQueueConfig {
+    /// Maximum number of queued datagrams.
+    pub max_messages: usize,
+    /// Maximum total size of queued datagrams, in bytes.
+    ///
+    /// A datagram larger than this is still accepted
+    /// into an empty queue, so it can always be sent.
+    pub max_bytes: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 1024,
+            max_bytes: 4 * 1024 * 1024, // 4 MiB
+            policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// A snapshot of the depth of a send queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueStats {
+    /// Number of datagrams in the queue.
+    pub messages: usize,
+    /// Total size of the datagrams in the queue, in bytes.
+    pub bytes: usize,
+    /// Largest number of bytes the queue has held.
+    pub peak_bytes: usize,
+    /// Number of datagrams dropped by [`OverflowPolicy::DropOldest`].
+    pub dropped: u64,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum QueueError {
+    #[error("the send queue is closed")]
+    Closed,
+    #[error("the send queue is full")]
+    Overflow,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    queue: VecDeque<Datagram>,
+    bytes: usize,
+    peak_bytes: usize,
+    closed: bool,
+    overflowed: bool,
+}
+
+/// A bounded queue of datagrams, shared by a [`Client`]
+/// and the send loop task that empties it.
+///
+/// [`Client`]: crate::Client
+#[derive(Debug)]
+pub struct SendQueue {
+    config: SendQueueConfig,
+    state: Mutex<QueueState>,
+    dropped: AtomicU64,
+    /// Notifies the send loop that datagrams were queued, or the queue closed.
+    queued: Notify,
+    /// Notifies blocked senders that room was made, or the queue closed.
+    space: Notify,
+    /// Notifies the send loop that the queue overflowed.
+    overflow: Notify,
+}
+
+impl SendQueue {
+    pub fn new(config: SendQueueConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::default(),
+            dropped: AtomicU64::new(0),
+            queued: Notify::new(),
+            space: Notify::new(),
+            overflow: Notify::new(),
+        }
+    }
+
+    pub fn get_config(&self) -> SendQueueConfig {
+        self.config
+    }
+
+    /// Adds a datagram to the back of the queue, applying
+    /// the [`OverflowPolicy`] if it does not fit.
+    pub async fn push(&self, dg: Datagram) -> Result<(), QueueError> {
+        loop {
+            // register for a notification before checking, so one is not missed
+            let space = self.space.notified();
+            {
+                let mut state = self.state.lock().expect("Send queue lock poisoned.");
+
+                if state.closed {
+                    return Err(QueueError::Closed);
+                }
+                if !self.fits(&state, dg.size()) {
+                    match self.config.policy {
+                        OverflowPolicy::Block => {}
+                        OverflowPolicy::DropOldest => self.drop_until_fits(&mut state, dg.size()),
+                        OverflowPolicy::Disconnect => {
+                            state.closed = true;
+                            state.overflowed = true;
+                            state.queue.clear();
+                            state.bytes = 0;
+                            drop(state);
+
+                            self.queued.notify_one();
+                            self.space.notify_waiters();
+                            self.overflow.notify_waiters();
+                            return Err(QueueError::Overflow);
+                        }
+                    }
+                }
+                if self.fits(&state, dg.size()) {
+                    state.bytes += dg.size();
+                    state.peak_bytes = state.peak_bytes.max(state.bytes);
+                    state.queue.push_back(dg);
+                    drop(state);
+
+                    self.queued.notify_one();
+                    return Ok(());
+                }
+            }
+            space.await;
+        }
+    }
+
+    /// Takes up to `max` datagrams from the front of the queue, waiting
+    /// until at least one is queued.
+    ///
+    /// Returns `Ok(None)` once the queue is closed and empty, or
+    /// [`QueueError::Overflow`] if it was closed by an overflow.
+    pub async fn pop_many(&self, max: usize) -> Result<Option<Vec<Datagram>>, QueueError> {
+        loop {
+            let queued = self.queued.notified();
+            {
+                let mut state = self.state.lock().expect("Send queue lock poisoned.");
+
+                if state.overflowed {
+                    return Err(QueueError::Overflow);
+                }
+                if !state.queue.is_empty() {
+                    let n: usize = max.min(state.queue.len());
+                    let batch: Vec<Datagram> = state.queue.drain(..n).collect();
+
+                    state.bytes -= batch.iter().map(Datagram::size).sum::<usize>();
+                    drop(state);
+
+                    self.space.notify_waiters();
+                    return Ok(Some(batch));
+                }
+                if state.closed {
+                    return Ok(None);
+                }
+            }
+            queued.await;
+        }
+    }
+
+    /// Closes the queue. Datagrams that were already queued can still
+    /// be taken with [`SendQueue::pop_many`], but no more can be pushed.
+    pub fn close(&self) {
+        self.state.lock().expect("Send queue lock poisoned.").closed = true;
+
+        self.queued.notify_one();
+        self.space.notify_waiters();
+    }
+
+    /// Waits until the queue is closed by [`OverflowPolicy::Disconnect`].
+    pub async fn overflowed(&self) {
+        loop {
+            let overflow = self.overflow.notified();
+
+            if self.state.lock().expect("Send queue lock poisoned.").overflowed {
+                return;
+            }
+            overflow.await;
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().expect("Send queue lock poisoned.").closed
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().expect("Send queue lock poisoned.");
+
+        QueueStats {
+            messages: state.queue.len(),
+            bytes: state.bytes,
+            peak_bytes: state.peak_bytes,
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn fits(&self, state: &QueueState, size: usize) -> bool {
+        if state.queue.is_empty() {
+            return true;
+        }
+        state.queue.len() < self.config.max_messages && state.bytes + size <= self.config.max_bytes
+    }
+
+    fn drop_until_fits(&self, state: &mut QueueState, size: usize) {
+        while !self.fits(state, size) {
+            let dg: Datagram = state.queue.pop_front().expect("Empty queue did not fit.");
+            state.bytes -= dg.size();
+
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn datagram(size: usize) -> Datagram {
+        let mut dg: Datagram = Datagram::default();
+        dg.add_data(vec![0_u8; size]).unwrap();
+        dg
+    }
+
+    fn queue(max_messages: usize, max_bytes: usize, policy: OverflowPolicy) -> SendQueue {
+        SendQueue::new(SendQueueConfig {
+            max_messages,
+            max_bytes,
+            policy,
+        })
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let queue: SendQueue = queue(2, 100, OverflowPolicy::DropOldest);
+
+        for size in [10, 20, 30] {
+            queue.push(datagram(size)).await.unwrap();
+        }
+        let batch: Vec<Datagram> = queue.pop_many(10).await.unwrap().unwrap();
+
+        assert_eq!(batch.iter().map(Datagram::size).collect::<Vec<_>>(), [20, 30]);
+        assert_eq!(queue.stats().dropped, 1);
+        assert_eq!(queue.stats().peak_bytes, 50);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_by_bytes() {
+        let queue: SendQueue = queue(10, 50, OverflowPolicy::DropOldest);
+
+        for size in [20, 20, 40] {
+            queue.push(datagram(size)).await.unwrap();
+        }
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                messages: 1,
+                bytes: 40,
+                peak_bytes: 40,
+                dropped: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_datagram_fits_empty_queue() {
+        let queue: SendQueue = queue(10, 50, OverflowPolicy::Disconnect);
+
+        queue.push(datagram(60)).await.unwrap();
+        assert_eq!(queue.stats().bytes, 60);
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let queue: SendQueue = queue(1, 100, OverflowPolicy::Disconnect);
+
+        queue.push(datagram(10)).await.unwrap();
+        assert_eq!(queue.push(datagram(10)).await, Err(QueueError::Overflow));
+
+        assert!(queue.is_closed());
+        assert!(matches!(queue.pop_many(10).await, Err(QueueError::Overflow)));
+        assert_eq!(queue.push(datagram(10)).await, Err(QueueError::Closed));
+    }
+
+    #[tokio::test]
+    async fn block_until_space() {
+        let queue: Arc<SendQueue> = Arc::new(queue(1, 100, OverflowPolicy::Block));
+        queue.push(datagram(10)).await.unwrap();
+
+        let pusher = tokio::spawn({
+            let queue: Arc<SendQueue> = queue.clone();
+            async move { queue.push(datagram(20)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!pusher.is_finished());
+
+        assert_eq!(queue.pop_many(10).await.unwrap().unwrap().len(), 1);
+        pusher.await.unwrap().unwrap();
+        assert_eq!(queue.stats().bytes, 20);
+    }
+
+    #[tokio::test]
+    async fn close_drains_queue() {
+        let queue: Arc<SendQueue> = Arc::new(queue(1, 100, OverflowPolicy::Block));
+        queue.push(datagram(10)).await.unwrap();
+
+        let pusher = tokio::spawn({
+            let queue: Arc<SendQueue> = queue.clone();
+            async move { queue.push(datagram(20)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        queue.close();
+
+        // blocked senders are woken, but queued datagrams are still sent
+        assert_eq!(pusher.await.unwrap(), Err(QueueError::Closed));
+        assert_eq!(queue.pop_many(10).await.unwrap().unwrap().len(), 1);
+        assert!(matches!(queue.pop_many(10).await, Ok(None)));
+    }
+}
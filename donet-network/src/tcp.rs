@@ -17,44 +17,365 @@
     License along with Donet. If not, see <https://www.gnu.org/licenses/>.
 */
 
+//! Stream sockets, over TCP or Unix domain sockets.
+//!
+//! Addresses are either `<host>:<port>`, or `unix:<path>` for a Unix
+//! domain socket, which co-located services may use to skip the TCP
+//! loopback. A `memory:<name>` address uses the [`memory`] transport
+//! instead of a socket. See [`Endpoint`].
+//!
+//! Unix domain sockets are only available on Unix platforms. Elsewhere,
+//! binding or connecting to a `unix:<path>` address is an error.
+
+use crate::addr::{Endpoint, PeerAddr, MEMORY_PREFIX, UNIX_PREFIX};
+use crate::memory;
+use crate::tls::{CertificateDer, ClientConfig, ServerConfig, TlsError};
+use crate::transport::BoxedTransport;
+use crate::Client;
 use log::info;
-use std::io::Result;
+use rustls::pki_types::ServerName;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Time allowed for a TLS handshake to complete.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A listening socket, bound to a TCP or Unix domain socket address.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    Memory(memory::Listener),
+}
+
+/// A connected TCP or Unix domain socket.
+#[derive(Debug)]
+pub enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// An in-memory connection, and the number of its peer.
+    Memory(DuplexStream, PeerAddr),
+}
 
 pub struct Acceptor {
-    pub socket: TcpListener,
+    pub socket: Listener,
     pub address: String,
+    tls: Option<TlsAcceptor>,
+}
+
+/// A connection accepted by an [`Acceptor`], which
+/// becomes a [`Client`] once its handshake is done.
+pub struct Incoming {
+    pub socket: Socket,
+    pub address: PeerAddr,
+    tls: Option<TlsAcceptor>,
 }
 
 pub struct Connection {
-    pub socket: TcpStream,
     pub address: String,
+    client: Client,
+}
+
+impl Listener {
+    pub async fn bind(uri: &str) -> Result<Self> {
+        match Endpoint::parse(uri) {
+            Endpoint::Tcp(address) => Ok(Self::Tcp(TcpListener::bind(address).await?)),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Self::Unix(bind_unix(path)?)),
+            #[cfg(not(unix))]
+            Endpoint::Unix(path) => Err(unix_unsupported(path)),
+            Endpoint::Memory(name) => Ok(Self::Memory(memory::Listener::bind(name)?)),
+        }
+    }
+
+    /// Accepts a new connection. Unix domain socket peers are
+    /// numbered with [`PeerAddr::next_unix`].
+    pub async fn accept(&self) -> Result<(Socket, PeerAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (socket, address) = listener.accept().await?;
+                Ok((Socket::Tcp(socket), address.into()))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Socket::Unix(socket), PeerAddr::next_unix()))
+            }
+            Self::Memory(listener) => {
+                let (socket, address) = listener.accept().await?;
+                Ok((Socket::Memory(socket, address), address))
+            }
+        }
+    }
+
+    /// Returns the address that connects to this listener, which
+    /// differs from the bound address if it was bound to port 0.
+    pub fn local_uri(&self) -> Result<String> {
+        match self {
+            Self::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Self::Unix(listener) => match listener.local_addr()?.as_pathname() {
+                Some(path) => Ok(format!("{}{}", UNIX_PREFIX, path.display())),
+                None => Err(Error::new(ErrorKind::AddrNotAvailable, "Unix socket is unnamed.")),
+            },
+            Self::Memory(listener) => Ok(format!("{}{}", MEMORY_PREFIX, listener.get_name())),
+        }
+    }
+}
+
+impl Socket {
+    pub async fn connect(uri: &str) -> Result<Self> {
+        match Endpoint::parse(uri) {
+            Endpoint::Tcp(address) => Ok(Self::Tcp(TcpStream::connect(address).await?)),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Ok(Self::Unix(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Endpoint::Unix(path) => Err(unix_unsupported(path)),
+            Endpoint::Memory(name) => {
+                let (socket, address) = memory::connect(name).await?;
+                Ok(Self::Memory(socket, address))
+            }
+        }
+    }
+}
+
+impl From<Socket> for Client {
+    fn from(value: Socket) -> Self {
+        match value {
+            Socket::Tcp(socket) => socket.into(),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.into(),
+            Socket::Memory(socket, address) => Client::from_stream(address, address, socket),
+        }
+    }
+}
+
+/// For sockets that carry something other than framed datagrams.
+impl From<Socket> for BoxedTransport {
+    fn from(value: Socket) -> Self {
+        match value {
+            Socket::Tcp(socket) => Self::new(socket),
+            #[cfg(unix)]
+            Socket::Unix(socket) => Self::new(socket),
+            Socket::Memory(socket, _) => Self::new(socket),
+        }
+    }
+}
+
+/// Binds a Unix domain socket, replacing a socket file
+/// left behind by a process that is no longer listening.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(err) if err.kind() == ErrorKind::AddrInUse && is_stale_socket(path) => {
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        res => res,
+    }
+}
+
+#[cfg(unix)]
+fn is_stale_socket(path: &Path) -> bool {
+    matches!(
+        std::os::unix::net::UnixStream::connect(path),
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused
+    )
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported(path: &Path) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!(
+            "Unix domain sockets are not supported on this platform: {}",
+            path.display()
+        ),
+    )
 }
 
 impl Acceptor {
     pub async fn bind(uri: &str) -> Result<Self> {
-        let socket: TcpListener = TcpListener::bind(uri).await?;
+        let socket: Listener = Listener::bind(uri).await?;
+
+        info!("Opened new listening socket at {}.", uri);
+
+        Ok(Self {
+            socket,
+            address: String::from(uri),
+            tls: None,
+        })
+    }
 
-        info!("Opened new TCP listening socket at {}.", uri);
+    /// Binds a listening socket whose connections use TLS.
+    pub async fn bind_tls(uri: &str, config: Arc<ServerConfig>) -> Result<Self> {
+        let socket: Listener = Listener::bind(uri).await?;
+
+        info!("Opened new TLS listening socket at {}.", uri);
 
         Ok(Self {
             socket,
             address: String::from(uri),
+            tls: Some(TlsAcceptor::from(config)),
         })
     }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Accepts a new connection.
+    ///
+    /// The TLS handshake, if any, is done by [`Incoming::handshake`],
+    /// so that a slow handshake does not hold up accepting others.
+    pub async fn accept(&self) -> Result<Incoming> {
+        let (socket, address) = self.socket.accept().await?;
+
+        Ok(Incoming {
+            socket,
+            address,
+            tls: self.tls.clone(),
+        })
+    }
+}
+
+impl Incoming {
+    /// Does the TLS handshake, if the [`Acceptor`] uses TLS,
+    /// and returns a [`Client`] for the connection.
+    pub async fn handshake(self) -> Result<Client> {
+        match (self.socket, self.tls) {
+            (Socket::Tcp(socket), None) => Ok(socket.into()),
+            (Socket::Tcp(socket), Some(tls)) => {
+                let local: PeerAddr = socket.local_addr()?.into();
+                let stream = accept_tls(&tls, socket).await?;
+
+                Ok(tls_client(self.address, local, stream))
+            }
+            // keep the number given to the peer when it was accepted
+            #[cfg(unix)]
+            (Socket::Unix(socket), None) => Ok(Client::from_stream(self.address, self.address, socket)),
+            #[cfg(unix)]
+            (Socket::Unix(socket), Some(tls)) => {
+                let stream = accept_tls(&tls, socket).await?;
+
+                Ok(tls_client(self.address, self.address, stream))
+            }
+            (socket @ Socket::Memory(..), None) => Ok(socket.into()),
+            (Socket::Memory(socket, address), Some(tls)) => {
+                let stream = accept_tls(&tls, socket).await?;
+
+                Ok(tls_client(address, address, stream))
+            }
+        }
+    }
+}
+
+/// Creates the [`Client`] of an accepted TLS stream, which
+/// keeps the certificate that the remote presented, if any.
+fn tls_client<S>(remote: PeerAddr, local: PeerAddr, stream: tokio_rustls::server::TlsStream<S>) -> Client
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let peer_certificate: Option<CertificateDer<'static>> = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|chain| chain.first())
+        .map(|cert| cert.clone().into_owned());
+
+    let mut client: Client = Client::from_stream(remote, local, stream);
+    client.set_peer_certificate(peer_certificate);
+    client
+}
+
+async fn accept_tls<S>(tls: &TlsAcceptor, socket: S) -> Result<tokio_rustls::server::TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(socket))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "TLS handshake timed out."))?
+}
+
+async fn connect_tls<S>(
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+    socket: S,
+) -> Result<tokio_rustls::client::TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, connector.connect(server_name, socket))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "TLS handshake timed out."))?
 }
 
 impl Connection {
     pub async fn connect(uri: &str) -> Result<Self> {
-        let socket: TcpStream = TcpStream::connect(uri).await?;
+        let socket: Socket = Socket::connect(uri).await?;
 
-        info!("Opened new TCP connection to {}.", uri);
+        info!("Opened new connection to {}.", uri);
 
         Ok(Self {
-            socket,
             address: String::from(uri),
+            client: socket.into(),
         })
     }
+
+    /// Opens a TLS connection. The server must present a certificate
+    /// for `server_name` that is trusted by the given configuration.
+    pub async fn connect_tls(uri: &str, config: Arc<ClientConfig>, server_name: &str) -> Result<Self> {
+        let server_name: ServerName<'static> = ServerName::try_from(server_name.to_owned())
+            .map_err(|_| TlsError::InvalidServerName(server_name.to_owned()))?;
+
+        let connector: TlsConnector = TlsConnector::from(config);
+
+        let client: Client = match Socket::connect(uri).await? {
+            Socket::Tcp(socket) => {
+                let (remote, local) = (socket.peer_addr()?.into(), socket.local_addr()?.into());
+                let stream = connect_tls(connector, server_name, socket).await?;
+
+                Client::from_stream(remote, local, stream)
+            }
+            #[cfg(unix)]
+            Socket::Unix(socket) => {
+                let peer: PeerAddr = PeerAddr::next_unix();
+                let stream = connect_tls(connector, server_name, socket).await?;
+
+                Client::from_stream(peer, peer, stream)
+            }
+            Socket::Memory(socket, peer) => {
+                let stream = connect_tls(connector, server_name, socket).await?;
+
+                Client::from_stream(peer, peer, stream)
+            }
+        };
+
+        info!("Opened new TLS connection to {}.", uri);
+
+        Ok(Self {
+            address: String::from(uri),
+            client,
+        })
+    }
+}
+
+/// Allows for upgrading a [`Connection`] structure, to
+/// a [`Client`] structure for advanced functionality, such
+/// as receiving and sending datagrams asynchronously.
+impl From<Connection> for Client {
+    fn from(value: Connection) -> Self {
+        value.client
+    }
 }
 
 #[cfg(test)]
@@ -101,4 +422,61 @@ mod tests {
             Err(err) => panic!("TCPConnection failed to establish: {:?}", err),
         }
     }
+
+    #[cfg(unix)]
+    mod unix {
+        use super::*;
+        use crate::test_util::{datagram, recv_datagram, spawn_accepted};
+        use crate::{Client, ClientEvent};
+        use std::path::PathBuf;
+        use tokio::sync::mpsc;
+
+        /// Returns a path for a Unix domain socket, unique to this test run.
+        fn socket_path(name: &str) -> PathBuf {
+            let path: PathBuf =
+                std::env::temp_dir().join(format!("donet-{}-{}.sock", std::process::id(), name));
+            let _ = std::fs::remove_file(&path);
+            path
+        }
+
+        #[tokio::test]
+        async fn unix_socket_datagrams() {
+            let uri: String = format!("unix:{}", socket_path("datagrams").display());
+            let acceptor: Acceptor = Acceptor::bind(&uri).await.unwrap();
+            assert_eq!(acceptor.socket.local_uri().unwrap(), uri);
+
+            let (accepted, mut server_rx) =
+                spawn_accepted(async move { acceptor.accept().await?.handshake().await });
+
+            let mut client: Client = Connection::connect(&uri).await.unwrap().into();
+            let (client_tx, mut client_rx) = mpsc::channel::<ClientEvent>(8);
+            let _handle = client.spawn_recv_send_tasks(client_tx).await;
+            let mut server: Client = accepted.await.unwrap();
+
+            client.stage_datagram(datagram(&[0xAA, 0xBB])).await.unwrap();
+            server.stage_datagram(datagram(&[0xAA, 0xBB])).await.unwrap();
+
+            for rx in [&mut server_rx, &mut client_rx] {
+                assert_eq!(recv_datagram(rx).await, [0xAA, 0xBB]);
+            }
+            // the server numbers its Unix peers
+            assert_ne!(server.get_remote(), client.get_remote());
+        }
+
+        #[tokio::test]
+        async fn unix_socket_rebind() {
+            let path: PathBuf = socket_path("rebind");
+            let uri: String = format!("unix:{}", path.display());
+
+            let acceptor: Acceptor = Acceptor::bind(&uri).await.unwrap();
+            assert!(Acceptor::bind(&uri).await.is_err(), "Socket in use was replaced.");
+
+            // the socket file is left behind when the listener is dropped
+            drop(acceptor);
+            assert!(path.exists());
+
+            let _acceptor: Acceptor = Acceptor::bind(&uri).await.unwrap();
+            Connection::connect(&uri).await.unwrap();
+        }
+    }
 }
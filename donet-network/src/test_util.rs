@@ -0,0 +1,66 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Fixtures shared by the tests of the transports.
+
+use crate::{Client, ClientEvent, ConnectionHandle, DisconnectReason};
+use donet_core::datagram::datagram::Datagram;
+use std::future::Future;
+use std::io;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub fn datagram(bytes: &[u8]) -> Datagram {
+    let mut dg: Datagram = Datagram::default();
+    dg.add_data(bytes).unwrap();
+    dg
+}
+
+/// Receives the next event, expecting a received datagram.
+pub async fn recv_datagram(rx: &mut mpsc::Receiver<ClientEvent>) -> Vec<u8> {
+    match rx.recv().await.unwrap() {
+        ClientEvent::Received(data) => data.dg.get_buffer().to_vec(),
+        ClientEvent::Disconnected(d) => panic!("Unexpected disconnect: {}", d.reason),
+    }
+}
+
+/// Receives the next event, expecting a disconnect.
+pub async fn recv_disconnect(rx: &mut mpsc::Receiver<ClientEvent>) -> DisconnectReason {
+    match rx.recv().await.unwrap() {
+        ClientEvent::Received(_) => panic!("Unexpected datagram."),
+        ClientEvent::Disconnected(d) => d.reason,
+    }
+}
+
+/// Spawns a task that accepts a connection with `accept`, and spawns
+/// the tasks of the accepted [`Client`]. Returns the task, which
+/// outputs the client, and the receiver of the client's events.
+pub fn spawn_accepted<F>(accept: F) -> (JoinHandle<Client>, mpsc::Receiver<ClientEvent>)
+where
+    F: Future<Output = io::Result<Client>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<ClientEvent>(8);
+
+    let accepted = tokio::spawn(async move {
+        let mut client: Client = accept.await.unwrap();
+        let _handle: ConnectionHandle = client.spawn_recv_send_tasks(tx).await;
+        client
+    });
+    (accepted, rx)
+}
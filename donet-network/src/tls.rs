@@ -0,0 +1,337 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! TLS for Donet TCP connections, using rustls.
+//!
+//! TLS is set up per listening socket with [`tcp::Acceptor::bind_tls`],
+//! and per outgoing connection with [`tcp::Connection::connect_tls`].
+//! The TLS stream is given to a [`Client`], so datagrams are framed
+//! the same way as over plain TCP.
+//!
+//! For internal links, such as between message directors, the server
+//! can require clients to present a certificate signed by a given CA
+//! (mutual TLS), by passing `client_ca` to [`server_config`].
+//!
+//! [`tcp::Acceptor::bind_tls`]: crate::tcp::Acceptor::bind_tls
+//! [`tcp::Connection::connect_tls`]: crate::tcp::Connection::connect_tls
+//! [`Client`]: crate::Client
+
+use ring::digest;
+use rustls::crypto::CryptoProvider;
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+pub use rustls::{ClientConfig, ServerConfig};
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("failed to read '{0}'; {1}")]
+    Read(String, io::Error),
+    #[error("no certificates found in '{0}'")]
+    NoCertificates(String),
+    #[error("no private key found in '{0}'")]
+    NoPrivateKey(String),
+    #[error("invalid server name '{0}'")]
+    InvalidServerName(String),
+    #[error("invalid client CA; {0}")]
+    ClientCA(#[from] rustls::server::VerifierBuilderError),
+    #[error("tls error; {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+impl From<TlsError> for io::Error {
+    fn from(value: TlsError) -> Self {
+        match value {
+            TlsError::Read(_, ref err) => io::Error::new(err.kind(), value.to_string()),
+            _ => io::Error::new(io::ErrorKind::InvalidInput, value),
+        }
+    }
+}
+
+/// A certificate chain and the private key of its first certificate.
+pub struct Identity {
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl Identity {
+    /// Loads an identity from a PEM certificate chain and a PEM private key.
+    pub fn from_files(cert: &Path, key: &Path) -> Result<Self, TlsError> {
+        Ok(Self {
+            cert_chain: load_certs(cert)?,
+            key: load_private_key(key)?,
+        })
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| TlsError::Read(path.display().to_string(), err))
+}
+
+/// Loads all certificates from a PEM file.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<_, _>>()
+        .map_err(|err| TlsError::Read(path.display().to_string(), err))?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+/// Loads the first private key from a PEM file.
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|err| TlsError::Read(path.display().to_string(), err))?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.display().to_string()))
+}
+
+/// Loads a CA bundle from a PEM file, to verify the certificates of peers.
+pub fn load_root_store(path: &Path) -> Result<RootCertStore, TlsError> {
+    let mut roots: RootCertStore = RootCertStore::empty();
+
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// Returns the SHA-256 fingerprint of a certificate, in lowercase hex,
+/// which identifies the certificate of a peer.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    digest::digest(&digest::SHA256, cert.as_ref())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Creates the TLS configuration of a listening socket.
+///
+/// If `client_ca` is given, clients must present a certificate
+/// signed by one of its CAs, else the handshake fails.
+pub fn server_config(
+    identity: Identity,
+    client_ca: Option<RootCertStore>,
+) -> Result<Arc<ServerConfig>, TlsError> {
+    let builder = ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+
+    let builder = match client_ca {
+        Some(roots) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider()).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(
+        builder.with_single_cert(identity.cert_chain, identity.key)?,
+    ))
+}
+
+/// Creates the TLS configuration of outgoing connections.
+///
+/// Servers must present a certificate signed by one of the CAs in
+/// `roots`. If `identity` is given, it is presented to servers that
+/// require a client certificate.
+pub fn client_config(
+    roots: RootCertStore,
+    identity: Option<Identity>,
+) -> Result<Arc<ClientConfig>, TlsError> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+
+    Ok(Arc::new(match identity {
+        Some(identity) => builder.with_client_auth_cert(identity.cert_chain, identity.key)?,
+        None => builder.with_no_client_auth(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp::{Acceptor, Connection};
+    use crate::test_util::recv_datagram;
+    use crate::{Client, ClientEvent};
+    use donet_core::datagram::datagram::Datagram;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use std::path::PathBuf;
+    use tokio::sync::mpsc;
+
+    /// A CA generated on the fly, for issuing test certificates.
+    pub struct TestCA {
+        ca: CertifiedKey,
+    }
+
+    impl TestCA {
+        pub fn new() -> Self {
+            let key: KeyPair = KeyPair::generate().unwrap();
+            let mut params: CertificateParams = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+            Self {
+                ca: CertifiedKey {
+                    cert: params.self_signed(&key).unwrap(),
+                    key_pair: key,
+                },
+            }
+        }
+
+        pub fn roots(&self) -> RootCertStore {
+            let mut roots: RootCertStore = RootCertStore::empty();
+            roots.add(self.ca.cert.der().clone()).unwrap();
+            roots
+        }
+
+        /// Issues a certificate for `localhost`.
+        pub fn issue(&self) -> (CertifiedKey, Identity) {
+            let key: KeyPair = KeyPair::generate().unwrap();
+            let params: CertificateParams = CertificateParams::new(vec!["localhost".into()]).unwrap();
+            let cert = params.signed_by(&key, &self.ca.cert, &self.ca.key_pair).unwrap();
+
+            let identity: Identity = Identity {
+                cert_chain: vec![cert.der().clone()],
+                key: PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+            };
+            (CertifiedKey { cert, key_pair: key }, identity)
+        }
+    }
+
+    /// Accepts one connection over TLS, and returns the accepted [`Client`]
+    /// or the handshake error, and the result of connecting to it.
+    async fn connect(
+        server: Arc<ServerConfig>,
+        client: Arc<ClientConfig>,
+    ) -> (io::Result<Client>, io::Result<Connection>) {
+        let acceptor: Acceptor = Acceptor::bind_tls("127.0.0.1:0", server).await.unwrap();
+        let address: String = acceptor.socket.local_uri().unwrap();
+
+        let accepted = tokio::spawn(async move { acceptor.accept().await?.handshake().await });
+        let connected = Connection::connect_tls(&address, client, "localhost").await;
+
+        (accepted.await.unwrap(), connected)
+    }
+
+    #[tokio::test]
+    async fn datagrams_over_tls() {
+        let ca: TestCA = TestCA::new();
+        let (_, server_identity) = ca.issue();
+
+        let server = server_config(server_identity, None).unwrap();
+        let client = client_config(ca.roots(), None).unwrap();
+
+        let (accepted, connected) = connect(server, client).await;
+        let mut server_side: Client = accepted.unwrap();
+        assert!(server_side.get_peer_certificate().is_none());
+        let mut client_side: Client = connected.unwrap().into();
+
+        let (server_tx, mut server_rx) = mpsc::channel::<ClientEvent>(8);
+        let (client_tx, mut client_rx) = mpsc::channel::<ClientEvent>(8);
+        let _server_handle = server_side.spawn_recv_send_tasks(server_tx).await;
+        let _client_handle = client_side.spawn_recv_send_tasks(client_tx).await;
+
+        let mut dg: Datagram = Datagram::default();
+        dg.add_data([0xAA, 0xBB]).unwrap();
+
+        client_side.stage_datagram(dg.clone()).await.unwrap();
+        assert_eq!(recv_datagram(&mut server_rx).await, [0xAA, 0xBB]);
+
+        server_side.stage_datagram(dg).await.unwrap();
+        assert_eq!(recv_datagram(&mut client_rx).await, [0xAA, 0xBB]);
+    }
+
+    #[tokio::test]
+    async fn untrusted_server() {
+        let (_, server_identity) = TestCA::new().issue();
+
+        let server = server_config(server_identity, None).unwrap();
+        let client = client_config(TestCA::new().roots(), None).unwrap();
+
+        let (_, connected) = connect(server, client).await;
+        assert!(connected.is_err());
+    }
+
+    #[tokio::test]
+    async fn mutual_tls() {
+        let ca: TestCA = TestCA::new();
+        let (_, server_identity) = ca.issue();
+        let (_, client_identity) = ca.issue();
+
+        let client_cert: CertificateDer<'static> = client_identity.cert_chain[0].clone();
+
+        let server = server_config(server_identity, Some(ca.roots())).unwrap();
+        let client = client_config(ca.roots(), Some(client_identity)).unwrap();
+
+        let (accepted, connected) = connect(server, client).await;
+        assert!(connected.is_ok());
+
+        // the server side knows the client by its certificate
+        let accepted: Client = accepted.unwrap();
+        let presented: &CertificateDer<'static> = accepted.get_peer_certificate().unwrap();
+        assert_eq!(fingerprint(presented), fingerprint(&client_cert));
+        assert_eq!(fingerprint(presented).len(), 64);
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_without_client_cert() {
+        let ca: TestCA = TestCA::new();
+        let (_, server_identity) = ca.issue();
+
+        let server = server_config(server_identity, Some(ca.roots())).unwrap();
+        let client = client_config(ca.roots(), None).unwrap();
+
+        let (accepted, _) = connect(server, client).await;
+        assert!(accepted.is_err());
+    }
+
+    #[test]
+    fn load_pem_files() {
+        let ca: TestCA = TestCA::new();
+        let (cert, _) = ca.issue();
+
+        let dir: PathBuf = std::env::temp_dir().join(format!("donet-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let (cert_path, key_path, ca_path) = (dir.join("cert.pem"), dir.join("key.pem"), dir.join("ca.pem"));
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        std::fs::write(&ca_path, ca.ca.cert.pem()).unwrap();
+
+        let identity: Identity = Identity::from_files(&cert_path, &key_path).unwrap();
+        assert!(server_config(identity, Some(load_root_store(&ca_path).unwrap())).is_ok());
+
+        // a key is not a certificate
+        assert!(matches!(load_certs(&key_path), Err(TlsError::NoCertificates(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
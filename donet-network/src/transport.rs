@@ -0,0 +1,110 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Byte streams that a [`Client`] frames datagrams over.
+//!
+//! Datagrams are framed the same way over every [`Transport`]. A
+//! [`Client`] is generic over its transport, which defaults to
+//! [`BoxedTransport`], so that the clients accepted by one listening
+//! socket have the same type whether or not they use TLS.
+//!
+//! [`Client`]: crate::Client
+
+use crate::{StreamReader, StreamWriter};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::{tcp, TcpStream};
+#[cfg(unix)]
+use tokio::net::{unix, UnixStream};
+
+/// A connected byte stream, which is split into the halves
+/// owned by the receive and send tasks of a [`Client`].
+///
+/// [`Client`]: crate::Client
+pub trait Transport: Send + 'static {
+    type Reader: AsyncRead + Send + Unpin + 'static;
+    type Writer: AsyncWrite + Send + Unpin + 'static;
+
+    fn into_split(self) -> (Self::Reader, Self::Writer);
+}
+
+impl Transport for TcpStream {
+    type Reader = tcp::OwnedReadHalf;
+    type Writer = tcp::OwnedWriteHalf;
+
+    fn into_split(self) -> (Self::Reader, Self::Writer) {
+        TcpStream::into_split(self)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    type Reader = unix::OwnedReadHalf;
+    type Writer = unix::OwnedWriteHalf;
+
+    fn into_split(self) -> (Self::Reader, Self::Writer) {
+        UnixStream::into_split(self)
+    }
+}
+
+/// Implements [`Transport`] for streams that are split with [`tokio::io::split`].
+macro_rules! split_transport {
+    ($stream:ty $(, $param:ident)?) => {
+        impl$(<$param>)? Transport for $stream
+        $(where
+            $param: AsyncRead + AsyncWrite + Send + Unpin + 'static,)?
+        {
+            type Reader = ReadHalf<Self>;
+            type Writer = WriteHalf<Self>;
+
+            fn into_split(self) -> (Self::Reader, Self::Writer) {
+                tokio::io::split(self)
+            }
+        }
+    };
+}
+
+split_transport!(DuplexStream);
+split_transport!(tokio_rustls::server::TlsStream<S>, S);
+split_transport!(tokio_rustls::client::TlsStream<S>, S);
+
+/// A [`Transport`] of any kind.
+pub struct BoxedTransport {
+    reader: StreamReader,
+    writer: StreamWriter,
+}
+
+impl BoxedTransport {
+    pub fn new<T: Transport>(transport: T) -> Self {
+        let (reader, writer) = transport.into_split();
+
+        Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+        }
+    }
+}
+
+impl Transport for BoxedTransport {
+    type Reader = StreamReader;
+    type Writer = StreamWriter;
+
+    fn into_split(self) -> (Self::Reader, Self::Writer) {
+        (self.reader, self.writer)
+    }
+}
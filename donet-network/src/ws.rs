@@ -0,0 +1,374 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! WebSocket transport, for clients that cannot open raw TCP
+//! sockets, such as web browsers.
+//!
+//! Each datagram is sent as one binary WebSocket message, with
+//! the same size tag prefix as over TCP. A peer that sends a
+//! text message, or a message that is not exactly one datagram,
+//! violates the protocol and is disconnected.
+//!
+//! The WebSocket is wrapped as a byte stream and given to a
+//! [`Client`], so services receive the same [`ClientEvent`]s
+//! whichever transport a peer used.
+//!
+//! [`ClientEvent`]: crate::ClientEvent
+
+use crate::frame::DG_SIZE_TAG_LEN;
+use crate::transport::Transport;
+use crate::Client;
+use bytes::{Buf, Bytes, BytesMut};
+use donet_core::globals::{DgSizeTag, DG_SIZE_MAX};
+use futures_util::{Sink, Stream};
+use log::info;
+use std::io::{self, Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// Time allowed for a WebSocket handshake to complete.
+const WS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes written to a [`WsStream`] that are buffered before
+/// whole datagrams must be sent as messages.
+const WS_WRITE_BUFFER_SIZE: usize = 64 * 1024; // 64 kb
+
+pub struct Acceptor {
+    pub socket: TcpListener,
+    pub address: String,
+}
+
+/// A TCP connection accepted by an [`Acceptor`], which becomes
+/// a [`Client`] once its WebSocket handshake is done.
+pub struct Incoming {
+    pub socket: TcpStream,
+    pub address: SocketAddr,
+}
+
+pub struct Connection {
+    pub address: String,
+    client: Client,
+}
+
+impl Acceptor {
+    pub async fn bind(uri: &str) -> Result<Self> {
+        let socket: TcpListener = TcpListener::bind(uri).await?;
+
+        info!("Opened new WebSocket listening socket at {}.", uri);
+
+        Ok(Self {
+            socket,
+            address: String::from(uri),
+        })
+    }
+
+    /// Accepts a new TCP connection. The WebSocket
+    /// handshake is done by [`Incoming::handshake`].
+    pub async fn accept(&self) -> Result<Incoming> {
+        let (socket, address) = self.socket.accept().await?;
+
+        Ok(Incoming { socket, address })
+    }
+}
+
+impl Incoming {
+    /// Does the WebSocket handshake, and returns a [`Client`] for the connection.
+    pub async fn handshake(self) -> Result<Client> {
+        let local: SocketAddr = self.socket.local_addr()?;
+
+        let ws = tokio::time::timeout(
+            WS_HANDSHAKE_TIMEOUT,
+            tokio_tungstenite::accept_async_with_config(self.socket, Some(config())),
+        )
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "WebSocket handshake timed out."))?
+        .map_err(to_io_error)?;
+
+        Ok(Client::from_stream(
+            self.address.into(),
+            local.into(),
+            WsStream::new(ws),
+        ))
+    }
+}
+
+impl Connection {
+    /// Opens a WebSocket connection to the given `ws://` URL.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (ws, _) = tokio::time::timeout(
+            WS_HANDSHAKE_TIMEOUT,
+            tokio_tungstenite::connect_async_with_config(url, Some(config()), true),
+        )
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "WebSocket handshake timed out."))?
+        .map_err(to_io_error)?;
+
+        let (remote, local) = match ws.get_ref() {
+            MaybeTlsStream::Plain(socket) => (socket.peer_addr()?, socket.local_addr()?),
+            _ => return Err(Error::new(ErrorKind::Unsupported, "Expected a plain TCP stream.")),
+        };
+
+        info!("Opened new WebSocket connection to {}.", url);
+
+        Ok(Self {
+            address: String::from(url),
+            client: Client::from_stream(remote.into(), local.into(), WsStream::new(ws)),
+        })
+    }
+}
+
+impl From<Connection> for Client {
+    fn from(value: Connection) -> Self {
+        value.client
+    }
+}
+
+impl<S> Transport for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    type Reader = ReadHalf<Self>;
+    type Writer = WriteHalf<Self>;
+
+    fn into_split(self) -> (Self::Reader, Self::Writer) {
+        tokio::io::split(self)
+    }
+}
+
+/// Limits messages to the size of one datagram and its size tag.
+fn config() -> WebSocketConfig {
+    let max: usize = DG_SIZE_TAG_LEN + usize::from(DG_SIZE_MAX);
+
+    WebSocketConfig::default()
+        .max_message_size(Some(max))
+        .max_frame_size(Some(max))
+}
+
+fn to_io_error(err: WsError) -> Error {
+    match err {
+        WsError::Io(err) => err,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => Error::from(ErrorKind::NotConnected),
+        err => Error::new(ErrorKind::InvalidData, err),
+    }
+}
+
+/// Returns the length of the datagram frame at the
+/// start of `bytes`, including its size tag.
+fn frame_len(bytes: &[u8]) -> Option<usize> {
+    let tag: [u8; DG_SIZE_TAG_LEN] = bytes.get(..DG_SIZE_TAG_LEN)?.try_into().ok()?;
+
+    Some(DG_SIZE_TAG_LEN + usize::from(DgSizeTag::from_le_bytes(tag)))
+}
+
+/// Wraps a [`WebSocketStream`] as a byte stream of size-tagged datagrams.
+///
+/// Each received binary message must hold exactly one datagram. Written
+/// bytes are buffered until a whole datagram is written, which is then
+/// sent as one binary message.
+struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    /// The unread part of the last received message.
+    read_buf: Bytes,
+    /// Written bytes that have not been sent as a message yet.
+    write_buf: BytesMut,
+}
+
+impl<S> WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Bytes::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    /// Sends each whole datagram in the write buffer as a message.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while let Some(len) = frame_len(&self.write_buf).filter(|len| *len <= self.write_buf.len()) {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io_error)?;
+
+            let frame: Bytes = self.write_buf.split_to(len).freeze();
+
+            Pin::new(&mut self.inner)
+                .start_send(Message::Binary(frame))
+                .map_err(to_io_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<()>> {
+        let this: &mut Self = self.get_mut();
+
+        while this.read_buf.is_empty() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    if frame_len(&data) != Some(data.len()) {
+                        return Poll::Ready(Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "WebSocket message is not exactly one datagram.",
+                        )));
+                    }
+                    this.read_buf = data;
+                }
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "WebSocket text messages are not supported.",
+                    )));
+                }
+                // a close message, or the end of the stream, is the end of file
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // pings are answered by tungstenite
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Poll::Ready(Err(to_io_error(err))),
+            }
+        }
+        let len: usize = buf.remaining().min(this.read_buf.len());
+
+        buf.put_slice(&this.read_buf[..len]);
+        this.read_buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this: &mut Self = self.get_mut();
+
+        if this.write_buf.len() >= WS_WRITE_BUFFER_SIZE {
+            ready!(this.poll_send(cx))?;
+        }
+        this.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this: &mut Self = self.get_mut();
+
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx).map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().inner)
+            .poll_close(cx)
+            .map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{datagram, recv_datagram, recv_disconnect, spawn_accepted};
+    use crate::{ClientEvent, ConnectionError, DisconnectReason};
+    use futures_util::SinkExt;
+    use tokio::sync::mpsc;
+
+    /// Accepts one WebSocket connection, and returns the accepted
+    /// [`Client`] with its tasks spawned, and the URL to connect to.
+    async fn accept() -> (
+        tokio::task::JoinHandle<Client>,
+        mpsc::Receiver<ClientEvent>,
+        String,
+    ) {
+        let acceptor: Acceptor = Acceptor::bind("127.0.0.1:0").await.unwrap();
+        let url: String = format!("ws://{}/", acceptor.socket.local_addr().unwrap());
+
+        let (accepted, rx) = spawn_accepted(async move { acceptor.accept().await?.handshake().await });
+        (accepted, rx, url)
+    }
+
+    #[tokio::test]
+    async fn datagrams_over_websocket() {
+        let (accepted, mut server_rx, url) = accept().await;
+
+        let mut client: Client = Connection::connect(&url).await.unwrap().into();
+        let (client_tx, mut client_rx) = mpsc::channel::<ClientEvent>(8);
+        let _handle = client.spawn_recv_send_tasks(client_tx).await;
+        let mut server: Client = accepted.await.unwrap();
+
+        client.stage_datagram(datagram(&[0xAA])).await.unwrap();
+        client.stage_datagram(datagram(&[0xBB, 0xCC])).await.unwrap();
+        assert_eq!(recv_datagram(&mut server_rx).await, [0xAA]);
+        assert_eq!(recv_datagram(&mut server_rx).await, [0xBB, 0xCC]);
+
+        server.stage_datagram(datagram(&[0xDD])).await.unwrap();
+        assert_eq!(recv_datagram(&mut client_rx).await, [0xDD]);
+
+        client.close();
+        assert!(matches!(
+            recv_disconnect(&mut server_rx).await,
+            DisconnectReason::RemoteClosed
+        ));
+    }
+
+    #[tokio::test]
+    async fn one_datagram_per_message() {
+        let (accepted, mut server_rx, url) = accept().await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let _server: Client = accepted.await.unwrap();
+
+        // the raw WebSocket messages carry the size tag
+        ws.send(Message::binary(vec![0x01, 0x00, 0xAA])).await.unwrap();
+        assert_eq!(recv_datagram(&mut server_rx).await, [0xAA]);
+
+        // two datagrams in one message
+        ws.send(Message::binary(vec![0x01, 0x00, 0xAA, 0x01, 0x00, 0xBB]))
+            .await
+            .unwrap();
+
+        let reason: DisconnectReason = recv_disconnect(&mut server_rx).await;
+        assert!(
+            matches!(reason, DisconnectReason::Error(ConnectionError::Io(err)) if err.kind() == ErrorKind::InvalidData)
+        );
+    }
+
+    #[tokio::test]
+    async fn text_message() {
+        let (accepted, mut server_rx, url) = accept().await;
+        let (mut ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let _server: Client = accepted.await.unwrap();
+
+        ws.send(Message::text("hello")).await.unwrap();
+
+        let reason: DisconnectReason = recv_disconnect(&mut server_rx).await;
+        assert!(
+            matches!(reason, DisconnectReason::Error(ConnectionError::Io(err)) if err.kind() == ErrorKind::InvalidData)
+        );
+    }
+}
@@ -41,6 +41,7 @@ use donet_core::{dconfig::DCFileConfig, read_dc_files};
 use donet_daemon::config::*;
 use donet_daemon::logger;
 use donet_daemon::logger::DaemonLogger;
+use donet_daemon::metrics;
 use donet_daemon::service::*;
 use log::*;
 use std::fs::File;
@@ -217,6 +218,7 @@ fn main() -> std::io::Result<()> {
     // are safe to start the Tokio asynchronous runtime.
     let tokio_runtime: Runtime = Builder::new_multi_thread()
         .enable_io()
+        .enable_time()
         .thread_stack_size(2 * 1024 * 1024) // default: 2MB
         .build()?;
 
@@ -226,6 +228,12 @@ fn main() -> std::io::Result<()> {
         // Tokio join handles for spawned tasks of services started.
         let mut service_handles: Vec<JoinHandle<std::io::Result<()>>> = vec![];
 
+        // services register their metrics as they boot
+        let metrics_handle: Option<JoinHandle<()>> = match &daemon_config.daemon.metrics_bind {
+            Some(bind) => Some(metrics::serve(bind).await?),
+            None => None,
+        };
+
         let want_client_agent: bool = services.client_agent.is_some();
         let want_message_director: bool = services.message_director.is_some();
         let want_state_server: bool = services.state_server.is_some();
@@ -235,9 +243,13 @@ fn main() -> std::io::Result<()> {
 
         cfg_if! {
             if #[cfg(feature = "client-agent")] {
+                use donet_client_agent::ClientAgent;
+
                 if want_client_agent {
                     info!("Booting Client Agent service.");
-                    todo!("CA not yet implemented.")
+
+                    let handle = ClientAgent::start(daemon_config.clone(), None).await?;
+                    service_handles.push(handle);
                 }
             } else {
                 if want_client_agent {
@@ -338,6 +350,9 @@ fn main() -> std::io::Result<()> {
         for handle in &service_handles {
             handle.abort();
         }
+        if let Some(handle) = metrics_handle {
+            handle.abort();
+        }
         // Await task handles to wrap things up; Expect a cancellation error.
         for handle in service_handles {
             assert!(handle.await.unwrap_err().is_cancelled());
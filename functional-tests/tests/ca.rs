@@ -0,0 +1,204 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Functional testing for the Client Agent service of
+//! the Donet server.
+//!
+//! The TOML configuration file used for the daemon is
+//! located in a file named "ca.toml" in this directory.
+
+use donet_core::globals::*;
+use std::env;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::thread::sleep;
+use std::time::Duration;
+
+static DAEMON_BIN: &str = "donetd";
+static DAEMON_TOML: &str = "ca.toml";
+
+/// Must be the same as the ones found in the TOML!
+static SERVICE_BIND_ADDR: &str = "127.0.0.1:57140";
+static WS_BIND_ADDR: &str = "127.0.0.1:57141";
+static MAX_FRAME_SIZE: DgSizeTag = 1024; // bytes
+
+static NETWORK_PROCESS_TIME: u64 = 100; // milliseconds
+static TCP_READ_TIMEOUT: u64 = 100; // milliseconds
+static TCP_READ_BUFFER_SIZE: usize = 64; // bytes
+
+/// Kills child processes before panicking.
+macro_rules! clean_panic {
+    ($proc:expr, $str:tt, $($f:expr),*) => {
+        {
+            kill_procs($proc)?;
+            panic!($str, $($f),*);
+        }
+    }
+}
+
+/// Utility function for killing all spawned [`Child`] processes.
+fn kill_procs(procs: &mut [Child]) -> std::io::Result<()> {
+    for proc in procs.iter_mut() {
+        proc.kill()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn ca_functional_testing() -> std::io::Result<()> {
+    let build_dir: String =
+        env::var("MESON_BUILD_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let src_dir: String =
+        env::var("MESON_SOURCE_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let pwd: String = format!("{}/functional-tests/tests", src_dir);
+
+    let mut procs: Vec<Child> = vec![];
+
+    procs.push(
+        Command::new(format!("{}/{}", build_dir, DAEMON_BIN))
+            .current_dir(pwd)
+            .arg(DAEMON_TOML)
+            .spawn()
+            .expect("Donet daemon failed to launch."),
+    );
+
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    test_accepts_client(&mut procs)?;
+    test_max_frame_size(&mut procs)?;
+    test_ws_max_frame_size(&mut procs)?;
+
+    // all tests ran without panicking or returning an error, so lets
+    // finally verify that the donet daemon is still standing
+    let donet: &mut Child = procs.get_mut(0).expect("Donet process should be found.");
+
+    assert!(donet.try_wait().unwrap().is_none(), "Daemon crashed.");
+    donet.kill()
+}
+
+/// Connects a client to the given listener of the Client Agent.
+fn connect_client(procs: &mut [Child], address: &str) -> std::io::Result<TcpStream> {
+    let sock = match TcpStream::connect(address) {
+        Ok(sock) => sock,
+        Err(err) => clean_panic!(procs, "Could not connect to the client agent.: {}", err),
+    };
+    sock.set_read_timeout(Some(Duration::from_millis(TCP_READ_TIMEOUT)))?;
+    Ok(sock)
+}
+
+/// Returns a datagram of the given size, with its size tag.
+fn sized_datagram(size: DgSizeTag) -> Vec<u8> {
+    let mut dg: Vec<u8> = size.to_le_bytes().to_vec();
+    dg.resize(dg.len() + usize::from(size), 0);
+    dg
+}
+
+fn test_accepts_client(procs: &mut [Child]) -> std::io::Result<()> {
+    eprintln!("test_accepts_client()");
+
+    let mut sock: TcpStream = connect_client(procs, SERVICE_BIND_ADDR)?;
+
+    if let Err(err) = sock.write_all(&sized_datagram(MAX_FRAME_SIZE)) {
+        clean_panic!(procs, "Could not write to the client agent.: {}", err);
+    }
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    // the connection stays open, so the read times out
+    match sock.read(&mut [0_u8; TCP_READ_BUFFER_SIZE]) {
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(()),
+        Err(err) => clean_panic!(procs, "Got unexpected IO error: {}", err),
+        Ok(bytes_read) => clean_panic!(procs, "Received {} unexpected bytes.", bytes_read),
+    }
+}
+
+fn test_max_frame_size(procs: &mut [Child]) -> std::io::Result<()> {
+    eprintln!("test_max_frame_size()");
+
+    let mut sock: TcpStream = connect_client(procs, SERVICE_BIND_ADDR)?;
+
+    // the size tag is over the 'max_frame_size' in the TOML
+    if let Err(err) = sock.write_all(&sized_datagram(MAX_FRAME_SIZE + 1)) {
+        clean_panic!(procs, "Could not write to the client agent.: {}", err);
+    }
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    // the CA closes the connection, which may be reset if the
+    // oversized datagram was not read to its end
+    match sock.read(&mut [0_u8; TCP_READ_BUFFER_SIZE]) {
+        Ok(0) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::ConnectionReset => Ok(()),
+        Err(err) => clean_panic!(procs, "Got unexpected IO error: {}", err),
+        Ok(bytes_read) => clean_panic!(procs, "Received {} unexpected bytes.", bytes_read),
+    }
+}
+
+fn test_ws_max_frame_size(procs: &mut [Child]) -> std::io::Result<()> {
+    eprintln!("test_ws_max_frame_size()");
+
+    let mut sock: TcpStream = connect_client(procs, WS_BIND_ADDR)?;
+
+    let request: String = format!(
+        "GET / HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        WS_BIND_ADDR
+    );
+    if let Err(err) = sock.write_all(request.as_bytes()) {
+        clean_panic!(procs, "Could not write to the client agent.: {}", err);
+    }
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    let mut read_buf = [0_u8; 256];
+    let response: String = match sock.read(&mut read_buf) {
+        Ok(bytes_read) => String::from_utf8_lossy(&read_buf[..bytes_read]).into_owned(),
+        Err(err) => clean_panic!(procs, "Got unexpected IO error: {}", err),
+    };
+    if !response.starts_with("HTTP/1.1 101") {
+        clean_panic!(procs, "WebSocket handshake failed: {}", response);
+    }
+
+    // one masked binary message, with a mask of zeros, holding a
+    // datagram over the 'max_frame_size' in the TOML
+    let payload: Vec<u8> = sized_datagram(MAX_FRAME_SIZE + 1);
+    let mut message: Vec<u8> = vec![0x82, 0x80 | 126];
+
+    message.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    message.extend_from_slice(&[0_u8; 4]);
+    message.extend_from_slice(&payload);
+
+    if let Err(err) = sock.write_all(&message) {
+        clean_panic!(procs, "Could not write to the client agent.: {}", err);
+    }
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    // the CA closes the connection, with or without a close message
+    match sock.read(&mut read_buf) {
+        Ok(0) => Ok(()),
+        Ok(_) if read_buf[0] == 0x88 => Ok(()),
+        Err(err) if err.kind() == ErrorKind::ConnectionReset => Ok(()),
+        Err(err) => clean_panic!(procs, "Got unexpected IO error: {}", err),
+        Ok(bytes_read) => clean_panic!(procs, "Received {} unexpected bytes.", bytes_read),
+    }
+}
@@ -36,8 +36,10 @@ use std::time::Duration;
 static DAEMON_BIN: &str = "donetd";
 static DAEMON_TOML: &str = "md.toml";
 
-/// Must be the same as the one found in the TOML!
+/// Must be the same as the ones found in the TOML!
 static SERVICE_BIND_ADDR: &str = "127.0.0.1:57123";
+static METRICS_BIND_ADDR: &str = "127.0.0.1:57130";
+static MAX_FRAME_SIZE: DgSizeTag = 1024; // bytes
 
 static NETWORK_PROCESS_TIME: u64 = 100; // milliseconds
 static TCP_READ_TIMEOUT: u64 = 100; // milliseconds
@@ -180,6 +182,10 @@ fn md_functional_testing() -> std::io::Result<()> {
     // run functional tests
     test_add_channels(&mut procs, &mut sock)?;
     test_add_range(&mut procs, &mut sock)?;
+    test_post_remove_on_disconnect(&mut procs, &mut sock)?;
+    test_cleared_post_remove_on_disconnect(&mut procs, &mut sock)?;
+    test_max_frame_size(&mut procs)?;
+    test_metrics(&mut procs)?;
 
     // all tests ran without panicking or returning an error, so lets
     // finally verify that the donet daemon is still standing
@@ -284,6 +290,128 @@ fn test_add_range(procs: &mut Vec<Child>, sock: &mut TcpStream) -> std::io::Resu
     Ok(())
 }
 
+/// Connects a second subscriber, which is killed by the tests.
+fn connect_subscriber(procs: &mut Vec<Child>) -> std::io::Result<TcpStream> {
+    match TcpStream::connect(SERVICE_BIND_ADDR) {
+        Ok(sock) => Ok(sock),
+        Err(err) => clean_panic!(procs, "Could not connect to the message director.: {}", err),
+    }
+}
+
+/// Returns a post remove datagram sent to the given channel, without a size tag.
+fn post_remove_datagram(channel: Channel) -> Datagram {
+    let mut dg: Datagram = Datagram::default();
+    dg.add_internal_header(vec![channel], 1337, Protocol::SSDeleteAIObjects.into())
+        .unwrap();
+    dg
+}
+
+fn test_post_remove_on_disconnect(procs: &mut Vec<Child>, sock: &mut TcpStream) -> std::io::Result<()> {
+    eprintln!("test_post_remove_on_disconnect()");
+
+    let mut ai_sock: TcpStream = connect_subscriber(procs)?;
+
+    // the post remove is sent to a channel that our first subscriber is on
+    let post_remove: Datagram = post_remove_datagram(401000000);
+
+    clean_sock_write_all!(procs, ai_sock, &msgs::add_post_remove(1337, post_remove.clone()));
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    // kill the subscriber partway through sending a datagram
+    clean_sock_write_all!(procs, ai_sock, &msgs::add_channel(403000000)[..5]);
+    drop(ai_sock);
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    let mut expected: Vec<u8> = (post_remove.size() as DgSizeTag).to_le_bytes().to_vec();
+    expected.extend_from_slice(post_remove.get_buffer());
+
+    let mut read_buf = [0_u8; TCP_READ_BUFFER_SIZE];
+    let bytes_read: usize = clean_sock_read(procs, sock, &mut read_buf)?;
+
+    clean_assert_eq!(
+        procs,
+        read_buf[..bytes_read],
+        expected[..],
+        "did not receive the post remove of the killed subscriber"
+    );
+    Ok(())
+}
+
+fn test_cleared_post_remove_on_disconnect(
+    procs: &mut Vec<Child>,
+    sock: &mut TcpStream,
+) -> std::io::Result<()> {
+    eprintln!("test_cleared_post_remove_on_disconnect()");
+
+    let mut ai_sock: TcpStream = connect_subscriber(procs)?;
+
+    let mut dg: Vec<u8> = msgs::add_post_remove(1337, post_remove_datagram(401000000));
+    dg.append(&mut msgs::clear_post_removes(1337));
+
+    clean_sock_write_all!(procs, ai_sock, &dg);
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    drop(ai_sock);
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    // the read times out, as no post remove is left to send
+    match sock.read(&mut [0_u8; TCP_READ_BUFFER_SIZE]) {
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(()),
+        Err(err) => clean_panic!(procs, "Got unexpected IO error: {}", err),
+        Ok(bytes_read) => clean_panic!(procs, "Received {} unexpected bytes.", bytes_read),
+    }
+}
+
+fn test_max_frame_size(procs: &mut Vec<Child>) -> std::io::Result<()> {
+    eprintln!("test_max_frame_size()");
+
+    let mut ai_sock: TcpStream = connect_subscriber(procs)?;
+    ai_sock.set_read_timeout(Some(Duration::from_millis(TCP_READ_TIMEOUT)))?;
+
+    // the size tag is over the 'max_frame_size' in the TOML
+    let mut dg: Vec<u8> = (MAX_FRAME_SIZE + 1).to_le_bytes().to_vec();
+    dg.resize(dg.len() + usize::from(MAX_FRAME_SIZE) + 1, 0);
+
+    clean_sock_write_all!(procs, ai_sock, &dg);
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    // the MD closes the connection, which may be reset if the
+    // oversized datagram was not read to its end
+    match ai_sock.read(&mut [0_u8; TCP_READ_BUFFER_SIZE]) {
+        Ok(0) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::ConnectionReset => Ok(()),
+        Err(err) => clean_panic!(procs, "Got unexpected IO error: {}", err),
+        Ok(bytes_read) => clean_panic!(procs, "Received {} unexpected bytes.", bytes_read),
+    }
+}
+
+fn test_metrics(procs: &mut Vec<Child>) -> std::io::Result<()> {
+    eprintln!("test_metrics()");
+
+    let mut sock = match TcpStream::connect(METRICS_BIND_ADDR) {
+        Ok(sock) => sock,
+        Err(err) => clean_panic!(procs, "Could not connect to the metrics endpoint.: {}", err),
+    };
+    clean_sock_write_all!(procs, sock, b"GET /metrics HTTP/1.1\r\nHost: md\r\n\r\n");
+
+    // the daemon closes the connection once it has answered
+    let mut response: String = String::new();
+    sock.read_to_string(&mut response)?;
+
+    clean_assert_eq!(procs, response.lines().next(), Some("HTTP/1.1 200 OK"));
+
+    for metric in [
+        "donet_md_received_datagrams_total ",
+        "donet_md_routed_datagrams_total ",
+        "donet_md_messages_total{msg_type=\"CAAddInterest\"} ",
+        "donet_md_fan_out_bucket{le=\"1.0\"} ",
+        "donet_md_routing_latency_seconds_count ",
+    ] {
+        clean_assert_eq!(procs, response.contains(metric), true, "metric is missing");
+    }
+    Ok(())
+}
+
 mod msgs {
     use super::*;
 
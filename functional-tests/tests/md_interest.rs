@@ -0,0 +1,252 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Functional testing for the interest tracking of the Message
+//! Director service of the Donet server.
+//!
+//! The test plays the upstream MD of the daemon, and checks that the
+//! daemon only sends it messages that the rest of the cluster may
+//! want, and that channels are only added and removed upstream when
+//! they get their first subscriber, or lose their last one. It also
+//! plays a downstream MD, which watches the interest of the daemon.
+//!
+//! The TOML configuration file used for the daemon is
+//! located in a file named "md_interest.toml" in this directory.
+
+use donet_core::datagram::datagram::*;
+use donet_core::globals::*;
+use donet_core::Protocol;
+use std::env;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{Child, Command};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+static DAEMON_BIN: &str = "donetd";
+static DAEMON_TOML: &str = "md_interest.toml";
+
+/// Must be the same as the ones found in the TOML!
+static SERVICE_BIND_ADDR: &str = "127.0.0.1:57128";
+static UPSTREAM_BIND_ADDR: &str = "127.0.0.1:57129";
+
+static NETWORK_PROCESS_TIME: u64 = 100; // milliseconds
+static CONNECT_TIMEOUT: u64 = 5000; // milliseconds
+static TCP_READ_TIMEOUT: u64 = 2000; // milliseconds
+
+#[test]
+fn md_interest_functional_testing() -> std::io::Result<()> {
+    let build_dir: String =
+        env::var("MESON_BUILD_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let src_dir: String =
+        env::var("MESON_SOURCE_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let pwd: String = format!("{}/functional-tests/tests", src_dir);
+
+    // the daemon connects to its upstream MD on startup, so listen first
+    let upstream: TcpListener = TcpListener::bind(UPSTREAM_BIND_ADDR)?;
+    upstream.set_nonblocking(true)?;
+
+    let mut donet: Child = Command::new(format!("{}/{}", build_dir, DAEMON_BIN))
+        .current_dir(pwd)
+        .arg(DAEMON_TOML)
+        .spawn()
+        .expect("Donet daemon failed to launch.");
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| test_interest(&upstream)));
+
+    // A [`Child`] process does not kill itself on drop, so
+    // we kill it manually here, before failing the test.
+    let crashed: bool = donet.try_wait()?.is_some();
+    donet.kill()?;
+
+    match result {
+        Ok(result) => result?,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+    assert!(!crashed, "Daemon crashed.");
+    Ok(())
+}
+
+fn test_interest(upstream: &TcpListener) -> std::io::Result<()> {
+    let mut link: TcpStream = accept(upstream)?;
+    let resume: Vec<u8> = msgs::control(Protocol::MDResume, &[0]);
+    let watch: Vec<u8> = msgs::control(Protocol::MDWatchInterest, &[]);
+
+    // the daemon resumes its session, which is random, before watching our interest
+    assert_eq!(
+        read(&mut link, resume.len())?[..resume.len() - 8],
+        resume[..resume.len() - 8]
+    );
+    assert_eq!(read(&mut link, watch.len())?, watch);
+
+    // two services subscribe to the same channel, which is added upstream once
+    let mut sock: TcpStream = connect(SERVICE_BIND_ADDR)?;
+    let mut sock_2: TcpStream = connect(SERVICE_BIND_ADDR)?;
+
+    let add_channel: Vec<u8> = msgs::control(Protocol::MDAddChannel, &[1234]);
+
+    sock.write_all(&add_channel)?;
+    assert_eq!(read(&mut link, add_channel.len())?, add_channel);
+
+    sock_2.write_all(&add_channel)?;
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    // until we sync our interest, everything is sent upstream
+    let routed: Vec<u8> = msgs::internal(1234, 99);
+
+    sock.write_all(&routed)?;
+    assert_eq!(read(&mut link, routed.len())?, routed);
+
+    // once synced, a channel with subscribers on the daemon is only
+    // sent upstream if we want it, unlike channels without any
+    link.write_all(&msgs::control(Protocol::MDInterestSynced, &[]))?;
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    assert_next_routed(&mut sock, &mut link, &[msgs::internal(1234, 100)])?;
+
+    link.write_all(&msgs::control(Protocol::MDAddChannel, &[1234]))?;
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    let routed: Vec<u8> = msgs::internal(1234, 101);
+
+    sock.write_all(&routed)?;
+    assert_eq!(read(&mut link, routed.len())?, routed);
+
+    link.write_all(&msgs::control(Protocol::MDRemoveChannel, &[1234]))?;
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    assert_next_routed(&mut sock, &mut link, &[msgs::internal(1234, 102)])?;
+
+    // a downstream MD is told what everyone else subscribes to
+    let mut downstream: TcpStream = connect(SERVICE_BIND_ADDR)?;
+    downstream.write_all(&watch)?;
+
+    let mut expected: Vec<u8> = add_channel.clone();
+    expected.append(&mut msgs::control(Protocol::MDInterestSynced, &[]));
+
+    assert_eq!(read(&mut downstream, expected.len())?, expected);
+
+    // including what the upstream MD subscribes to
+    let add_range: Vec<u8> = msgs::control(Protocol::MDAddRange, &[5000, 5999]);
+
+    link.write_all(&add_range)?;
+    assert_eq!(read(&mut downstream, add_range.len())?, add_range);
+
+    // the channel is removed upstream once, when its last subscriber leaves
+    let remove_channel: Vec<u8> = msgs::control(Protocol::MDRemoveChannel, &[1234]);
+
+    sock_2.write_all(&remove_channel)?;
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+    sock.write_all(&remove_channel)?;
+
+    assert_eq!(read(&mut link, remove_channel.len())?, remove_channel);
+    assert_eq!(read(&mut downstream, remove_channel.len())?, remove_channel);
+
+    assert_next_routed(&mut sock, &mut link, &[])?;
+    Ok(())
+}
+
+/// Sends the given messages, which the upstream MD must not get,
+/// followed by one that it must get next, as it has no subscribers.
+fn assert_next_routed(
+    sock: &mut TcpStream,
+    link: &mut TcpStream,
+    dropped: &[Vec<u8>],
+) -> std::io::Result<()> {
+    for msg in dropped {
+        sock.write_all(msg)?;
+    }
+    let routed: Vec<u8> = msgs::internal(777, 1337);
+
+    sock.write_all(&routed)?;
+    assert_eq!(read(link, routed.len())?, routed);
+    Ok(())
+}
+
+fn connect(addr: &str) -> std::io::Result<TcpStream> {
+    let sock: TcpStream = TcpStream::connect(addr)?;
+
+    sock.set_read_timeout(Some(Duration::from_millis(TCP_READ_TIMEOUT)))?;
+    Ok(sock)
+}
+
+/// Waits for the daemon to connect to our upstream MD socket.
+fn accept(upstream: &TcpListener) -> std::io::Result<TcpStream> {
+    let start: Instant = Instant::now();
+
+    loop {
+        match upstream.accept() {
+            Ok((sock, _)) => {
+                sock.set_nonblocking(false)?;
+                sock.set_read_timeout(Some(Duration::from_millis(TCP_READ_TIMEOUT)))?;
+                return Ok(sock);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                assert!(
+                    start.elapsed() < Duration::from_millis(CONNECT_TIMEOUT),
+                    "Daemon did not connect upstream."
+                );
+                sleep(Duration::from_millis(10));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn read(sock: &mut TcpStream, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf: Vec<u8> = vec![0; len];
+    sock.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+mod msgs {
+    use super::*;
+
+    fn size_tagged(datagram: Datagram) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_size(datagram.size() as DgSizeTag).unwrap();
+        dg.add_data(datagram.get_data()).unwrap();
+        dg.get_data()
+    }
+
+    /// Returns a size tagged internal message.
+    pub fn internal(recipient: Channel, sender: Channel) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_internal_header(vec![recipient], sender, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+        size_tagged(dg)
+    }
+
+    /// Returns a size tagged control message with the given channels.
+    pub fn control(msg_type: Protocol, channels: &[Channel]) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(msg_type.into()).unwrap();
+
+        for channel in channels {
+            dg.add_channel(*channel).unwrap();
+        }
+        size_tagged(dg)
+    }
+}
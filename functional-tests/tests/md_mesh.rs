@@ -0,0 +1,271 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Functional testing for the mesh mode of the Message
+//! Director service of the Donet server.
+//!
+//! The test plays a peer MD of the daemon, and checks that datagrams
+//! are only forwarded to it for the channels it added, that the
+//! datagrams it forwards are routed, and that the daemon keeps
+//! routing and reconnects once its links to the peer are lost.
+//!
+//! The TOML configuration file used for the daemon is
+//! located in a file named "md_mesh.toml" in this directory.
+
+use donet_core::datagram::datagram::*;
+use donet_core::globals::*;
+use donet_core::Protocol;
+use std::env;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{Child, Command};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+static DAEMON_BIN: &str = "donetd";
+static DAEMON_TOML: &str = "md_mesh.toml";
+
+/// Must be the same as the ones found in the TOML!
+static SERVICE_BIND_ADDR: &str = "127.0.0.1:57126";
+static PEER_BIND_ADDR: &str = "127.0.0.1:57127";
+static DAEMON_MD_ID: u32 = 7;
+
+static PEER_MD_ID: u32 = 8;
+
+static NETWORK_PROCESS_TIME: u64 = 100; // milliseconds
+static CONNECT_TIMEOUT: u64 = 5000; // milliseconds
+static TCP_READ_TIMEOUT: u64 = 2000; // milliseconds
+
+#[test]
+fn md_mesh_functional_testing() -> std::io::Result<()> {
+    let build_dir: String =
+        env::var("MESON_BUILD_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let src_dir: String =
+        env::var("MESON_SOURCE_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let pwd: String = format!("{}/functional-tests/tests", src_dir);
+
+    // the daemon links to its peers on startup, so listen first
+    let peer: TcpListener = TcpListener::bind(PEER_BIND_ADDR)?;
+    peer.set_nonblocking(true)?;
+
+    let mut donet: Child = Command::new(format!("{}/{}", build_dir, DAEMON_BIN))
+        .current_dir(pwd)
+        .arg(DAEMON_TOML)
+        .spawn()
+        .expect("Donet daemon failed to launch.");
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| test_mesh(&peer)));
+
+    // A [`Child`] process does not kill itself on drop, so
+    // we kill it manually here, before failing the test.
+    let crashed: bool = donet.try_wait()?.is_some();
+    donet.kill()?;
+
+    match result {
+        Ok(result) => result?,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+    assert!(!crashed, "Daemon crashed.");
+    Ok(())
+}
+
+fn test_mesh(peer: &TcpListener) -> std::io::Result<()> {
+    // the daemon introduces itself over its link to us
+    let mut link: TcpStream = accept(peer)?;
+    let hello: Vec<u8> = msgs::peer_hello(DAEMON_MD_ID);
+
+    assert_eq!(read(&mut link, hello.len())?, hello);
+
+    // setup our TCP socket to interact with the MD as a subscriber
+    let mut sock: TcpStream = connect(SERVICE_BIND_ADDR)?;
+
+    // the interest of its subscribers is added over the link
+    let add_channel: Vec<u8> = msgs::add_channel(1234);
+
+    sock.write_all(&add_channel)?;
+    assert_eq!(read(&mut link, add_channel.len())?, add_channel);
+
+    // link to the daemon as its peer, and add our own interest
+    let mut peer_sock: TcpStream = connect(SERVICE_BIND_ADDR)?;
+
+    let mut interest: Vec<u8> = msgs::peer_hello(PEER_MD_ID);
+    interest.append(&mut msgs::add_channel(5000));
+
+    peer_sock.write_all(&interest)?;
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    // only datagrams for the channels we added are forwarded to us
+    let uninteresting: Datagram = msgs::internal(6000, 99);
+    let interesting: Datagram = msgs::internal(5000, 99);
+
+    sock.write_all(&msgs::size_tagged(uninteresting))?;
+    sock.write_all(&msgs::size_tagged(interesting.clone()))?;
+
+    let forward: Vec<u8> = msgs::peer_forward(DAEMON_MD_ID, interesting);
+    assert_eq!(read(&mut peer_sock, forward.len())?, forward);
+
+    // our interest was not passed on as the daemon's own
+    peer_sock.set_nonblocking(true)?;
+    link.set_nonblocking(true)?;
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    assert!(is_idle(&mut peer_sock), "Forwarded an uninteresting datagram.");
+    assert!(is_idle(&mut link), "Forwarded our interest back to us.");
+
+    peer_sock.set_nonblocking(false)?;
+    link.set_nonblocking(false)?;
+
+    // datagrams we forward are routed to its subscribers, unless
+    // they are tagged with its own ID, which means they looped
+    let looped: Datagram = msgs::internal(1234, 55);
+    let routed: Datagram = msgs::internal(1234, 56);
+
+    link.write_all(&msgs::peer_forward(DAEMON_MD_ID, looped))?;
+    link.write_all(&msgs::peer_forward(PEER_MD_ID, routed.clone()))?;
+
+    let expected: Vec<u8> = msgs::size_tagged(routed);
+    assert_eq!(read(&mut sock, expected.len())?, expected);
+
+    // lose the peer. the daemon keeps routing to its subscribers
+    drop(link);
+    drop(peer_sock);
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    let mut add_local: Vec<u8> = msgs::add_channel(5000);
+    let local: Vec<u8> = msgs::size_tagged(msgs::internal(5000, 99));
+
+    add_local.extend_from_slice(&local);
+    sock.write_all(&add_local)?;
+    assert_eq!(read(&mut sock, local.len())?, local);
+
+    // once the peer is back, the daemon introduces itself and replays
+    // its interest, and the peer's forwarded datagrams are routed again
+    let mut link: TcpStream = accept(peer)?;
+
+    let mut expected: Vec<u8> = msgs::peer_hello(DAEMON_MD_ID);
+    expected.append(&mut msgs::add_channel(1234));
+    expected.append(&mut msgs::add_channel(5000));
+
+    assert_eq!(read(&mut link, expected.len())?, expected);
+
+    let routed: Datagram = msgs::internal(1234, 57);
+
+    link.write_all(&msgs::peer_forward(PEER_MD_ID, routed.clone()))?;
+
+    let expected: Vec<u8> = msgs::size_tagged(routed);
+    assert_eq!(read(&mut sock, expected.len())?, expected);
+    Ok(())
+}
+
+fn connect(addr: &str) -> std::io::Result<TcpStream> {
+    let sock: TcpStream = TcpStream::connect(addr)?;
+
+    sock.set_read_timeout(Some(Duration::from_millis(TCP_READ_TIMEOUT)))?;
+    Ok(sock)
+}
+
+/// Waits for the daemon to connect to our peer MD socket.
+fn accept(peer: &TcpListener) -> std::io::Result<TcpStream> {
+    let start: Instant = Instant::now();
+
+    loop {
+        match peer.accept() {
+            Ok((sock, _)) => {
+                sock.set_nonblocking(false)?;
+                sock.set_read_timeout(Some(Duration::from_millis(TCP_READ_TIMEOUT)))?;
+                return Ok(sock);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                assert!(
+                    start.elapsed() < Duration::from_millis(CONNECT_TIMEOUT),
+                    "Daemon did not link to its peer."
+                );
+                sleep(Duration::from_millis(10));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn read(sock: &mut TcpStream, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf: Vec<u8> = vec![0; len];
+    sock.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Returns `true` if nothing was received on a non-blocking socket.
+fn is_idle(sock: &mut TcpStream) -> bool {
+    let mut buf: [u8; 1] = [0];
+
+    matches!(sock.read(&mut buf), Err(err) if err.kind() == std::io::ErrorKind::WouldBlock)
+}
+
+mod msgs {
+    use super::*;
+    use donet_core::messages::{MDPeerForward, MDPeerHello, Payload, ProtocolMessage};
+
+    /// Returns an internal message, without a size tag.
+    pub fn internal(recipient: Channel, sender: Channel) -> Datagram {
+        let mut dg = Datagram::default();
+
+        dg.add_internal_header(vec![recipient], sender, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+        dg
+    }
+
+    pub fn size_tagged(datagram: Datagram) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_size(datagram.size() as DgSizeTag).unwrap();
+        dg.add_data(datagram.get_data()).unwrap();
+        dg.get_data()
+    }
+
+    pub fn add_channel(channel: Channel) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(Protocol::MDAddChannel.into()).unwrap();
+        dg.add_channel(channel).unwrap();
+        size_tagged(dg)
+    }
+
+    pub fn peer_hello(md_id: u32) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(MDPeerHello::MSG_TYPE.into()).unwrap();
+        MDPeerHello { md_id }.encode(&mut dg).unwrap();
+        size_tagged(dg)
+    }
+
+    pub fn peer_forward(origin: u32, mut datagram: Datagram) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(MDPeerForward::MSG_TYPE.into()).unwrap();
+
+        let forward = MDPeerForward {
+            origin,
+            datagram: Payload(datagram.get_bytes()),
+        };
+        forward.encode(&mut dg).unwrap();
+        size_tagged(dg)
+    }
+}
@@ -0,0 +1,240 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Functional testing for the persistence of the Message
+//! Director service of the Donet server.
+//!
+//! The test kills the daemon while two services are connected, and
+//! restarts it. The service that resumes its session within the grace
+//! period is asked to resync, and its post removes are not routed.
+//! The post removes of the other service are routed once it ends.
+//!
+//! The TOML configuration file used for the daemon is
+//! located in a file named "md_persist.toml" in this directory.
+
+use donet_core::datagram::datagram::*;
+use donet_core::globals::*;
+use donet_core::Protocol;
+use std::env;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{Child, Command};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+static DAEMON_BIN: &str = "donetd";
+static DAEMON_TOML: &str = "md_persist.toml";
+
+/// Must be the same as the ones found in the TOML!
+static SERVICE_BIND_ADDR: &str = "127.0.0.1:57131";
+static STATE_PATH: &str = "md_persist.state";
+static GRACE_PERIOD: u64 = 2000; // milliseconds
+
+static NETWORK_PROCESS_TIME: u64 = 200; // milliseconds
+static CONNECT_TIMEOUT: u64 = 5000; // milliseconds
+static TCP_READ_TIMEOUT: u64 = 2000; // milliseconds
+
+static OBSERVER_CHANNEL: Channel = 4000;
+static RESUMED_SENDER: Channel = 100;
+static GONE_SENDER: Channel = 200;
+
+#[test]
+fn md_persist_functional_testing() -> std::io::Result<()> {
+    let build_dir: String =
+        env::var("MESON_BUILD_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let src_dir: String =
+        env::var("MESON_SOURCE_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let pwd: String = format!("{}/functional-tests/tests", src_dir);
+    let spawn = || {
+        Command::new(format!("{}/{}", build_dir, DAEMON_BIN))
+            .current_dir(&pwd)
+            .arg(DAEMON_TOML)
+            .spawn()
+            .expect("Donet daemon failed to launch.")
+    };
+
+    // a state left behind by an earlier run would be recovered
+    remove_state(&pwd);
+
+    let mut procs: Vec<Child> = vec![spawn()];
+    let result = panic::catch_unwind(AssertUnwindSafe(|| test_resume(&mut procs, spawn)));
+
+    // A [`Child`] process does not kill itself on drop, so
+    // we kill it manually here, before failing the test.
+    let crashed: bool = procs.last_mut().unwrap().try_wait()?.is_some();
+
+    for donet in &mut procs {
+        donet.kill()?;
+        donet.wait()?;
+    }
+    remove_state(&pwd);
+
+    match result {
+        Ok(result) => result?,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+    assert!(!crashed, "Daemon crashed.");
+    Ok(())
+}
+
+fn test_resume(procs: &mut Vec<Child>, spawn: impl Fn() -> Child) -> std::io::Result<()> {
+    let resumed_session: u64 = 0x1234_5678_9abc_def0;
+    let gone_session: u64 = 0x0fed_cba9_8765_4321;
+
+    let resumed_post_remove: Datagram = msgs::internal(OBSERVER_CHANNEL, RESUMED_SENDER);
+    let gone_post_remove: Datagram = msgs::internal(OBSERVER_CHANNEL, GONE_SENDER);
+
+    // both services name their sessions, and add a post remove
+    let mut resumed: TcpStream = connect()?;
+    let mut gone: TcpStream = connect()?;
+
+    let mut setup: Vec<u8> = msgs::resume(resumed_session);
+    setup.append(&mut msgs::add_post_remove(RESUMED_SENDER, resumed_post_remove));
+    resumed.write_all(&setup)?;
+
+    let mut setup: Vec<u8> = msgs::resume(gone_session);
+    setup.append(&mut msgs::add_post_remove(GONE_SENDER, gone_post_remove.clone()));
+    gone.write_all(&setup)?;
+
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    // kill the daemon before the services disconnect, so it cannot route their post removes
+    let donet: &mut Child = procs.last_mut().unwrap();
+    donet.kill()?;
+    donet.wait()?;
+
+    drop(resumed);
+    drop(gone);
+    procs.push(spawn());
+
+    let mut observer: TcpStream = connect()?;
+    observer.write_all(&msgs::add_channel(OBSERVER_CHANNEL))?;
+
+    // the service that comes back within the grace period is asked to resync
+    let mut resumed: TcpStream = connect()?;
+    resumed.write_all(&msgs::resume(resumed_session))?;
+
+    let resync: Vec<u8> = msgs::resync();
+    assert_eq!(read(&mut resumed, resync.len())?, resync);
+
+    // only the post remove of the service that did not resume is routed
+    let expected: Vec<u8> = msgs::size_tagged(gone_post_remove);
+
+    observer.set_read_timeout(Some(Duration::from_millis(GRACE_PERIOD + TCP_READ_TIMEOUT)))?;
+    assert_eq!(read(&mut observer, expected.len())?, expected);
+
+    observer.set_read_timeout(Some(Duration::from_millis(NETWORK_PROCESS_TIME)))?;
+    match observer.read(&mut [0; 1]) {
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+        other => panic!("Post remove of the resumed service was routed: {:?}", other),
+    }
+    Ok(())
+}
+
+/// Connects to the daemon, once it is listening.
+fn connect() -> std::io::Result<TcpStream> {
+    let start: Instant = Instant::now();
+
+    loop {
+        match TcpStream::connect(SERVICE_BIND_ADDR) {
+            Ok(sock) => {
+                sock.set_read_timeout(Some(Duration::from_millis(TCP_READ_TIMEOUT)))?;
+                return Ok(sock);
+            }
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                assert!(
+                    start.elapsed() < Duration::from_millis(CONNECT_TIMEOUT),
+                    "Daemon did not start listening."
+                );
+                sleep(Duration::from_millis(10));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn read(sock: &mut TcpStream, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf: Vec<u8> = vec![0; len];
+    sock.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Removes the snapshot and journal of the daemon, if any.
+fn remove_state(pwd: &str) {
+    for suffix in ["", ".journal", ".tmp"] {
+        let _ = fs::remove_file(format!("{}/{}{}", pwd, STATE_PATH, suffix));
+    }
+}
+
+mod msgs {
+    use super::*;
+
+    /// Returns an internal message, without a size tag.
+    pub fn internal(recipient: Channel, sender: Channel) -> Datagram {
+        let mut dg = Datagram::default();
+
+        dg.add_internal_header(vec![recipient], sender, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+        dg
+    }
+
+    pub fn size_tagged(datagram: Datagram) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_size(datagram.size() as DgSizeTag).unwrap();
+        dg.add_data(datagram.get_data()).unwrap();
+        dg.get_data()
+    }
+
+    pub fn resume(session: u64) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(Protocol::MDResume.into()).unwrap();
+        dg.add_u64(session).unwrap();
+        size_tagged(dg)
+    }
+
+    pub fn resync() -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(Protocol::MDResync.into()).unwrap();
+        size_tagged(dg)
+    }
+
+    pub fn add_channel(channel: Channel) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(Protocol::MDAddChannel.into()).unwrap();
+        dg.add_channel(channel).unwrap();
+        size_tagged(dg)
+    }
+
+    pub fn add_post_remove(sender: Channel, datagram: Datagram) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(Protocol::MDAddPostRemove.into()).unwrap();
+        dg.add_channel(sender).unwrap();
+        dg.add_blob(datagram.get_data()).unwrap();
+        size_tagged(dg)
+    }
+}
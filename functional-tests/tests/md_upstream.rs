@@ -0,0 +1,236 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Functional testing for the upstream link of the Message
+//! Director service of the Donet server.
+//!
+//! The test plays the upstream MD, and drops the daemon's link to
+//! check that its subscriptions are replayed when it reconnects.
+//! The upstream MD never syncs its interest, so the daemon sends
+//! it every message from its subscribers.
+//!
+//! The TOML configuration file used for the daemon is
+//! located in a file named "md_upstream.toml" in this directory.
+
+use donet_core::datagram::datagram::*;
+use donet_core::globals::*;
+use donet_core::Protocol;
+use std::env;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{Child, Command};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+static DAEMON_BIN: &str = "donetd";
+static DAEMON_TOML: &str = "md_upstream.toml";
+
+/// Must be the same as the ones found in the TOML!
+static SERVICE_BIND_ADDR: &str = "127.0.0.1:57124";
+static UPSTREAM_BIND_ADDR: &str = "127.0.0.1:57125";
+
+static NETWORK_PROCESS_TIME: u64 = 100; // milliseconds
+static CONNECT_TIMEOUT: u64 = 5000; // milliseconds
+static TCP_READ_TIMEOUT: u64 = 2000; // milliseconds
+
+#[test]
+fn md_upstream_functional_testing() -> std::io::Result<()> {
+    let build_dir: String =
+        env::var("MESON_BUILD_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let src_dir: String =
+        env::var("MESON_SOURCE_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    let pwd: String = format!("{}/functional-tests/tests", src_dir);
+
+    // the daemon connects to its upstream MD on startup, so listen first
+    let upstream: TcpListener = TcpListener::bind(UPSTREAM_BIND_ADDR)?;
+    upstream.set_nonblocking(true)?;
+
+    let mut donet: Child = Command::new(format!("{}/{}", build_dir, DAEMON_BIN))
+        .current_dir(pwd)
+        .arg(DAEMON_TOML)
+        .spawn()
+        .expect("Donet daemon failed to launch.");
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| test_reconnect(&upstream)));
+
+    // A [`Child`] process does not kill itself on drop, so
+    // we kill it manually here, before failing the test.
+    let crashed: bool = donet.try_wait()?.is_some();
+    donet.kill()?;
+
+    match result {
+        Ok(result) => result?,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+    assert!(!crashed, "Daemon crashed.");
+    Ok(())
+}
+
+fn test_reconnect(upstream: &TcpListener) -> std::io::Result<()> {
+    let mut link: TcpStream = accept(upstream)?;
+    let watch: Vec<u8> = msgs::watch_interest();
+
+    // the daemon resumes its session, then asks for the interest of the rest of the cluster
+    let session: u64 = read_resume(&mut link)?;
+    assert_eq!(read(&mut link, watch.len())?, watch);
+
+    // setup our TCP socket to interact with the MD as a subscriber
+    let mut sock: TcpStream = TcpStream::connect(SERVICE_BIND_ADDR)?;
+
+    let post_remove: Datagram = msgs::internal(555, 99);
+
+    let mut subscriptions: Vec<u8> = msgs::add_channel(1234);
+    subscriptions.append(&mut msgs::add_range(5000..6000));
+    subscriptions.append(&mut msgs::add_post_remove(99, post_remove));
+
+    // the subscriptions are forwarded to the upstream MD
+    sock.write_all(&subscriptions)?;
+    assert_eq!(read(&mut link, subscriptions.len())?, subscriptions);
+
+    // drop the link, and route a message upstream while it is down
+    drop(link);
+    sleep(Duration::from_millis(NETWORK_PROCESS_TIME));
+
+    let routed: Vec<u8> = msgs::size_tagged(msgs::internal(777, 1337));
+    sock.write_all(&routed)?;
+
+    // the new link resumes the same session, and gets our
+    // subscriptions, then the buffered message
+    let mut link: TcpStream = accept(upstream)?;
+    assert_eq!(read_resume(&mut link)?, session);
+
+    let mut expected: Vec<u8> = watch;
+    expected.extend_from_slice(&subscriptions);
+    expected.extend_from_slice(&routed);
+
+    assert_eq!(read(&mut link, expected.len())?, expected);
+    Ok(())
+}
+
+/// Waits for the daemon to connect to our upstream MD socket.
+fn accept(upstream: &TcpListener) -> std::io::Result<TcpStream> {
+    let start: Instant = Instant::now();
+
+    loop {
+        match upstream.accept() {
+            Ok((sock, _)) => {
+                sock.set_nonblocking(false)?;
+                sock.set_read_timeout(Some(Duration::from_millis(TCP_READ_TIMEOUT)))?;
+                return Ok(sock);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                assert!(
+                    start.elapsed() < Duration::from_millis(CONNECT_TIMEOUT),
+                    "Daemon did not connect upstream."
+                );
+                sleep(Duration::from_millis(10));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn read(sock: &mut TcpStream, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf: Vec<u8> = vec![0; len];
+    sock.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads the `CONTROL_RESUME` that each link starts with, and returns its session.
+fn read_resume(link: &mut TcpStream) -> std::io::Result<u64> {
+    let resume: Vec<u8> = read(link, msgs::resume(0).len())?;
+    let session: u64 = u64::from_le_bytes(resume[resume.len() - 8..].try_into().unwrap());
+
+    assert_eq!(resume, msgs::resume(session));
+    Ok(session)
+}
+
+mod msgs {
+    use super::*;
+
+    /// Returns an internal message, without a size tag.
+    pub fn internal(recipient: Channel, sender: Channel) -> Datagram {
+        let mut dg = Datagram::default();
+
+        dg.add_internal_header(vec![recipient], sender, Protocol::SSObjectSetOwner.into())
+            .unwrap();
+        dg
+    }
+
+    pub fn size_tagged(datagram: Datagram) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_size(datagram.size() as DgSizeTag).unwrap();
+        dg.add_data(datagram.get_data()).unwrap();
+        dg.get_data()
+    }
+
+    pub fn watch_interest() -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(Protocol::MDWatchInterest.into()).unwrap();
+        size_tagged(dg)
+    }
+
+    pub fn resume(session: u64) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        dg.add_control_header(Protocol::MDResume.into()).unwrap();
+        dg.add_u64(session).unwrap();
+        size_tagged(dg)
+    }
+
+    pub fn add_channel(channel: Channel) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        // control header, 1 channel
+        dg.add_size(11 + 8).unwrap();
+        dg.add_control_header(Protocol::MDAddChannel.into()).unwrap();
+
+        dg.add_channel(channel).unwrap();
+        dg.get_data()
+    }
+
+    pub fn add_range(range: std::ops::Range<Channel>) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        // control header, 2 channels
+        dg.add_size(11 + 8 + 8).unwrap();
+        dg.add_control_header(Protocol::MDAddRange.into()).unwrap();
+
+        dg.add_channel(range.start).unwrap();
+        dg.add_channel(range.end).unwrap();
+        dg.get_data()
+    }
+
+    pub fn add_post_remove(sender: Channel, datagram: Datagram) -> Vec<u8> {
+        let mut dg = Datagram::default();
+
+        // control header, 1 channel, 1 blob
+        dg.add_size(11 + 8 + 2 + datagram.size() as DgSizeTag).unwrap();
+        dg.add_control_header(Protocol::MDAddPostRemove.into()).unwrap();
+
+        dg.add_channel(sender).unwrap();
+        dg.add_blob(datagram.get_data()).unwrap();
+        dg.get_data()
+    }
+}
@@ -0,0 +1,194 @@
+/*
+    This file is part of Donet.
+
+    Copyright © 2024 Max Rodriguez <me@maxrdz.com>
+
+    Donet is free software; you can redistribute it and/or modify
+    it under the terms of the GNU Affero General Public License,
+    as published by the Free Software Foundation, either version 3
+    of the License, or (at your option) any later version.
+
+    Donet is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+    GNU Affero General Public License for more details.
+
+    You should have received a copy of the GNU Affero General Public
+    License along with Donet. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Checks that the message types documented in the Donet manual,
+//! the [`Protocol`] enum, and the Wireshark dissector all agree.
+//!
+//! The protocol reference in `docs/protocol/` is the source of truth.
+//! A table of message types is generated from its section headings,
+//! such as `CLIENT_HELLO (1)`, and compared against the others.
+
+use donet_core::globals::MsgType;
+use donet_core::Protocol;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+static DOCS_DIR: &str = "docs/protocol";
+static DISSECTOR: &str = "tools/wireshark/donet_protocol.lua";
+
+/// Maps message type IDs to their name in the protocol reference.
+type MessageTable = BTreeMap<MsgType, String>;
+
+fn source_root() -> PathBuf {
+    let src_dir: String =
+        env::var("MESON_SOURCE_ROOT").expect("Functional tests need to be ran through Meson.");
+
+    PathBuf::from(src_dir)
+}
+
+/// Parses a section heading of the form `NAME (ID)`.
+fn parse_heading(line: &str) -> Option<(MsgType, String)> {
+    let (name, id) = line.trim_end().strip_suffix(')')?.split_once(" (")?;
+
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_uppercase() || c == '_') {
+        return None;
+    }
+    Some((id.parse().ok()?, name.to_owned()))
+}
+
+/// Generates the message table from the headings in every
+/// reStructuredText file in the protocol reference.
+///
+/// Each heading must be preceded by a `.. _ID:` anchor,
+/// which the message tables in `index.rst` link to.
+fn docs_table(root: &Path) -> MessageTable {
+    let mut table: MessageTable = MessageTable::new();
+    let mut rst_files: Vec<PathBuf> = std::fs::read_dir(root.join(DOCS_DIR))
+        .expect("Failed to read protocol docs directory.")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rst"))
+        .collect();
+
+    rst_files.sort();
+
+    for path in rst_files {
+        let text: String = std::fs::read_to_string(&path).unwrap();
+        let mut anchor: Option<MsgType> = None;
+
+        for line in text.lines() {
+            if let Some(id) = line.strip_prefix(".. _").and_then(|l| l.strip_suffix(':')) {
+                anchor = id.parse().ok();
+                continue;
+            }
+            let Some((id, name)) = parse_heading(line) else {
+                continue;
+            };
+            assert_eq!(
+                anchor,
+                Some(id),
+                "{}: heading `{}` does not have a matching `.. _{}:` anchor.",
+                path.display(),
+                line,
+                id
+            );
+            if let Some(other) = table.insert(id, name.clone()) {
+                panic!(
+                    "Message type {} is documented twice, as {} and {}.",
+                    id, other, name
+                );
+            }
+        }
+    }
+    assert!(!table.is_empty(), "No message types found in the protocol docs.");
+    table
+}
+
+/// Generates the message table from the `message_table`
+/// entries in the Wireshark dissector, which are of the form:
+///
+/// ```lua
+/// [1] = {
+///     name="CLIENT_HELLO",
+/// ```
+fn dissector_table(root: &Path) -> MessageTable {
+    let mut table: MessageTable = MessageTable::new();
+    let text: String = std::fs::read_to_string(root.join(DISSECTOR)).unwrap();
+    let mut lines = text.lines().map(str::trim);
+
+    while let Some(line) = lines.next() {
+        let Some(id) = line.strip_prefix('[').and_then(|l| l.strip_suffix("] = {")) else {
+            continue;
+        };
+        let id: MsgType = id.parse().expect("Invalid message type ID in dissector.");
+        let name: &str = lines
+            .next()
+            .and_then(|l| l.strip_prefix("name=\""))
+            .and_then(|l| l.strip_suffix("\","))
+            .unwrap_or_else(|| panic!("Dissector entry {} does not have a name.", id));
+
+        if let Some(other) = table.insert(id, name.to_owned()) {
+            panic!(
+                "Message type {} is in the dissector twice, as {} and {}.",
+                id, other, name
+            );
+        }
+    }
+    table
+}
+
+/// Returns the IDs that are in one set but not the other.
+fn difference<'a>(a: &'a [MsgType], b: &'a [MsgType]) -> Vec<MsgType> {
+    a.iter().filter(|id| !b.contains(id)).copied().collect()
+}
+
+#[test]
+fn protocol_enum_matches_docs() {
+    let docs: MessageTable = docs_table(&source_root());
+
+    let documented: Vec<MsgType> = docs.keys().copied().collect();
+    let defined: Vec<MsgType> = (0..=MsgType::MAX)
+        .filter(|id| Protocol::try_from(*id).is_ok())
+        .collect();
+
+    let undefined: Vec<(MsgType, &String)> = difference(&documented, &defined)
+        .into_iter()
+        .map(|id| (id, &docs[&id]))
+        .collect();
+    let undocumented: Vec<MsgType> = difference(&defined, &documented);
+
+    assert!(
+        undefined.is_empty(),
+        "Documented message types missing from the Protocol enum: {:?}",
+        undefined
+    );
+    assert!(
+        undocumented.is_empty(),
+        "Protocol enum message types missing from the docs: {:?}",
+        undocumented
+    );
+    for (id, _) in docs {
+        let msg_type: Protocol = Protocol::try_from(id).unwrap();
+        assert_eq!(MsgType::from(msg_type), id);
+    }
+}
+
+#[test]
+fn wireshark_dissector_matches_docs() {
+    let root: PathBuf = source_root();
+    let docs: MessageTable = docs_table(&root);
+    let dissector: MessageTable = dissector_table(&root);
+
+    for (id, name) in &docs {
+        assert_eq!(
+            dissector.get(id),
+            Some(name),
+            "Dissector entry for message type {} does not match the docs.",
+            id
+        );
+    }
+    for (id, name) in &dissector {
+        assert!(
+            docs.contains_key(id),
+            "Dissector has message type {} ({}), which is not in the docs.",
+            id,
+            name
+        );
+    }
+}
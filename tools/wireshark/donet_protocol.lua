@@ -130,6 +130,12 @@ local message_table = {
 			return "" -- TODO: Dissect
 		end
 	},
+	[161] = {
+		name="CLIENT_OBJECT_LEAVING_OWNER",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
 	[172] = {
 		name="CLIENT_ENTER_OBJECT_REQUIRED_OWNER",
 		dissector=function(buf, root)
@@ -278,7 +284,7 @@ local message_table = {
 		end
 	},
 	[1111] = {
-		name="CLIENTAGENT_CLEAR_POST_REMOVE",
+		name="CLIENTAGENT_CLEAR_POST_REMOVES",
 		dissector=function(buf, root)
 			return "" -- TODO: Dissect
 		end
@@ -301,12 +307,6 @@ local message_table = {
 			return "" -- TODO: Dissect
 		end
 	},
-	[1204] = {
-		name="CLIENTAGENT_DONE_INTEREST_RESP",
-		dissector=function(buf, root)
-			return "" -- TODO: Dissect
-		end
-	},
 	[2000] = {
 		name="STATESERVER_CREATE_OBJECT_WITH_REQUIRED",
 		dissector=function(buf, root)
@@ -509,6 +509,18 @@ local message_table = {
 			return "" -- TODO: Dissect
 		end
 	},
+	[2064] = {
+		name="STATESERVER_OBJECT_GET_OWNER",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
+	[2065] = {
+		name="STATESERVER_OBJECT_GET_OWNER_RESP",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
 	[2066] = {
 		name="STATESERVER_OBJECT_ENTER_INTEREST_WITH_REQUIRED",
 		dissector=function(buf, root)
@@ -827,6 +839,18 @@ local message_table = {
 			return "" -- TODO: Dissect
 		end
 	},
+	[3028] = {
+		name="DBSERVER_OBJECT_SET_FIELDS_IF_EMPTY",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
+	[3029] = {
+		name="DBSERVER_OBJECT_SET_FIELDS_IF_EMPTY_RESP",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
 	[3030] = {
 		name="DBSERVER_OBJECT_DELETE_FIELD",
 		dissector=function(buf, root)
@@ -899,6 +923,66 @@ local message_table = {
 			return "" -- TODO: Dissect
 		end
 	},
+	[9020] = {
+		name="CONTROL_PEER_HELLO",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
+	[9021] = {
+		name="CONTROL_PEER_FORWARD",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
+	[9022] = {
+		name="CONTROL_WATCH_INTEREST",
+		dissector=function(buf, root)
+			return "" -- No arguments
+		end
+	},
+	[9023] = {
+		name="CONTROL_INTEREST_SYNCED",
+		dissector=function(buf, root)
+			return "" -- No arguments
+		end
+	},
+	[9024] = {
+		name="CONTROL_ADD_TRACE",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
+	[9025] = {
+		name="CONTROL_REMOVE_TRACE",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
+	[9026] = {
+		name="CONTROL_TRACED",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
+	[9027] = {
+		name="CONTROL_AUTHENTICATE",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
+	[9028] = {
+		name="CONTROL_RESUME",
+		dissector=function(buf, root)
+			return "" -- TODO: Dissect
+		end
+	},
+	[9029] = {
+		name="CONTROL_RESYNC",
+		dissector=function(buf, root)
+			return "" -- No arguments
+		end
+	},
 }
 
 -- Adds SRC PORT -> DST PORT prefix to the packet info, similar to